@@ -0,0 +1,5 @@
+	DO .5 <- #1
+	DO READ OUT .5
+	DO .7 <- #65535$#1
+	DO READ OUT .7
+	PLEASE GIVE UP
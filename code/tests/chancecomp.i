@@ -0,0 +1,6 @@
+	DO .2 <- #1
+(1)	DO READ OUT #1
+	PLEASE GIVE UP
+	DO %50 COME FROM .2
+	DO READ OUT #2
+	PLEASE DO (1) NEXT
@@ -0,0 +1,8 @@
+	DO WRITE IN .1
+(1)	DO (9) NEXT
+	DO READ OUT #1
+	PLEASE GIVE UP
+	DO %50 COME FROM (1)
+	DO READ OUT #2
+	PLEASE DO (1) NEXT
+(9)	DO RESUME .1
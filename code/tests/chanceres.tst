@@ -0,0 +1 @@
+ONE
@@ -0,0 +1,16 @@
+	DO WRITE IN .1
+	DO (10) NEXT
+	PLEASE READ OUT #1
+	DO WRITE IN .2
+	DO (30) NEXT
+	DO READ OUT #5
+	PLEASE GIVE UP
+(10)	DO (20) NEXT
+	DO READ OUT #2
+	DO RESUME #1
+(20)	PLEASE READ OUT #3
+	DO RESUME .1
+(30)	PLEASE FORGET .2
+	DO READ OUT #4
+	DO WRITE IN .3
+	PLEASE RESUME .3
@@ -0,0 +1,3 @@
+TWO
+ONE
+ZERO
@@ -0,0 +1,7 @@
+	DO ABSTAIN FROM (1)
+	DO READ OUT #1
+	PLEASE GIVE UP
+(3)	PLEASE RESUME #1
+(2)	DO (3) NEXT
+	DO GIVE UP
+(1)	DO (2) NEXT
@@ -0,0 +1,10 @@
+	DO .1 <- #3
+	DO (10) NEXT
+	DO READ OUT .1
+	PLEASE GIVE UP
+(10)	DO STASH .1
+	DO READ OUT .1
+	PLEASE WRITE IN .1
+	DO READ OUT .1
+	DO RETRIEVE .1
+	PLEASE RESUME #1
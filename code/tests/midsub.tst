@@ -0,0 +1 @@
+FOUR
@@ -0,0 +1,5 @@
+	DO (1) NEXT
+	DO GIVE UP
+(1)	PLEASE READ OUT #1
+	DO (1) NEXT
+	DO RESUME #1
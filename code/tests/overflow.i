@@ -0,0 +1,12 @@
+	DO .1 <- #7
+	DO .2 <- #0
+	PLEASE DO (1040) NEXT
+	DO READ OUT .3
+	DO .1 <- #256
+	DO .2 <- #255
+	PLEASE DO (1030) NEXT
+	DO READ OUT .3
+	DO .2 <- #256
+	PLEASE DO (1030) NEXT
+	DO READ OUT .3
+	PLEASE GIVE UP
@@ -0,0 +1,28 @@
+	DO .1 <- #1
+	DO STASH .1
+	DO .1 <- #2
+	PLEASE DO .5 <- .1
+	DO RETRIEVE .1
+	DO READ OUT .1 + .5
+	DO IGNORE .2
+	PLEASE DO .2 <- #5
+	DO .4 <- .2
+	DO READ OUT .2 + .4
+	DO REMEMBER .2
+	DO .2 <- #6
+	PLEASE READ OUT .2
+	DO :1 <- #3
+	DO :2 <- :1
+	DO STASH :1
+	PLEASE DO :1 <- #4
+	DO RETRIEVE :1
+	DO READ OUT :1 + :2
+	DO IGNORE :1
+	DO :1 <- #7
+	PLEASE DO :2 <- :1
+	DO READ OUT :2
+	DO STASH :2
+	DO :2 <- #8
+	DO RETRIEVE :2
+	PLEASE READ OUT :2
+	DO GIVE UP
@@ -0,0 +1,10 @@
+	DO :1 <- #300
+	DO .1 <- :1
+	DO READ OUT .1
+	PLEASE DO :1 <- '#65535$#0'~'#65535$#0'
+	DO .2 <- :1
+	DO READ OUT .2
+	DO :2 <- #65535$#65535
+	PLEASE DO .3 <- :2
+	DO READ OUT .3
+	PLEASE GIVE UP
@@ -0,0 +1,255 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// Command line arguments of compiled programs.
+///
+/// This module is only part of the runtime library, not of Rick itself (which
+/// only compiles it for its tests).  Every compiled program understands the
+/// following options:
+///
+/// * `--seed N`: seed for the random number generator (for statements with an
+///   execution chance)
+/// * `--trace`: print every statement to stderr before it is executed
+/// * `--steps N`: stop with an error after N statements
+/// * `--input FILE`, `--output FILE`: read from/write to files instead of
+///   stdin/stdout
+/// * `--wimpmode`: read and write numbers in decimal, instead of as spelled
+///   out English digits and Roman numerals
+/// * `--help`
+
+use std::env;
+use std::fs::File;
+use std::io::{ self, BufRead, BufReader, BufWriter, Read, Stdin, Stdout, Write, stdin, stdout };
+use std::process::exit;
+
+use err::{ Res, IE777, IE888, IE980, IE990 };
+
+const USAGE: &'static str = "\
+Options:
+    --seed N        seed the random number generator with N
+    --trace         print each statement to stderr before executing it
+    --steps N       stop with an error after executing N statements
+    --input FILE    read input from FILE instead of stdin
+    --output FILE   write output to FILE instead of stdout
+    --wimpmode      read and write numbers in decimal
+    --error-format FORMAT
+                    print errors as classic text (default) or json
+    -h, --help      print this help message
+";
+
+/// Where the program reads input from.
+pub enum Input {
+    Stdin(BufReader<Stdin>),
+    File(BufReader<File>),
+}
+
+/// Where the program writes output to.
+pub enum Output {
+    Stdout(Stdout),
+    File(BufWriter<File>),
+}
+
+/// Tracing and step counting, checked before each statement.
+pub struct Monitor {
+    /// True if there is anything to do.
+    pub active: bool,
+    trace: bool,
+    steps: u64,
+    limit: Option<u64>,
+}
+
+pub struct Args {
+    pub seed: Option<u32>,
+    pub wimp: bool,
+    pub monitor: Monitor,
+    pub input: Input,
+    pub output: Output,
+    /// True if errors are to be printed as JSON.
+    pub json: bool,
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Input::Stdin(ref mut r) => r.read(buf),
+            Input::File(ref mut r) => r.read(buf),
+        }
+    }
+}
+
+impl BufRead for Input {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match *self {
+            Input::Stdin(ref mut r) => r.fill_buf(),
+            Input::File(ref mut r) => r.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match *self {
+            Input::Stdin(ref mut r) => r.consume(amt),
+            Input::File(ref mut r) => r.consume(amt),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Output::Stdout(ref mut w) => w.write(buf),
+            Output::File(ref mut w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Output::Stdout(ref mut w) => w.flush(),
+            Output::File(ref mut w) => w.flush(),
+        }
+    }
+}
+
+impl Monitor {
+    /// Called before executing a statement.
+    pub fn step(&mut self, line: usize, stmt: &str) -> Res<()> {
+        self.steps += 1;
+        if let Some(limit) = self.limit {
+            if self.steps > limit {
+                return IE980.err_with(None, line);
+            }
+        }
+        if self.trace {
+            let _ = writeln!(io::stderr(), "{:5}: {}", line, stmt);
+        }
+        Ok(())
+    }
+
+    /// Count statements that were already executed by the compiler.
+    pub fn skip(&mut self, steps: u64) {
+        self.steps += steps;
+    }
+}
+
+fn number<T: ::std::str::FromStr>(arg: Option<String>) -> Res<T> {
+    match arg.map(|v| v.parse()) {
+        Some(Ok(v)) => Ok(v),
+        _ => IE990.err(),
+    }
+}
+
+impl Args {
+    /// Parse the arguments given to the program.  For --help, the usage is
+    /// printed and the process exits.
+    pub fn from_env() -> Res<Args> {
+        Args::parse(env::args())
+    }
+
+    /// Parse the given arguments, the first being the program name.
+    pub fn parse<I: Iterator<Item=String>>(mut argv: I) -> Res<Args> {
+        let mut args = Args {
+            seed: None,
+            wimp: false,
+            monitor: Monitor { active: false, trace: false, steps: 0, limit: None },
+            input: Input::Stdin(BufReader::new(stdin())),
+            output: Output::Stdout(stdout()),
+            json: false,
+        };
+        let progname = argv.next().unwrap_or("program".into());
+        while let Some(arg) = argv.next() {
+            // allow --opt=value as well as --opt value
+            let (opt, mut value) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => (arg[..i].to_string(),
+                                                     Some(arg[i+1..].to_string())),
+                _ => (arg.clone(), None),
+            };
+            let takes_value = ["--seed", "--steps", "--input", "--output",
+                               "--error-format"].contains(&&*opt);
+            if takes_value && value.is_none() {
+                value = argv.next();
+                if value.is_none() {
+                    return IE990.err();
+                }
+            } else if !takes_value && value.is_some() {
+                return IE990.err();
+            }
+            match &*opt {
+                "--seed"     => args.seed = Some(try!(number(value))),
+                "--trace"    => args.monitor.trace = true,
+                "--steps"    => args.monitor.limit = Some(try!(number(value))),
+                "--wimpmode" => args.wimp = true,
+                "--input"    => match File::open(value.unwrap()) {
+                    Ok(f)  => args.input = Input::File(BufReader::new(f)),
+                    Err(_) => return IE777.err(),
+                },
+                "--output"   => match File::create(value.unwrap()) {
+                    Ok(f)  => args.output = Output::File(BufWriter::new(f)),
+                    Err(_) => return IE888.err(),
+                },
+                "--error-format" => match &*value.unwrap() {
+                    "classic" => args.json = false,
+                    "json"    => args.json = true,
+                    _         => return IE990.err(),
+                },
+                "-h" | "--help" => {
+                    print!("Usage: {} [options]\n\n{}", progname, USAGE);
+                    exit(0);
+                }
+                _ => return IE990.err(),
+            }
+        }
+        args.monitor.active = args.monitor.trace || args.monitor.limit.is_some();
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Args;
+    use err::Res;
+
+    fn parse(argv: &[&str]) -> Res<Args> {
+        Args::parse(Some("prog").iter().chain(argv).map(|v| v.to_string()))
+    }
+
+    fn error(argv: &[&str]) -> String {
+        parse(argv).err().unwrap().to_code()
+    }
+
+    #[test]
+    fn option_values() {
+        // --opt=value and --opt value mean the same
+        assert_eq!(parse(&["--seed=5"]).unwrap().seed, Some(5));
+        assert_eq!(parse(&["--seed", "5"]).unwrap().seed, Some(5));
+        let args = parse(&["--steps", "100", "--error-format=json", "--wimpmode"]).unwrap();
+        assert_eq!(args.monitor.limit, Some(100));
+        assert!(args.monitor.active && args.json && args.wimp);
+        assert!(!parse(&[]).unwrap().monitor.active);
+    }
+
+    #[test]
+    fn bad_options() {
+        const E990: &'static str = "err::IE990.err_with(None, 0)";
+        // missing value
+        assert_eq!(error(&["--seed"]), E990);
+        assert_eq!(error(&["--trace", "--steps"]), E990);
+        // value that is not expected or not a number
+        assert_eq!(error(&["--trace=yes"]), E990);
+        assert_eq!(error(&["--seed=five"]), E990);
+        assert_eq!(error(&["--error-format", "xml"]), E990);
+        assert_eq!(error(&["--frobnicate"]), E990);
+    }
+}
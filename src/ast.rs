@@ -24,7 +24,8 @@ use std::default::Default;
 use std::fmt::{ Display, Error, Formatter };
 
 use err::RtError;
-use lex::SrcLine;
+use lex::{ SrcLine, Span };
+use stdops::{ Array, Bind };
 
 /// A label
 pub type Label = u16;
@@ -33,7 +34,7 @@ pub type Label = u16;
 pub type LogLine = u16;
 
 /// A whole program, with meta-information used at eval-time.
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Program {
     /// Statements in the program.
     pub stmts: Vec<Stmt>,
@@ -52,10 +53,37 @@ pub struct Program {
     /// The line on which the compiler bug E774 should be triggered.
     /// If this is set to a number >= stmts.len(), the bug is disabled.
     pub bugline: LogLine,
+    /// If the optimizer has already run the beginning of the program, the state
+    /// to start from.
+    pub start: Option<Snapshot>,
+}
+
+/// The state of a program that has been run up to some statement (by the
+/// optimizer, see opt.rs).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Snapshot {
+    /// Index of the statement to continue with.
+    pub pctr: usize,
+    /// Output written so far.
+    pub output: Vec<u8>,
+    /// Number of statements executed so far.
+    pub steps: usize,
+    /// Variables, with their stashes and IGNORE state.
+    pub spot: Vec<Bind<u16>>,
+    pub twospot: Vec<Bind<u32>>,
+    pub tail: Vec<Bind<Array<u16>>>,
+    pub hybrid: Vec<Bind<Array<u32>>>,
+    /// The NEXT stack, as indices of the NEXT statements.
+    pub jumps: Vec<LogLine>,
+    /// Abstain counter for each statement.
+    pub abstain: Vec<u32>,
+    /// Binary I/O "tape" state.
+    pub last_in: u8,
+    pub last_out: u8,
 }
 
 /// A single statement.
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Stmt {
     pub body: StmtBody,
     pub props: StmtProps,
@@ -65,13 +93,15 @@ pub struct Stmt {
 }
 
 /// Common properties for all statements.
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct StmtProps {
     /// Source line of the statement.
     pub srcline: SrcLine,
     /// Source line of the next statement (provides "on the way to") in error
     /// messages.
     pub onthewayto: SrcLine,
+    /// Bytes of the source the statement was parsed from, see cst.rs.
+    pub span: Span,
     /// Label of the line, or zero if no label.
     pub label: Label,
     /// Execution chance in %, usually 100.
@@ -83,11 +113,11 @@ pub struct StmtProps {
 }
 
 /// Type-of-statement dependent data.
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum StmtBody {
     /// An undecodable statement ("splat"), resulting in a runtime error when
-    /// executed (and not abstained).
-    Error(RtError),
+    /// executed (and not abstained).  Also keeps the statement's source text.
+    Error(RtError, String),
     /// A calculation ("gets") operation.
     Calc(Var, Expr),
     /// An array dimensioning operation (same syntax as Calc).
@@ -145,13 +175,16 @@ pub enum Expr {
     RsXor(Box<Expr>, Box<Expr>),
     RsRshift(Box<Expr>, Box<Expr>),
     RsLshift(Box<Expr>, Box<Expr>),
-    // RsEqual(Box<Expr>, Box<Expr>),
+    RsEqual(Box<Expr>, Box<Expr>),
     RsNotEqual(Box<Expr>, Box<Expr>),
     RsPlus(Box<Expr>, Box<Expr>),
     RsMinus(Box<Expr>, Box<Expr>),
-    // RsTimes(Box<Expr>, Box<Expr>),
-    // RsDivide(Box<Expr>, Box<Expr>),
-    // RsModulus(Box<Expr>, Box<Expr>),
+    /// Arithmetic like the syslib's: the result must fit into the given width,
+    /// otherwise it is an error (the syslib's "DOUBLE OR SINGLE PRECISION
+    /// OVERFLOW").  Division by zero gives 0, and the remainder is the dividend.
+    RsTimes(VType, Box<Expr>, Box<Expr>),
+    RsDivide(VType, Box<Expr>, Box<Expr>),
+    RsModulus(VType, Box<Expr>, Box<Expr>),
 }
 
 /// Type of an expression, used when the width actually matters.
@@ -162,7 +195,7 @@ pub enum VType {
 }
 
 /// Specification of targets for an ABSTAIN or REINSTATE.
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum Abstain {
     Label(Label),
     Calc,
@@ -182,7 +215,7 @@ pub enum Abstain {
 }
 
 /// Specification of the target for a COME FROM.
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum ComeFrom {
     Label(Label),
     Expr(Expr),
@@ -204,7 +237,7 @@ impl Stmt {
     /// escape value.
     pub fn stype(&self) -> Abstain {
         match self.body {
-            StmtBody::Error(_) => Abstain::Label(0),
+            StmtBody::Error(..) => Abstain::Label(0),
             StmtBody::Calc(..) => Abstain::Calc,
             StmtBody::Dim(..) => Abstain::Calc,
             StmtBody::DoNext(_) => Abstain::Next,
@@ -225,6 +258,141 @@ impl Stmt {
         }
     }
 
+    /// All expressions in the statement, including subscripts, in post-order:
+    /// operands come before their operator, and everything comes in the order
+    /// it appears in the source.
+    pub fn exprs(&self) -> Vec<&Expr> {
+        let mut res = Vec::new();
+        match self.body {
+            StmtBody::Calc(ref var, ref expr) => {
+                var.collect_exprs(&mut res);
+                expr.collect_exprs(&mut res);
+            }
+            StmtBody::Dim(ref var, ref exprs) => {
+                var.collect_exprs(&mut res);
+                for expr in exprs {
+                    expr.collect_exprs(&mut res);
+                }
+            }
+            StmtBody::ComeFrom(ComeFrom::Expr(ref expr)) |
+            StmtBody::Resume(ref expr) |
+            StmtBody::Forget(ref expr) |
+            StmtBody::Abstain(Some(ref expr), _) => expr.collect_exprs(&mut res),
+            StmtBody::Ignore(ref vars) |
+            StmtBody::Remember(ref vars) |
+            StmtBody::Stash(ref vars) |
+            StmtBody::Retrieve(ref vars) |
+            StmtBody::WriteIn(ref vars) => {
+                for var in vars {
+                    var.collect_exprs(&mut res);
+                }
+            }
+            StmtBody::ReadOut(ref exprs) => {
+                for expr in exprs {
+                    expr.collect_exprs(&mut res);
+                }
+            }
+            _ => { }
+        }
+        res
+    }
+
+    /// Walk all references to variables, and call a visitor function for each.
+    /// Variables are visited before the variables in their subscripts.
+    pub fn walk_vars<F>(&mut self, mut visitor: F)
+        where F: FnMut(&mut Var) -> ()
+    {
+        let visitor = &mut visitor;
+
+        fn walk_var<F>(var: &mut Var, visitor: &mut F)
+            where F: FnMut(&mut Var) -> ()
+        {
+            visitor(var);
+            match *var {
+                Var::A16(_, ref mut es) |
+                Var::A32(_, ref mut es) => {
+                    for e in es {
+                        walk_expr(e, visitor);
+                    }
+                }
+                Var::I16(_) |
+                Var::I32(_) => { }
+            }
+        }
+
+        fn walk_expr<F>(expr: &mut Expr, visitor: &mut F)
+            where F: FnMut(&mut Var) -> ()
+        {
+            match *expr {
+                Expr::Var(ref mut v) => walk_var(v, visitor),
+                Expr::And(_, ref mut e) |
+                Expr::Or(_, ref mut e) |
+                Expr::Xor(_, ref mut e) |
+                Expr::RsNot(ref mut e) => walk_expr(e, visitor),
+                Expr::Mingle(ref mut e, ref mut e2) |
+                Expr::Select(_, ref mut e, ref mut e2) |
+                Expr::RsAnd(ref mut e, ref mut e2) |
+                Expr::RsOr(ref mut e, ref mut e2) |
+                Expr::RsXor(ref mut e, ref mut e2) |
+                Expr::RsRshift(ref mut e, ref mut e2) |
+                Expr::RsLshift(ref mut e, ref mut e2) |
+                Expr::RsEqual(ref mut e, ref mut e2) |
+                Expr::RsNotEqual(ref mut e, ref mut e2) |
+                Expr::RsPlus(ref mut e, ref mut e2) |
+                Expr::RsMinus(ref mut e, ref mut e2) |
+                Expr::RsTimes(_, ref mut e, ref mut e2) |
+                Expr::RsDivide(_, ref mut e, ref mut e2) |
+                Expr::RsModulus(_, ref mut e, ref mut e2) => {
+                    walk_expr(e, visitor);
+                    walk_expr(e2, visitor);
+                }
+                Expr::Num(_, _) => { }
+            }
+        }
+
+        match self.body {
+            StmtBody::Calc(ref mut v, ref mut e) => {
+                walk_var(v, visitor);
+                walk_expr(e, visitor);
+            }
+            StmtBody::Dim(ref mut v, ref mut es) => {
+                walk_var(v, visitor);
+                for e in es {
+                    walk_expr(e, visitor);
+                }
+            }
+            StmtBody::Resume(ref mut e) |
+            StmtBody::Forget(ref mut e) => {
+                walk_expr(e, visitor);
+            }
+            StmtBody::Abstain(ref mut maybe_e, _) => {
+                for e in maybe_e.iter_mut() {
+                    walk_expr(e, visitor);
+                }
+            }
+            StmtBody::ComeFrom(ref mut spec) => {
+                if let ComeFrom::Expr(ref mut e) = *spec {
+                    walk_expr(e, visitor);
+                }
+            }
+            StmtBody::Ignore(ref mut vs) |
+            StmtBody::Remember(ref mut vs) |
+            StmtBody::Stash(ref mut vs) |
+            StmtBody::Retrieve(ref mut vs) |
+            StmtBody::WriteIn(ref mut vs) => {
+                for v in vs {
+                    walk_var(v, visitor);
+                }
+            }
+            StmtBody::ReadOut(ref mut es) => {
+                for e in es {
+                    walk_expr(e, visitor);
+                }
+            }
+            _ => { }
+        }
+    }
+
     /// Synthesize a statement with default metadata.
     pub fn new_with(body: StmtBody) -> Stmt {
         Stmt { body: body, props: StmtProps::default(),
@@ -251,14 +419,36 @@ impl Expr {
             Expr::Num(vtype, _) => vtype,
             Expr::And(vtype, _) | Expr::Or(vtype, _) | Expr::Xor(vtype, _) => vtype,
             Expr::Select(vtype, _, _) => vtype,
+            Expr::RsTimes(vtype, _, _) | Expr::RsDivide(vtype, _, _) |
+            Expr::RsModulus(vtype, _, _) => vtype,
             Expr::Mingle(..) => VType::I32,
             Expr::RsAnd(..) | Expr::RsOr(..) | Expr::RsXor(..) |
             Expr::RsNot(..) | Expr::RsRshift(..) | Expr::RsLshift(..) |
-            Expr::RsNotEqual(..) | Expr::RsMinus(..) |
+            Expr::RsEqual(..) | Expr::RsNotEqual(..) | Expr::RsMinus(..) |
             Expr::RsPlus(..) => VType::I32,
             Expr::Var(ref v) => v.get_vtype(),
         }
     }
+
+    fn collect_exprs<'a>(&'a self, res: &mut Vec<&'a Expr>) {
+        match *self {
+            Expr::Num(..) => { }
+            Expr::Var(ref var) => var.collect_exprs(res),
+            Expr::And(_, ref vx) | Expr::Or(_, ref vx) | Expr::Xor(_, ref vx) |
+            Expr::RsNot(ref vx) => vx.collect_exprs(res),
+            Expr::Mingle(ref vx, ref wx) | Expr::Select(_, ref vx, ref wx) |
+            Expr::RsAnd(ref vx, ref wx) | Expr::RsOr(ref vx, ref wx) |
+            Expr::RsXor(ref vx, ref wx) | Expr::RsRshift(ref vx, ref wx) |
+            Expr::RsLshift(ref vx, ref wx) | Expr::RsEqual(ref vx, ref wx) |
+            Expr::RsNotEqual(ref vx, ref wx) | Expr::RsPlus(ref vx, ref wx) |
+            Expr::RsMinus(ref vx, ref wx) | Expr::RsTimes(_, ref vx, ref wx) |
+            Expr::RsDivide(_, ref vx, ref wx) | Expr::RsModulus(_, ref vx, ref wx) => {
+                vx.collect_exprs(res);
+                wx.collect_exprs(res);
+            }
+        }
+        res.push(self);
+    }
 }
 
 impl Var {
@@ -293,6 +483,17 @@ impl Var {
         }
     }
 
+    fn collect_exprs<'a>(&'a self, res: &mut Vec<&'a Expr>) {
+        match *self {
+            Var::A16(_, ref subs) | Var::A32(_, ref subs) => {
+                for sub in subs {
+                    sub.collect_exprs(res);
+                }
+            }
+            _ => { }
+        }
+    }
+
     /// Get the VType for this Var.
     pub fn get_vtype(&self) -> VType {
         match *self {
@@ -314,6 +515,7 @@ impl Default for StmtProps {
         StmtProps { label: 0,
                     srcline: 0,
                     onthewayto: 0,
+                    span: Span::default(),
                     chance: 100,
                     polite: false,
                     disabled: false, }
@@ -360,7 +562,7 @@ impl Display for Stmt {
 impl Display for StmtBody {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
         match *self {
-            StmtBody::Error(ref err) => write!(fmt, "* {}", err.short_string()),
+            StmtBody::Error(ref err, _) => write!(fmt, "* {}", err.short_string()),
             StmtBody::Calc(ref var, ref expr) => write!(fmt, "{} <- {}", var, expr),
             StmtBody::Dim(ref var, ref exprs) => write!(fmt, "{} <- {}", var,
                                                         self.fmt_bylist(exprs)),
@@ -429,10 +631,16 @@ impl Display for Expr {
             Expr::RsXor(ref x, ref y) => write!(fmt, "({} ^ {})", x, y),
             Expr::RsRshift(ref x, ref y) => write!(fmt, "({} >> {})", x, y),
             Expr::RsLshift(ref x, ref y) => write!(fmt, "({} << {})", x, y),
-            // Expr::RsEqual(ref x, ref y) => write!(fmt, "({} == {})", x, y),
+            Expr::RsEqual(ref x, ref y) => write!(fmt, "({} == {})", x, y),
             Expr::RsNotEqual(ref x, ref y) => write!(fmt, "({} != {})", x, y),
             Expr::RsPlus(ref x, ref y) => write!(fmt, "({} + {})", x, y),
             Expr::RsMinus(ref x, ref y) => write!(fmt, "({} - {})", x, y),
+            Expr::RsTimes(t, ref x, ref y) => write!(fmt, "({} *{} {})", x,
+                                                     if t == VType::I16 { "16" } else { "32" }, y),
+            Expr::RsDivide(t, ref x, ref y) => write!(fmt, "({} /{} {})", x,
+                                                      if t == VType::I16 { "16" } else { "32" }, y),
+            Expr::RsModulus(t, ref x, ref y) => write!(fmt, "({} %{} {})", x,
+                                                       if t == VType::I16 { "16" } else { "32" }, y),
         }
     }
 }
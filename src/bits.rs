@@ -0,0 +1,559 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// Bit-level symbolic simplification of expressions.
+///
+/// Where the rules in `idioms.rules` only know about a fixed list of shapes,
+/// this computes the exact function of each bit of an expression, in terms of
+/// the bits of its inputs (variables, and anything else that can't be analyzed).
+/// Each bit is kept in algebraic normal form: the XOR of products of input bits,
+/// which is unique for every boolean function, so that two expressions are
+/// equivalent exactly if their bits are the same.
+///
+/// From these bits, a native expression is built if possible: constants,
+/// shifts, masks and rotations of a single input, bitwise AND/OR/XOR of two
+/// inputs (or several of these, for different bits), and small additions and
+/// (in)equality tests.  The result is accepted if it has the same bits and is
+/// cheaper than the original expression.
+///
+/// The normal form of some functions (e.g. the upper bits of a sum, or equality
+/// of two 16-bit numbers) is too large to compute; in that case, the expression
+/// is left alone.
+
+use std::collections::BTreeSet;
+
+use ast::{ Expr, Var, VType };
+use rules::{ width, operands_mut };
+
+
+/// Maximum number of products in the normal form of one bit.
+const MAX_TERMS: usize = 64;
+
+/// Maximum number of different shift/operation groups in a rebuilt expression.
+const MAX_GROUPS: usize = 4;
+
+/// A boolean function in algebraic normal form: the XOR of products, each given
+/// as the mask of input bits (slots) that are ANDed.  The empty product is 1.
+type Anf = BTreeSet<u64>;
+
+/// The symbolic value of an expression: the function of each of its 32 bits.
+type Bits = Vec<Anf>;
+
+macro_rules! some {
+    ($e:expr) => (match $e { Some(v) => v, None => return None })
+}
+
+
+/// Simplify the expression and its operands, as far as possible.  Returns true
+/// if anything was changed.
+pub fn simplify(expr: &mut Expr) -> bool {
+    if has_intercal_ops(expr) {
+        if let Some(new) = Analyzer::new().rebuild_expr(expr) {
+            if cost(&new) < cost(expr) {
+                *expr = new;
+                return true;
+            }
+        }
+    }
+    let mut changed = false;
+    for arg in operands_mut(expr) {
+        changed |= simplify(arg);
+    }
+    changed
+}
+
+/// Kinds of bits in a rebuilt expression.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Kind {
+    Input,
+    And,
+    Or,
+    Xor,
+}
+
+/// Inputs of the expression being analyzed.
+struct Analyzer {
+    /// Input expressions, with their first slot and their width.
+    inputs: Vec<(Expr, usize, usize)>,
+    /// Number of slots in use.
+    slots: usize,
+}
+
+impl Analyzer {
+    fn new() -> Analyzer {
+        Analyzer { inputs: Vec::new(), slots: 0 }
+    }
+
+    /// Compute the bits of an expression.
+    fn eval(&mut self, expr: &Expr) -> Option<Bits> {
+        let bits = match *expr {
+            Expr::Num(_, v) => constant(v),
+            Expr::Var(_) => return self.input(expr),
+            Expr::Mingle(ref vx, ref wx) => {
+                let v = some!(self.eval(vx));
+                let w = some!(self.eval(wx));
+                // values that don't fit into 16 bits are an error at runtime
+                if !fits16(&v) || !fits16(&w) {
+                    return self.input(expr);
+                }
+                (0..32).map(|i| if i % 2 == 0 { w[i / 2].clone() }
+                                else { v[i / 2].clone() }).collect()
+            }
+            Expr::Select(vtype, ref vx, ref wx) => {
+                let w = some!(self.eval(wx));
+                let mask = match value(&w) {
+                    Some(mask) => mask,
+                    None => return self.input(expr),
+                };
+                if vtype == VType::I16 && mask > 0xFFFF {
+                    return self.input(expr);
+                }
+                let v = some!(self.eval(vx));
+                let mut bits: Bits = (0..32).filter(|&i| mask & (1 << i) != 0)
+                                            .map(|i| v[i].clone()).collect();
+                bits.resize(32, Anf::new());
+                bits
+            }
+            Expr::And(vtype, ref vx) => return self.unary(expr, vtype, vx, Kind::And),
+            Expr::Or(vtype, ref vx) => return self.unary(expr, vtype, vx, Kind::Or),
+            Expr::Xor(vtype, ref vx) => return self.unary(expr, vtype, vx, Kind::Xor),
+            Expr::RsNot(ref vx) => {
+                let v = some!(self.eval(vx));
+                v.iter().map(not).collect()
+            }
+            Expr::RsAnd(ref vx, ref wx) => some!(self.bitwise(vx, wx, Kind::And)),
+            Expr::RsOr(ref vx, ref wx) => some!(self.bitwise(vx, wx, Kind::Or)),
+            Expr::RsXor(ref vx, ref wx) => some!(self.bitwise(vx, wx, Kind::Xor)),
+            Expr::RsRshift(ref vx, box Expr::Num(_, n)) if n < 32 => {
+                let v = some!(self.eval(vx));
+                let mut bits: Bits = v[n as usize..].to_vec();
+                bits.resize(32, Anf::new());
+                bits
+            }
+            Expr::RsLshift(ref vx, box Expr::Num(_, n)) if n < 32 => {
+                let v = some!(self.eval(vx));
+                let mut bits = vec![Anf::new(); n as usize];
+                bits.extend(v[..32 - n as usize].iter().cloned());
+                bits
+            }
+            Expr::RsNotEqual(ref vx, ref wx) => {
+                let v = some!(self.eval(vx));
+                let w = some!(self.eval(wx));
+                match not_equal(&v, &w) {
+                    Some(bits) => bits,
+                    None => return self.input(expr),
+                }
+            }
+            Expr::RsEqual(ref vx, ref wx) => {
+                let v = some!(self.eval(vx));
+                let w = some!(self.eval(wx));
+                match not_equal(&v, &w) {
+                    Some(mut bits) => {
+                        bits[0] = not(&bits[0]);
+                        bits
+                    }
+                    None => return self.input(expr),
+                }
+            }
+            Expr::RsPlus(ref vx, ref wx) => {
+                let v = some!(self.eval(vx));
+                let w = some!(self.eval(wx));
+                match add(&v, &w) {
+                    Some(bits) => bits,
+                    None => return self.input(expr),
+                }
+            }
+            _ => return self.input(expr),
+        };
+        Some(bits)
+    }
+
+    /// Compute the bits of an expression that is taken as an input.
+    fn input(&mut self, expr: &Expr) -> Option<Bits> {
+        let (first, nbits) = match self.inputs.iter().find(|i| i.0 == *expr) {
+            Some(&(_, first, nbits)) => (first, nbits),
+            None => {
+                // the result of a comparison is a single bit
+                let nbits = match *expr {
+                    Expr::RsEqual(..) | Expr::RsNotEqual(..) => 1,
+                    _ => width(expr) as usize,
+                };
+                if self.slots + nbits > 64 {
+                    return None;
+                }
+                self.inputs.push((expr.clone(), self.slots, nbits));
+                self.slots += nbits;
+                (self.slots - nbits, nbits)
+            }
+        };
+        Some((0..32).map(|i| if i < nbits { single(1 << (first + i)) }
+                             else { Anf::new() }).collect())
+    }
+
+    /// Compute the bits of a unary INTERCAL operator: each bit is combined with
+    /// the next one (rotating).
+    fn unary(&mut self, expr: &Expr, vtype: VType, vx: &Expr, kind: Kind) -> Option<Bits> {
+        let v = some!(self.eval(vx));
+        let n = if vtype == VType::I16 { 16 } else { 32 };
+        if n == 16 && !fits16(&v) {
+            return self.input(expr);
+        }
+        let mut bits = Vec::with_capacity(32);
+        for i in 0..n {
+            bits.push(some!(combine(kind, &v[i], &v[(i + 1) % n])));
+        }
+        bits.resize(32, Anf::new());
+        Some(bits)
+    }
+
+    /// Compute the bits of a bitwise Rust operator.
+    fn bitwise(&mut self, vx: &Expr, wx: &Expr, kind: Kind) -> Option<Bits> {
+        let v = some!(self.eval(vx));
+        let w = some!(self.eval(wx));
+        let mut bits = Vec::with_capacity(32);
+        for i in 0..32 {
+            bits.push(some!(combine(kind, &v[i], &w[i])));
+        }
+        Some(bits)
+    }
+
+    /// Find the input and bit of a slot.
+    fn slot(&self, slot: usize) -> (usize, usize) {
+        for (n, &(_, first, nbits)) in self.inputs.iter().enumerate() {
+            if slot >= first && slot < first + nbits {
+                return (n, slot - first);
+            }
+        }
+        unreachable!()
+    }
+
+    /// Build an equivalent native expression, if possible.
+    fn rebuild_expr(&mut self, expr: &Expr) -> Option<Expr> {
+        let bits = some!(self.eval(expr));
+        let candidate = match self.rebuild_bitwise(&bits) {
+            Some(candidate) => candidate,
+            None => some!(self.rebuild_arith(&bits)),
+        };
+        // make sure that the result really is the same, and that no input
+        // that could fail to evaluate (like an array element) was dropped
+        if self.eval(&candidate).as_ref() != Some(&bits) {
+            return None;
+        }
+        for &(ref input, _, _) in &self.inputs {
+            match *input {
+                Expr::Var(Var::I16(_)) | Expr::Var(Var::I32(_)) => (),
+                _ => if !contains(&candidate, input) { return None; },
+            }
+        }
+        Some(candidate)
+    }
+
+    /// Build an expression that combines shifted inputs bitwise.  Bits that are
+    /// computed the same way from the same inputs, shifted by the same amount,
+    /// are collected into one group.
+    fn rebuild_bitwise(&mut self, bits: &Bits) -> Option<Expr> {
+        // group key: kind, input and shift of the first and second operand
+        let mut groups: Vec<((Kind, usize, i32, usize, i32), u32)> = Vec::new();
+        let (mut ones, mut negated) = (0u32, 0u32);
+        for (i, anf) in bits.iter().enumerate() {
+            let bit = 1u32 << i;
+            if anf.contains(&0) {
+                negated |= bit;
+            }
+            let terms: Vec<u64> = anf.iter().cloned().filter(|&t| t != 0).collect();
+            let used = terms.iter().fold(0, |acc, &t| acc | t);
+            if used == 0 {
+                continue;
+            }
+            let (a, b) = (used.trailing_zeros() as usize, 63 - used.leading_zeros() as usize);
+            let (kind, second) = if a == b {
+                (Kind::Input, a)
+            } else if used.count_ones() > 2 {
+                return None;
+            } else {
+                let (ta, tb, tab) = (1u64 << a, 1u64 << b, (1u64 << a) | (1u64 << b));
+                if terms == [ta, tb] {
+                    (Kind::Xor, b)
+                } else if terms == [tab] {
+                    (Kind::And, b)
+                } else if terms == [ta, tb, tab] {
+                    (Kind::Or, b)
+                } else {
+                    return None;
+                }
+            };
+            let (in1, bit1) = self.slot(a);
+            let (in2, bit2) = self.slot(second);
+            let key = (kind, in1, i as i32 - bit1 as i32, in2, i as i32 - bit2 as i32);
+            match groups.iter().position(|g| g.0 == key) {
+                Some(k) => groups[k].1 |= bit,
+                None => groups.push((key, bit)),
+            }
+        }
+        // constant one bits are negated zeros
+        for (i, anf) in bits.iter().enumerate() {
+            if anf.len() == 1 && anf.contains(&0) {
+                ones |= 1 << i;
+            }
+        }
+        negated &= !ones;
+        if groups.len() > MAX_GROUPS {
+            return None;
+        }
+        let mut result: Option<Expr> = None;
+        for &((kind, in1, shift1, in2, shift2), mask) in &groups {
+            let mut term = self.shifted(in1, shift1);
+            if kind != Kind::Input {
+                let other = self.shifted(in2, shift2);
+                term = match kind {
+                    Kind::And => Expr::RsAnd(box term, box other),
+                    Kind::Or  => Expr::RsOr(box term, box other),
+                    _         => Expr::RsXor(box term, box other),
+                };
+            }
+            // the mask is not needed if the term has no other bits anyway
+            let unmasked = some!(self.eval(&term));
+            if unmasked.iter().enumerate().any(|(i, anf)| mask & (1 << i) == 0 && !anf.is_empty()) {
+                term = Expr::RsAnd(box term, box num(mask));
+            }
+            result = Some(match result {
+                None => term,
+                Some(prev) => Expr::RsOr(box prev, box term),
+            });
+        }
+        if ones != 0 {
+            result = Some(match result {
+                None => num(ones),
+                Some(prev) => Expr::RsOr(box prev, box num(ones)),
+            });
+        }
+        let mut result = some!(result);
+        if negated == 0xFFFFFFFF {
+            result = Expr::RsNot(box result);
+        } else if negated != 0 {
+            result = Expr::RsXor(box result, box num(negated));
+        }
+        Some(result)
+    }
+
+    /// An input, shifted so that bit (i - shift) ends up at bit i.
+    fn shifted(&self, input: usize, shift: i32) -> Expr {
+        let expr = self.inputs[input].0.clone();
+        if shift > 0 {
+            Expr::RsLshift(box expr, box num(shift as u32))
+        } else if shift < 0 {
+            Expr::RsRshift(box expr, box num(-shift as u32))
+        } else {
+            expr
+        }
+    }
+
+    /// Build a sum or an inequality test of the (masked) inputs.
+    fn rebuild_arith(&mut self, bits: &Bits) -> Option<Expr> {
+        // which bits of which inputs are used
+        let used = bits.iter().flat_map(|anf| anf.iter()).fold(0, |acc, &t| acc | t);
+        let mut operands = Vec::new();
+        for &(ref expr, first, nbits) in &self.inputs {
+            let mask = ((used >> first) & ((1 << nbits) - 1)) as u32;
+            if mask == 0 {
+                continue;
+            } else if mask > 0xFFFF {
+                // the sum could overflow
+                return None;
+            } else if mask == ((1u64 << nbits) - 1) as u32 {
+                operands.push(expr.clone());
+            } else {
+                operands.push(Expr::RsAnd(box expr.clone(), box num(mask)));
+            }
+        }
+        if operands.len() > 2 {
+            return None;
+        }
+        // the constant part, i.e. the value if all inputs are zero
+        let base = bits.iter().enumerate().fold(0u32, |acc, (i, anf)| {
+            if anf.contains(&0) { acc | (1 << i) } else { acc }
+        });
+        if base > 0xFFFF {
+            return None;
+        }
+        let mut candidates = Vec::new();
+        if operands.len() == 2 {
+            let (v, w) = (&operands[0], &operands[1]);
+            candidates.push(Expr::RsEqual(box v.clone(), box w.clone()));
+            candidates.push(Expr::RsNotEqual(box v.clone(), box w.clone()));
+            let sum = Expr::RsPlus(box v.clone(), box w.clone());
+            candidates.push(if base != 0 { Expr::RsPlus(box sum, box num(base)) } else { sum });
+        } else if operands.len() == 1 && base != 0 {
+            candidates.push(Expr::RsPlus(box operands[0].clone(), box num(base)));
+            candidates.push(Expr::RsEqual(box operands[0].clone(), box num(base)));
+            candidates.push(Expr::RsNotEqual(box operands[0].clone(), box num(base)));
+        } else if operands.len() == 1 {
+            candidates.push(Expr::RsNotEqual(box operands[0].clone(), box num(0)));
+        }
+        for candidate in candidates {
+            if self.eval(&candidate).as_ref() == Some(bits) {
+                return Some(candidate);
+            }
+        }
+        None
+    }
+}
+
+
+fn num(v: u32) -> Expr {
+    Expr::Num(VType::I32, v)
+}
+
+fn single(term: u64) -> Anf {
+    let mut anf = Anf::new();
+    anf.insert(term);
+    anf
+}
+
+/// The bits of a constant.
+fn constant(v: u32) -> Bits {
+    (0..32).map(|i| if v & (1 << i) != 0 { single(0) } else { Anf::new() }).collect()
+}
+
+/// The value of constant bits.
+fn value(bits: &Bits) -> Option<u32> {
+    let mut v = 0;
+    for (i, anf) in bits.iter().enumerate() {
+        if anf.len() == 1 && anf.contains(&0) {
+            v |= 1 << i;
+        } else if !anf.is_empty() {
+            return None;
+        }
+    }
+    Some(v)
+}
+
+fn fits16(bits: &Bits) -> bool {
+    bits[16..].iter().all(|anf| anf.is_empty())
+}
+
+fn xor(a: &Anf, b: &Anf) -> Anf {
+    a.symmetric_difference(b).cloned().collect()
+}
+
+fn not(a: &Anf) -> Anf {
+    xor(a, &single(0))
+}
+
+fn and(a: &Anf, b: &Anf) -> Option<Anf> {
+    let mut res = Anf::new();
+    for &x in a {
+        for &y in b {
+            // x AND x = x, so products are unions of the input bits
+            let t = x | y;
+            if !res.remove(&t) {
+                res.insert(t);
+            }
+        }
+    }
+    if res.len() > MAX_TERMS { None } else { Some(res) }
+}
+
+fn combine(kind: Kind, a: &Anf, b: &Anf) -> Option<Anf> {
+    match kind {
+        Kind::And => and(a, b),
+        Kind::Or => and(a, b).map(|ab| xor(&xor(a, b), &ab)),
+        _ => Some(xor(a, b)),
+    }
+}
+
+/// The bits of a 32-bit sum (ripple-carry).
+fn add(v: &Bits, w: &Bits) -> Option<Bits> {
+    let mut carry = Anf::new();
+    let mut bits = Vec::with_capacity(32);
+    for i in 0..32 {
+        let half = xor(&v[i], &w[i]);
+        bits.push(xor(&half, &carry));
+        if i < 31 {
+            // carry is the majority of the three bits
+            carry = xor(&some!(and(&v[i], &w[i])), &some!(and(&carry, &half)));
+        }
+    }
+    Some(bits)
+}
+
+/// The bits of an inequality test: 1 if any bit differs.
+fn not_equal(v: &Bits, w: &Bits) -> Option<Bits> {
+    let mut same = single(0);
+    for i in 0..32 {
+        same = some!(and(&same, &not(&xor(&v[i], &w[i]))));
+    }
+    let mut bits = vec![not(&same)];
+    bits.resize(32, Anf::new());
+    Some(bits)
+}
+
+fn has_intercal_ops(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Num(..) | Expr::Var(..) => false,
+        Expr::Mingle(..) | Expr::Select(..) | Expr::And(..) |
+        Expr::Or(..) | Expr::Xor(..) => true,
+        Expr::RsNot(ref vx) => has_intercal_ops(vx),
+        Expr::RsAnd(ref vx, ref wx) | Expr::RsOr(ref vx, ref wx) |
+        Expr::RsXor(ref vx, ref wx) | Expr::RsRshift(ref vx, ref wx) |
+        Expr::RsLshift(ref vx, ref wx) | Expr::RsEqual(ref vx, ref wx) |
+        Expr::RsNotEqual(ref vx, ref wx) | Expr::RsPlus(ref vx, ref wx) |
+        Expr::RsMinus(ref vx, ref wx) | Expr::RsTimes(_, ref vx, ref wx) |
+        Expr::RsDivide(_, ref vx, ref wx) | Expr::RsModulus(_, ref vx, ref wx) =>
+            has_intercal_ops(vx) || has_intercal_ops(wx),
+    }
+}
+
+/// Check if the expression contains the given subexpression.
+fn contains(expr: &Expr, sub: &Expr) -> bool {
+    if expr == sub {
+        return true;
+    }
+    match *expr {
+        Expr::Num(..) | Expr::Var(..) => false,
+        Expr::And(_, ref vx) | Expr::Or(_, ref vx) | Expr::Xor(_, ref vx) |
+        Expr::RsNot(ref vx) => contains(vx, sub),
+        Expr::Mingle(ref vx, ref wx) | Expr::Select(_, ref vx, ref wx) |
+        Expr::RsAnd(ref vx, ref wx) | Expr::RsOr(ref vx, ref wx) |
+        Expr::RsXor(ref vx, ref wx) | Expr::RsRshift(ref vx, ref wx) |
+        Expr::RsLshift(ref vx, ref wx) | Expr::RsEqual(ref vx, ref wx) |
+        Expr::RsNotEqual(ref vx, ref wx) | Expr::RsPlus(ref vx, ref wx) |
+        Expr::RsMinus(ref vx, ref wx) | Expr::RsTimes(_, ref vx, ref wx) |
+        Expr::RsDivide(_, ref vx, ref wx) | Expr::RsModulus(_, ref vx, ref wx) =>
+            contains(vx, sub) || contains(wx, sub),
+    }
+}
+
+/// A rough estimate of the cost of evaluating an expression; the INTERCAL
+/// operators are implemented with loops or several native operations.
+fn cost(expr: &Expr) -> usize {
+    match *expr {
+        Expr::Num(..) | Expr::Var(..) => 0,
+        Expr::Mingle(ref vx, ref wx) | Expr::Select(_, ref vx, ref wx) =>
+            8 + cost(vx) + cost(wx),
+        Expr::And(_, ref vx) | Expr::Or(_, ref vx) | Expr::Xor(_, ref vx) => 4 + cost(vx),
+        Expr::RsNot(ref vx) => 1 + cost(vx),
+        Expr::RsAnd(ref vx, ref wx) | Expr::RsOr(ref vx, ref wx) |
+        Expr::RsXor(ref vx, ref wx) | Expr::RsRshift(ref vx, ref wx) |
+        Expr::RsLshift(ref vx, ref wx) | Expr::RsEqual(ref vx, ref wx) |
+        Expr::RsNotEqual(ref vx, ref wx) | Expr::RsPlus(ref vx, ref wx) |
+        Expr::RsMinus(ref vx, ref wx) => 1 + cost(vx) + cost(wx),
+        Expr::RsTimes(_, ref vx, ref wx) => 2 + cost(vx) + cost(wx),
+        Expr::RsDivide(_, ref vx, ref wx) | Expr::RsModulus(_, ref vx, ref wx) =>
+            4 + cost(vx) + cost(wx),
+    }
+}
@@ -0,0 +1,195 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// A cache of compiled executables.
+///
+/// Running rustc is by far the slowest part of compiling an INTERCAL program,
+/// so finished executables are kept in the cache directory (see runtime.rs),
+/// under a key that is a hash of everything that goes into them: the source,
+/// the parts of the parsed program chosen at random (the line of the compiler
+/// bug), all options that influence code generation and rustc, the rustc
+/// version and the Rick executable itself (which contains the included
+/// libraries and the code generator).  Hashing the whole executable would take
+/// longer than many compiles, so only its size and modification time go in.
+///
+/// Every entry is a directory named after the key, containing the executable
+/// and a file with the name of the input.  The latter is rewritten whenever the
+/// entry is used, so that its modification time says when it was last needed.
+
+use std::env;
+use std::fs::{ File, copy, create_dir_all, read_dir, remove_dir_all, rename };
+use std::io::{ Read, Write };
+use std::path::PathBuf;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use rand;
+
+use err::{ Res, IE888 };
+use runtime::{ cache_dir, fnv1a, FNV_INIT };
+
+const EXE_NAME:  &'static str = "program";
+const INFO_NAME: &'static str = "input";
+/// Prefix of entries that are still being written.
+const TMP_PREFIX: &'static str = "tmp-";
+
+pub struct Cache {
+    dir: PathBuf,
+}
+
+/// Information about a cache entry.
+pub struct Entry {
+    pub key: String,
+    pub input: String,
+    pub size: u64,
+    pub age: Duration,
+}
+
+/// Compute a cache key from all parts that contribute to an executable.
+pub fn key(parts: &[&[u8]]) -> String {
+    let exe = env::current_exe().and_then(|path| path.metadata()).map(|meta| {
+        let mtime = meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                        .unwrap_or(Duration::from_secs(0));
+        format!("{} {}.{:09}", meta.len(), mtime.as_secs(), mtime.subsec_nanos())
+    }).unwrap_or(String::new());
+    let mut hash = fnv1a(FNV_INIT, exe.as_bytes());
+    for part in parts {
+        // include the length so that parts can't run into each other
+        hash = fnv1a(hash, format!("{}:", part.len()).as_bytes());
+        hash = fnv1a(hash, part);
+    }
+    format!("{:016x}", hash)
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache { dir: cache_dir().join("builds") }
+    }
+
+    /// Copy the executable for the key to the given place, if it is cached.
+    pub fn fetch(&self, key: &str, infile: &str, exename: &str) -> bool {
+        let entry = self.dir.join(key);
+        if copy(entry.join(EXE_NAME), exename).is_err() {
+            return false;
+        }
+        // mark as used
+        if let Ok(mut f) = File::create(entry.join(INFO_NAME)) {
+            let _ = f.write_all(infile.as_bytes());
+        }
+        true
+    }
+
+    /// Put a freshly built executable into the cache.
+    pub fn store(&self, key: &str, infile: &str, exename: &str) -> Res<()> {
+        // build the entry under a temporary name and move it into place, so
+        // that nobody ever sees a half-written executable
+        let tmp = self.dir.join(format!("{}{:08x}", TMP_PREFIX, rand::random::<u32>()));
+        if create_dir_all(&tmp).is_err() || copy(exename, tmp.join(EXE_NAME)).is_err() {
+            let _ = remove_dir_all(&tmp);
+            return IE888.err();
+        }
+        let written = File::create(tmp.join(INFO_NAME))
+            .and_then(|mut f| f.write_all(infile.as_bytes()));
+        if written.is_err() {
+            let _ = remove_dir_all(&tmp);
+            return IE888.err();
+        }
+        if rename(&tmp, self.dir.join(key)).is_err() {
+            // somebody else was faster
+            let _ = remove_dir_all(&tmp);
+        }
+        Ok(())
+    }
+
+    /// List all (complete) entries of the cache, most recently used first.
+    pub fn entries(&self) -> Vec<Entry> {
+        let mut entries = vec![];
+        let dir = match read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(_)  => return entries,
+        };
+        let now = SystemTime::now();
+        for item in dir {
+            let path = match item {
+                Ok(item) => item.path(),
+                Err(_)   => continue,
+            };
+            let key = path.file_name().unwrap().to_string_lossy().into_owned();
+            // another rick may be storing this one right now
+            if key.starts_with(TMP_PREFIX) {
+                continue;
+            }
+            let mut input = String::new();
+            if let Ok(mut f) = File::open(path.join(INFO_NAME)) {
+                let _ = f.read_to_string(&mut input);
+            }
+            let age = path.join(INFO_NAME).metadata().and_then(|m| m.modified()).ok()
+                .and_then(|t| now.duration_since(t).ok()).unwrap_or(Duration::from_secs(0));
+            let size = path.join(EXE_NAME).metadata().map(|m| m.len()).unwrap_or(0);
+            entries.push(Entry { key: key, input: input, size: size, age: age });
+        }
+        entries.sort_by(|a, b| a.age.cmp(&b.age));
+        entries
+    }
+
+    /// Remove all entries that were not used within the given time, and return
+    /// the number of entries and bytes freed.
+    pub fn prune(&self, max_age: Duration) -> (usize, u64) {
+        let mut removed = (0, 0);
+        for entry in self.entries() {
+            if entry.age >= max_age && remove_dir_all(self.dir.join(&entry.key)).is_ok() {
+                removed.0 += 1;
+                removed.1 += entry.size;
+            }
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{ File, create_dir_all, remove_dir_all };
+    use std::time::Duration;
+
+    use rand;
+
+    use super::{ Cache, key, EXE_NAME, INFO_NAME, TMP_PREFIX };
+
+    #[test]
+    fn key_covers_all_parts() {
+        let base = key(&[b"\tDO GIVE UP\n", b"true [] false"]);
+        assert_eq!(key(&[b"\tDO GIVE UP\n", b"true [] false"]), base);
+        assert!(key(&[b"\tDO GIVE UP\n", b"true [] true"]) != base);
+        assert!(key(&[b"\tDO GIVE UP", b"\ntrue [] false"]) != base);
+    }
+
+    #[test]
+    fn prune_leaves_unfinished_entries() {
+        let dir = env::temp_dir().join(format!("rick-test-{:08x}", rand::random::<u32>()));
+        for name in &["0123456789abcdef".to_string(), format!("{}0000beef", TMP_PREFIX)] {
+            create_dir_all(dir.join(name)).unwrap();
+            File::create(dir.join(name).join(EXE_NAME)).unwrap();
+            File::create(dir.join(name).join(INFO_NAME)).unwrap();
+        }
+        let cache = Cache { dir: dir.clone() };
+        assert_eq!(cache.entries().len(), 1);
+        assert_eq!(cache.prune(Duration::from_secs(0)), (1, 0));
+        assert!(!dir.join("0123456789abcdef").exists());
+        assert!(dir.join(format!("{}0000beef", TMP_PREFIX)).exists());
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,392 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// Recovers subroutines from the NEXT/RESUME structure of a program.
+///
+/// INTERCAL has no subroutines, but nearly every program uses `DO (n) NEXT` as
+/// a call and `RESUME #1` as the matching return.  Here, every label that is
+/// the target of a NEXT is taken as a subroutine entry, and its body is found
+/// by walking the control-flow graph from there, stepping over NEXTs (assuming
+/// they return normally) and stopping at RESUMEs.  Everything that doesn't fit
+/// the pattern is recorded as a non-local exit: RESUME with anything but #1,
+/// and FORGET.
+///
+/// Constant FORGETs are also used to turn NEXTs into gotos, so the height of the
+/// NEXT stack (relative to the subroutine entry) is tracked for every call in
+/// the body.  With that, the maximum depth of the NEXT stack is computed for
+/// every subroutine; it is unbounded if there is recursion that makes the
+/// stack grow.  Computed COME FROMs are ignored.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use ast::{ Program, StmtBody, Expr, Label };
+use cfg::{ Cfg, EdgeKind, Target, may_skip };
+use err::Res;
+
+
+/// Maximum size of the NEXT stack; one more NEXT is error E123.
+pub const MAX_DEPTH: usize = 80;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Subroutine {
+    /// The entry label; 0 for the main program.
+    pub label: Label,
+    /// Index of the entry statement.
+    pub entry: usize,
+    /// Blocks (see cfg.rs) that belong to the subroutine.  A block can belong
+    /// to more than one subroutine.
+    pub blocks: Vec<usize>,
+    /// NEXT statements that call this subroutine.
+    pub callers: Vec<usize>,
+    /// NEXT statements in the body, with the called subroutine's index and
+    /// the NEXT stack height (relative to the entry) before the call.  Since
+    /// a body can FORGET its own entry, the height can be negative.
+    pub calls: Vec<(usize, usize, isize)>,
+    /// RESUME #1 statements that return from the subroutine.
+    pub returns: Vec<usize>,
+    /// RESUME and FORGET statements that leave more than one level.
+    pub nonlocal: Vec<usize>,
+    /// Maximum growth of the NEXT stack while the subroutine is running, or
+    /// None if it is unbounded.
+    pub depth: Option<usize>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CallGraph {
+    /// The main program comes first, then the subroutines by label.
+    pub subs: Vec<Subroutine>,
+}
+
+impl CallGraph {
+    pub fn new(program: &Program, cfg: &Cfg) -> CallGraph {
+        let mut subs = vec![Subroutine::new(0, 0)];
+        let mut index = vec![None; program.stmts.len()];
+        index[0] = Some(0);
+        for stmt in &program.stmts {
+            if let StmtBody::DoNext(n) = stmt.body {
+                if let Some(&j) = program.labels.get(&n) {
+                    let j = j as usize;
+                    if index[j].is_none() && j != 0 {
+                        index[j] = Some(subs.len());
+                        subs.push(Subroutine::new(n, j));
+                    }
+                }
+            }
+        }
+        subs[1..].sort_by(|a, b| a.label.cmp(&b.label));
+        for (i, sub) in subs.iter().enumerate() {
+            index[sub.entry] = Some(i);
+        }
+        for sub in &mut subs {
+            sub.explore(program, cfg, &index);
+        }
+        for i in 0..subs.len() {
+            for j in 0..subs[i].calls.len() {
+                let (stmt, callee, _) = subs[i].calls[j];
+                if !subs[callee].callers.contains(&stmt) {
+                    subs[callee].callers.push(stmt);
+                }
+            }
+        }
+        for sub in &mut subs {
+            sub.callers.sort();
+        }
+        let mut graph = CallGraph { subs: subs };
+        graph.compute_depths();
+        graph
+    }
+
+    /// Compute the maximum NEXT stack depth of all subroutines, as the longest
+    /// path in the call graph.  Subroutines that can reach a cycle that grows
+    /// the stack get an unbounded depth.
+    fn compute_depths(&mut self) {
+        let n = self.subs.len();
+        let mut depth = vec![0isize; n];
+        let mut unbounded = vec![false; n];
+        // after n rounds, only nodes on or before growing cycles still change
+        for round in 0..n + 1 {
+            let mut changed = false;
+            for i in 0..n {
+                for &(_, callee, height) in &self.subs[i].calls {
+                    let d = height + 1 + depth[callee];
+                    if d > depth[i] {
+                        depth[i] = d;
+                        changed = true;
+                        if round == n {
+                            unbounded[i] = true;
+                        }
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        // everything that calls something unbounded is unbounded
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in 0..n {
+                if !unbounded[i] && self.subs[i].calls.iter().any(|&(_, c, _)| unbounded[c]) {
+                    unbounded[i] = true;
+                    changed = true;
+                }
+            }
+        }
+        for i in 0..n {
+            self.subs[i].depth = if unbounded[i] { None } else { Some(depth[i] as usize) };
+        }
+    }
+
+    /// Return the subroutines reachable from each subroutine by calls.
+    fn reach(&self) -> Vec<Vec<bool>> {
+        let n = self.subs.len();
+        let mut reach = vec![vec![false; n]; n];
+        for i in 0..n {
+            let mut todo = vec![i];
+            while let Some(j) = todo.pop() {
+                for &(_, c, _) in &self.subs[j].calls {
+                    if !reach[i][c] {
+                        reach[i][c] = true;
+                        todo.push(c);
+                    }
+                }
+            }
+        }
+        reach
+    }
+
+    /// Find the subroutines that are part of a recursion that makes the NEXT
+    /// stack grow, by looking at each set of mutually recursive subroutines
+    /// on its own.
+    fn growing(&self, reach: &[Vec<bool>]) -> Vec<bool> {
+        let n = self.subs.len();
+        let mut growing = vec![false; n];
+        for first in 0..n {
+            // only look at every recursive group once, from its first member
+            let group = (0..n).filter(|&j| reach[first][j] && reach[j][first])
+                              .collect::<Vec<_>>();
+            if group.is_empty() || group[0] != first {
+                continue;
+            }
+            let mut depth = vec![0isize; n];
+            for round in 0..group.len() + 1 {
+                let mut changed = false;
+                for &i in &group {
+                    for &(_, callee, height) in &self.subs[i].calls {
+                        if group.contains(&callee) && height + 1 + depth[callee] > depth[i] {
+                            depth[i] = height + 1 + depth[callee];
+                            changed = true;
+                        }
+                    }
+                }
+                if !changed {
+                    break;
+                }
+                if round == group.len() {
+                    for &i in &group {
+                        growing[i] = true;
+                    }
+                }
+            }
+        }
+        growing
+    }
+
+    /// Find NEXT statements that could overflow the NEXT stack: those that
+    /// grow the stack within a growing recursion, or if there is no such
+    /// recursion, the first one to exceed the limit.
+    pub fn overflows(&self) -> Vec<usize> {
+        let reach = self.reach();
+        let growing = self.growing(&reach);
+        let mut result = Vec::new();
+        for (i, sub) in self.subs.iter().enumerate() {
+            for &(stmt, callee, height) in &sub.calls {
+                if height >= 0 && growing[i] && reach[callee][i] &&
+                    !result.contains(&stmt) {
+                    result.push(stmt);
+                }
+            }
+        }
+        if result.is_empty() {
+            if let Some(depth) = self.subs[0].depth {
+                if depth > MAX_DEPTH {
+                    // follow the deepest path until it goes over the limit
+                    let mut cur = 0;
+                    let mut level = 0;
+                    loop {
+                        let &(stmt, callee, height) = self.subs[cur].calls.iter()
+                            .max_by_key(|&&(_, c, h)| h + self.subs[c].depth.unwrap() as isize)
+                            .unwrap();
+                        level += height + 1;
+                        if level > MAX_DEPTH as isize {
+                            result.push(stmt);
+                            break;
+                        }
+                        cur = callee;
+                    }
+                }
+            }
+        }
+        result.sort();
+        result
+    }
+
+    fn sub_name(&self, i: usize) -> String {
+        if i == 0 { "main".into() } else { format!("({})", self.subs[i].label) }
+    }
+
+    /// Write the call graph as text, one subroutine per paragraph.
+    pub fn write_text(&self, program: &Program, o: &mut Write) -> Res<()> {
+        let lines = |stmts: &[usize]| stmts.iter()
+            .map(|&i| program.stmts[i].props.srcline.to_string())
+            .collect::<Vec<_>>().join(", ");
+        for (i, sub) in self.subs.iter().enumerate() {
+            try!(write!(o, "{} at line {}\n", self.sub_name(i),
+                        program.stmts[sub.entry].props.srcline));
+            if !sub.callers.is_empty() {
+                try!(write!(o, "    called from lines {}\n", lines(&sub.callers)));
+            }
+            let mut callees = sub.calls.iter().map(|&(_, c, _)| c).collect::<Vec<_>>();
+            callees.sort();
+            callees.dedup();
+            if !callees.is_empty() {
+                let names = callees.iter().map(|&c| self.sub_name(c)).collect::<Vec<_>>();
+                try!(write!(o, "    calls {}\n", names.join(", ")));
+            }
+            if !sub.returns.is_empty() {
+                try!(write!(o, "    returns at lines {}\n", lines(&sub.returns)));
+            }
+            if !sub.nonlocal.is_empty() {
+                try!(write!(o, "    non-local exits at lines {}\n", lines(&sub.nonlocal)));
+            }
+            match sub.depth {
+                Some(d) => try!(write!(o, "    maximum NEXT stack depth {}\n", d)),
+                None => try!(write!(o, "    unbounded NEXT stack depth\n")),
+            }
+        }
+        for stmt in self.overflows() {
+            try!(write!(o, "warning: NEXT at line {} could overflow the NEXT stack \
+                            (more than {} entries)\n",
+                        program.stmts[stmt].props.srcline, MAX_DEPTH));
+        }
+        Ok(())
+    }
+
+    /// Write the call graph in Graphviz DOT format.
+    pub fn write_dot(&self, program: &Program, o: &mut Write) -> Res<()> {
+        try!(write!(o, "digraph calls {{\n"));
+        try!(write!(o, "  node [shape=box];\n"));
+        for (i, sub) in self.subs.iter().enumerate() {
+            let depth = match sub.depth {
+                Some(d) => d.to_string(),
+                None => "unbounded".into(),
+            };
+            try!(write!(o, "  s{} [label=\"{}\\nline {}\\ndepth {}\"{}];\n", i,
+                        self.sub_name(i), program.stmts[sub.entry].props.srcline, depth,
+                        if sub.nonlocal.is_empty() { "" } else { ", style=dashed" }));
+        }
+        for (i, sub) in self.subs.iter().enumerate() {
+            let mut callees = BTreeMap::new();
+            for &(_, c, h) in &sub.calls {
+                let height = callees.entry(c).or_insert(h);
+                if h > *height {
+                    *height = h;
+                }
+            }
+            for (c, height) in callees {
+                // calls that don't grow the stack are drawn as gotos
+                let attrs = if height < 0 { " [style=dotted]" } else { "" };
+                try!(write!(o, "  s{} -> s{}{};\n", i, c, attrs));
+            }
+        }
+        try!(write!(o, "}}\n"));
+        Ok(())
+    }
+}
+
+impl Subroutine {
+    fn new(label: Label, entry: usize) -> Subroutine {
+        Subroutine { label: label, entry: entry, blocks: vec![], callers: vec![],
+                     calls: vec![], returns: vec![], nonlocal: vec![], depth: Some(0) }
+    }
+
+    /// Find the blocks and interesting statements of the subroutine, and the
+    /// (maximum) NEXT stack height at the start of every block.
+    fn explore(&mut self, program: &Program, cfg: &Cfg, index: &[Option<usize>]) {
+        let mut height: Vec<Option<isize>> = vec![None; cfg.blocks.len()];
+        let entry = cfg.block_of[self.entry];
+        height[entry] = Some(0);
+        let mut todo = vec![entry];
+        while let Some(b) = todo.pop() {
+            let block = &cfg.blocks[b];
+            let mut h = height[b].unwrap();
+            for stmt in &program.stmts[block.start..block.end] {
+                if let StmtBody::Forget(Expr::Num(_, k)) = stmt.body {
+                    if !may_skip(stmt) {
+                        h -= k as isize;
+                    }
+                }
+            }
+            for edge in block.succs.iter().chain(&block.returns) {
+                match (edge.kind, edge.target) {
+                    (EdgeKind::Next, _) | (EdgeKind::Resume, _) => { }
+                    (_, Target::Block(t)) => {
+                        if height[t].map_or(true, |old| h > old) {
+                            height[t] = Some(h);
+                            todo.push(t);
+                        }
+                    }
+                    (_, Target::Exit) => { }
+                }
+            }
+        }
+        self.blocks = (0..height.len()).filter(|&b| height[b].is_some()).collect();
+        for &b in &self.blocks {
+            let block = &cfg.blocks[b];
+            let mut h = height[b].unwrap();
+            for i in block.start..block.end {
+                let stmt = &program.stmts[i];
+                match stmt.body {
+                    StmtBody::DoNext(n) => {
+                        if let Some(&j) = program.labels.get(&n) {
+                            if let Some(callee) = index[j as usize] {
+                                self.calls.push((i, callee, h));
+                            }
+                        }
+                    }
+                    StmtBody::Resume(Expr::Num(_, 1)) => self.returns.push(i),
+                    StmtBody::Resume(_) => self.nonlocal.push(i),
+                    StmtBody::Forget(Expr::Num(_, 0)) => { }
+                    StmtBody::Forget(Expr::Num(_, k)) => {
+                        if !may_skip(stmt) {
+                            h -= k as isize;
+                        }
+                        // forgetting below the entry is leaving the subroutine
+                        if h < 0 {
+                            self.nonlocal.push(i);
+                        }
+                    }
+                    // a computed FORGET can always do that
+                    StmtBody::Forget(_) => self.nonlocal.push(i),
+                    _ => { }
+                }
+            }
+        }
+    }
+}
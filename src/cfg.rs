@@ -0,0 +1,349 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// Builds a control-flow graph of an INTERCAL program.
+///
+/// INTERCAL has no shortage of ways to transfer control: NEXT jumps to a label
+/// and pushes the NEXT stack, RESUME pops it and returns after one of the NEXTs,
+/// COME FROM snatches control after the statement with a label (or of a type),
+/// TRY AGAIN starts over, and every statement may be ABSTAINed from or have an
+/// execution chance.  The graph built here models all of these, conservatively
+/// where the target is not known before runtime:
+///
+/// * RESUME can return to every statement following a NEXT.
+/// * A computed COME FROM can come from every statement with a label.
+/// * A statement that may not execute (it is, or can be, abstained from, or it
+///   has a chance below 100%) also continues with the next statement.
+///
+/// Errors at runtime are not modeled, except for splats which always error out
+/// when executed.  The graph can be written in Graphviz DOT format.
+
+use std::io::Write;
+
+use ast::{ Program, Stmt, StmtBody, ComeFrom };
+use err::Res;
+
+
+/// Why control can go from one block to another.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EdgeKind {
+    /// Normal execution continues with the next statement.
+    Fall,
+    /// The last statement of the block may not execute.
+    Skip,
+    /// A NEXT jump.
+    Next,
+    /// A RESUME returning to a statement after a NEXT.
+    Resume,
+    /// A COME FROM with a label or gerund.
+    ComeFrom,
+    /// A computed COME FROM.
+    ComputedComeFrom,
+    /// TRY AGAIN back to the first statement.
+    TryAgain,
+    /// GIVE UP, error or falling off the end.
+    Exit,
+}
+
+/// Target of an edge.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Target {
+    Block(usize),
+    Exit,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    pub target: Target,
+}
+
+/// A basic block: a range of statements that, once entered at the first one,
+/// are executed in order (skipping abstained statements).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Block {
+    /// Index of the first statement.
+    pub start: usize,
+    /// Index after the last statement.
+    pub end: usize,
+    pub succs: Vec<Edge>,
+    /// For a block ending in a NEXT: where execution continues when that NEXT
+    /// is RESUMEd to.
+    pub returns: Vec<Edge>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+    /// Maps statement index to block index.
+    pub block_of: Vec<usize>,
+    /// The block where execution starts (not the first one if the program
+    /// starts from a snapshot).
+    pub entry: usize,
+}
+
+/// Can the statement be skipped when control reaches it?
+pub fn may_skip(stmt: &Stmt) -> bool {
+    stmt.props.disabled || stmt.can_abstain || stmt.props.chance < 100
+}
+
+/// Can the statement be executed at all?
+pub fn may_run(stmt: &Stmt) -> bool {
+    (!stmt.props.disabled || stmt.can_abstain) && stmt.props.chance > 0
+}
+
+impl Cfg {
+    pub fn new(program: &Program) -> Cfg {
+        let stmts = &program.stmts;
+        let nstmts = stmts.len();
+        let complex = program.uses_complex_comefrom;
+        let computed = stmts.iter().enumerate().filter_map(|(i, s)| match s.body {
+            StmtBody::ComeFrom(ComeFrom::Expr(_)) => Some(i),
+            _ => None,
+        }).collect::<Vec<_>>();
+
+        // find the block leaders: the first statement, every jump target, and
+        // every statement after one that can transfer control elsewhere
+        let mut leader = vec![false; nstmts + 1];
+        leader[0] = true;
+        let start = program.start.as_ref().map_or(0, |start| start.pctr);
+        leader[start] = true;
+        for (i, stmt) in stmts.iter().enumerate() {
+            if let StmtBody::ComeFrom(_) = stmt.body {
+                leader[i] = true;
+            }
+            if stmt.props.label > 0 {
+                leader[i] = true;
+            }
+            if ends_block(stmt, complex) {
+                leader[i + 1] = true;
+            }
+        }
+        let mut blocks = Vec::new();
+        let mut block_of = vec![0; nstmts];
+        for i in 0..nstmts {
+            if leader[i] {
+                blocks.push(Block { start: i, end: i, succs: Vec::new(), returns: Vec::new() });
+            }
+            let n = blocks.len() - 1;
+            blocks[n].end = i + 1;
+            block_of[i] = n;
+        }
+
+        // statements that follow a NEXT, i.e. where a RESUME can return to
+        let mut returns = stmts.iter().enumerate().filter_map(|(i, s)| match s.body {
+            StmtBody::DoNext(_) if may_run(s) => Some(i),
+            _ => None,
+        }).collect::<Vec<_>>();
+        // a snapshot's NEXTs have run already, even if abstained from now
+        if let Some(ref start) = program.start {
+            for &j in &start.jumps {
+                if !returns.contains(&(j as usize)) {
+                    returns.push(j as usize);
+                }
+            }
+        }
+
+        {
+            let builder = Builder { program: program, block_of: &block_of, computed: &computed };
+            for block in &mut blocks {
+                let last = block.end - 1;
+                let stmt = &stmts[last];
+                let mut succs = Vec::new();
+                if !is_jump(stmt) {
+                    builder.after(last, EdgeKind::Fall, &mut succs);
+                } else if may_run(stmt) {
+                    match stmt.body {
+                        StmtBody::DoNext(n) => {
+                            match program.labels.get(&n) {
+                                Some(&j) => succs.push(builder.edge(EdgeKind::Next, j as usize)),
+                                None => succs.push(Edge { kind: EdgeKind::Exit,
+                                                          target: Target::Exit }),
+                            }
+                        }
+                        StmtBody::Resume(_) => {
+                            for &j in &returns {
+                                builder.after(j, EdgeKind::Resume, &mut succs);
+                            }
+                        }
+                        StmtBody::TryAgain => succs.push(builder.edge(EdgeKind::TryAgain, 0)),
+                        StmtBody::GiveUp | StmtBody::Error(..) => {
+                            succs.push(Edge { kind: EdgeKind::Exit, target: Target::Exit });
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+                if is_jump(stmt) && may_skip(stmt) {
+                    builder.after(last, EdgeKind::Skip, &mut succs);
+                }
+                // remove duplicates, keeping the first (most specific) kind
+                let mut unique: Vec<Edge> = Vec::new();
+                for edge in succs {
+                    if !unique.iter().any(|e| e.target == edge.target) {
+                        unique.push(edge);
+                    }
+                }
+                block.succs = unique;
+                if let StmtBody::DoNext(_) = stmt.body {
+                    builder.after(last, EdgeKind::Fall, &mut block.returns);
+                }
+            }
+        }
+        Cfg { entry: block_of[start], blocks: blocks, block_of: block_of }
+    }
+
+    /// Return which blocks can be reached from the start of the program.
+    ///
+    /// A RESUME can only return after a NEXT that was executed, so instead of
+    /// following RESUME edges, the return edges of reachable NEXTs are followed
+    /// (and of the NEXTs that are already on the stack in a snapshot).
+    pub fn reachable(&self, program: &Program) -> Vec<bool> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut todo = vec![self.entry];
+        if let Some(ref start) = program.start {
+            for &j in &start.jumps {
+                for edge in &self.blocks[self.block_of[j as usize]].returns {
+                    if let Target::Block(k) = edge.target {
+                        todo.push(k);
+                    }
+                }
+            }
+        }
+        while let Some(i) = todo.pop() {
+            if i >= seen.len() || seen[i] {
+                continue;
+            }
+            seen[i] = true;
+            let block = &self.blocks[i];
+            for edge in block.succs.iter().chain(&block.returns) {
+                if edge.kind == EdgeKind::Resume {
+                    continue;
+                }
+                if let Target::Block(j) = edge.target {
+                    todo.push(j);
+                }
+            }
+        }
+        seen
+    }
+
+    /// Write the graph in Graphviz DOT format.
+    pub fn write_dot(&self, program: &Program, o: &mut Write) -> Res<()> {
+        try!(write!(o, "digraph program {{\n"));
+        try!(write!(o, "  node [shape=box, fontname=monospace];\n"));
+        try!(write!(o, "  exit [shape=doublecircle, label=\"exit\"];\n"));
+        for (i, block) in self.blocks.iter().enumerate() {
+            let first = &program.stmts[block.start];
+            let last = &program.stmts[block.end - 1];
+            let mut label = if first.props.srcline == last.props.srcline {
+                format!("block {} (line {})\\l", i, first.props.srcline)
+            } else {
+                format!("block {} (lines {}-{})\\l", i, first.props.srcline,
+                        last.props.srcline)
+            };
+            for stmt in &program.stmts[block.start..block.end] {
+                label.push_str(&dot_escape(&stmt.to_string()));
+                label.push_str("\\l");
+            }
+            try!(write!(o, "  b{} [label=\"{}\"];\n", i, label));
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            for edge in &block.succs {
+                let target = match edge.target {
+                    Target::Block(j) => format!("b{}", j),
+                    Target::Exit => "exit".into(),
+                };
+                let attrs = match edge.kind {
+                    EdgeKind::Fall => "",
+                    EdgeKind::Skip => " [style=dashed]",
+                    EdgeKind::Next => " [label=\"NEXT\", color=blue]",
+                    EdgeKind::Resume => " [label=\"RESUME\", style=dotted, color=blue]",
+                    EdgeKind::ComeFrom => " [label=\"COME FROM\", color=red]",
+                    EdgeKind::ComputedComeFrom =>
+                        " [label=\"COME FROM?\", style=dotted, color=red]",
+                    EdgeKind::TryAgain => " [label=\"TRY AGAIN\"]",
+                    EdgeKind::Exit => "",
+                };
+                try!(write!(o, "  b{} -> {}{};\n", i, target, attrs));
+            }
+        }
+        try!(write!(o, "}}\n"));
+        Ok(())
+    }
+}
+
+struct Builder<'a> {
+    program: &'a Program,
+    block_of: &'a Vec<usize>,
+    computed: &'a Vec<usize>,
+}
+
+impl<'a> Builder<'a> {
+    fn edge(&self, kind: EdgeKind, stmt: usize) -> Edge {
+        Edge { kind: kind, target: Target::Block(self.block_of[stmt]) }
+    }
+
+    /// Add the edges for continuing normally after statement `i`, which
+    /// includes being taken away by a COME FROM.  When returning there with
+    /// RESUME, all edges are RESUME edges.
+    fn after(&self, i: usize, kind: EdgeKind, succs: &mut Vec<Edge>) {
+        let stmts = &self.program.stmts;
+        let resume = kind == EdgeKind::Resume;
+        let mut certain = false;
+        if let Some(j) = stmts[i].comefrom {
+            let j = j as usize;
+            if may_run(&stmts[j]) {
+                succs.push(self.edge(if resume { kind } else { EdgeKind::ComeFrom }, j));
+                certain = !may_skip(&stmts[j]);
+            }
+        }
+        if self.program.uses_complex_comefrom && stmts[i].props.label > 0 {
+            for &j in self.computed {
+                if may_run(&stmts[j]) {
+                    succs.push(self.edge(if resume { kind } else { EdgeKind::ComputedComeFrom },
+                                         j));
+                }
+            }
+        }
+        if !certain {
+            if i + 1 < stmts.len() {
+                succs.push(self.edge(kind, i + 1));
+            } else {
+                succs.push(Edge { kind: if resume { kind } else { EdgeKind::Exit },
+                                  target: Target::Exit });
+            }
+        }
+    }
+}
+
+/// Does the statement transfer control somewhere else than the next statement?
+fn is_jump(stmt: &Stmt) -> bool {
+    match stmt.body {
+        StmtBody::DoNext(_) | StmtBody::Resume(_) | StmtBody::TryAgain |
+        StmtBody::GiveUp | StmtBody::Error(..) => true,
+        _ => false,
+    }
+}
+
+/// Must a block end after this statement?
+fn ends_block(stmt: &Stmt, complex_comefrom: bool) -> bool {
+    is_jump(stmt) || stmt.comefrom.is_some() || (complex_comefrom && stmt.props.label > 0)
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\l")
+}
@@ -24,22 +24,41 @@
 ///
 /// All of the INTERCAL statements are generated into one monstrous function with a
 /// big switch over the current logical line (pctr is the "program counter").  This
-/// function is called from main(), which does not much else.
+/// function is called from main(), which does not much else.  The switch has one
+/// arm for every basic block (see cfg.rs), since execution can only start at the
+/// beginning of a block; inside the arm, statements simply follow each other.
+///
+/// Subroutines (see callgraph.rs) that are well-behaved enough are additionally
+/// generated as Rust functions of their own, and NEXTing to them is a native
+/// call.  Well-behaved means that they only return with RESUME #1, only call
+/// other such subroutines, can't be COME FROM anywhere inside, and have no
+/// control-flow statements that can be ABSTAINed from.  Since these functions
+/// need access to all variables, all program state lives in a struct.
 ///
 /// A lot of the generated code is similar to what eval.rs does at runtime, but most
-/// of the common code lives in stdops.rs.
+/// of the common code lives in stdops.rs, which is part of the runtime library the
+/// generated program is linked with (see runtime.rs).
+///
+/// Checks that a value fits into 16 bits are only generated where width.rs
+/// can't prove that it does.
 
+use std::collections::BTreeMap;
+use std::fmt::Debug;
 use std::fs::File;
 use std::io::{ BufWriter, Write };
 use std::rc::Rc;
 use std::u16;
 
-use ast::{ Program, Stmt, StmtBody, Expr, Var, VType, Abstain, ComeFrom };
+use ast::{ Program, Stmt, StmtBody, Expr, Var, VType, Abstain, ComeFrom, Label };
+use callgraph::CallGraph;
+use cfg::Cfg;
 use err::{ Res, IE129, IE533, IE994 };
 use lex::SrcLine;
+use opt::Optimizer;
+use stdops::{ Array, Bind };
+use width::Widths;
 
-const STDOPS_MOD_STR: &'static str = include_str!("stdops.rs");
-const ERR_MOD_STR:    &'static str = include_str!("err.rs");
+use runtime;
 
 
 pub type WRes = Res<()>;  // write result, always unit
@@ -49,7 +68,15 @@ pub struct Generator {
     debug: bool,
     random: bool,
     o: BufWriter<File>,
+    /// Name of the INTERCAL source file, for error messages.
+    srcname: String,
     line: SrcLine,
+    /// Subroutines generated as functions: entry label to the basic blocks
+    /// (as ranges of statements).
+    native: BTreeMap<Label, Vec<(usize, usize)>>,
+    /// True while generating one of these functions.
+    in_sub: bool,
+    widths: Widths,
 }
 
 /// An ad-hoc way to generate a newline followed by a certain amount of indentation.
@@ -75,28 +102,41 @@ macro_rules! w {
 
 
 impl Generator {
-    pub fn new(program: Program, outfile: File, debug: bool, random: bool) -> Generator {
+    pub fn new(program: Program, outfile: File, srcname: &str, debug: bool,
+               random: bool) -> Generator {
+        let widths = Widths::new(&program);
         Generator {
             program: Rc::new(program),
             debug: debug,
             random: random,
             o: BufWriter::new(outfile),
+            srcname: srcname.into(),
             line: 0,
+            native: BTreeMap::new(),
+            in_sub: false,
+            widths: widths,
         }
     }
 
     /// The main (and only) public method of the generator.
     pub fn generate(&mut self) -> WRes {
         let program = self.program.clone();
+        let cfg = Cfg::new(&program);
+        self.native = native_subs(&program, &cfg);
         try!(self.gen_attrs());
-        try!(self.gen_stdmods());
+        try!(self.gen_runtime());
         try!(self.gen_header());
-        try!(self.gen_program_vars());
-        try!(self.gen_loop_header());
-        for (i, stmt) in program.stmts.iter().enumerate() {
-            try!(self.gen_stmt_wrap(i, stmt));
+        try!(self.gen_state());
+        for (&label, blocks) in &self.native.clone() {
+            try!(self.gen_sub(label, blocks));
+        }
+        try!(self.gen_run_header());
+        for block in &cfg.blocks {
+            try!(self.gen_block(block.start, block.end));
         }
         try!(self.gen_loop_footer());
+        try!(self.gen_main_header());
+        try!(self.gen_program_vars());
         try!(self.gen_footer());
         try!(self.o.flush());
         Ok(())
@@ -112,23 +152,62 @@ impl Generator {
         Ok(())
     }
 
-    fn gen_stdmods(&mut self) -> WRes {
-        w!(self.o; "#[allow(dead_code)]\nmod err{{\n{}\n}}\n\n", ERR_MOD_STR);
-        w!(self.o; "#[allow(dead_code)]\nmod stdops{{\n{}\n}}\n\n", STDOPS_MOD_STR);
+    fn gen_runtime(&mut self) -> WRes {
+        w!(self.o; "\n\nextern crate {};\n", runtime::CRATE_NAME);
+        w!(self.o; "\nuse {}::v{}::{{ err, stdops, args }};\n", runtime::CRATE_NAME,
+           runtime::API_VERSION);
+        Ok(())
+    }
+
+    /// Generate a subroutine as a function.  The function has its own loop and
+    /// match over the blocks of the subroutine.
+    fn gen_sub(&mut self, label: Label, blocks: &[(usize, usize)]) -> WRes {
+        let program = self.program.clone();
+        self.in_sub = true;
+        w!(self.o; "\n\n#[allow(unused_mut, unused_parens, unused_variables, \
+                    unused_assignments, unreachable_code)]");
+        w!(self.o; "\nfn sub_{}(s: &mut State) -> err::Res<()> {{", label);
+        w!(self.o, 4; "let mut pctr: usize = {};", program.labels[&label]);
+        try!(self.write("
+    loop {
+        match pctr {"));
+        for &(start, end) in blocks {
+            try!(self.gen_block(start, end));
+        }
+        w!(self.o, 12; "_ => return err::IE633.err(),");
+        try!(self.write("
+        }
+    }
+}"));
+        self.in_sub = false;
+        Ok(())
+    }
+
+    /// Generate a match arm for the basic block of statements start..end.
+    fn gen_block(&mut self, start: usize, end: usize) -> WRes {
+        let program = self.program.clone();
+        w!(self.o, 12; "{} => {{", start);
+        for i in start..end {
+            try!(self.gen_stmt_wrap(i, &program.stmts[i]));
+        }
+        w!(self.o, 16; "pctr = {};", end);
+        w!(self.o, 12; "}}");
         Ok(())
     }
 
     fn gen_stmt_wrap(&mut self, i: usize, stmt: &Stmt) -> WRes {
         self.line = stmt.props.onthewayto;
-        // create a match arm
-        w!(self.o, 12; "/* {} */", stmt);
-        w!(self.o, 12; "{} => {{", i);
+        w!(self.o, 16; "/* {} */", stmt);
+        w!(self.o, 16; "s.line = {};", stmt.props.srcline);
         if self.debug {
             w!(self.o, 16; "println!(\"{{}}\", \"{}\");", stmt);
         }
+        // tracing and step limit, if requested at runtime
+        w!(self.o, 16; "if s.monitor.active {{ tri!(s.monitor.step({}, {:?})); }}",
+           stmt.props.srcline, stmt.to_string());
         // check abstention
         if stmt.can_abstain {
-            w!(self.o, 16; "if abstain[{}] == 0 {{", i);
+            w!(self.o, 16; "if s.abstain[{}] == 0 {{", i);
         } else {
             if stmt.props.disabled {
                 w!(self.o, 16; "if false {{");
@@ -138,12 +217,12 @@ impl Generator {
         }
         // check chance for statement execution
         if stmt.props.chance < 100 {
-            w!(self.o, 18; "let (passed, new_rand_st) = check_chance({}, rand_st);",
+            w!(self.o, 18; "let (passed, new_rand_st) = check_chance({}, s.rand_st);",
                stmt.props.chance);
-            w!(self.o, 18; "rand_st = new_rand_st;");
+            w!(self.o, 18; "s.rand_st = new_rand_st;");
             w!(self.o, 18; "if passed {{");
         }
-        try!(self.gen_stmt(stmt));
+        try!(self.gen_stmt(i, stmt));
         // end of chance check
         if stmt.props.chance < 100 {
             w!(self.o, 18; "}}");
@@ -165,11 +244,11 @@ impl Generator {
             try!(self.gen_comefrom_check(&cand1, &label));
         } else if let Some(next) = stmt.comefrom {
             let chance = self.program.stmts[next as usize].props.chance;
-            w!(self.o, 16; "if abstain[{}] == 0 {{   // COME FROM", next);
+            w!(self.o, 16; "if s.abstain[{}] == 0 {{   // COME FROM", next);
             if chance < 100 {
-                w!(self.o, 18; "let (passed, new_rand_st) = check_chance({}, rand_st);",
+                w!(self.o, 18; "let (passed, new_rand_st) = check_chance({}, s.rand_st);",
                    chance);
-                w!(self.o, 18; "rand_st = new_rand_st;");
+                w!(self.o, 18; "s.rand_st = new_rand_st;");
                 w!(self.o, 18; "if passed {{");
             }
             w!(self.o, 20; "pctr = {};", next);
@@ -179,36 +258,49 @@ impl Generator {
             }
             w!(self.o, 16; "}}");
         }
-        // end of match arm
-        w!(self.o, 16; "pctr += 1;");  // different from i + 1 after Resume
-        w!(self.o, 12; "}}");
         Ok(())
     }
 
-    fn gen_stmt(&mut self, stmt: &Stmt) -> WRes {
+    fn gen_stmt(&mut self, i: usize, stmt: &Stmt) -> WRes {
         match stmt.body {
             StmtBody::DoNext(n) => {
                 let next = match self.program.labels.get(&n) {
                     Some(i) => i,
                     None    => return IE129.err()
                 };
+                if self.native.contains_key(&n) {
+                    // the subroutine's frame is only counted, not pushed onto
+                    // the jumps stack
+                    w!(self.o; "
+                    if s.jumps.len() + s.depth >= 80 {{
+                        return err::IE123.err_with(None, {});
+                    }}
+                    s.depth += 1;
+                    if let Err(mut err) = sub_{}(s) {{
+                        err.push_next({});
+                        return Err(err);
+                    }}
+                    s.depth -= 1;", self.program.stmts[*next as usize].props.srcline, n,
+                   stmt.props.srcline);
+                    return Ok(());
+                }
                 // Jumps are a bit problematic: when we resume, we'd need full
                 // information about the current statement which is not available
                 // at runtime.  Therefore we have to put the comefrom and the
                 // label of the statement on the next stack as well.
                 w!(self.o; "
-                    if jumps.len() >= 80 {{
+                    if s.jumps.len() >= 80 {{
                         return err::IE123.err_with(None, {});
                     }}
-                    jumps.push((pctr, {:?}, {}));
+                    s.jumps.push(({}, {:?}, {}));
                     pctr = {};
                     continue;", self.program.stmts[*next as usize].props.srcline,
-                   stmt.comefrom, stmt.props.label, next);
+                   i, stmt.comefrom, stmt.props.label, next);
             }
             StmtBody::GiveUp => {
                 w!(self.o, 20; "break;");
             }
-            StmtBody::Error(ref e) => {
+            StmtBody::Error(ref e, _) => {
                 w!(self.o, 20; "return {};", e.to_code());
             }
             StmtBody::ComeFrom(_) => {
@@ -217,38 +309,49 @@ impl Generator {
             StmtBody::Dim(ref var, ref exprs) => {
                 try!(self.gen_eval_subs(exprs));
                 match *var {
-                    Var::A16(n, _) => w!(self.o, 20; "try!(a{}.dimension(subs, {}));",
+                    Var::A16(n, _) => w!(self.o, 20; "tri!(s.a{}.dimension(subs, {}));",
                                          n, self.line),
-                    Var::A32(n, _) => w!(self.o, 20; "try!(b{}.dimension(subs, {}));",
+                    Var::A32(n, _) => w!(self.o, 20; "tri!(s.b{}.dimension(subs, {}));",
                                          n, self.line),
                     _ => return IE994.err_with(None, self.line),
                 }
             }
             StmtBody::Calc(ref var, ref expr) => {
                 try!(self.gen_eval_expr(expr));
-                try!(self.gen_assign(var));
+                let checked = !self.widths.fits(var, expr);
+                try!(self.gen_assign(var, checked));
+            }
+            StmtBody::Resume(_) if self.in_sub => {
+                // can only be RESUME #1
+                w!(self.o, 20; "return Ok(());");
             }
             StmtBody::Resume(ref expr) => {
                 try!(self.gen_eval_expr(expr));
                 w!(self.o, 20; "let (old_pctr, comefrom, label) = \
-                   try!(pop_jumps(&mut jumps, val, true, {})).expect(\"uh oh\");", self.line);
+                   tri!(pop_jumps(&mut s.jumps, val, true, {})).expect(\"uh oh\");", self.line);
                 if self.program.uses_complex_comefrom {
                     try!(self.gen_comefrom_check("comefrom", "label"));
                 } else {
-                    // XXX: chance check missing here
-                    w!(self.o, 20; "if let Some(next) = comefrom {{
-                        if abstain[next] == 0 {{
-                            pctr = next;
-                            continue;
-                        }}
-                    }}");
+                    w!(self.o, 20; "if let Some(next) = comefrom {{");
+                    w!(self.o, 24; "if s.abstain[next] == 0 {{");
+                    let chance = try!(self.gen_comefrom_chance("next", 28));
+                    if chance {
+                        w!(self.o, 28; "if passed {{");
+                    }
+                    w!(self.o, 32; "pctr = next;");
+                    w!(self.o, 32; "continue;");
+                    if chance {
+                        w!(self.o, 28; "}}");
+                    }
+                    w!(self.o, 24; "}}");
+                    w!(self.o, 20; "}}");
                 }
                 w!(self.o, 20; "pctr = old_pctr + 1;
                     continue;");
             }
             StmtBody::Forget(ref expr) => {
                 try!(self.gen_eval_expr(expr));
-                w!(self.o, 20; "try!(pop_jumps(&mut jumps, val, false, {}));", self.line);
+                w!(self.o, 20; "tri!(pop_jumps(&mut s.jumps, val, false, {}));", self.line);
             }
             StmtBody::Ignore(ref vars) => {
                 for var in vars {
@@ -267,7 +370,7 @@ impl Generator {
             }
             StmtBody::Retrieve(ref vars) => {
                 for var in vars {
-                    w!(self.o, 20; "try!({}.retrieve({}));",
+                    w!(self.o, 20; "tri!({}.retrieve({}));",
                        Generator::get_varname(var), self.line);
                 }
             }
@@ -292,15 +395,17 @@ impl Generator {
                 for expr in exprs {
                     match *expr {
                         Expr::Var(ref var) if var.is_dim() => {
-                            w!(self.o, 20; "try!({}.readout(&mut stdout, &mut last_out, {}));",
+                            w!(self.o, 20; "tri!({}.readout(&mut s.stdout, &mut s.last_out, {}));",
                                Generator::get_varname(var), self.line);
                         }
                         Expr::Var(_) => {
                             try!(self.gen_eval_expr(expr));
-                            w!(self.o, 20; "try!(write_number(&mut stdout, val, {}));", self.line);
+                            w!(self.o, 20; "tri!(write_number_as(&mut s.stdout, val, s.wimp, {}));",
+                               self.line);
                         }
                         Expr::Num(_, v) => {
-                            w!(self.o, 20; "try!(write_number(&mut stdout, {}, {}));", v, self.line);
+                            w!(self.o, 20; "tri!(write_number_as(&mut s.stdout, {}, s.wimp, {}));",
+                               v, self.line);
                         }
                         _ => return IE994.err_with(None, self.line),
                     };
@@ -309,12 +414,12 @@ impl Generator {
             StmtBody::WriteIn(ref vars) => {
                 for var in vars {
                     if var.is_dim() {
-                        w!(self.o, 20; "try!({}.writein(&mut last_in, {}));",
+                        w!(self.o, 20; "tri!({}.writein_from(&mut s.stdin, &mut s.last_in, {}));",
                            Generator::get_varname(var), self.line);
                     } else {
-                        w!(self.o, 20; "let val = try!(read_number({}));",
+                        w!(self.o, 20; "let val = tri!(read_number_from(&mut s.stdin, s.wimp, {}));",
                            self.line);
-                        try!(self.gen_assign(var));
+                        try!(self.gen_assign(var, true));
                     }
                 }
             }
@@ -323,7 +428,7 @@ impl Generator {
                 w!(self.o, 20; "continue;");
             }
             StmtBody::Print(ref s) => {
-                w!(self.o, 20; "if let Err(_) = stdout.write(&{:?}) {{", s);
+                w!(self.o, 20; "if let Err(_) = s.stdout.write(&{:?}) {{", s);
                 w!(self.o, 24; "return err::IE252.err_with(None, {})", self.line);
                 w!(self.o, 20; "}}");
             }
@@ -345,26 +450,53 @@ impl Generator {
         }
         w!(self.o, 20; "if candidates.len() > 1 {{ return err::IE555.err_with(None, {}); }}",
            self.line);
-        // XXX: chance check missing here
-        w!(self.o, 20; "if candidates.len() == 1 && abstain[candidates[0]] == 0 {{");
-        w!(self.o, 24; "pctr = candidates[0];");
-        w!(self.o, 24; "continue;");
+        w!(self.o, 20; "if candidates.len() == 1 && s.abstain[candidates[0]] == 0 {{");
+        let chance = try!(self.gen_comefrom_chance("candidates[0]", 24));
+        if chance {
+            w!(self.o, 24; "if passed {{");
+        }
+        w!(self.o, 28; "pctr = candidates[0];");
+        w!(self.o, 28; "continue;");
+        if chance {
+            w!(self.o, 24; "}}");
+        }
         w!(self.o, 20; "}}");
         Ok(())
     }
 
+    /// Check the % chance of the COME FROM whose index is only known at
+    /// runtime, setting `passed`.  Returns false (and generates nothing) if no
+    /// COME FROM in the program has a chance.
+    fn gen_comefrom_chance(&mut self, next: &str, indent: usize) -> Res<bool> {
+        let chances = self.program.stmts.iter().enumerate().filter_map(|(i, stmt)| {
+            match stmt.body {
+                StmtBody::ComeFrom(_) if stmt.props.chance < 100 =>
+                    Some(format!("{} => {}, ", i, stmt.props.chance)),
+                _ => None,
+            }
+        }).collect::<String>();
+        if chances.is_empty() {
+            return Ok(false);
+        }
+        w!(self.o, indent; "let (passed, new_rand_st) = check_chance(match {} {{ {}_ => 100 }}, \
+                            s.rand_st);", next, chances);
+        w!(self.o, indent; "s.rand_st = new_rand_st;");
+        Ok(true)
+    }
+
     /// Get the Rust name of the given variable reference.
     fn get_varname(var: &Var) -> String {
         match *var {
-            Var::I16(n) => format!("v{}", n),
-            Var::I32(n) => format!("w{}", n),
-            Var::A16(n, _) => format!("a{}", n),
-            Var::A32(n, _) => format!("b{}", n),
+            Var::I16(n) => format!("s.v{}", n),
+            Var::I32(n) => format!("s.w{}", n),
+            Var::A16(n, _) => format!("s.a{}", n),
+            Var::A32(n, _) => format!("s.b{}", n),
         }
     }
 
     /// Generate an assignment of "val" to the given variable/array element.
-    fn gen_assign(&mut self, var: &Var) -> WRes {
+    /// Without `checked`, the value is known to fit.
+    fn gen_assign(&mut self, var: &Var, checked: bool) -> WRes {
         // if the variable can't be IGNOREd, we can skip the check for it
         let suffix = if match *var {
             Var::I16(n) => self.program.var_info.0[n].can_ignore,
@@ -377,36 +509,34 @@ impl Generator {
                 // yes, we have to check this at this point - INTERCAL has no
                 // real types, so the magnitude of the value is the only
                 // reliable indicator whether we can put it into the variable
-                w!(self.o; "
-                    if val > (std::u16::MAX as u32) {{
-                        return err::IE275.err_with(None, {});
-                    }}", self.line);
-                w!(self.o, 20; "v{}.assign{}(val as u16);", n, suffix);
+                if checked {
+                    try!(self.gen_check_275());
+                }
+                w!(self.o, 20; "s.v{}.assign{}(val as u16);", n, suffix);
             }
-            Var::I32(n) => w!(self.o, 20; "w{}.assign{}(val);", n, suffix),
+            Var::I32(n) => w!(self.o, 20; "s.w{}.assign{}(val);", n, suffix),
             Var::A16(n, ref subs) => {
-                w!(self.o; "
-                    if val > (std::u16::MAX as u32) {{
-                        return err::IE275.err_with(None, {});
-                    }}", self.line);
+                if checked {
+                    try!(self.gen_check_275());
+                }
                 if subs.len() == 1 {
-                    w!(self.o, 20; "try!(a{}.set{}(", n, suffix);
+                    w!(self.o, 20; "tri!(s.a{}.set{}(", n, suffix);
                     try!(self.gen_eval(&subs[0], " as usize"));
                     w!(self.o; ", val as u16, {}));", self.line);
                 } else {
                     try!(self.gen_eval_subs(subs));
-                    w!(self.o, 20; "try!(a{}.set_md{}(subs, val as u16, {}));",
+                    w!(self.o, 20; "tri!(s.a{}.set_md{}(subs, val as u16, {}));",
                        n, suffix, self.line);
                 }
             }
             Var::A32(n, ref subs) => {
                 if subs.len() == 1 {
-                    w!(self.o, 20; "try!(b{}.set{}(", n, suffix);
+                    w!(self.o, 20; "tri!(s.b{}.set{}(", n, suffix);
                     try!(self.gen_eval(&subs[0], " as usize"));
                     w!(self.o; ", val, {}));", self.line);
                 } else {
                     try!(self.gen_eval_subs(subs));
-                    w!(self.o, 20; "try!(b{}.set_md{}(subs, val, {}));",
+                    w!(self.o, 20; "tri!(s.b{}.set_md{}(subs, val, {}));",
                        n, suffix, self.line);
                 }
             }
@@ -417,18 +547,28 @@ impl Generator {
     /// Helper for ABSTAIN.
     fn gen_abstain(&mut self, what: &Abstain, gen: &Fn(String) -> String) -> WRes {
         if let &Abstain::Label(lbl) = what {
-            let idx = self.program.labels[&lbl];
-            w!(self.o, 20; "abstain[{}] = {};", idx, gen(format!("(abstain[{}] as u32)", idx)));
+            if let Some(&idx) = self.program.labels.get(&lbl) {
+                w!(self.o, 20; "s.abstain[{}] = {};", idx, gen(format!("(s.abstain[{}] as u32)", idx)));
+            }
         } else {
             for (i, stype) in self.program.stmt_types.iter().enumerate() {
                 if stype == what {
-                    w!(self.o, 20; "abstain[{}] = {};", i, gen(format!("(abstain[{}] as u32)", i)));
+                    w!(self.o, 20; "s.abstain[{}] = {};", i, gen(format!("(s.abstain[{}] as u32)", i)));
                 }
             }
         }
         Ok(())
     }
 
+    /// Check that "val" fits into a 16-bit variable.
+    fn gen_check_275(&mut self) -> WRes {
+        w!(self.o; "
+                    if val > (std::u16::MAX as u32) {{
+                        return err::IE275.err_with(None, {});
+                    }}", self.line);
+        Ok(())
+    }
+
     /// Evaluate an expression and assign it to "val".
     fn gen_eval_expr(&mut self, expr: &Expr) -> WRes {
         w!(self.o, 20; "let val = ");
@@ -467,9 +607,7 @@ impl Generator {
                     }
                     try!(self.gen_eval(vx, ""));
                 } else {
-                    w!(self.o; "try!(check_ovf(");
-                    try!(self.gen_eval(vx, ""));
-                    w!(self.o; ", {}))", self.line);
+                    try!(self.gen_eval_16(vx));
                 }
                 w!(self.o; ", ");
                 if let box Expr::Num(_, n) = *wx {
@@ -478,9 +616,7 @@ impl Generator {
                     }
                     try!(self.gen_eval(wx, ""));
                 } else {
-                    w!(self.o; "try!(check_ovf(");
-                    try!(self.gen_eval(wx, ""));
-                    w!(self.o; ", {}))", self.line);
+                    try!(self.gen_eval_16(wx));
                 }
                 w!(self.o; "){}", astype);
             }
@@ -489,9 +625,7 @@ impl Generator {
                 try!(self.gen_eval(vx, ""));
                 w!(self.o; ", ");
                 if vtype == VType::I16 {
-                    w!(self.o; "try!(check_ovf(");
-                    try!(self.gen_eval(wx, ""));
-                    w!(self.o; ", {}))", self.line);
+                    try!(self.gen_eval_16(wx));
                 } else {
                     try!(self.gen_eval(wx, ""));
                 }
@@ -531,16 +665,47 @@ impl Generator {
             Expr::RsXor(ref vx, ref wx) => try!(self.gen_binop(vx, wx, "^", astype)),
             Expr::RsRshift(ref vx, ref wx) => try!(self.gen_binop(vx, wx, ">>", astype)),
             Expr::RsLshift(ref vx, ref wx) => try!(self.gen_binop_extrapar(vx, wx, "<<", astype)),
-            // Expr::RsEqual(ref vx, ref wx) => try!(self.gen_binop(
-            //     vx, wx, "==", if astype == "" { " as u32" } else { astype })),
+            Expr::RsEqual(ref vx, ref wx) => try!(self.gen_binop(
+                vx, wx, "==", if astype == "" { " as u32" } else { astype })),
             Expr::RsNotEqual(ref vx, ref wx) => try!(self.gen_binop(
                 vx, wx, "!=", if astype == "" { " as u32" } else { astype })),
             Expr::RsPlus(ref vx, ref wx) => try!(self.gen_binop(vx, wx, "+", astype)),
             Expr::RsMinus(ref vx, ref wx) => try!(self.gen_binop(vx, wx, "-", astype)),
+            Expr::RsTimes(vtype, ref vx, ref wx) => try!(self.gen_arith(vx, wx, "times",
+                                                                        vtype, astype)),
+            Expr::RsDivide(vtype, ref vx, ref wx) => try!(self.gen_arith(vx, wx, "divide",
+                                                                         vtype, astype)),
+            Expr::RsModulus(vtype, ref vx, ref wx) => try!(self.gen_arith(vx, wx, "modulus",
+                                                                          vtype, astype)),
         }
         Ok(())
     }
 
+    /// Evaluate an operand that must fit into 16 bits, with a check unless it is
+    /// known to fit.
+    fn gen_eval_16(&mut self, expr: &Expr) -> WRes {
+        if self.widths.fits16(expr) {
+            try!(self.gen_eval(expr, ""));
+        } else {
+            w!(self.o; "tri!(check_ovf(");
+            try!(self.gen_eval(expr, ""));
+            w!(self.o; ", {}))", self.line);
+        }
+        Ok(())
+    }
+
+    /// Generate arithmetic that can overflow, using the stdops function for
+    /// the result width.
+    fn gen_arith(&mut self, vx: &Expr, wx: &Expr, func: &str, vtype: VType,
+                 astype: &str) -> WRes {
+        w!(self.o; "tri!({}_{}(", func, if vtype == VType::I16 { 16 } else { 32 });
+        try!(self.gen_eval(vx, ""));
+        w!(self.o; ", ");
+        try!(self.gen_eval(wx, ""));
+        w!(self.o; ", {})){}", self.line, astype);
+        Ok(())
+    }
+
     fn gen_binop(&mut self, vx: &Expr, wx: &Expr, op: &str, astype: &str) -> WRes {
         w!(self.o; "(");
         try!(self.gen_eval(vx, ""));
@@ -562,11 +727,11 @@ impl Generator {
     /// Generate variable lookup inside an expression.
     fn gen_lookup(&mut self, var: &Var, astype: &str) -> WRes {
         match *var {
-            Var::I16(n) => w!(self.o; "(v{}.val{})", n,
+            Var::I16(n) => w!(self.o; "(s.v{}.val{})", n,
                               if astype == "" { " as u32" } else { astype }),
-            Var::I32(n) => w!(self.o; "w{}.val{}", n, astype),
+            Var::I32(n) => w!(self.o; "s.w{}.val{}", n, astype),
             Var::A16(n, ref subs) => {
-                w!(self.o; "(try!(a{}.", n);
+                w!(self.o; "(tri!(s.a{}.", n);
                 if subs.len() == 1 {
                     w!(self.o; "get(");
                     try!(self.gen_eval(&subs[0], " as usize"));
@@ -583,7 +748,7 @@ impl Generator {
                 w!(self.o; ", {})){})", self.line, if astype == "" { " as u32" } else { astype });
             }
             Var::A32(n, ref subs) => {
-                w!(self.o; "try!(b{}.", n);
+                w!(self.o; "tri!(s.b{}.", n);
                 if subs.len() == 1 {
                     w!(self.o; "get(");
                     try!(self.gen_eval(&subs[0], " as usize"));
@@ -603,51 +768,147 @@ impl Generator {
         Ok(())
     }
 
-    /// Generates local let-bindings for all the stuff we need to keep track of.
-    fn gen_program_vars(&mut self) -> WRes {
+    /// Generates the struct for all the stuff we need to keep track of.
+    fn gen_state(&mut self) -> WRes {
         let vars = &self.program.var_info;
-        // program counter
-        w!(self.o, 4; "let mut pctr: usize = 0;");
-        // output stream
-        w!(self.o, 4; "let mut stdout = std::io::stdout();");
-        // NEXT stack (80 entries only)
-        w!(self.o, 4; "let mut jumps: Vec<(usize, Option<usize>, u16)> = Vec::with_capacity(80);");
+        w!(self.o; "\n#[allow(dead_code)]\nstruct State {{");
+        // input and output streams, and runtime options
+        w!(self.o, 4; "stdin: args::Input,");
+        w!(self.o, 4; "stdout: args::Output,");
+        w!(self.o, 4; "wimp: bool,");
+        w!(self.o, 4; "monitor: args::Monitor,");
+        // source line of the current statement, for error messages
+        w!(self.o, 4; "line: usize,");
+        // NEXT stack (80 entries only), and number of running subroutine functions
+        w!(self.o, 4; "jumps: Vec<(usize, Option<usize>, u16)>,");
+        w!(self.o, 4; "depth: usize,");
         // current input and output state
-        w!(self.o, 4; "let mut last_in: u8 = 0;");
-        w!(self.o, 4; "let mut last_out: u8 = 0;");
+        w!(self.o, 4; "last_in: u8,");
+        w!(self.o, 4; "last_out: u8,");
         // random number generator state
-        w!(self.o, 4; "let mut rand_st: u32;");
+        w!(self.o, 4; "rand_st: u32,");
         // one binding for each variable used by the program
         for i in 0..vars.0.len() {
-            w!(self.o, 4; "let mut v{}: Bind<u16> = Bind::new(0);", i);
+            w!(self.o, 4; "v{}: Bind<u16>,", i);
         }
         for i in 0..vars.1.len() {
-            w!(self.o, 4; "let mut w{}: Bind<u32> = Bind::new(0);", i);
+            w!(self.o, 4; "w{}: Bind<u32>,", i);
         }
         for i in 0..vars.2.len() {
-            w!(self.o, 4; "let mut a{}: Bind<Array<u16>> = Bind::new(Array::empty());", i);
+            w!(self.o, 4; "a{}: Bind<Array<u16>>,", i);
         }
         for i in 0..vars.3.len() {
-            w!(self.o, 4; "let mut b{}: Bind<Array<u32>> = Bind::new(Array::empty());", i);
+            w!(self.o, 4; "b{}: Bind<Array<u32>>,", i);
         }
-        // list of abstention state for each statement, can initially be 0 or 1
-        w!(self.o, 4; "let mut abstain = [");
+        // abstention state for each statement
+        w!(self.o, 4; "abstain: [u32; {}],", self.program.stmts.len());
+        w!(self.o; "\n}}");
+        // source line of each statement, to show the NEXT stack in errors
+        w!(self.o; "\n#[allow(dead_code)]\nstatic SRCLINES: [usize; {}] = [",
+           self.program.stmts.len());
         for (i, stmt) in self.program.stmts.iter().enumerate() {
-            if i % 24 == 0 {
-                w!(self.o, 7; "");
+            if i % 16 == 0 {
+                w!(self.o, 3; "");
             }
-            w!(self.o; " {},", if stmt.props.disabled { "1" } else { "0" });
+            w!(self.o; " {},", stmt.props.srcline);
         }
-        w!(self.o, 4; "];");
+        w!(self.o; "\n];");
         Ok(())
     }
 
-    fn gen_loop_header(&mut self) -> WRes {
+    /// Generates the initial state, and local bindings for the main loop.
+    fn gen_program_vars(&mut self) -> WRes {
+        let vars = &self.program.var_info;
+        w!(self.o, 4; "let mut state = State {{");
+        w!(self.o, 8; "stdin: args.input,");
+        w!(self.o, 8; "stdout: args.output,");
+        w!(self.o, 8; "wimp: args.wimp,");
+        w!(self.o, 8; "monitor: args.monitor,");
+        w!(self.o, 8; "line: 0,");
+        if let Some(ref start) = self.program.start {
+            // the state reached by the optimizer, see opt_snapshot
+            w!(self.o, 8; "jumps: {{");
+            w!(self.o, 12; "let mut jumps = Vec::with_capacity(80);");
+            for &j in &start.jumps {
+                let stmt = &self.program.stmts[j as usize];
+                w!(self.o, 12; "jumps.push(({}, {:?}, {}));", j, stmt.comefrom, stmt.props.label);
+            }
+            w!(self.o, 12; "jumps");
+            w!(self.o, 8; "}},");
+            w!(self.o, 8; "depth: 0,");
+            w!(self.o, 8; "last_in: {},", start.last_in);
+            w!(self.o, 8; "last_out: {},", start.last_out);
+            w!(self.o, 8; "rand_st: 0,");
+            for (i, bind) in start.spot.iter().enumerate() {
+                w!(self.o, 8; "v{}: {},", i, bind_init(bind, &|v| v.to_string()));
+            }
+            for (i, bind) in start.twospot.iter().enumerate() {
+                w!(self.o, 8; "w{}: {},", i, bind_init(bind, &|v| v.to_string()));
+            }
+            for (i, bind) in start.tail.iter().enumerate() {
+                w!(self.o, 8; "a{}: {},", i, bind_init(bind, &array_init));
+            }
+            for (i, bind) in start.hybrid.iter().enumerate() {
+                w!(self.o, 8; "b{}: {},", i, bind_init(bind, &array_init));
+            }
+        } else {
+            w!(self.o, 8; "jumps: Vec::with_capacity(80),");
+            w!(self.o, 8; "depth: 0,");
+            w!(self.o, 8; "last_in: 0,");
+            w!(self.o, 8; "last_out: 0,");
+            w!(self.o, 8; "rand_st: 0,");
+            for i in 0..vars.0.len() {
+                w!(self.o, 8; "v{}: Bind::new(0),", i);
+            }
+            for i in 0..vars.1.len() {
+                w!(self.o, 8; "w{}: Bind::new(0),", i);
+            }
+            for i in 0..vars.2.len() {
+                w!(self.o, 8; "a{}: Bind::new(Array::empty()),", i);
+            }
+            for i in 0..vars.3.len() {
+                w!(self.o, 8; "b{}: Bind::new(Array::empty()),", i);
+            }
+        }
+        // abstention state for each statement, initially 0 or 1 (any count
+        // after a snapshot)
+        w!(self.o, 8; "abstain: [");
+        for (i, stmt) in self.program.stmts.iter().enumerate() {
+            if i % 24 == 0 {
+                w!(self.o, 11; "");
+            }
+            match self.program.start {
+                Some(ref start) => w!(self.o; " {},", start.abstain[i]),
+                None => w!(self.o; " {},", if stmt.props.disabled { "1" } else { "0" }),
+            }
+        }
+        w!(self.o, 8; "],");
+        w!(self.o, 4; "}};");
         if self.random {
-            w!(self.o, 4; "rand_st = get_random_seed();");
+            w!(self.o, 4; "state.rand_st = args.seed.unwrap_or_else(get_random_seed);");
         } else {
-            w!(self.o, 4; "rand_st = 0;");
+            w!(self.o, 4; "state.rand_st = args.seed.unwrap_or(0);");
+        }
+        if let Some(ref start) = self.program.start {
+            // the statements run by the optimizer count for --steps
+            w!(self.o, 4; "state.monitor.skip({});", start.steps);
+            if !start.output.is_empty() {
+                w!(self.o, 4; "if let Err(_) = state.stdout.write(&{:?}) {{", start.output);
+                w!(self.o, 8; "return err::IE252.err();");
+                w!(self.o, 4; "}}");
+            }
         }
+        Ok(())
+    }
+
+    /// Generates the function running the main program.
+    fn gen_run_header(&mut self) -> WRes {
+        try!(self.write("
+
+#[allow(unused_mut, unused_parens, unused_variables, unused_assignments, unreachable_code)]
+fn run(s: &mut State) -> err::Res<()> {"));
+        let start = self.program.start.as_ref().map_or(0, |start| start.pctr);
+        w!(self.o, 4; "let mut pctr: usize = {};", start);
         self.write("
     loop {
         match pctr {")
@@ -655,7 +916,7 @@ impl Generator {
 
     fn gen_loop_footer(&mut self) -> WRes {
         w!(self.o, 12; "_ => {{");
-        if let StmtBody::TryAgain = self.program.stmts[self.program.stmts.len() - 1].body {
+        if let Some(&Stmt { body: StmtBody::TryAgain, .. }) = self.program.stmts.last() {
             w!(self.o, 16; "break;");
         } else {
             w!(self.o, 16; "return err::IE633.err();");
@@ -664,26 +925,119 @@ impl Generator {
             }
         }
     }
-    Ok(())")
+    Ok(())
+}")
     }
 
     fn gen_header(&mut self) -> WRes {
+        w!(self.o; "\nuse std::io::Write;\nuse {}::v{}::stdops::*;\n", runtime::CRATE_NAME,
+           runtime::API_VERSION);
+        // try! can't be used since "try" is a keyword from the 2018 edition on
         self.write("
-use std::io::Write;
-use stdops::*;
+macro_rules! tri {
+    ($e:expr) => (match $e { Ok(v) => v, Err(e) => return Err(From::from(e)) })
+}
+")
+    }
 
-#[allow(unused_mut, unused_parens, unused_variables, unused_assignments, unreachable_code)]
-fn main_inner() -> err::Res<()> {")
+    fn gen_main_header(&mut self) -> WRes {
+        self.write("
+
+fn main_inner(args: args::Args) -> err::Res<()> {")
     }
 
+    /// Generates the end of main_inner, which runs the program and adds the
+    /// current statement and the NEXT stack to errors, and main.
     fn gen_footer(&mut self) -> WRes {
-        self.write("
+        try!(self.write("
+    if let Err(mut err) = run(&mut state) {
+        if state.line > 0 {
+            err.set_stmt(state.line, None);
+        }
+        for &(pctr, _, _) in state.jumps.iter().rev() {
+            err.push_next(SRCLINES[pctr]);
+        }
+        return Err(err);
+    }
+    Ok(())
 }
 
 fn main() {
-    if let Err(err) = main_inner() {
-        print!(\"{}\", err.to_string());
+    let args = match args::Args::from_env() {
+        Ok(args) => args,
+        Err(err) => err.exit(true),
+    };
+    let json = args.json;
+    if let Err(err) = main_inner(args) {"));
+        w!(self.o, 8; "err.exit_as(true, json, Some({:?}), None);", self.srcname);
+        self.write("
     }
 }\n")
     }
 }
+
+
+/// Rust expression for a variable binding of a snapshot.
+fn bind_init<T>(bind: &Bind<T>, init: &Fn(&T) -> String) -> String {
+    if bind.stack.is_empty() && bind.rw {
+        format!("Bind::new({})", init(&bind.val))
+    } else {
+        let stack = bind.stack.iter().map(init).collect::<Vec<_>>().join(", ");
+        format!("Bind {{ val: {}, stack: vec![{}], rw: {} }}", init(&bind.val), stack, bind.rw)
+    }
+}
+
+/// Rust expression for an array of a snapshot.
+fn array_init<T: Debug>(arr: &Array<T>) -> String {
+    format!("Array {{ dims: vec!{:?}, elems: vec!{:?} }}", arr.dims, arr.elems)
+}
+
+/// Find the subroutines that can be generated as functions, and their blocks.
+fn native_subs(program: &Program, cfg: &Cfg) -> BTreeMap<Label, Vec<(usize, usize)>> {
+    let mut result = BTreeMap::new();
+    // with computed COME FROM, every label is a possible exit
+    if program.uses_complex_comefrom {
+        return result;
+    }
+    let graph = CallGraph::new(program, cfg);
+    let abstainable = Optimizer::abstainable(program);
+    let mut ok = graph.subs.iter().enumerate().map(|(i, sub)| {
+        // the main program is not a subroutine
+        i > 0 && sub.nonlocal.is_empty() && sub.blocks.iter().all(|&b| {
+            let block = &cfg.blocks[b];
+            (block.start..block.end).all(|j| {
+                let stmt = &program.stmts[j];
+                let control = match stmt.body {
+                    StmtBody::ComeFrom(_) | StmtBody::TryAgain | StmtBody::GiveUp => return false,
+                    StmtBody::DoNext(_) | StmtBody::Resume(_) | StmtBody::Forget(_) => true,
+                    _ => false,
+                };
+                stmt.comefrom.is_none() &&
+                    !(control && (abstainable[j] || may_skip_fixed(stmt)))
+            })
+        })
+    }).collect::<Vec<_>>();
+    // subroutines can only call other subroutines that are functions
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in 0..graph.subs.len() {
+            if ok[i] && !graph.subs[i].calls.iter().all(|&(_, c, _)| ok[c]) {
+                ok[i] = false;
+                changed = true;
+            }
+        }
+    }
+    for (i, sub) in graph.subs.iter().enumerate() {
+        if ok[i] {
+            let blocks = sub.blocks.iter().map(|&b| (cfg.blocks[b].start, cfg.blocks[b].end));
+            result.insert(sub.label, blocks.collect());
+        }
+    }
+    result
+}
+
+/// Can the statement be skipped, other than by ABSTAIN?
+fn may_skip_fixed(stmt: &Stmt) -> bool {
+    stmt.props.disabled || stmt.props.chance < 100
+}
@@ -0,0 +1,301 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// Differential testing: runs a program in the plain interpreter, the optimized
+/// interpreter and as an optimized executable, and compares the results.
+///
+/// All three are child processes (of Rick itself, or of the compiled program),
+/// get the same input, use the same random seed (--seed) and no E774 (-b).  Their
+/// output, error code and line, and exit status are compared to those of the
+/// plain interpreter.  For the first difference, the statements leading up to
+/// it are shown: for an error, the last ones executed; for output, the ones up
+/// to the statement that wrote the first differing byte, which is found by
+/// bisecting with `--steps`.  The statements come from `--trace`, which the
+/// interpreter and compiled programs print in the same format.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{ Command, Stdio };
+use std::thread;
+
+use time;
+
+use err::{ Res, IE666, EXIT_COMPILE, EXIT_RUNTIME };
+
+
+/// Number of statements shown before a difference.
+const TRACE_LINES: usize = 10;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Variant {
+    Plain,
+    Optimized,
+    Compiled,
+}
+
+/// The result of running one variant.
+#[derive(PartialEq, Eq)]
+struct Outcome {
+    output: Vec<u8>,
+    /// Error code and line, if the program ended with an error.
+    error: Option<(String, String)>,
+    status: i32,
+}
+
+pub struct Crosscheck {
+    infile: String,
+    input: Vec<u8>,
+    steps: usize,
+    seed: u32,
+    rick: PathBuf,
+    /// The compiled program, which is removed when the Crosscheck is dropped.
+    exe: PathBuf,
+}
+
+impl Variant {
+    fn name(&self) -> &'static str {
+        match *self {
+            Variant::Plain     => "plain",
+            Variant::Optimized => "optimized",
+            Variant::Compiled  => "compiled",
+        }
+    }
+}
+
+impl Outcome {
+    fn describe(&self) -> String {
+        let end = match self.error {
+            Some((ref code, ref line)) => format!("{} in line {}", code, line),
+            None => "no error".into(),
+        };
+        format!("exit status {}, {}, {} bytes of output", self.status, end, self.output.len())
+    }
+}
+
+impl Crosscheck {
+    /// Prepare to check `infile`, with the given input, a limit of `steps`
+    /// statements for each run, and the random `seed` for all of them.
+    pub fn new(infile: &str, input: Vec<u8>, steps: usize, seed: u32) -> Res<Crosscheck> {
+        let rick = match env::current_exe() {
+            Ok(path) => path,
+            Err(_)   => return IE666.err(),
+        };
+        let now = time::get_time();
+        let exe = env::temp_dir().join(format!("rick-crosscheck-{}-{}", now.sec, now.nsec));
+        Ok(Crosscheck { infile: infile.into(), input: input, steps: steps, seed: seed,
+                        rick: rick, exe: exe })
+    }
+
+    /// Run all variants and report the results.  Returns true if they all agree
+    /// (or the program can't be run, or the plain interpreter ran into the step
+    /// limit, so that there is nothing to compare).  Unless `details` is given,
+    /// differences are only counted, not explained.
+    pub fn run(&self, o: &mut Write, details: bool) -> Res<bool> {
+        let plain = try!(self.outcome(Variant::Plain, self.steps, false));
+        try!(writeln!(o, "{:10} {}", "plain:", plain.describe()));
+        if plain.status >= EXIT_COMPILE && plain.status < EXIT_RUNTIME {
+            try!(writeln!(o, "program can't be run, nothing to compare"));
+            return Ok(true);
+        }
+        if plain.error.as_ref().map_or(false, |e| e.0 == "E980") {
+            try!(writeln!(o, "stopped after {} statements, nothing to compare", self.steps));
+            return Ok(true);
+        }
+        let mut same = true;
+        for &variant in &[Variant::Optimized, Variant::Compiled] {
+            if variant == Variant::Compiled && !try!(self.build(o)) {
+                same = false;
+                continue;
+            }
+            let outcome = try!(self.outcome(variant, self.steps, false));
+            try!(writeln!(o, "{:10} {}", format!("{}:", variant.name()), outcome.describe()));
+            if outcome != plain {
+                if details {
+                    try!(self.report(o, variant, &plain, &outcome));
+                }
+                same = false;
+            }
+        }
+        Ok(same)
+    }
+
+    /// The error code with which the plain interpreter ends the program, if any.
+    pub fn plain_error(&self) -> Res<Option<String>> {
+        let plain = try!(self.outcome(Variant::Plain, self.steps, false));
+        Ok(plain.error.map(|e| e.0))
+    }
+
+    /// Compile the optimized executable.  A failure is reported, since it is
+    /// just as much a bug as a wrong result.
+    fn build(&self, o: &mut Write) -> Res<bool> {
+        let out = match Command::new(&self.rick).arg("-bRo").arg("--no-cache")
+                                                .arg("--output").arg(&self.exe)
+                                                .arg(&self.infile).output() {
+            Ok(out) => out,
+            Err(_)  => return IE666.err(),
+        };
+        if !out.status.success() {
+            try!(writeln!(o, "{:10} build failed:", "compiled:"));
+            try!(o.write_all(&out.stderr));
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// The command line to run a variant.
+    fn command(&self, variant: Variant, steps: usize, trace: bool) -> Command {
+        let mut cmd = match variant {
+            Variant::Plain     => Command::new(&self.rick),
+            Variant::Optimized => Command::new(&self.rick),
+            Variant::Compiled  => Command::new(&self.exe),
+        };
+        match variant {
+            Variant::Plain     => { cmd.arg("-ibR").arg(&self.infile); }
+            Variant::Optimized => { cmd.arg("-ibRo").arg(&self.infile); }
+            Variant::Compiled  => { }
+        }
+        cmd.arg("--error-format").arg("json").arg("--steps").arg(steps.to_string())
+           .arg("--seed").arg(self.seed.to_string());
+        if trace {
+            cmd.arg("--trace");
+        }
+        cmd
+    }
+
+    /// Run a variant with the input, and return its outcome and stderr.
+    fn execute(&self, variant: Variant, steps: usize, trace: bool) -> Res<(Outcome, String)> {
+        let mut cmd = self.command(variant, steps, trace);
+        cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(_)    => return IE666.err(),
+        };
+        // write the input from another thread, the program might not read all of it
+        let mut stdin = child.stdin.take().unwrap();
+        let input = self.input.clone();
+        let writer = thread::spawn(move || { let _ = stdin.write_all(&input); });
+        let out = match child.wait_with_output() {
+            Ok(out) => out,
+            Err(_)  => return IE666.err(),
+        };
+        let _ = writer.join();
+        let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+        let error = stderr.lines().filter(|l| l.starts_with('{')).last().map(|l| {
+            (json_field(l, "code").trim_matches('"').to_string(), json_field(l, "line"))
+        });
+        let outcome = Outcome { output: out.stdout, error: error,
+                                status: out.status.code().unwrap_or(-1) };
+        Ok((outcome, stderr))
+    }
+
+    fn outcome(&self, variant: Variant, steps: usize, trace: bool) -> Res<Outcome> {
+        self.execute(variant, steps, trace).map(|res| res.0)
+    }
+
+    /// Report how the outcome of a variant differs from the plain one.
+    fn report(&self, o: &mut Write, variant: Variant, plain: &Outcome, other: &Outcome)
+              -> Res<()> {
+        let pos = plain.output.iter().zip(&other.output).position(|(a, b)| a != b);
+        let pos = match pos {
+            Some(pos) => Some(pos),
+            None if plain.output.len() != other.output.len() =>
+                Some(plain.output.len().min(other.output.len())),
+            None => None,
+        };
+        match pos {
+            Some(pos) => {
+                let line = plain.output[..pos].iter().filter(|&&c| c == b'\n').count() + 1;
+                try!(writeln!(o, "\n{} differs from plain: output byte {} (line {})",
+                              variant.name(), pos, line));
+                for &(v, outcome) in &[(Variant::Plain, plain), (variant, other)] {
+                    try!(writeln!(o, "  {:10} {:?}", format!("{}:", v.name()),
+                                  output_line(&outcome.output, pos)));
+                }
+                for &(v, outcome) in &[(Variant::Plain, plain), (variant, other)] {
+                    // the statement that wrote the byte, or the end of the run
+                    let steps = if outcome.output.len() > pos {
+                        try!(self.find_step(v, pos))
+                    } else {
+                        self.steps
+                    };
+                    try!(self.write_trace(o, v, steps));
+                }
+            }
+            None => {
+                try!(writeln!(o, "\n{} differs from plain: {} instead of {}", variant.name(),
+                              other.describe(), plain.describe()));
+                try!(self.write_trace(o, Variant::Plain, self.steps));
+                try!(self.write_trace(o, variant, self.steps));
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the number of statements after which the variant has written more
+    /// than `pos` bytes.
+    fn find_step(&self, variant: Variant, pos: usize) -> Res<usize> {
+        let (mut lo, mut hi) = (0, self.steps);
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            if try!(self.outcome(variant, mid, false)).output.len() > pos {
+                hi = mid;
+            } else {
+                lo = mid;
+            }
+        }
+        Ok(hi)
+    }
+
+    /// Write the last statements the variant executes within `steps`.
+    fn write_trace(&self, o: &mut Write, variant: Variant, steps: usize) -> Res<()> {
+        let (_, stderr) = try!(self.execute(variant, steps, true));
+        let lines = stderr.lines().filter(|l| !l.starts_with('{')).collect::<Vec<_>>();
+        try!(writeln!(o, "{}, statements up to there:", variant.name()));
+        for line in &lines[lines.len().saturating_sub(TRACE_LINES)..] {
+            try!(writeln!(o, "  {}", line));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Crosscheck {
+    fn drop(&mut self) {
+        // it may not have been built at all
+        let _ = fs::remove_file(&self.exe);
+    }
+}
+
+/// The raw value of a field of a JSON error object (see err.rs), e.g. a
+/// quoted string or a number.
+fn json_field(json: &str, key: &str) -> String {
+    let start = match json.find(&format!("\"{}\":", key)) {
+        Some(i) => i + key.len() + 3,
+        None    => return "null".into(),
+    };
+    let end = json[start..].find(|c| c == ',' || c == '}').map_or(json.len(), |i| start + i);
+    json[start..end].into()
+}
+
+/// The line of output that contains the byte at `pos`, as far as it was written.
+fn output_line(output: &[u8], pos: usize) -> String {
+    let pos = pos.min(output.len());
+    let start = output[..pos].iter().rposition(|&c| c == b'\n').map_or(0, |i| i + 1);
+    let end = output[pos..].iter().position(|&c| c == b'\n').map_or(output.len(), |i| pos + i);
+    String::from_utf8_lossy(&output[start..end]).into_owned()
+}
@@ -0,0 +1,181 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// A lossless concrete syntax tree.
+///
+/// The AST forgets everything about the source except for line numbers.  The
+/// CST keeps the rest: every token with its byte span, the whitespace between
+/// the tokens, and for every statement the spans of the statement itself and of
+/// all its expressions.  Together, the tokens and the whitespace cover the
+/// source completely, so it can be reproduced byte for byte.
+///
+/// Statements are linked to their CST node by the span in their properties;
+/// expressions are linked by their position in the statement (see
+/// `Stmt::exprs`).
+
+use std::borrow::Cow;
+
+use ast::{ Stmt, Expr };
+use lex::{ Token, Span, SrcLine, SrcPos };
+
+
+/// An element of the source: a token or the whitespace between tokens.
+#[derive(PartialEq, Eq, Debug)]
+#[allow(dead_code)]  // only used by tools and in tests
+pub enum Elem<'a> {
+    Token(&'a Token),
+    Trivia(Span),
+}
+
+/// The concrete syntax of a statement.
+#[derive(PartialEq, Eq, Debug)]
+pub struct StmtNode {
+    /// From the first token (the label, if any) to the last.
+    pub span: Span,
+    /// True if the statement could not be parsed.  Comments are splats too.
+    pub splat: bool,
+    /// The spans of all expressions, in the order of `Stmt::exprs`.  Groups
+    /// (sparks and rabbit-ears) are included in the span of the expression
+    /// inside them.
+    pub exprs: Vec<Span>,
+}
+
+pub struct Cst {
+    source: Vec<u8>,
+    /// Line number of the first line.
+    first_line: SrcLine,
+    /// Byte offsets of the beginnings of lines.
+    line_starts: Vec<SrcPos>,
+    /// All tokens in source order; the ! shorthand is a single WOW token.
+    pub tokens: Vec<Token>,
+    /// All statements in source order.
+    pub stmts: Vec<StmtNode>,
+}
+
+impl Cst {
+    pub fn new(code: &[u8], first_line: SrcLine) -> Cst {
+        let mut line_starts = vec![0];
+        line_starts.extend(code.iter().enumerate()
+                           .filter(|&(_, &c)| c == b'\n').map(|(i, _)| i + 1));
+        Cst { source: code.to_vec(), first_line: first_line, line_starts: line_starts,
+              tokens: Vec::new(), stmts: Vec::new() }
+    }
+
+    /// The source text of a span.
+    pub fn text(&self, span: Span) -> Cow<str> {
+        String::from_utf8_lossy(&self.source[span.start..span.end])
+    }
+
+    /// Line number and column (both starting at 1) of a byte offset.  Columns
+    /// count characters, not bytes.
+    pub fn line_col(&self, pos: SrcPos) -> (SrcLine, usize) {
+        let idx = match self.line_starts.binary_search(&pos) {
+            Ok(idx) => idx,
+            Err(idx) => idx - 1,
+        };
+        let start = self.line_starts[idx];
+        let col = String::from_utf8_lossy(&self.source[start..pos]).chars().count() + 1;
+        (idx + self.first_line, col)
+    }
+
+    /// The span of a whole line, without the newline.
+    pub fn line_span(&self, line: SrcLine) -> Span {
+        let idx = line - self.first_line;
+        let start = self.line_starts[idx];
+        let end = self.line_starts.get(idx + 1).map_or(self.source.len(), |&p| p - 1);
+        Span { start: start, end: end }
+    }
+
+    /// The text of a whole line, without the newline.
+    pub fn line_text(&self, line: SrcLine) -> String {
+        self.text(self.line_span(line)).into_owned()
+    }
+
+    /// All tokens and the whitespace between them, in source order.
+    #[allow(dead_code)]  // only used by tools and in tests
+    pub fn elems(&self) -> Vec<Elem> {
+        let mut res = Vec::new();
+        let mut pos = 0;
+        for tok in &self.tokens {
+            if tok.2.start > pos {
+                res.push(Elem::Trivia(Span { start: pos, end: tok.2.start }));
+            }
+            res.push(Elem::Token(tok));
+            pos = tok.2.end;
+        }
+        if self.source.len() > pos {
+            res.push(Elem::Trivia(Span { start: pos, end: self.source.len() }));
+        }
+        res
+    }
+
+    /// The tokens within a span.
+    pub fn tokens_in(&self, span: Span) -> &[Token] {
+        let from = self.tokens.iter().position(|t| t.2.start >= span.start)
+                                     .unwrap_or(self.tokens.len());
+        let to = self.tokens[from..].iter().position(|t| t.2.end > span.end)
+                                           .map_or(self.tokens.len(), |i| from + i);
+        &self.tokens[from..to]
+    }
+
+    /// The CST node of a statement parsed from this source.
+    pub fn stmt_node(&self, stmt: &Stmt) -> Option<&StmtNode> {
+        let span = stmt.props.span;
+        match self.stmts.binary_search_by(|n| n.span.start.cmp(&span.start)) {
+            Ok(i) if self.stmts[i].span == span => Some(&self.stmts[i]),
+            _ => None,
+        }
+    }
+
+    /// The span of an expression within a statement parsed from this source.
+    pub fn expr_span(&self, stmt: &Stmt, expr: &Expr) -> Option<Span> {
+        let node = match self.stmt_node(stmt) {
+            Some(node) => node,
+            None => return None,
+        };
+        stmt.exprs().iter().position(|e| *e as *const Expr == expr as *const Expr)
+                           .and_then(|i| node.exprs.get(i).cloned())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Elem;
+    use lex::TT;
+    use parse::Parser;
+
+    #[test]
+    fn elems_cover_the_source() {
+        // the cent sign is Latin-1, so this is not valid UTF-8
+        let code = b"(1)\tDO .1 <- #1\xA2#2\n\tPLEASE\n  DO GIVE UP\n".to_vec();
+        let mut parser = Parser::new(&code, 1, false);
+        parser.parse().unwrap();
+        let cst = parser.cst();
+        let mut res: Vec<u8> = Vec::new();
+        for elem in cst.elems() {
+            let span = match elem { Elem::Token(tok) => tok.2, Elem::Trivia(span) => span };
+            res.extend(&code[span.start..span.end]);
+        }
+        assert_eq!(res, code);
+        let money = cst.tokens.iter().find(|t| t.0 == TT::MONEY).unwrap();
+        assert_eq!(money.2.end - money.2.start, 1);
+        assert_eq!(cst.line_col(money.2.end), (1, 17));
+        assert_eq!(cst.stmts.len(), 2);
+        assert_eq!(cst.line_col(cst.stmts[1].span.end), (3, 13));
+    }
+}
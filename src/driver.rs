@@ -0,0 +1,221 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// Drives rustc to build an executable from the generated Rust source.
+///
+/// The compiler is taken from the `RUSTC` environment variable unless given
+/// explicitly, and gets extra codegen flags and a target triple passed through.
+/// Diagnostics are requested from rustc in its short format and parsed, so that
+/// each one can be reported together with the INTERCAL statement it was
+/// generated from (every statement is preceded by a comment in the output).
+
+use std::env;
+use std::fs::{ File, remove_file };
+use std::io::{ BufRead, BufReader, Write, stderr };
+use std::process::{ Command, Stdio };
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use rand;
+
+use err::{ Res, IE666 };
+use mandel;
+use runtime;
+
+pub struct Driver {
+    pub rustc: String,
+    pub opt: bool,
+    pub codegen: Vec<String>,
+    pub target: Option<String>,
+    pub keep: bool,
+}
+
+/// A diagnostic reported by rustc.
+pub struct Diagnostic {
+    pub level: String,
+    pub file: String,
+    pub line: usize,
+    pub col: usize,
+    pub msg: String,
+}
+
+impl Diagnostic {
+    /// Parse a line of rustc's short error format,
+    /// "file:line:col: level[code]: message".
+    pub fn parse(line: &str) -> Option<Diagnostic> {
+        let parts: Vec<_> = line.splitn(5, ": ").collect();
+        if parts.len() < 2 {
+            return None;
+        }
+        let loc: Vec<_> = parts[0].rsplitn(3, ':').collect();
+        if loc.len() != 3 {
+            return None;
+        }
+        let (line, col) = match (loc[1].parse(), loc[0].parse()) {
+            (Ok(line), Ok(col)) => (line, col),
+            _ => return None,
+        };
+        Some(Diagnostic {
+            level: parts[1].into(),
+            file: loc[2].into(),
+            line: line,
+            col: col,
+            msg: parts[2..].join(": "),
+        })
+    }
+
+    /// Find the INTERCAL statement that the code at this line was generated
+    /// from.
+    pub fn statement(&self) -> Option<String> {
+        let f = match File::open(&self.file) {
+            Ok(f)  => f,
+            Err(_) => return None,
+        };
+        let mut stmt = None;
+        for line in BufReader::new(f).lines().take(self.line) {
+            let line = match line {
+                Ok(line) => line,
+                Err(_)   => return None,
+            };
+            let line = line.trim();
+            if line.starts_with("/* ") && line.ends_with(" */") {
+                stmt = Some(line[3..line.len()-3].into());
+            }
+        }
+        stmt
+    }
+}
+
+impl Driver {
+    pub fn new() -> Driver {
+        Driver {
+            rustc: env::var("RUSTC").unwrap_or("rustc".into()),
+            opt: false,
+            codegen: vec![],
+            target: None,
+            keep: false,
+        }
+    }
+
+    /// Compile the generated source into the given executable.
+    pub fn build(&self, srcname: &str, outname: &str) -> Res<()> {
+        let lib = try!(runtime::library(&self.rustc, self.target.as_ref().map(|t| &t[..])));
+        let mut cmd = Command::new(&self.rustc);
+        if self.opt {
+            cmd.arg("-O");
+        }
+        for flag in &self.codegen {
+            cmd.arg("-C").arg(flag);
+        }
+        if let Some(ref target) = self.target {
+            cmd.arg("--target").arg(target);
+        }
+        cmd.arg("--error-format").arg("short");
+        // nightlies from before it was stabilized want this for the short format
+        if try!(runtime::rustc_version(&self.rustc)).windows(8).any(|w| w == b"-nightly") {
+            cmd.arg("-Z").arg("unstable-options");
+        }
+        cmd.arg("--extern").arg(format!("{}={}", runtime::CRATE_NAME, lib.display()));
+        cmd.arg("-o").arg(outname);
+        cmd.arg(srcname);
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let child = match cmd.spawn() {
+            Err(_) => return IE666.err(),
+            Ok(ch) => ch,
+        };
+        // make the user comfortable...
+        let (wchan, rchan) = mpsc::channel();
+        let threadhandle = thread::spawn(move || {
+            let mut printer = mandel::MandelPrinter::new();
+            while let Err(mpsc::TryRecvError::Empty) = rchan.try_recv() {
+                printer.print_char(true);
+                thread::sleep(Duration::from_millis(
+                    (2u64).pow((rand::random::<u8>() / 40) as u32)));
+            }
+            printer.finish_current();
+        });
+
+        let wait_res = child.wait_with_output();
+        wchan.send(()).unwrap();
+        threadhandle.join().unwrap();
+        let out = match wait_res {
+            Err(_)  => return IE666.err(),
+            Ok(out) => out,
+        };
+        let mut stderr = stderr();
+        for line in String::from_utf8_lossy(&out.stderr).lines() {
+            match Diagnostic::parse(line) {
+                Some(diag) => {
+                    try!(writeln!(stderr, "{}:{}:{}: {}: {}", diag.file, diag.line, diag.col,
+                                  diag.level, diag.msg));
+                    if let Some(stmt) = diag.statement() {
+                        try!(writeln!(stderr, "    in statement: {}", stmt));
+                    }
+                }
+                None => try!(writeln!(stderr, "{}", line)),
+            }
+        }
+        if !out.status.success() {
+            return IE666.err();
+        }
+        if !self.keep {
+            let _ = remove_file(srcname);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{ File, remove_file };
+    use std::io::Write;
+
+    use rand;
+
+    use super::Diagnostic;
+
+    #[test]
+    fn parse_short_format() {
+        let diag = Diagnostic::parse("x.rs:12:5: error[E0425]: cannot find value `y`: oops")
+            .unwrap();
+        assert_eq!((&diag.file[..], diag.line, diag.col), ("x.rs", 12, 5));
+        assert_eq!(diag.level, "error[E0425]");
+        assert_eq!(diag.msg, "cannot find value `y`: oops");
+        // only the last two colons of the location separate line and column
+        let diag = Diagnostic::parse("C:\\tmp\\x.rs:3:1: warning: unused").unwrap();
+        assert_eq!((&diag.file[..], diag.line, diag.col), ("C:\\tmp\\x.rs", 3, 1));
+        assert!(Diagnostic::parse("error: aborting due to previous error").is_none());
+        assert!(Diagnostic::parse("x.rs:a:b: error: no location").is_none());
+    }
+
+    #[test]
+    fn find_statement() {
+        let path = env::temp_dir().join(format!("rick-test-{:08x}.rs", rand::random::<u32>()));
+        let mut f = File::create(&path).unwrap();
+        f.write_all(b"fn main() {\n    /* #001 DO .1 <- #1 */\n    let x = 1;\n\
+                      /* #002 PLEASE GIVE UP */\n    x\n}\n").unwrap();
+        let name = path.to_string_lossy();
+        let at = |line| Diagnostic::parse(&format!("{}:{}:5: error: oops", name, line))
+            .unwrap().statement();
+        assert_eq!(at(1), None);
+        assert_eq!(at(3), Some("#001 DO .1 <- #1".into()));
+        assert_eq!(at(5), Some("#002 PLEASE GIVE UP".into()));
+        remove_file(&path).unwrap();
+    }
+}
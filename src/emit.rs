@@ -0,0 +1,658 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// Translates AST back to INTERCAL.
+///
+/// Unlike the Display implementation in ast.rs, which is only good for staring at,
+/// this produces a program that rick (or any other INTERCAL-72 compiler with the
+/// usual extensions) will accept again.  That means everything the optimizer came
+/// up with has to be expressed with the five operators INTERCAL actually has:
+///
+/// * the native Rs* operators are lowered to mingle/select/unary combinations;
+///   addition and subtraction become a ripple-carry adder, one bit at a time
+/// * 32-bit constants are synthesized by mingling two 16-bit literals
+/// * Print statements become a tail array that is dimensioned, filled and read
+///   out in binary mode
+///
+/// Labels, execution chances, NOT and PLEASE are kept as they are.  Statements
+/// made up by the emitter get just enough PLEASEs to satisfy the politeness
+/// check (only if that is not enough, because the optimizer removed too many
+/// statements, the PLEASEs of the others are changed).  Variables keep the
+/// numbers the parser gave them, plus one.
+///
+/// A few things can't be reproduced exactly: splats are written out as their
+/// own source text (so their error message no longer shows anything else that
+/// was on the same line), narrowing to 16 bits and multiplications that
+/// overflow mask instead of raising E275 or E000, and a Print leaves the
+/// binary output tape at its last character (the emitter assumes nothing else
+/// has been read out before a Print, which holds for all programs the
+/// optimizer creates).
+
+use std::cmp::{ max, min };
+use std::io::Write;
+
+use ast::{ Program, Stmt, StmtBody, Expr, Var, VType, ComeFrom, Label };
+use err::{ Res, IE994 };
+use parse::polite_bounds;
+use stdops::select;
+use width::bound;
+
+
+/// A line of output, before politeness is decided.
+struct Line {
+    label: Label,
+    /// True if the statement said PLEASE.  Synthesized statements start out
+    /// rude and may be made polite by `balance_politeness`.
+    polite: bool,
+    /// True if the emitter made up the statement.
+    synthetic: bool,
+    disabled: bool,
+    chance: u8,
+    /// Everything after the initiator, or the whole line for splats.
+    text: String,
+    verbatim: bool,
+}
+
+pub struct Emitter<'a> {
+    program: &'a Program,
+    lines: Vec<Line>,
+    /// State of the binary output tape, for Print expansion.
+    last_out: u8,
+}
+
+/// Binary bitwise operations that can be built from a unary operator.
+#[derive(Clone, Copy)]
+enum BitOp {
+    And,
+    Or,
+    Xor,
+}
+
+
+impl<'a> Emitter<'a> {
+    pub fn new(program: &'a Program) -> Emitter<'a> {
+        Emitter { program: program, lines: Vec::new(), last_out: 0 }
+    }
+
+    /// The main public method of the emitter.
+    pub fn emit(&mut self, o: &mut Write) -> Res<()> {
+        let program = self.program;
+        for stmt in &program.stmts {
+            try!(self.emit_stmt(stmt));
+        }
+        let current = self.lines.iter().map(|l| l.polite).collect::<Vec<_>>();
+        let fixed = self.lines.iter().map(|l| !l.synthetic).collect::<Vec<_>>();
+        let mut polite = balance_politeness(&current, &fixed);
+        let (lo, hi) = polite_bounds(polite.len());
+        let npolite = polite.iter().filter(|&&p| p).count();
+        if npolite < lo || npolite > hi {
+            // dead code elimination can leave too many or few polite statements
+            polite = balance_politeness(&current, &vec![false; current.len()]);
+        }
+        for (line, polite) in self.lines.iter().zip(polite) {
+            if line.verbatim {
+                try!(write!(o, "{}\n", line.text));
+            } else {
+                try!(write!(o, "{}\n", format_line(line.label, polite, line.disabled,
+                                                   line.chance, &line.text)));
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, stmt: &Stmt, text: String) {
+        self.lines.push(Line { label: stmt.props.label,
+                               polite: stmt.props.polite,
+                               synthetic: false,
+                               disabled: stmt.props.disabled,
+                               chance: stmt.props.chance,
+                               text: text,
+                               verbatim: false });
+    }
+
+    fn push_synthetic(&mut self, text: String) {
+        self.lines.push(Line { label: 0,
+                               polite: false,
+                               synthetic: true,
+                               disabled: false,
+                               chance: 100,
+                               text: text,
+                               verbatim: false });
+    }
+
+    fn emit_stmt(&mut self, stmt: &Stmt) -> Res<()> {
+        match stmt.body {
+            StmtBody::Error(_, ref text) => {
+                self.lines.push(Line { label: stmt.props.label,
+                                       polite: stmt.props.polite,
+                                       synthetic: false,
+                                       disabled: stmt.props.disabled,
+                                       chance: stmt.props.chance,
+                                       text: text.clone(),
+                                       verbatim: true });
+                Ok(())
+            }
+            StmtBody::Print(ref s) => self.emit_print(stmt, s),
+            ref body => {
+                let text = try!(format_body(body, 1).map_err(|mut e| {
+                    e.set_line(stmt.props.srcline);
+                    e
+                }));
+                self.push(stmt, text);
+                Ok(())
+            }
+        }
+    }
+
+    /// Expand a Print statement into binary array output.
+    fn emit_print(&mut self, stmt: &Stmt, bytes: &[u8]) -> Res<()> {
+        if bytes.is_empty() {
+            // nothing to print, but the statement might be a jump target; a GIVE UP
+            // that is NOT'd can never be reinstated, so it makes a perfect no-op
+            self.push(stmt, format!("GIVE UP"));
+            let line = self.lines.last_mut().expect("we just pushed a line");
+            line.disabled = true;
+            return Ok(());
+        }
+        // use a tail array that the program doesn't know about
+        let tail = Var::A16(self.program.var_info.2.len(), vec![]);
+        let name = try!(fmt_var(&tail, 0, 1));
+        for (i, chunk) in bytes.chunks(0xFFFF).enumerate() {
+            let dim = format!("{} <- #{}", name, chunk.len());
+            if i == 0 {
+                self.push(stmt, dim);
+            } else {
+                self.push_synthetic(dim);
+            }
+            for (j, &byte) in chunk.iter().enumerate() {
+                // this inverts what Array::readout does
+                let c = reverse_bits(byte);
+                let val = self.last_out.wrapping_sub(c);
+                self.last_out = c;
+                self.push_synthetic(format!("{} SUB #{} <- #{}", name, j + 1, val));
+            }
+            self.push_synthetic(format!("READ OUT {}", name));
+        }
+        Ok(())
+    }
+}
+
+/// Format the body of a statement (everything after the initiator, NOT and
+/// chance) as INTERCAL source.  Variable numbers are offset by `base`.  Splats
+/// and Print statements have no such representation.
+pub fn format_body(body: &StmtBody, base: usize) -> Res<String> {
+    Ok(match *body {
+        StmtBody::Calc(ref var, ref expr) => {
+            format!("{} <- {}", try!(fmt_var(var, 0, base)), try!(fmt_top(expr, base)))
+        }
+        StmtBody::Dim(ref var, ref exprs) => {
+            let mut dims = Vec::new();
+            for expr in exprs {
+                dims.push(try!(fmt_top(expr, base)));
+            }
+            format!("{} <- {}", try!(fmt_var(var, 0, base)), dims.join(" BY "))
+        }
+        StmtBody::DoNext(lbl) => format!("({}) NEXT", lbl),
+        StmtBody::ComeFrom(ComeFrom::Expr(ref e)) => {
+            format!("COME FROM {}", try!(fmt_top(e, base)))
+        }
+        StmtBody::ComeFrom(ref spec) => format!("COME FROM {}", spec),
+        StmtBody::Resume(ref e) => format!("RESUME {}", try!(fmt_top(e, base))),
+        StmtBody::Forget(ref e) => format!("FORGET {}", try!(fmt_top(e, base))),
+        StmtBody::Ignore(ref vars) => format!("IGNORE {}", try!(fmt_varlist(vars, base))),
+        StmtBody::Remember(ref vars) => format!("REMEMBER {}", try!(fmt_varlist(vars, base))),
+        StmtBody::Stash(ref vars) => format!("STASH {}", try!(fmt_varlist(vars, base))),
+        StmtBody::Retrieve(ref vars) => format!("RETRIEVE {}", try!(fmt_varlist(vars, base))),
+        StmtBody::Abstain(ref expr, ref whats) => {
+            let whats = whats.iter().map(|w| format!("{}", w)).collect::<Vec<_>>();
+            match *expr {
+                None => format!("ABSTAIN FROM {}", whats.join(" + ")),
+                Some(ref e) => format!("ABSTAIN {} FROM {}", try!(fmt_top(e, base)),
+                                       whats.join(" + ")),
+            }
+        }
+        StmtBody::Reinstate(ref whats) => {
+            let whats = whats.iter().map(|w| format!("{}", w)).collect::<Vec<_>>();
+            format!("REINSTATE {}", whats.join(" + "))
+        }
+        StmtBody::WriteIn(ref vars) => format!("WRITE IN {}", try!(fmt_varlist(vars, base))),
+        StmtBody::ReadOut(ref exprs) => {
+            let mut items = Vec::new();
+            for expr in exprs {
+                match *expr {
+                    Expr::Var(ref var) => items.push(try!(fmt_var(var, 0, base))),
+                    Expr::Num(_, v) if v <= 0xFFFF => items.push(format!("#{}", v)),
+                    _ => return IE994.err(),
+                }
+            }
+            format!("READ OUT {}", items.join(" + "))
+        }
+        StmtBody::TryAgain => format!("TRY AGAIN"),
+        StmtBody::GiveUp => format!("GIVE UP"),
+        StmtBody::Error(..) | StmtBody::Print(_) => return IE994.err(),
+    })
+}
+
+/// Decide PLEASE or DO for every statement.  `current` is the politeness the
+/// statements have now, and statements marked in `fixed` must keep it.  If the
+/// number of polite statements is outside what the parser accepts, statements
+/// are flipped (spread evenly over the program) until one in four is polite.
+pub fn balance_politeness(current: &[bool], fixed: &[bool]) -> Vec<bool> {
+    let mut res = current.to_vec();
+    let (lo, hi) = polite_bounds(res.len());
+    let npolite = res.iter().filter(|&&p| p).count();
+    if npolite >= lo && npolite <= hi {
+        return res;
+    }
+    let target = max(lo, min(hi, res.len() / 4));
+    let (want, flip_to) = if npolite < lo {
+        (target - npolite, true)
+    } else {
+        (npolite - target, false)
+    };
+    let candidates = (0..res.len()).filter(|&i| !fixed[i] && res[i] != flip_to)
+                                   .collect::<Vec<_>>();
+    let n = min(want, candidates.len());
+    for j in 0..n {
+        res[candidates[j * candidates.len() / n]] = flip_to;
+    }
+    res
+}
+
+/// Format a statement line in the layout of the sample programs.
+pub fn format_line(label: Label, polite: bool, disabled: bool, chance: u8,
+                   text: &str) -> String {
+    let label = if label > 0 { format!("({})", label) } else { String::new() };
+    let mut res = format!("{:<8}{}", label, if polite { "PLEASE DO" } else { "       DO" });
+    if disabled {
+        res.push_str(" NOT");
+    }
+    if chance < 100 {
+        res.push_str(&format!(" %{}", chance));
+    }
+    res.push(' ');
+    res.push_str(text);
+    res
+}
+
+fn reverse_bits(mut c: u8) -> u8 {
+    c = (c & 0x0f) << 4 | (c & 0xf0) >> 4;
+    c = (c & 0x33) << 2 | (c & 0xcc) >> 2;
+    c = (c & 0x55) << 1 | (c & 0xaa) >> 1;
+    c
+}
+
+
+// Formatting of lowered expressions.
+
+/// Lower and format an expression that is not an operand of anything.
+fn fmt_top(expr: &Expr, base: usize) -> Res<String> {
+    Ok(fmt_expr(&try!(lower(expr)), 0, true, base))
+}
+
+fn fmt_varlist(vars: &Vec<Var>, base: usize) -> Res<String> {
+    let mut res = Vec::new();
+    for var in vars {
+        res.push(try!(fmt_var(var, 0, base)));
+    }
+    Ok(res.join(" + "))
+}
+
+/// Format a variable, lowering its subscripts.
+fn fmt_var(var: &Var, depth: usize, base: usize) -> Res<String> {
+    Ok(fmt_var_lowered(&try!(lower_var(var)), depth, base))
+}
+
+/// Put a group around an expression; sparks and rabbit-ears alternate so that
+/// nesting is never ambiguous.
+fn group(s: &str, depth: usize) -> String {
+    if depth % 2 == 0 { format!("'{}'", s) } else { format!("\"{}\"", s) }
+}
+
+fn has_subs(var: &Var) -> bool {
+    match *var {
+        Var::A16(_, ref subs) | Var::A32(_, ref subs) => !subs.is_empty(),
+        _ => false,
+    }
+}
+
+/// Format a lowered expression.  `depth` is the number of enclosing groups, and
+/// `top` is true if the expression does not need a group of its own.
+fn fmt_expr(expr: &Expr, depth: usize, top: bool, base: usize) -> String {
+    let inner = if top { depth } else { depth + 1 };
+    let wrap = |s: String| if top { s } else { group(&s, depth) };
+    match *expr {
+        Expr::Num(_, v) => format!("#{}", v),
+        Expr::Var(ref var) => {
+            // subscripts gobble up everything that looks like an expression, so
+            // an array element has to be grouped if anything follows it
+            if has_subs(var) {
+                wrap(fmt_var_lowered(var, inner, base))
+            } else {
+                fmt_var_lowered(var, depth, base)
+            }
+        }
+        Expr::Mingle(ref vx, ref wx) => {
+            wrap(format!("{}${}", fmt_expr(vx, inner, false, base),
+                         fmt_expr(wx, inner, false, base)))
+        }
+        Expr::Select(_, ref vx, ref wx) => {
+            wrap(format!("{}~{}", fmt_expr(vx, inner, false, base),
+                         fmt_expr(wx, inner, false, base)))
+        }
+        Expr::And(vtype, ref vx) => fmt_unary("&", vtype, vx, depth, top, base),
+        Expr::Or(vtype, ref vx) => fmt_unary("V", vtype, vx, depth, top, base),
+        Expr::Xor(vtype, ref vx) => fmt_unary("?", vtype, vx, depth, top, base),
+        _ => unreachable!("expression was not lowered"),
+    }
+}
+
+/// Format a variable whose subscripts are already lowered, with an optional
+/// inline unary operator.
+fn fmt_var_op(var: &Var, op: &str, depth: usize, base: usize) -> String {
+    match *var {
+        Var::I16(n) => format!(".{}{}", op, n + base),
+        Var::I32(n) => format!(":{}{}", op, n + base),
+        Var::A16(n, ref subs) | Var::A32(n, ref subs) => {
+            let sigil = if let Var::A16(..) = *var { "," } else { ";" };
+            let mut res = format!("{}{}{}", sigil, op, n + base);
+            if !subs.is_empty() {
+                res.push_str(" SUB");
+                for sub in subs {
+                    res.push(' ');
+                    res.push_str(&fmt_expr(sub, depth, false, base));
+                }
+            }
+            res
+        }
+    }
+}
+
+fn fmt_var_lowered(var: &Var, depth: usize, base: usize) -> String {
+    fmt_var_op(var, "", depth, base)
+}
+
+/// Can the operand of a unary operator be written with the operator inline?
+/// The inline form (".&1") is always parsed as a 16-bit operator.
+fn inlinable(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Var(_) => true,
+        Expr::Num(_, v) => v <= 0xFFFF,
+        _ => false,
+    }
+}
+
+fn fmt_unary(sym: &str, vtype: VType, vx: &Expr, depth: usize, top: bool,
+             base: usize) -> String {
+    if vtype == VType::I16 && inlinable(vx) {
+        match *vx {
+            Expr::Num(_, v) => return format!("#{}{}", sym, v),
+            Expr::Var(ref var) if !has_subs(var) => return fmt_var_op(var, sym, depth, base),
+            Expr::Var(ref var) if top => return fmt_var_op(var, sym, depth, base),
+            Expr::Var(ref var) => return group(&fmt_var_op(var, sym, depth + 1, base), depth),
+            _ => { }
+        }
+    }
+    if top {
+        format!("{}{}", sym, fmt_expr(vx, depth, true, base))
+    } else {
+        group(&format!("{}{}", sym, fmt_expr(vx, depth + 1, true, base)), depth)
+    }
+}
+
+
+// Lowering of expressions to pure INTERCAL.
+//
+// All expressions built here have the width that the parser will assign to
+// them when it sees their source again, so that the unary operators and select
+// come out with the same width as in the original program.
+
+fn num16(v: u32) -> Expr {
+    Expr::Num(VType::I16, v)
+}
+
+fn mingle_(vx: Expr, wx: Expr) -> Expr {
+    Expr::Mingle(box vx, box wx)
+}
+
+fn select_(vx: Expr, wx: Expr) -> Expr {
+    Expr::Select(wx.get_vtype(), box vx, box wx)
+}
+
+/// A unary operator of the given width.
+fn unary(op: BitOp, vtype: VType, vx: Expr) -> Expr {
+    let vx = if vtype == VType::I16 && inlinable(&vx) { vx } else { retype(vx, vtype) };
+    match op {
+        BitOp::And => Expr::And(vtype, box vx),
+        BitOp::Or  => Expr::Or(vtype, box vx),
+        BitOp::Xor => Expr::Xor(vtype, box vx),
+    }
+}
+
+/// A 32-bit constant, made from its odd and even bits.
+fn konst(v: u32) -> Expr {
+    mingle_(num16(select(v, 0xAAAAAAAA)), num16(select(v, 0x55555555)))
+}
+
+/// A constant of whatever width suffices.
+fn mask(v: u32) -> Expr {
+    if v <= 0xFFFF { num16(v) } else { konst(v) }
+}
+
+/// Make sure the expression is parsed with the given width.
+fn retype(expr: Expr, vtype: VType) -> Expr {
+    if expr.get_vtype() == vtype {
+        expr
+    } else if vtype == VType::I32 {
+        // interleave with zeros and select the bits again
+        select_(mingle_(num16(0), expr), konst(0x55555555))
+    } else {
+        select_(expr, num16(0xFFFF))
+    }
+}
+
+/// Upper bound on the number of significant bits of an expression's value.
+fn width(expr: &Expr) -> u32 {
+    bound(expr, &|var: &Var| if var.get_vtype() == VType::I16 { 16 } else { 32 })
+}
+
+/// Lower an expression to one that uses only INTERCAL operators.
+fn lower(expr: &Expr) -> Res<Expr> {
+    Ok(match *expr {
+        Expr::Num(VType::I16, v) if v <= 0xFFFF => num16(v),
+        Expr::Num(_, v) => konst(v),
+        Expr::Var(ref var) => Expr::Var(try!(lower_var(var))),
+        Expr::Mingle(ref vx, ref wx) => mingle_(try!(lower(vx)), try!(lower(wx))),
+        Expr::Select(vtype, ref vx, ref wx) => {
+            select_(try!(lower(vx)), retype(try!(lower(wx)), vtype))
+        }
+        Expr::And(vtype, ref vx) => unary(BitOp::And, vtype, try!(lower(vx))),
+        Expr::Or(vtype, ref vx) => unary(BitOp::Or, vtype, try!(lower(vx))),
+        Expr::Xor(vtype, ref vx) => unary(BitOp::Xor, vtype, try!(lower(vx))),
+        Expr::RsNot(ref vx) => bitwise(BitOp::Xor, try!(lower(vx)), konst(0xFFFFFFFF)),
+        Expr::RsAnd(ref vx, ref wx) => bitwise(BitOp::And, try!(lower(vx)), try!(lower(wx))),
+        Expr::RsOr(ref vx, ref wx) => bitwise(BitOp::Or, try!(lower(vx)), try!(lower(wx))),
+        Expr::RsXor(ref vx, ref wx) => bitwise(BitOp::Xor, try!(lower(vx)), try!(lower(wx))),
+        Expr::RsRshift(ref vx, box Expr::Num(_, k)) => shr(try!(lower(vx)), k),
+        Expr::RsLshift(ref vx, box Expr::Num(_, k)) => shl(try!(lower(vx)), k),
+        // the optimizer only ever shifts by constants
+        Expr::RsRshift(..) | Expr::RsLshift(..) => return IE994.err(),
+        Expr::RsNotEqual(ref vx, ref wx) => try!(not_equal(vx, wx)),
+        Expr::RsEqual(ref vx, ref wx) => {
+            bitwise16(BitOp::Xor, try!(not_equal(vx, wx)), num16(1))
+        }
+        Expr::RsPlus(ref vx, ref wx) => {
+            add(try!(lower(vx)), try!(lower(wx)), width(expr), false)
+        }
+        Expr::RsMinus(ref vx, ref wx) => {
+            // like rick itself, assume that the subtraction doesn't underflow
+            add(try!(lower(vx)), try!(lower(wx)), width(expr), true)
+        }
+        Expr::RsTimes(_, ref vx, box Expr::Num(_, c)) => {
+            // shift and add, one term per set bit of the constant
+            let x = try!(lower(vx));
+            let nbits = width(expr);
+            let mut terms = (0..32).filter(|&k| c & (1 << k) != 0).map(|k| shl(x.clone(), k));
+            let first = terms.next().unwrap_or(num16(0));
+            terms.fold(first, |sum, term| add(sum, term, nbits, false))
+        }
+        Expr::RsDivide(_, _, box Expr::Num(_, 0)) => num16(0),
+        Expr::RsModulus(_, ref vx, box Expr::Num(_, 0)) => try!(lower(vx)),
+        Expr::RsDivide(_, ref vx, box Expr::Num(_, c)) if c.is_power_of_two() => {
+            shr(try!(lower(vx)), c.trailing_zeros())
+        }
+        Expr::RsModulus(_, ref vx, box Expr::Num(_, c)) if c.is_power_of_two() => {
+            bitwise(BitOp::And, try!(lower(vx)), mask(c - 1))
+        }
+        // the idioms only ever multiply by constants, and nothing divides
+        // by anything but powers of two
+        Expr::RsTimes(..) | Expr::RsDivide(..) | Expr::RsModulus(..) => return IE994.err(),
+    })
+}
+
+/// 1 if the two values differ, else 0.
+fn not_equal(vx: &Expr, wx: &Expr) -> Res<Expr> {
+    let diff = match (vx, wx) {
+        (_, &Expr::Num(_, 0)) => try!(lower(vx)),
+        (&Expr::Num(_, 0), _) => try!(lower(wx)),
+        _ => bitwise(BitOp::Xor, try!(lower(vx)), try!(lower(wx))),
+    };
+    // (x ~ x) has bit 0 set exactly if x is nonzero
+    Ok(select_(select_(diff.clone(), diff), num16(1)))
+}
+
+fn lower_var(var: &Var) -> Res<Var> {
+    Ok(match *var {
+        Var::A16(n, ref subs) => Var::A16(n, try!(subs.iter().map(lower).collect())),
+        Var::A32(n, ref subs) => Var::A32(n, try!(subs.iter().map(lower).collect())),
+        ref other => other.clone(),
+    })
+}
+
+/// A binary bitwise operation on two values of up to 16 bits:
+/// UNOP(x $ y) ~ #0$#65535.
+fn bitwise16(op: BitOp, vx: Expr, wx: Expr) -> Expr {
+    select_(unary(op, VType::I32, mingle_(vx, wx)), konst(0x55555555))
+}
+
+/// A binary bitwise operation on two 32-bit values: apply it to the odd and
+/// even bits separately and mingle the results together again.
+fn bitwise(op: BitOp, vx: Expr, wx: Expr) -> Expr {
+    if width(&vx) <= 16 && width(&wx) <= 16 {
+        return bitwise16(op, vx, wx);
+    }
+    let odd = bitwise16(op, select_(vx.clone(), konst(0xAAAAAAAA)),
+                        select_(wx.clone(), konst(0xAAAAAAAA)));
+    let even = bitwise16(op, select_(vx, konst(0x55555555)),
+                         select_(wx, konst(0x55555555)));
+    mingle_(odd, even)
+}
+
+fn shr(vx: Expr, k: u32) -> Expr {
+    if k == 0 {
+        vx
+    } else if k >= 32 || k >= width(&vx) {
+        num16(0)
+    } else if width(&vx) <= 16 {
+        select_(vx, num16((0xFFFF << k) & 0xFFFF))
+    } else {
+        select_(vx, konst(0xFFFFFFFF << k))
+    }
+}
+
+/// Shift a 16-bit value left by one, dropping the top bit:
+/// (x $ #0) ~ #10922$#43691 (which is 0x2AAAAAAB).
+fn shl1_16(vx: Expr) -> Expr {
+    select_(mingle_(vx, num16(0)), konst(0x2AAAAAAB))
+}
+
+fn shl16(mut vx: Expr, k: u32) -> Expr {
+    if k >= 16 {
+        return num16(0);
+    }
+    for _ in 0..k {
+        vx = shl1_16(vx);
+    }
+    vx
+}
+
+fn shl(vx: Expr, k: u32) -> Expr {
+    if k == 0 {
+        return vx;
+    } else if k >= 32 {
+        return num16(0);
+    } else if width(&vx) + k <= 16 {
+        return shl16(vx, k);
+    }
+    // bit i of the odd (even) half of the result is bit 2i+1-k (2i-k) of the
+    // input, which is a bit of its odd or even half, depending on k
+    let odd = select_(vx.clone(), konst(0xAAAAAAAA));
+    let even = select_(vx, konst(0x55555555));
+    let m = k / 2;
+    if k % 2 == 0 {
+        mingle_(shl16(odd, m), shl16(even, m))
+    } else {
+        mingle_(shl16(even, m), shl16(odd, m + 1))
+    }
+}
+
+/// A ripple-carry adder over the lowest `nbits` bits.  For subtraction, the
+/// second operand is inverted and the carry starts at one.
+fn add(vx: Expr, wx: Expr, nbits: u32, subtract: bool) -> Expr {
+    if nbits == 0 {
+        return num16(0);
+    }
+    let bit = |e: &Expr, i: u32| select_(e.clone(), mask(1 << i));
+    let mut carry = if subtract { Some(num16(1)) } else { None };
+    let mut sum = Vec::new();
+    for i in 0..nbits {
+        let a = bit(&vx, i);
+        let mut b = bit(&wx, i);
+        if subtract {
+            b = bitwise16(BitOp::Xor, b, num16(1));
+        }
+        let half = bitwise16(BitOp::Xor, a.clone(), b.clone());
+        let gen = bitwise16(BitOp::And, a, b);
+        match carry.take() {
+            None => {
+                sum.push(half);
+                carry = Some(gen);
+            }
+            Some(c) => {
+                sum.push(bitwise16(BitOp::Xor, half.clone(), c.clone()));
+                if i + 1 < nbits {
+                    carry = Some(bitwise16(BitOp::Or, gen,
+                                           bitwise16(BitOp::And, c, half)));
+                }
+            }
+        }
+    }
+    pack(sum)
+}
+
+/// Put single bits (lowest first) together into one value.
+fn pack(mut bits: Vec<Expr>) -> Expr {
+    if bits.len() == 1 {
+        return bits.pop().expect("one bit is there");
+    }
+    let mut odd = Vec::new();
+    let mut even = Vec::new();
+    for (i, bit) in bits.into_iter().enumerate() {
+        if i % 2 == 0 { even.push(bit) } else { odd.push(bit) }
+    }
+    mingle_(pack(odd), pack(even))
+}
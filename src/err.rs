@@ -30,8 +30,25 @@
 /// interpreter sets the correct line number before it hands the error up to its
 /// caller.  In compiled code, no such adjustment is done, so errors have to get the
 /// correct line numbers when created.
+///
+/// Errors are printed to stderr, and the process exits with a status that tells
+/// what went wrong (see `RtError::exit_status`): 0 means that the program ended
+/// normally (usually with GIVE UP), 10-19 that it never got to run, and 20-29 that
+/// it failed while running.
+///
+/// Besides the classic text, errors can be written as JSON objects, which
+/// additionally contain the statement that caused the error (as far as known),
+/// and for errors at runtime, the NEXT stack at that time.
+
+use std::error::Error;
+use std::fmt::{ self, Display, Formatter };
+use std::io::{ self, Write };
+use std::process;
 
-use std::io;
+/// Exit status base for errors before the program runs (parsing, compiling).
+pub const EXIT_COMPILE: i32 = 10;
+/// Exit status base for errors while the program runs.
+pub const EXIT_RUNTIME: i32 = 20;
 
 /// Result of a statement.
 pub type Res<T> = Result<T, RtError>;
@@ -48,6 +65,12 @@ pub struct RtError {
     error:  &'static ErrDesc,
     addstr: Option<String>,
     lineno: usize,
+    /// Source line of the statement that caused the error, if known.
+    srcline: Option<usize>,
+    /// Byte offsets of the statement in the source file, if known.
+    span: Option<(usize, usize)>,
+    /// Source lines of the NEXT statements on the NEXT stack, oldest first.
+    stack: Vec<usize>,
 }
 
 impl RtError {
@@ -55,18 +78,90 @@ impl RtError {
         self.lineno = lineno;
     }
 
-    pub fn to_string(&self) -> String {
+    /// Set the statement that caused the error, unless it is already known.
+    pub fn set_stmt(&mut self, srcline: usize, span: Option<(usize, usize)>) {
+        if self.srcline.is_none() {
+            self.srcline = Some(srcline);
+            self.span = span;
+        }
+    }
+
+    /// Source line of the statement that caused the error, if known.
+    pub fn srcline(&self) -> Option<usize> {
+        self.srcline
+    }
+
+    /// Add a NEXT to the bottom of the recorded NEXT stack.
+    pub fn push_next(&mut self, srcline: usize) {
+        self.stack.insert(0, srcline);
+    }
+
+    fn message(&self) -> String {
         let mut msg = String::from(self.error.msg);
         if let Some(ref s) = self.addstr {
             msg = msg.replace("{}", &s);
         }
-        let lineinfo = match self.error.way {
-            Some(s) => String::from(s),
-            None => format!("ON THE WAY TO {}", self.lineno),
+        msg
+    }
+
+    /// Format the error as a JSON object on a single line.  If the source is
+    /// given, the columns and text of the statement are included.
+    pub fn to_json(&self, file: Option<&str>, source: Option<&[u8]>) -> String {
+        let mut res = format!("{{\"file\":{},\"line\":{},",
+                              file.map_or("null".into(), json_str),
+                              self.srcline.map_or("null".into(), |l| l.to_string()));
+        match (self.span, source) {
+            (Some((start, end)), Some(source)) if end <= source.len() => {
+                let end_line = self.srcline.map_or("null".into(), |l| {
+                    (l + source[start..end].iter().filter(|&&c| c == b'\n').count()).to_string()
+                });
+                res.push_str(&format!("\"column\":{},\"end_line\":{},\"end_column\":{},\
+                                       \"start\":{},\"end\":{},\"source\":{},",
+                                      column(source, start), end_line, column(source, end),
+                                      start, end,
+                                      json_str(&String::from_utf8_lossy(&source[start..end]))));
+            }
+            _ => res.push_str("\"column\":null,\"end_line\":null,\"end_column\":null,\
+                               \"start\":null,\"end\":null,\"source\":null,"),
+        }
+        let way = match self.error.way {
+            Some(s) => json_str(s),
+            None    => self.lineno.to_string(),
         };
-        format!("ICL{:03}I\t{}\n\t{}\
-                 \n        CORRECT SOURCE AND RESUBNIT\n",
-                self.error.num, msg, lineinfo)
+        let stack: Vec<_> = self.stack.iter().map(|l| l.to_string()).collect();
+        res.push_str(&format!("\"level\":\"error\",\"code\":\"E{:03}\",\"message\":{},\
+                               \"on_the_way_to\":{},\"next_stack\":[{}]}}\n",
+                              self.error.num, json_str(&self.message()), way,
+                              stack.join(",")));
+        res
+    }
+
+    /// The process exit status for this error: EXIT_COMPILE or EXIT_RUNTIME, plus
+    /// the hundreds digit of the error number (which roughly gives the kind of
+    /// error, e.g. 1 for lost control flow, 5 for arithmetic and I/O).
+    pub fn exit_status(&self, runtime: bool) -> i32 {
+        let base = if runtime { EXIT_RUNTIME } else { EXIT_COMPILE };
+        base + (self.error.num / 100) as i32
+    }
+
+    /// Print the error to stderr and exit with the corresponding status.
+    /// Program output that is still buffered is written first, since
+    /// `process::exit` would lose it.
+    pub fn exit(&self, runtime: bool) -> ! {
+        let _ = io::stdout().flush();
+        let _ = write!(io::stderr(), "{}", self);
+        process::exit(self.exit_status(runtime))
+    }
+
+    /// Like `exit`, but can print the error as JSON (see `to_json`).
+    pub fn exit_as(&self, runtime: bool, json: bool, file: Option<&str>,
+                   source: Option<&[u8]>) -> ! {
+        if json {
+            let _ = io::stdout().flush();
+            let _ = write!(io::stderr(), "{}", self.to_json(file, source));
+            process::exit(self.exit_status(runtime))
+        }
+        self.exit(runtime)
     }
 
     pub fn short_string(&self) -> &str {
@@ -82,6 +177,46 @@ impl RtError {
     }
 }
 
+/// The 1-based column (in characters) of a byte offset into the source.
+fn column(source: &[u8], pos: usize) -> usize {
+    let linestart = source[..pos].iter().rposition(|&c| c == b'\n').map_or(0, |i| i + 1);
+    String::from_utf8_lossy(&source[linestart..pos]).chars().count() + 1
+}
+
+/// Quote a string for JSON.
+pub fn json_str(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+impl Display for RtError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let lineinfo = match self.error.way {
+            Some(s) => String::from(s),
+            None => format!("ON THE WAY TO {}", self.lineno),
+        };
+        write!(f, "ICL{:03}I\t{}\n\t{}\n        CORRECT SOURCE AND RESUBNIT\n",
+               self.error.num, self.message(), lineinfo)
+    }
+}
+
+impl Error for RtError {
+    fn description(&self) -> &str {
+        self.error.msg
+    }
+}
+
 impl From<io::Error> for RtError {
     fn from(_: io::Error) -> RtError {
         IE888.new(None, 0)
@@ -92,19 +227,28 @@ impl ErrDesc {
     pub fn new(&'static self, addstr: Option<String>, line: usize) -> RtError {
         RtError { error: &self,
                   addstr: addstr,
-                  lineno: line }
+                  lineno: line,
+                  srcline: None,
+                  span: None,
+                  stack: vec![] }
     }
 
     pub fn err<T>(&'static self) -> Result<T, RtError> {
         Err(RtError { error: &self,
                       addstr: None,
-                      lineno: 0 })
+                      lineno: 0,
+                      srcline: None,
+                      span: None,
+                      stack: vec![] })
     }
 
     pub fn err_with<T>(&'static self, addstr: Option<&str>, line: usize) -> Result<T, RtError> {
         Err(RtError { error: &self,
                       addstr: addstr.map(|v| v.into()),
-                      lineno: line })
+                      lineno: line,
+                      srcline: None,
+                      span: None,
+                      stack: vec![] })
     }
 }
 
@@ -300,6 +444,13 @@ pub static IE888: ErrDesc = ErrDesc {
     way: None,
 };
 
+/* Program has executed more statements than allowed with --steps (Rick only). */
+pub static IE980: ErrDesc = ErrDesc {
+    num: 980,
+    msg: "PROGRAM HAS OUTSTAYED ITS WELCOME",
+    way: None,
+};
+
 /* Unknown invocation flag. */
 pub static IE990: ErrDesc = ErrDesc {
     num: 990,
@@ -327,3 +478,40 @@ pub static IE998: ErrDesc = ErrDesc {
     msg: "EXCUSE ME",
     way: Some("YOU MUST HAVE ME CONFUSED WITH SOME OTHER COMPILER"),
 };
+
+#[cfg(test)]
+mod tests {
+    use super::{ EXIT_COMPILE, EXIT_RUNTIME, IE000, IE123, IE633, IE990, json_str };
+
+    #[test]
+    fn exit_status() {
+        assert_eq!(IE000.new(None, 0).exit_status(false), EXIT_COMPILE);
+        assert_eq!(IE990.new(None, 0).exit_status(false), EXIT_COMPILE + 9);
+        assert_eq!(IE123.new(None, 0).exit_status(true), EXIT_RUNTIME + 1);
+        assert_eq!(IE633.new(None, 0).exit_status(true), EXIT_RUNTIME + 6);
+        assert_eq!((EXIT_COMPILE, EXIT_RUNTIME), (10, 20));
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(json_str("say \"hi\"\\\n\tto\u{1} ÉMILE"),
+                   "\"say \\\"hi\\\"\\\\\\n\\tto\\u0001 ÉMILE\"");
+
+        let source = b"DO .1 <- #1\nPLEASE DO\n  GIVE UP\n";
+        let mut err = IE633.new(None, 0);
+        err.set_stmt(2, Some((12, 31)));
+        err.push_next(1);
+        assert_eq!(err.to_json(Some("fall.i"), Some(source)),
+                   "{\"file\":\"fall.i\",\"line\":2,\"column\":1,\"end_line\":3,\"end_column\":10,\
+                    \"start\":12,\"end\":31,\"source\":\"PLEASE DO\\n  GIVE UP\",\
+                    \"level\":\"error\",\"code\":\"E633\",\"message\":\"PROGRAM FELL OFF THE EDGE\",\
+                    \"on_the_way_to\":\"ON THE WAY TO THE NEW WORLD\",\"next_stack\":[1]}\n");
+        // without a known statement or source, those fields are null
+        let err = IE000.new(Some("FOO \"BAR\"".into()), 5);
+        assert_eq!(err.to_json(None, None),
+                   "{\"file\":null,\"line\":null,\"column\":null,\"end_line\":null,\
+                    \"end_column\":null,\"start\":null,\"end\":null,\"source\":null,\
+                    \"level\":\"error\",\"code\":\"E000\",\"message\":\"FOO \\\"BAR\\\"\",\
+                    \"on_the_way_to\":5,\"next_stack\":[]}\n");
+    }
+}
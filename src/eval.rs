@@ -21,13 +21,15 @@
 /// the output while compiling (in the constant-output case).
 
 use std::fmt::{ Debug, Display };
-use std::io::Write;
+use std::io::{ Write, stderr };
 use std::u16;
 
-use err::{ Res, IE123, IE129, IE252, IE275, IE555, IE633, IE774, IE994 };
+use err::{ Res, RtError, IE123, IE129, IE252, IE275, IE555, IE633, IE774, IE980, IE994 };
 use ast::{ self, Program, Stmt, StmtBody, ComeFrom, Expr, Var, VType };
 use stdops::{ Bind, Array, write_number, read_number, check_chance, check_ovf, pop_jumps,
-              get_random_seed, mingle, select, and_16, and_32, or_16, or_32, xor_16, xor_32 };
+              get_random_seed, mingle, select, and_16, and_32, or_16, or_32, xor_16, xor_32,
+              times_16, times_32, divide_16, divide_32, modulus_16, modulus_32 };
+use width::Widths;
 
 
 /// Represents a value (either 16-bit or 32-bit) at runtime.
@@ -98,6 +100,14 @@ pub struct Eval<'a> {
     rand_st: u32,
     /// Counts the number of executed statements.
     stmt_ctr: usize,
+    /// Maximum number of statements to execute.
+    step_limit: Option<usize>,
+    /// Whether to print each statement to stderr before executing it.
+    pub trace: bool,
+    /// For each statement, true if it is an assignment whose value is known to
+    /// fit into the variable (see width.rs).  All false unless `skip_checks`
+    /// was called.
+    fits: Vec<bool>,
 }
 
 /// Represents the control flow effect of an executed statement.
@@ -115,15 +125,15 @@ enum Flow {
 }
 
 impl<'a> Eval<'a> {
-    /// Construct a new evaluator.
+    /// Construct a new evaluator.  Without a `seed`, a random one is used.
     pub fn new(program: &'a Program, stdout: &'a mut Write, debug: bool,
-               random: bool) -> Eval<'a> {
+               seed: Option<u32>, step_limit: Option<usize>) -> Eval<'a> {
         let abs = program.stmts.iter().map(|stmt| stmt.props.disabled as u32).collect();
         let nvars = (program.var_info.0.len(),
                      program.var_info.1.len(),
                      program.var_info.2.len(),
                      program.var_info.3.len());
-        Eval {
+        let mut eval = Eval {
             program:  program,
             stdout:   stdout,
             debug:    debug,
@@ -132,31 +142,144 @@ impl<'a> Eval<'a> {
             tail:     vec![Bind::new(Array::empty()); nvars.2],
             hybrid:   vec![Bind::new(Array::empty()); nvars.3],
             jumps:    Vec::with_capacity(80),
-            rand_st:  if random { get_random_seed() } else { 0 },
+            rand_st:  seed.unwrap_or_else(get_random_seed),
             abstain:  abs,
             last_in:  0,
             last_out: 0,
             stmt_ctr: 0,
+            step_limit: step_limit,
+            trace: false,
+            fits: vec![false; program.stmts.len()],
+        };
+        if let Some(ref start) = program.start {
+            eval.spot = start.spot.clone();
+            eval.twospot = start.twospot.clone();
+            eval.tail = start.tail.clone();
+            eval.hybrid = start.hybrid.clone();
+            eval.jumps.extend(start.jumps.iter().cloned());
+            eval.abstain = start.abstain.clone();
+            eval.last_in = start.last_in;
+            eval.last_out = start.last_out;
+            eval.stmt_ctr = start.steps;
         }
+        eval
+    }
+
+    /// Leave out the E275 check of assignments whose value is known to fit into
+    /// the variable.  Only for optimized programs: the plain interpreter checks
+    /// everything, so that it can be compared with the optimized variants.
+    pub fn skip_checks(&mut self) {
+        let widths = Widths::new(self.program);
+        self.fits = self.program.stmts.iter().map(|stmt| match stmt.body {
+            StmtBody::Calc(ref var, ref expr) => widths.fits(var, expr),
+            _ => false,
+        }).collect();
     }
 
     /// Interpret the program.  Returns either the number of executed statements,
     /// or an error (RtError).
     pub fn eval(&mut self) -> Res<usize> {
         let mut pctr = 0;  // index of current statement
+        if let Some(ref start) = self.program.start {
+            // the optimizer already ran the program up to here
+            if let Err(_) = self.stdout.write(&start.output) {
+                return IE252.err();
+            }
+            pctr = start.pctr;
+        }
+        try!(self.run(pctr, None));
+        Ok(self.stmt_ctr)
+    }
+
+    /// Interpret the program only as long as it doesn't depend on the outside
+    /// world: until it would read input or use a random number, or for at most
+    /// `max_steps` statements.  Returns the index of the statement to continue
+    /// with, or None if the program ended.
+    pub fn eval_prefix(&mut self, max_steps: usize) -> Res<Option<usize>> {
+        self.run(0, Some(max_steps))
+    }
+
+    /// Capture the current state, to continue with statement `pctr`.  The output
+    /// so far went to the writer and is not included.
+    pub fn snapshot(&self, pctr: usize) -> ast::Snapshot {
+        ast::Snapshot {
+            pctr: pctr,
+            output: Vec::new(),
+            steps: self.stmt_ctr,
+            spot: self.spot.clone(),
+            twospot: self.twospot.clone(),
+            tail: self.tail.clone(),
+            hybrid: self.hybrid.clone(),
+            jumps: self.jumps.clone(),
+            abstain: self.abstain.clone(),
+            last_in: self.last_in,
+            last_out: self.last_out,
+        }
+    }
+
+    /// Can the statement be executed without input or random numbers?
+    fn deterministic(&self, pctr: usize) -> bool {
+        let program = self.program;
+        let stmt = &program.stmts[pctr];
+        if let StmtBody::WriteIn(_) = stmt.body {
+            return false;
+        }
+        if stmt.props.chance < 100 {
+            return false;
+        }
+        // the COME FROMs that can take over after the statement must not have a
+        // chance either
+        if let Some(next) = stmt.comefrom {
+            if program.stmts[next as usize].props.chance < 100 {
+                return false;
+            }
+        }
+        if program.uses_complex_comefrom && stmt.props.label > 0 {
+            for other in &program.stmts {
+                if let StmtBody::ComeFrom(ComeFrom::Expr(_)) = other.body {
+                    if other.props.chance < 100 {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// The main interpreter loop, starting at statement `pctr`.  If `max_steps`
+    /// is given, stop before a statement that is not deterministic, see
+    /// `eval_prefix`.
+    fn run(&mut self, mut pctr: usize, max_steps: Option<usize>) -> Res<Option<usize>> {
         let program = self.program.clone();
         let nstmts = program.stmts.len();
         loop {
             // check for falling off the end
             if pctr >= nstmts {
-                // if the last statement was a TRY AGAIN, falling off the end is fine
-                if let StmtBody::TryAgain = program.stmts[program.stmts.len() - 1].body {
-                    break;
+                match program.stmts.last() {
+                    // if the last statement was a TRY AGAIN, falling off the end is fine
+                    Some(&Stmt { body: StmtBody::TryAgain, .. }) => break,
+                    // like compiled programs, blame the last statement
+                    Some(last) => return Err(self.annotate(IE633.new(None, 0), last)),
+                    None => return IE633.err(),
+                }
+            }
+            if let Some(max_steps) = max_steps {
+                if self.stmt_ctr >= max_steps || !self.deterministic(pctr) {
+                    return Ok(Some(pctr));
                 }
-                return IE633.err();
             }
             self.stmt_ctr += 1;
             let stmt = &program.stmts[pctr];
+            if let Some(limit) = self.step_limit {
+                if self.stmt_ctr > limit {
+                    let err = IE980.new(None, stmt.props.srcline);
+                    return Err(self.annotate(err, stmt));
+                }
+            }
+            // same format as the --trace option of compiled programs
+            if self.trace {
+                let _ = writeln!(stderr(), "{:5}: {}", stmt.props.srcline, stmt);
+            }
             // execute statement if not abstained
             if self.abstain[pctr] == 0 {
                 // check execution chance
@@ -164,7 +287,8 @@ impl<'a> Eval<'a> {
                 self.rand_st = rand_st;
                 if passed {
                     // try to eval this statement
-                    let res = match self.eval_stmt(stmt) {
+                    let fits = self.fits[pctr];
+                    let res = match self.eval_stmt(stmt, fits) {
                         // on error, set the correct line number and bubble up
                         Err(mut err) => {
                             err.set_line(stmt.props.onthewayto);
@@ -174,7 +298,7 @@ impl<'a> Eval<'a> {
                                     err.set_line(program.stmts[*i as usize].props.srcline);
                                 }
                             }
-                            return Err(err);
+                            return Err(self.annotate(err, stmt));
                         }
                         Ok(res)  => res
                     };
@@ -199,7 +323,8 @@ impl<'a> Eval<'a> {
             }
             // if we are on the line with the compiler bug, error out
             if pctr == self.program.bugline as usize {
-                return IE774.err_with(None, stmt.props.onthewayto);
+                let err = IE774.new(None, stmt.props.onthewayto);
+                return Err(self.annotate(err, stmt));
             }
             // try to determine if we have to go to a COME FROM statement
             // (note: in general, program.stmts[pctr] != stmt)
@@ -215,7 +340,7 @@ impl<'a> Eval<'a> {
                         if v == my_label {
                             // as soon as we have multiple candidates, we can bail out
                             if maybe_next.is_some() {
-                                return IE555.err();
+                                return Err(self.annotate(IE555.new(None, 0), &program.stmts[pctr]));
                             }
                             maybe_next = Some(i as u16);
                         }
@@ -240,11 +365,21 @@ impl<'a> Eval<'a> {
             // no COME FROM, normal execution
             pctr += 1;
         }
-        Ok(self.stmt_ctr)
+        Ok(None)
     }
 
-    /// Interpret a single statement.
-    fn eval_stmt(&mut self, stmt: &Stmt) -> Res<Flow> {
+    /// Add the statement and the NEXT stack to an error.
+    fn annotate(&self, mut err: RtError, stmt: &Stmt) -> RtError {
+        err.set_stmt(stmt.props.srcline, Some((stmt.props.span.start, stmt.props.span.end)));
+        for &i in self.jumps.iter().rev() {
+            err.push_next(self.program.stmts[i as usize].props.srcline);
+        }
+        err
+    }
+
+    /// Interpret a single statement.  `fits` is true if an assignment needs no
+    /// check.
+    fn eval_stmt(&mut self, stmt: &Stmt, fits: bool) -> Res<Flow> {
         if self.debug {
             println!("\nExecuting Stmt #{} (state before following)", self.stmt_ctr);
             self.dump_state();
@@ -253,7 +388,7 @@ impl<'a> Eval<'a> {
         match stmt.body {
             StmtBody::Calc(ref var, ref expr) => {
                 let val = try!(self.eval_expr(expr));
-                try!(self.assign(var, val));
+                try!(self.assign(var, val, !fits));
                 Ok(Flow::Next)
             }
             StmtBody::Dim(ref var, ref exprs) => {
@@ -355,7 +490,7 @@ impl<'a> Eval<'a> {
                     } else {
                         // write in single var or array element
                         let n = try!(read_number(0));
-                        try!(self.assign(var, Val::from_u32(n)));
+                        try!(self.assign(var, Val::from_u32(n), true));
                     }
                 }
                 Ok(Flow::Next)
@@ -369,7 +504,7 @@ impl<'a> Eval<'a> {
             }
             StmtBody::TryAgain => Ok(Flow::FromTop),
             StmtBody::GiveUp => Ok(Flow::End),
-            StmtBody::Error(ref e) => Err((*e).clone()),
+            StmtBody::Error(ref e, _) => Err((*e).clone()),
         }
     }
 
@@ -447,11 +582,11 @@ impl<'a> Eval<'a> {
                 let w = try!(self.eval_expr(wx));
                 Ok(Val::I32(v.as_u32() << w.as_u32()))
             }
-            // Expr::RsEqual(ref vx, ref wx) => {
-            //     let v = try!(self.eval_expr(vx));
-            //     let w = try!(self.eval_expr(wx));
-            //     Ok(Val::I32((v.as_u32() == w.as_u32()) as u32))
-            // }
+            Expr::RsEqual(ref vx, ref wx) => {
+                let v = try!(self.eval_expr(vx));
+                let w = try!(self.eval_expr(wx));
+                Ok(Val::I32((v.as_u32() == w.as_u32()) as u32))
+            }
             Expr::RsNotEqual(ref vx, ref wx) => {
                 let v = try!(self.eval_expr(vx));
                 let w = try!(self.eval_expr(wx));
@@ -467,6 +602,30 @@ impl<'a> Eval<'a> {
                 let w = try!(self.eval_expr(wx));
                 Ok(Val::I32(v.as_u32() - w.as_u32()))
             }
+            Expr::RsTimes(vtype, ref vx, ref wx) => {
+                let v = try!(self.eval_expr(vx));
+                let w = try!(self.eval_expr(wx));
+                match vtype {
+                    VType::I16 => Ok(Val::I16(try!(times_16(v.as_u32(), w.as_u32(), 0)) as u16)),
+                    VType::I32 => Ok(Val::I32(try!(times_32(v.as_u32(), w.as_u32(), 0)))),
+                }
+            }
+            Expr::RsDivide(vtype, ref vx, ref wx) => {
+                let v = try!(self.eval_expr(vx));
+                let w = try!(self.eval_expr(wx));
+                match vtype {
+                    VType::I16 => Ok(Val::I16(try!(divide_16(v.as_u32(), w.as_u32(), 0)) as u16)),
+                    VType::I32 => Ok(Val::I32(try!(divide_32(v.as_u32(), w.as_u32(), 0)))),
+                }
+            }
+            Expr::RsModulus(vtype, ref vx, ref wx) => {
+                let v = try!(self.eval_expr(vx));
+                let w = try!(self.eval_expr(wx));
+                match vtype {
+                    VType::I16 => Ok(Val::I16(try!(modulus_16(v.as_u32(), w.as_u32(), 0)) as u16)),
+                    VType::I32 => Ok(Val::I32(try!(modulus_32(v.as_u32(), w.as_u32(), 0)))),
+                }
+            }
         }
     }
 
@@ -485,14 +644,15 @@ impl<'a> Eval<'a> {
         }
     }
 
-    /// Assign to a variable.
-    fn assign(&mut self, var: &Var, val: Val) -> Res<()> {
+    /// Assign to a variable.  Without `checked`, the value is known to fit.
+    fn assign(&mut self, var: &Var, val: Val, checked: bool) -> Res<()> {
+        let as_u16 = |val: Val| if checked { val.as_u16() } else { Ok(val.as_u32() as u16) };
         match *var {
-            Var::I16(n) => Ok(self.spot[n].assign(try!(val.as_u16()))),
+            Var::I16(n) => Ok(self.spot[n].assign(try!(as_u16(val)))),
             Var::I32(n) => Ok(self.twospot[n].assign(val.as_u32())),
             Var::A16(n, ref subs) => {
                 let subs = try!(self.eval_subs(subs));
-                self.tail[n].set_md(subs, try!(val.as_u16()), 0)
+                self.tail[n].set_md(subs, try!(as_u16(val)), 0)
             }
             Var::A32(n, ref subs) => {
                 let subs = try!(self.eval_subs(subs));
@@ -550,9 +710,11 @@ impl<'a> Eval<'a> {
     /// P()rocess an ABSTAIN or REINSTATE statement.  Cannot fail.
     fn abstain(&mut self, what: &ast::Abstain, f: &Fn(u32) -> u32) {
         if let &ast::Abstain::Label(lbl) = what {
-            let idx = self.program.labels[&lbl] as usize;
-            if self.program.stmts[idx].body != StmtBody::GiveUp {
-                self.abstain[idx] = f(self.abstain[idx]);
+            if let Some(&idx) = self.program.labels.get(&lbl) {
+                let idx = idx as usize;
+                if self.program.stmts[idx].body != StmtBody::GiveUp {
+                    self.abstain[idx] = f(self.abstain[idx]);
+                }
             }
         } else {
             for (i, stype) in self.program.stmt_types.iter().enumerate() {
@@ -0,0 +1,125 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// Canonical formatting of INTERCAL source ("rick fmt").
+///
+/// Every statement is put on a line of its own, with labels in a column of
+/// their own, and its body is written out from the AST, which normalizes
+/// spacing, grouping characters and operator spellings.  Variables keep their
+/// numbers.
+///
+/// Anything that does not parse is left alone: lines that contain a splat
+/// (which includes all comments) are copied verbatim, together with any
+/// statements sharing a line with them.
+///
+/// Finally, if the program is too rude or too polite, just enough PLEASEs are
+/// added or removed (spread evenly over the reformatted statements) to make the
+/// compiler accept it.
+
+use ast::{ Stmt, StmtBody };
+use emit::{ balance_politeness, format_body, format_line };
+use err::Res;
+use lex::Span;
+use parse::Parser;
+
+
+/// A run of statements that is formatted as a whole.
+struct Chunk {
+    /// Index of the first and one past the last statement.
+    stmts: (usize, usize),
+    /// Byte range of the source lines the statements are on.
+    lines: (usize, usize),
+    /// True if the lines are copied verbatim.
+    verbatim: bool,
+}
+
+/// Format the given source code.
+pub fn format(code: &Vec<u8>) -> Res<Vec<u8>> {
+    let (stmts, nown) = try!(Parser::new(code, 1, false).parse_with_stdlibs());
+    let chunks = find_chunks(code, &stmts[..nown]);
+
+    // decide politeness; library statements and verbatim lines can't change
+    let current = stmts.iter().map(|s| s.props.polite).collect::<Vec<_>>();
+    let mut fixed = vec![true; stmts.len()];
+    for chunk in &chunks {
+        for i in chunk.stmts.0..chunk.stmts.1 {
+            fixed[i] = chunk.verbatim;
+        }
+    }
+    let polite = balance_politeness(&current, &fixed);
+
+    let mut res = Vec::new();
+    let mut last_end = None;
+    for chunk in &chunks {
+        // keep the blank lines between chunks (reformatted statements that were
+        // spread over several lines end up on one, so this keeps line numbers
+        // only up to the first such statement)
+        if let Some(end) = last_end {
+            let newlines = code[end..chunk.lines.0].iter().filter(|&&c| c == b'\n').count();
+            for _ in 1..newlines {
+                res.push(b'\n');
+            }
+        }
+        last_end = Some(chunk.lines.1);
+        if chunk.verbatim {
+            res.extend_from_slice(&code[chunk.lines.0..chunk.lines.1]);
+            res.push(b'\n');
+            continue;
+        }
+        for i in chunk.stmts.0..chunk.stmts.1 {
+            let stmt = &stmts[i];
+            let text = try!(format_body(&stmt.body, 0).map_err(|mut e| {
+                e.set_line(stmt.props.srcline);
+                e
+            }));
+            let line = format_line(stmt.props.label, polite[i], stmt.props.disabled,
+                                   stmt.props.chance, &text);
+            res.extend_from_slice(line.as_bytes());
+            res.push(b'\n');
+        }
+    }
+    Ok(res)
+}
+
+/// Group the statements into runs that share source lines.
+fn find_chunks(code: &[u8], stmts: &[Stmt]) -> Vec<Chunk> {
+    let line_start = |pos: usize| {
+        code[..pos].iter().rposition(|&c| c == b'\n').map_or(0, |i| i + 1)
+    };
+    let line_end = |pos: usize| {
+        code[pos..].iter().position(|&c| c == b'\n').map_or(code.len(), |i| pos + i)
+    };
+    let mut chunks: Vec<Chunk> = Vec::new();
+    for (i, stmt) in stmts.iter().enumerate() {
+        let Span { start, end } = stmt.props.span;
+        let verbatim = match stmt.body {
+            StmtBody::Error(..) => true,
+            _ => false,
+        };
+        let lines = (line_start(start), line_end(end));
+        if let Some(last) = chunks.last_mut() {
+            if lines.0 < last.lines.1 {
+                last.stmts.1 = i + 1;
+                last.lines.1 = lines.1;
+                last.verbatim |= verbatim;
+                continue;
+            }
+        }
+        chunks.push(Chunk { stmts: (i, i + 1), lines: lines, verbatim: verbatim });
+    }
+    chunks
+}
@@ -0,0 +1,521 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// Generates random INTERCAL programs for testing Rick, and shrinks the ones
+/// that show a bug.
+///
+/// A generated program consists of a main part, ending with a GIVE UP, and a
+/// few subroutines, each ending with a RESUME.  Statements are random
+/// calculations (with expressions over all operators, in both widths), I/O,
+/// STASH/RETRIEVE, IGNORE/REMEMBER, ABSTAIN/REINSTATE (by label and gerund),
+/// NEXTs to subroutines and COME FROMs, with the occasional NOT or %.
+///
+/// To make sure that the programs terminate, control only ever moves forward:
+/// NEXT only calls subroutines that come later in the program, and COME FROM
+/// only refers to earlier statements in the same part.  (Abstaining from a
+/// RESUME or GIVE UP makes control fall through into the next part, which is
+/// also forward.)  Expressions are built to fit the width they are assigned
+/// to, so most programs don't end with an error early on.
+///
+/// The same seed always gives the same program and input.  Shrinking removes
+/// as many statements as possible from a program, as long as `crosscheck`
+/// still finds a difference.
+
+use std::cmp::min;
+use std::fs::File;
+use std::io::{ Write, sink };
+
+use ast::{ StmtBody, Expr, Var, VType, Abstain, ComeFrom, Label };
+use crosscheck::Crosscheck;
+use emit::{ format_body, format_line, balance_politeness };
+use err::{ Res, IE888 };
+use format::format;
+
+
+const NSPOT: usize = 4;
+const NTWOSPOT: usize = 3;
+/// Dimensions of the tail and hybrid arrays.  The first tail must have one
+/// dimension.
+const TAILS: [&'static [u32]; 2] = [&[4], &[3, 3]];
+const HYBRIDS: [&'static [u32]; 2] = [&[4], &[2, 3]];
+/// Number of lines of input generated.
+const NINPUT: usize = 64;
+const DIGITS: [&'static str; 10] = ["ZERO", "ONE", "TWO", "THREE", "FOUR",
+                                    "FIVE", "SIX", "SEVEN", "EIGHT", "NINE"];
+
+/// A small deterministic random number generator (xorshift64*), so that the
+/// programs for a seed don't depend on the rand crate.
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        Random(seed.wrapping_mul(0x9E3779B97F4A7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545F4914F6CDD1D) >> 32
+    }
+
+    /// A number in 0..n.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// True with the given probability in percent.
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+}
+
+/// A statement of the generated program.
+struct Line {
+    label: Label,
+    disabled: bool,
+    chance: u8,
+    body: StmtBody,
+}
+
+pub struct Fuzzer {
+    rng: Random,
+    size: usize,
+    /// Labels given out so far.
+    labels: Vec<Label>,
+}
+
+impl Fuzzer {
+    /// A generator for programs of about `size` statements.
+    pub fn new(seed: u64, size: usize) -> Fuzzer {
+        Fuzzer { rng: Random::new(seed), size: size, labels: Vec::new() }
+    }
+
+    /// Generate a program and input for it.
+    pub fn generate(&mut self) -> Res<(String, String)> {
+        let nsubs = 1 + self.rng.below(3);
+        let entries = (0..nsubs).map(|_| self.new_label()).collect::<Vec<_>>();
+        let per_part = self.size / (nsubs + 2) + 1;
+
+        // the main part: dimension the arrays, run, and show the variables
+        let mut lines = Vec::new();
+        for (n, dims) in TAILS.iter().enumerate() {
+            let dims = dims.iter().map(|&d| Expr::Num(VType::I16, d)).collect();
+            lines.push(simple(StmtBody::Dim(Var::A16(n, vec![]), dims)));
+        }
+        for (n, dims) in HYBRIDS.iter().enumerate() {
+            let dims = dims.iter().map(|&d| Expr::Num(VType::I16, d)).collect();
+            lines.push(simple(StmtBody::Dim(Var::A32(n, vec![]), dims)));
+        }
+        // stash everything twice, so that RETRIEVEs in the main part don't fail
+        // if their STASH was skipped
+        for _ in 0..2 {
+            lines.push(simple(StmtBody::Stash(all_vars())));
+        }
+        let main = self.part(2 * per_part, 0, &entries);
+        lines.extend(main);
+        for n in 0..NSPOT {
+            lines.push(simple(StmtBody::ReadOut(vec![Expr::Var(Var::I16(n))])));
+        }
+        for n in 0..NTWOSPOT {
+            lines.push(simple(StmtBody::ReadOut(vec![Expr::Var(Var::I32(n))])));
+        }
+        lines.push(simple(StmtBody::GiveUp));
+
+        // the subroutines, which can only call the ones after them
+        for i in 0..nsubs {
+            let sub = self.part(per_part, entries[i], &entries[i + 1..]);
+            lines.extend(sub);
+            lines.push(simple(StmtBody::Resume(Expr::Num(VType::I16, 1))));
+        }
+
+        // now that all labels exist, pick the targets of ABSTAIN and REINSTATE
+        let labels = lines.iter().map(|l| l.label).filter(|&l| l > 0).collect::<Vec<_>>();
+        for line in &mut lines {
+            match line.body {
+                StmtBody::Abstain(_, ref mut whats) | StmtBody::Reinstate(ref mut whats) => {
+                    for what in whats {
+                        if *what == Abstain::Label(0) {
+                            *what = Abstain::Label(labels[self.rng.below(labels.len())]);
+                        }
+                    }
+                }
+                _ => { }
+            }
+        }
+
+        let current = lines.iter().map(|_| self.rng.chance(25)).collect::<Vec<_>>();
+        let polite = balance_politeness(&current, &vec![false; lines.len()]);
+        let mut program = String::new();
+        for (line, &polite) in lines.iter().zip(&polite) {
+            let text = try!(format_body(&line.body, 1));
+            program.push_str(&format_line(line.label, polite, line.disabled, line.chance, &text));
+            program.push('\n');
+        }
+        let mut input = String::new();
+        for _ in 0..NINPUT {
+            let n = if self.rng.chance(50) { self.rng.below(10) } else { self.rng.below(65536) };
+            let digits = n.to_string().bytes().map(|d| DIGITS[(d - b'0') as usize])
+                                              .collect::<Vec<_>>();
+            input.push_str(&digits.join(" "));
+            input.push('\n');
+        }
+        Ok((program, input))
+    }
+
+    fn new_label(&mut self) -> Label {
+        loop {
+            let label = 1 + self.rng.below(999) as Label;
+            if !self.labels.contains(&label) {
+                self.labels.push(label);
+                return label;
+            }
+        }
+    }
+
+    /// Generate a straight-line part of the program, starting with the label
+    /// `entry` (if nonzero), which may NEXT to the subroutines with the given
+    /// labels.
+    fn part(&mut self, n: usize, entry: Label, callees: &[Label]) -> Vec<Line> {
+        let mut lines: Vec<Line> = Vec::new();
+        // labels of earlier statements that a COME FROM may still refer to
+        let mut come_from_targets = Vec::new();
+        // variables that a RETRIEVE can restore
+        let mut stashed = if entry == 0 { [all_vars(), all_vars()].concat() } else { vec![] };
+        while lines.len() < n {
+            let body = match self.rng.below(20) {
+                0...6 => {
+                    let var = self.var();
+                    let expr = self.expr_for(&var, 3);
+                    StmtBody::Calc(var, expr)
+                }
+                7 | 8 => {
+                    let mut items = vec![self.readout_item()];
+                    if self.rng.chance(30) {
+                        items.push(self.readout_item());
+                    }
+                    StmtBody::ReadOut(items)
+                }
+                9 => StmtBody::WriteIn(vec![self.var()]),
+                10 => {
+                    let var = self.whole_var();
+                    stashed.push(var.clone());
+                    StmtBody::Stash(vec![var])
+                }
+                11 if !stashed.is_empty() => {
+                    let i = self.rng.below(stashed.len());
+                    StmtBody::Retrieve(vec![stashed.remove(i)])
+                }
+                12 => StmtBody::Ignore(vec![self.whole_var()]),
+                13 => StmtBody::Remember(vec![self.whole_var()]),
+                14 => {
+                    let count = if self.rng.chance(20) { Some(self.expr16(1)) } else { None };
+                    StmtBody::Abstain(count, vec![self.abstain_target()])
+                }
+                15 => StmtBody::Reinstate(vec![self.abstain_target()]),
+                16 | 17 if !callees.is_empty() => {
+                    StmtBody::DoNext(callees[self.rng.below(callees.len())])
+                }
+                18 if !come_from_targets.is_empty() => {
+                    let i = self.rng.below(come_from_targets.len());
+                    StmtBody::ComeFrom(ComeFrom::Label(come_from_targets.remove(i)))
+                }
+                // FORGET in a subroutine would make its RESUME return too far
+                19 if entry == 0 => StmtBody::Forget(Expr::Num(VType::I16, 1)),
+                _ => continue,
+            };
+            let mut line = simple(body);
+            let plain = match line.body {
+                StmtBody::DoNext(_) | StmtBody::ComeFrom(_) | StmtBody::Forget(_) => false,
+                _ => true,
+            };
+            if lines.is_empty() && entry > 0 {
+                line.label = entry;
+            } else if self.rng.chance(30) {
+                line.label = self.new_label();
+            }
+            if line.label > 0 && plain {
+                come_from_targets.push(line.label);
+            }
+            if self.rng.chance(5) {
+                line.disabled = true;
+            }
+            if self.rng.chance(5) {
+                line.chance = 1 + self.rng.below(99) as u8;
+            }
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// A variable to assign to or read into.
+    fn var(&mut self) -> Var {
+        match self.rng.below(4) {
+            0 => Var::I16(self.rng.below(NSPOT)),
+            1 => Var::I32(self.rng.below(NTWOSPOT)),
+            2 => {
+                let n = self.rng.below(TAILS.len());
+                Var::A16(n, self.subscripts(TAILS[n]))
+            }
+            _ => {
+                let n = self.rng.below(HYBRIDS.len());
+                Var::A32(n, self.subscripts(HYBRIDS[n]))
+            }
+        }
+    }
+
+    /// A variable or whole array, for STASH and friends.
+    fn whole_var(&mut self) -> Var {
+        match self.rng.below(4) {
+            0 => Var::I16(self.rng.below(NSPOT)),
+            1 => Var::I32(self.rng.below(NTWOSPOT)),
+            2 => Var::A16(self.rng.below(TAILS.len()), vec![]),
+            _ => Var::A32(self.rng.below(HYBRIDS.len()), vec![]),
+        }
+    }
+
+    /// Subscripts for an array, within its dimensions.
+    fn subscripts(&mut self, dims: &[u32]) -> Vec<Expr> {
+        dims.iter().map(|&d| Expr::Num(VType::I16, 1 + self.rng.below(d as usize) as u32))
+                   .collect()
+    }
+
+    fn readout_item(&mut self) -> Expr {
+        match self.rng.below(8) {
+            0 => Expr::Num(VType::I16, self.constant()),
+            // only one-dimensional tails can be read out as a whole
+            1 => Expr::Var(Var::A16(0, vec![])),
+            _ => Expr::Var(self.var()),
+        }
+    }
+
+    fn abstain_target(&mut self) -> Abstain {
+        let gerunds = [Abstain::Calc, Abstain::Next, Abstain::Resume, Abstain::Forget,
+                       Abstain::Ignore, Abstain::Remember, Abstain::Stash, Abstain::Retrieve,
+                       Abstain::Abstain, Abstain::Reinstate, Abstain::ComeFrom,
+                       Abstain::ReadOut, Abstain::WriteIn];
+        if self.rng.chance(70) {
+            // filled in when all labels are known
+            Abstain::Label(0)
+        } else {
+            gerunds[self.rng.below(gerunds.len())].clone()
+        }
+    }
+
+    fn constant(&mut self) -> u32 {
+        match self.rng.below(4) {
+            0 => [0, 1, 2, 0xFFFF][self.rng.below(4)],
+            1 => self.rng.below(16) as u32,
+            _ => self.rng.below(65536) as u32,
+        }
+    }
+
+    /// An expression whose value fits into the variable.
+    fn expr_for(&mut self, var: &Var, depth: usize) -> Expr {
+        match var.get_vtype() {
+            VType::I16 => self.expr16(depth),
+            VType::I32 => self.expr32(depth),
+        }
+    }
+
+    /// An expression whose value always fits into 16 bits.
+    fn expr16(&mut self, depth: usize) -> Expr {
+        if depth == 0 || self.rng.chance(30) {
+            return match self.rng.below(3) {
+                0 => Expr::Num(VType::I16, self.constant()),
+                1 => Expr::Var(Var::I16(self.rng.below(NSPOT))),
+                _ => {
+                    let n = self.rng.below(TAILS.len());
+                    Expr::Var(Var::A16(n, self.subscripts(TAILS[n])))
+                }
+            };
+        }
+        match self.rng.below(3) {
+            0 => {
+                // a 32-bit unary operator could set the upper bits
+                let operand = self.expr16(depth - 1);
+                match operand.get_vtype() {
+                    VType::I16 => self.unary(operand),
+                    VType::I32 => operand,
+                }
+            }
+            1 => {
+                // selecting with a 16-bit mask gives at most 16 bits
+                let value = if self.rng.chance(50) { self.expr16(depth - 1) }
+                            else { self.expr32(depth - 1) };
+                let mask = self.expr16(depth - 1);
+                select(value, mask)
+            }
+            _ => {
+                // or with a 32-bit mask that has at most 16 bits set
+                let value = self.expr32(depth - 1);
+                let mut mask = self.rng.next() as u32;
+                while mask.count_ones() > 16 {
+                    mask &= mask - 1;
+                }
+                let (hi, lo) = unmingle(mask);
+                select(value, mingle(Expr::Num(VType::I16, hi), Expr::Num(VType::I16, lo)))
+            }
+        }
+    }
+
+    /// An expression with any 32-bit value.
+    fn expr32(&mut self, depth: usize) -> Expr {
+        if depth == 0 || self.rng.chance(25) {
+            return match self.rng.below(3) {
+                0 => Expr::Var(Var::I32(self.rng.below(NTWOSPOT))),
+                1 => {
+                    let n = self.rng.below(HYBRIDS.len());
+                    Expr::Var(Var::A32(n, self.subscripts(HYBRIDS[n])))
+                }
+                _ => self.expr16(0),
+            };
+        }
+        match self.rng.below(4) {
+            0 | 1 => {
+                let (vx, wx) = (self.expr16(depth - 1), self.expr16(depth - 1));
+                mingle(vx, wx)
+            }
+            2 => {
+                let (vx, wx) = (self.expr32(depth - 1), self.expr32(depth - 1));
+                select(vx, wx)
+            }
+            _ => {
+                let operand = self.expr32(depth - 1);
+                self.unary(operand)
+            }
+        }
+    }
+
+    /// A random unary operator, of the width the parser will give it.
+    fn unary(&mut self, operand: Expr) -> Expr {
+        let vtype = operand.get_vtype();
+        match self.rng.below(3) {
+            0 => Expr::And(vtype, box operand),
+            1 => Expr::Or(vtype, box operand),
+            _ => Expr::Xor(vtype, box operand),
+        }
+    }
+}
+
+/// All scalar variables and arrays of the program.
+fn all_vars() -> Vec<Var> {
+    let mut vars = Vec::new();
+    vars.extend((0..NSPOT).map(Var::I16));
+    vars.extend((0..NTWOSPOT).map(Var::I32));
+    vars.extend((0..TAILS.len()).map(|n| Var::A16(n, vec![])));
+    vars.extend((0..HYBRIDS.len()).map(|n| Var::A32(n, vec![])));
+    vars
+}
+
+fn simple(body: StmtBody) -> Line {
+    Line { label: 0, disabled: false, chance: 100, body: body }
+}
+
+fn mingle(vx: Expr, wx: Expr) -> Expr {
+    Expr::Mingle(box vx, box wx)
+}
+
+/// A select, of the width the parser will give it (that of the mask).
+fn select(vx: Expr, wx: Expr) -> Expr {
+    Expr::Select(wx.get_vtype(), box vx, box wx)
+}
+
+/// Split a 32-bit value into the two 16-bit values that mingle to it.
+fn unmingle(v: u32) -> (u32, u32) {
+    let (mut hi, mut lo) = (0, 0);
+    for i in 0..16 {
+        hi |= ((v >> (2 * i + 1)) & 1) << i;
+        lo |= ((v >> (2 * i)) & 1) << i;
+    }
+    (hi, lo)
+}
+
+/// Remove statements from the program as long as crosscheck (with the given
+/// input and step limit) still finds a difference.  The smallest program found
+/// is written to `outfile`, and its number of statements is returned.
+///
+/// To avoid ending up with a different bug (or one that is just a broken
+/// program, e.g. a NEXT to a removed label), the plain interpreter must also
+/// still end with the same error as for the original program.
+pub fn shrink(code: &Vec<u8>, input: &[u8], steps: usize, outfile: &str) -> Res<usize> {
+    let source = try!(format(code));
+    try!(write_file(outfile, &source));
+    let expected = try!(try!(Crosscheck::new(outfile, input.to_vec(), steps, 0)).plain_error());
+    let mut lines = statement_lines(&source);
+    let mut chunk = lines.len() / 2;
+    while chunk > 0 {
+        let mut start = 0;
+        let mut removed = false;
+        // keep at least one statement: an empty program is not interesting
+        while start < lines.len() && chunk < lines.len() {
+            let mut candidate = lines[..start].to_vec();
+            candidate.extend_from_slice(&lines[min(start + chunk, lines.len())..]);
+            // the parser might reject the candidate, e.g. for politeness, which
+            // format fixes
+            let source = match format(&candidate.join("\n").into_bytes()) {
+                Ok(source) => source,
+                Err(_)     => { start += chunk; continue; }
+            };
+            try!(write_file(outfile, &source));
+            if still_differs(outfile, input, steps, &expected) {
+                lines = statement_lines(&source);
+                removed = true;
+            } else {
+                start += chunk;
+            }
+        }
+        if !removed {
+            chunk /= 2;
+        }
+    }
+    let source = lines.join("\n") + "\n";
+    try!(write_file(outfile, source.as_bytes()));
+    Ok(lines.len())
+}
+
+/// Check whether the program in `outfile` is still interesting for `shrink`.
+/// If crosscheck fails for it, it is not.
+fn still_differs(outfile: &str, input: &[u8], steps: usize, expected: &Option<String>) -> bool {
+    let check = match Crosscheck::new(outfile, input.to_vec(), steps, 0) {
+        Ok(check) => check,
+        Err(_)    => return false,
+    };
+    match check.plain_error() {
+        Ok(ref error) if error == expected => { }
+        _ => return false,
+    }
+    match check.run(&mut sink(), false) {
+        Ok(same) => !same,
+        Err(_)   => false,
+    }
+}
+
+fn statement_lines(source: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(source).lines().filter(|l| !l.trim().is_empty())
+                                   .map(String::from).collect()
+}
+
+/// Write a generated or shrunk program (or its input) to a file.
+pub fn write_file(name: &str, data: &[u8]) -> Res<()> {
+    match File::create(name) {
+        Ok(mut f) => if f.write_all(data).is_ok() { return Ok(()); },
+        Err(_) => { }
+    }
+    IE888.err()
+}
+
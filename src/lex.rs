@@ -21,13 +21,23 @@
 /// special methods, such as the pretty standard "peek" and "push back" features.
 
 use std::io::Read;
+use std::mem;
 use std::u32;
 
 
 pub type SrcLine = usize;
+/// Byte offset into the source.
+pub type SrcPos = usize;
+
+/// A range of bytes in the source.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct Span {
+    pub start: SrcPos,
+    pub end: SrcPos,
+}
 
 #[derive(PartialEq, Eq, Clone, Debug)]
-pub struct Token(pub TT, pub SrcLine);
+pub struct Token(pub TT, pub SrcLine, pub Span);
 
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum TT {
@@ -109,6 +119,7 @@ type Lx<'a, R> = &'a mut RawLexer<R>;
 
 rustlex! RawLexer {
     property line: SrcLine = 1;
+    property pos: SrcPos = 0;
 
     let ANY = .;
     let NUM = ['0'-'9']+;
@@ -130,8 +141,8 @@ rustlex! RawLexer {
     NUM            => |l: Lx<R>| { let s = l.yystr();
                                    l.tok(s.parse().map(TT::NUMBER)
                                          .unwrap_or(TT::NUMBER(u32::MAX))) }
-    WS             => |_: Lx<R>| -> Option<Token> { None }
-    NL             => |l: Lx<R>| -> Option<Token> { l.line += 1; None }
+    WS             => |l: Lx<R>| l.skip()
+    NL             => |l: Lx<R>| { l.line += 1; l.skip() }
 
     '('            => |l: Lx<R>| l.tok(TT::WAX)
     ')'            => |l: Lx<R>| l.tok(TT::WANE)
@@ -201,17 +212,35 @@ rustlex! RawLexer {
 }
 
 impl<R: Read> RawLexer<R> {
+    /// Length of the current token in bytes.  yystr() can't be used for this:
+    /// it panics on tokens that aren't valid UTF-8, such as unknown characters
+    /// and the Latin-1 currency signs.
+    #[inline]
+    fn yylen(&self) -> usize {
+        let (tok, pos) = (self._input.tok, self._input.pos);
+        let full: usize = self._input.inp[tok.buf..pos.buf].iter().map(|b| b.len()).sum();
+        full + pos.off - tok.off
+    }
+
     #[inline]
-    fn tok(&self, t: TT) -> Option<Token> {
-        Some(Token(t, self.line))
+    fn tok(&mut self, t: TT) -> Option<Token> {
+        let start = self.pos;
+        self.pos += self.yylen();
+        Some(Token(t, self.line, Span { start: start, end: self.pos }))
     }
 
     #[inline]
     fn tok_with_nl(&mut self, t: TT) -> Option<Token> {
-        let ret = Token(t, self.line);
-        let newlines = self.yystr().chars().filter(|c| *c == '\n').count();
-        self.line += newlines;
-        Some(ret)
+        let (line, start) = (self.line, self.pos);
+        self.line += self.yystr().chars().filter(|c| *c == '\n').count();
+        self.pos += self.yylen();
+        Some(Token(t, line, Span { start: start, end: self.pos }))
+    }
+
+    #[inline]
+    fn skip(&mut self) -> Option<Token> {
+        self.pos += self.yylen();
+        None
     }
 }
 
@@ -220,18 +249,15 @@ pub struct Lexer<R: Read> {
     inner: RawLexer<R>,
     stash: Vec<Token>,
     line:  SrcLine,
+    /// All tokens read from the raw lexer, in source order.
+    log:   Vec<Token>,
 }
 
 impl<R: Read> Iterator for Lexer<R> {
     type Item = TT;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let ret = self.inner_next();
-        if let Some(tok) = ret {
-            self.line = tok.1;
-            return Some(tok.0);
-        }
-        None
+        self.next_token().map(|tok| tok.0)
     }
 }
 
@@ -243,10 +269,12 @@ impl<R: Read> Lexer<R> {
         }
         // else, request a new token from the lexer
         if let Some(mut tok) = self.inner.next() {
-            // handle ! = '. combination right now
-            if let Token(TT::WOW, lno) = tok {
-                self.stash.push(Token(TT::SPOT, lno));
-                tok = Token(TT::SPARK, lno);
+            self.log.push(tok.clone());
+            // handle ! = '. combination right now; the spark gets the character
+            // and the spot is empty
+            if let Token(TT::WOW, lno, span) = tok {
+                self.stash.push(Token(TT::SPOT, lno, Span { start: span.end, end: span.end }));
+                tok = Token(TT::SPARK, lno, span);
             }
             Some(tok)
         } else {
@@ -267,8 +295,29 @@ impl<R: Read> Lexer<R> {
         }
     }
 
-    pub fn push(&mut self, t: TT) {
-        self.stash.push(Token(t, self.line));
+    /// Byte offset of the next token, or None at the end of input.
+    pub fn peek_pos(&mut self) -> Option<SrcPos> {
+        self.peek();
+        self.stash.last().map(|v| v.2.start)
+    }
+
+    /// Get the next token with its position.
+    pub fn next_token(&mut self) -> Option<Token> {
+        let ret = self.inner_next();
+        if let Some(ref tok) = ret {
+            self.line = tok.1;
+        }
+        ret
+    }
+
+    /// Push back a token returned by `next_token`.
+    pub fn push_token(&mut self, t: Token) {
+        self.stash.push(t);
+    }
+
+    /// Take the list of all tokens read so far.
+    pub fn take_log(&mut self) -> Vec<Token> {
+        mem::replace(&mut self.log, Vec::new())
     }
 
     pub fn lineno(&self) -> SrcLine {
@@ -279,5 +328,5 @@ impl<R: Read> Lexer<R> {
 pub fn lex<R: Read>(reader: R, startline: usize) -> Lexer<R> {
     let mut raw = RawLexer::new(reader);
     raw.line = startline;
-    Lexer { inner: raw, stash: vec![], line: 1 }
+    Lexer { inner: raw, stash: vec![], line: 1, log: vec![] }
 }
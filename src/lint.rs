@@ -0,0 +1,569 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// A linter for INTERCAL programs ("rick lint").
+///
+/// The linter works on the statements as they come from the parser (plus the
+/// standard libraries that will be appended), before any of the checks in
+/// `post_process` can stop it, so that it can report all problems at once and
+/// point at their exact location using the concrete syntax tree.
+///
+/// Each check is a `Lint`, which can be allowed, reported as a warning or
+/// reported as an error.  Warnings are written one per line in the usual
+/// "file:line:col: level: message [lint]" format, or as one JSON object per
+/// line.
+
+use std::collections::{ BTreeMap, HashMap, HashSet };
+use std::io::Write;
+
+use ast::{ Program, Stmt, StmtBody, Expr, Var, Abstain, ComeFrom };
+use callgraph::{ CallGraph, MAX_DEPTH };
+use cfg::{ Cfg, Target };
+use cst::Cst;
+use err::{ Res, IE990, json_str };
+use lex::{ SrcLine, Span };
+use opt::Optimizer;
+use parse::{ Parser, polite_bounds };
+use width::minimum;
+
+
+pub struct Lint {
+    pub name: &'static str,
+    pub desc: &'static str,
+    pub default: Level,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+pub static UNUSED_LABEL: Lint = Lint {
+    name: "unused-label",
+    desc: "a label is never NEXTed to, COME FROM, ABSTAINed or REINSTATEd",
+    default: Level::Warn,
+};
+
+pub static UNKNOWN_LABEL: Lint = Lint {
+    name: "unknown-label",
+    desc: "a NEXT, COME FROM, ABSTAIN or REINSTATE refers to a missing label",
+    default: Level::Deny,
+};
+
+pub static UNASSIGNED_VAR: Lint = Lint {
+    name: "unassigned-var",
+    desc: "a variable is read before it is assigned",
+    default: Level::Warn,
+};
+
+pub static UNDIMMED_ARRAY: Lint = Lint {
+    name: "undimmed-array",
+    desc: "an array is subscripted, but never dimensioned",
+    default: Level::Warn,
+};
+
+pub static UNREACHABLE: Lint = Lint {
+    name: "unreachable",
+    desc: "a statement after GIVE UP can never be executed",
+    default: Level::Warn,
+};
+
+pub static CONST_RESUME: Lint = Lint {
+    name: "const-resume",
+    desc: "a RESUME or FORGET with a constant that always fails or does nothing",
+    default: Level::Warn,
+};
+
+pub static POLITENESS: Lint = Lint {
+    name: "politeness",
+    desc: "the program is (nearly) too polite or too rude to be compiled",
+    default: Level::Warn,
+};
+
+pub static NEXT_OVERFLOW: Lint = Lint {
+    name: "next-overflow",
+    desc: "recursive NEXTs could exceed the NEXT stack limit (E123)",
+    default: Level::Warn,
+};
+
+pub static ALWAYS_OVERFLOW: Lint = Lint {
+    name: "always-overflow",
+    desc: "a value that must fit into 16 bits never does (E275 or E533)",
+    default: Level::Warn,
+};
+
+/// All lints, in the order they are listed.  (A function rather than a static:
+/// statics referring to other statics break incremental builds on our nightly.)
+pub fn lints() -> [&'static Lint; 9] {
+    [&UNUSED_LABEL, &UNKNOWN_LABEL, &UNASSIGNED_VAR, &UNDIMMED_ARRAY, &UNREACHABLE,
+     &CONST_RESUME, &POLITENESS, &NEXT_OVERFLOW, &ALWAYS_OVERFLOW]
+}
+
+/// A reported problem.
+pub struct Warning {
+    pub lint: &'static Lint,
+    pub level: Level,
+    pub line: SrcLine,
+    pub col: usize,
+    pub span: Span,
+    pub msg: String,
+}
+
+pub struct Linter {
+    levels: HashMap<&'static str, Level>,
+}
+
+impl Linter {
+    pub fn new() -> Linter {
+        Linter { levels: lints().iter().map(|l| (l.name, l.default)).collect() }
+    }
+
+    /// Set the level of a lint by name; "all" sets all of them.
+    pub fn set_level(&mut self, name: &str, level: Level) -> Res<()> {
+        if name == "all" {
+            for lint in lints().iter() {
+                self.levels.insert(lint.name, level);
+            }
+            return Ok(());
+        }
+        match lints().iter().find(|l| l.name == name) {
+            Some(lint) => { self.levels.insert(lint.name, level); Ok(()) }
+            None => IE990.err(),
+        }
+    }
+
+    /// Check a program's source code.
+    pub fn lint(&self, code: &Vec<u8>) -> Res<Vec<Warning>> {
+        let mut parser = Parser::new(code, 1, false);
+        let (stmts, nown) = try!(parser.parse_with_stdlibs());
+        let mut cx = Context { levels: &self.levels, cst: parser.cst(),
+                               stmts: stmts, nown: nown, warnings: Vec::new() };
+        // the control-flow and call graphs need a complete program, which may
+        // not be possible (and is not worth building if nobody wants to know)
+        let program = if [&UNASSIGNED_VAR, &NEXT_OVERFLOW].iter()
+            .any(|lint| self.levels[lint.name] != Level::Allow)
+        {
+            Parser::new(code, 1, false).get_program().ok().map(Optimizer::opt_abstain_check)
+        } else {
+            None
+        };
+        let cfg = program.as_ref().map(Cfg::new);
+        cx.check_labels();
+        cx.check_vars(program.as_ref().and_then(|p| cfg.as_ref().map(|c| (p, c))));
+        cx.check_unreachable();
+        cx.check_resume();
+        cx.check_overflow();
+        cx.check_politeness();
+        if let (Some(program), Some(cfg)) = (program, cfg) {
+            cx.check_next_depth(program, &cfg);
+        }
+        let mut warnings = cx.warnings;
+        warnings.sort_by(|a, b| a.span.start.cmp(&b.span.start));
+        Ok(warnings)
+    }
+
+    /// Check the statements of a file that was already parsed (as returned by
+    /// `Parser::parse`), with the CST of its parser, for the always-overflow
+    /// lint.  This is what compiling and interpreting warn about.
+    pub fn lint_stmts(&self, stmts: &[Stmt], cst: &Cst) -> Vec<Warning> {
+        let level = self.levels[ALWAYS_OVERFLOW.name];
+        if level == Level::Allow {
+            return Vec::new();
+        }
+        overflows(stmts, cst).into_iter().map(|(span, msg)| {
+            let (line, col) = cst.line_col(span.start);
+            Warning { lint: &ALWAYS_OVERFLOW, level: level, line: line, col: col,
+                      span: span, msg: msg }
+        }).collect()
+    }
+}
+
+/// The spans and messages for `check_overflow`.  Only needs the statements, so
+/// that it can also be used for an already parsed program.
+fn overflows(stmts: &[Stmt], cst: &Cst) -> Vec<(Span, String)> {
+    let mut found = Vec::new();
+    for stmt in stmts {
+        let exprs = stmt.exprs();
+        let operand = exprs.iter().filter_map(|expr| match **expr {
+            Expr::Mingle(ref vx, ref wx) => {
+                [&**vx, &**wx].iter().cloned().find(|e| minimum(e) > 0xFFFF)
+            }
+            _ => None,
+        }).next();
+        if let Some(operand) = operand {
+            let span = cst.expr_span(stmt, operand).unwrap_or(stmt.props.span);
+            found.push((span, format!("mingle operand is at least {}, which always \
+                                       fails with E533", minimum(operand))));
+            continue;
+        }
+        match stmt.body {
+            StmtBody::Calc(ref var @ Var::I16(_), ref expr) |
+            StmtBody::Calc(ref var @ Var::A16(..), ref expr) if minimum(expr) > 0xFFFF => {
+                let span = cst.expr_span(stmt, expr).unwrap_or(stmt.props.span);
+                found.push((span, format!("value is at least {}, which never fits into \
+                                           {} (E275)", minimum(expr),
+                                          var_name(var.unique()))));
+            }
+            _ => {}
+        }
+    }
+    found
+}
+
+struct Context<'a> {
+    levels: &'a HashMap<&'static str, Level>,
+    cst: &'a Cst,
+    /// All statements, including the standard libraries.
+    stmts: Vec<Stmt>,
+    /// Number of statements from the checked file; only these get warnings.
+    nown: usize,
+    warnings: Vec<Warning>,
+}
+
+impl<'a> Context<'a> {
+    fn warn(&mut self, lint: &'static Lint, span: Span, msg: String) {
+        let level = self.levels[lint.name];
+        if level == Level::Allow {
+            return;
+        }
+        let (line, col) = self.cst.line_col(span.start);
+        self.warnings.push(Warning { lint: lint, level: level, line: line, col: col,
+                                     span: span, msg: msg });
+    }
+
+    /// Labels that are defined but unused, or used but not defined.
+    fn check_labels(&mut self) {
+        let mut defined = BTreeMap::new();
+        let mut used = HashSet::new();
+        let mut complex_comefrom = false;
+        let mut missing = Vec::new();
+        for stmt in &self.stmts {
+            if stmt.props.label > 0 {
+                defined.insert(stmt.props.label, stmt.props.span);
+            }
+        }
+        for (i, stmt) in self.stmts.iter().enumerate() {
+            let target = match stmt.body {
+                StmtBody::DoNext(n) |
+                StmtBody::ComeFrom(ComeFrom::Label(n)) => Some(n),
+                StmtBody::Abstain(_, ref whats) |
+                StmtBody::Reinstate(ref whats) => match whats[0] {
+                    Abstain::Label(n) => Some(n),
+                    _ => None,
+                },
+                StmtBody::ComeFrom(ComeFrom::Expr(_)) => {
+                    complex_comefrom = true;
+                    None
+                }
+                _ => None,
+            };
+            if let Some(n) = target {
+                used.insert(n);
+                if !defined.contains_key(&n) && i < self.nown {
+                    missing.push((n, stmt.props.span));
+                }
+            }
+        }
+        for (n, span) in missing {
+            self.warn(&UNKNOWN_LABEL, span, format!("label ({}) is not defined", n));
+        }
+        // a computed COME FROM can come from any label
+        if complex_comefrom {
+            return;
+        }
+        let unused = self.stmts[..self.nown].iter()
+            .filter(|s| s.props.label > 0 && !used.contains(&s.props.label))
+            .map(|s| (s.props.label, self.label_span(s)))
+            .collect::<Vec<_>>();
+        for (n, span) in unused {
+            self.warn(&UNUSED_LABEL, span, format!("label ({}) is never used", n));
+        }
+    }
+
+    /// The span of a statement's label, which comes first.
+    fn label_span(&self, stmt: &Stmt) -> Span {
+        let toks = self.cst.tokens_in(stmt.props.span);
+        match toks.iter().position(|t| t.0 == ::lex::TT::WANE) {
+            Some(i) => Span { start: stmt.props.span.start, end: toks[i].2.end },
+            None => stmt.props.span,
+        }
+    }
+
+    /// Variables that are read before they are assigned, and arrays that are
+    /// subscripted but never dimensioned.
+    ///
+    /// With a control-flow graph, a read is reported if no path from the start
+    /// of the program to it assigns the variable.  The graph is conservative,
+    /// so this misses some problems, but doesn't report correct programs.
+    /// Without a graph, only variables that are never assigned are reported.
+    fn check_vars(&mut self, graph: Option<(&Program, &Cfg)>) {
+        let mut assigned = HashSet::new();
+        let mut dimmed = HashSet::new();
+        let mut subscripted = Vec::new();
+        for (i, stmt) in self.stmts.iter_mut().enumerate() {
+            assigned.extend(assigned_vars(stmt));
+            if let StmtBody::Dim(ref var, _) = stmt.body {
+                dimmed.insert(var.unique());
+            }
+            if i < self.nown {
+                let span = stmt.props.span;
+                stmt.walk_vars(|var| match *var {
+                    Var::A16(_, ref subs) | Var::A32(_, ref subs) if !subs.is_empty() => {
+                        subscripted.push((var.unique(), span));
+                    }
+                    _ => { }
+                });
+            }
+        }
+        let mut reported = HashSet::new();
+        for (key, span) in subscripted {
+            if !dimmed.contains(&key) && reported.insert(key) {
+                self.warn(&UNDIMMED_ARRAY, span,
+                          format!("array {} is never dimensioned", var_name(key)));
+            }
+        }
+        // the statements of the program and the parsed file correspond, but
+        // the program's variables are renumbered
+        let before = match graph {
+            Some((program, cfg)) if program.stmts.len() == self.stmts.len() => {
+                self.assigned_before(program, cfg)
+            }
+            _ => vec![Some(assigned.clone()); self.stmts.len()],
+        };
+        let mut unassigned = Vec::new();
+        for (stmt, before) in self.stmts[..self.nown].iter().zip(before) {
+            let before = match before {
+                Some(before) => before,
+                None => continue,
+            };
+            for expr in stmt.exprs() {
+                match *expr {
+                    Expr::Var(ref var @ Var::I16(_)) | Expr::Var(ref var @ Var::I32(_)) => {
+                        let key = var.unique();
+                        if !before.contains(&key) && reported.insert(key) {
+                            let span = self.cst.expr_span(stmt, expr)
+                                                .unwrap_or(stmt.props.span);
+                            unassigned.push((key, span));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for (key, span) in unassigned {
+            let msg = if assigned.contains(&key) {
+                format!("variable {} is read before it is assigned", var_name(key))
+            } else {
+                format!("variable {} is read, but never assigned", var_name(key))
+            };
+            self.warn(&UNASSIGNED_VAR, span, msg);
+        }
+    }
+
+    /// For every statement, the variables that may have been assigned when
+    /// control reaches it, or None if it can't be reached at all.
+    fn assigned_before(&self, program: &Program, cfg: &Cfg)
+                       -> Vec<Option<HashSet<(u8, usize)>>> {
+        let reachable = cfg.reachable(program);
+        let mut entry = vec![HashSet::new(); cfg.blocks.len()];
+        let mut todo = (0..cfg.blocks.len()).filter(|&i| reachable[i]).collect::<Vec<_>>();
+        while let Some(i) = todo.pop() {
+            let block = &cfg.blocks[i];
+            let mut exit = entry[i].clone();
+            for stmt in &self.stmts[block.start..block.end] {
+                exit.extend(assigned_vars(stmt));
+            }
+            // RESUME edges are followed as well, since the subroutine might
+            // have assigned the variable before returning
+            for edge in &block.succs {
+                if let Target::Block(j) = edge.target {
+                    if !exit.is_subset(&entry[j]) {
+                        entry[j].extend(exit.iter().cloned());
+                        todo.push(j);
+                    }
+                }
+            }
+        }
+        let mut before = vec![None; self.stmts.len()];
+        for (i, block) in cfg.blocks.iter().enumerate() {
+            if !reachable[i] {
+                continue;
+            }
+            let mut current = entry[i].clone();
+            for j in block.start..block.end {
+                before[j] = Some(current.clone());
+                current.extend(assigned_vars(&self.stmts[j]));
+            }
+        }
+        before
+    }
+
+    /// Statements that can't be reached because they follow a GIVE UP.
+    fn check_unreachable(&mut self) {
+        let mut dead = false;
+        let mut found = Vec::new();
+        for stmt in &self.stmts[..self.nown] {
+            // labels can be NEXTed to, and execution continues after a COME FROM
+            let entry = stmt.props.label > 0 || match stmt.body {
+                StmtBody::ComeFrom(_) => true,
+                _ => false,
+            };
+            if entry {
+                dead = false;
+            }
+            match stmt.body {
+                // comments are not worth warning about
+                StmtBody::Error(..) => { }
+                _ if dead => {
+                    found.push(stmt.props.span);
+                    // report only the first of a run
+                    dead = false;
+                    continue;
+                }
+                _ => { }
+            }
+            if let StmtBody::GiveUp = stmt.body {
+                dead = !stmt.props.disabled && stmt.props.chance == 100;
+            }
+        }
+        for span in found {
+            self.warn(&UNREACHABLE, span, "statement after GIVE UP is unreachable".into());
+        }
+    }
+
+    /// RESUME #0 (always an error), RESUME #81 and beyond (the NEXT stack is
+    /// never that deep) and FORGET #0 (does nothing).
+    fn check_resume(&mut self) {
+        let mut found = Vec::new();
+        for stmt in &self.stmts[..self.nown] {
+            match stmt.body {
+                StmtBody::Resume(Expr::Num(_, 0)) => {
+                    found.push((stmt.props.span, "RESUME #0 always fails with E621"));
+                }
+                StmtBody::Resume(Expr::Num(_, n)) if n > 80 => {
+                    found.push((stmt.props.span,
+                                "the NEXT stack can never be deep enough for this RESUME"));
+                }
+                StmtBody::Forget(Expr::Num(_, 0)) => {
+                    found.push((stmt.props.span, "FORGET #0 does nothing"));
+                }
+                _ => { }
+            }
+        }
+        for (span, msg) in found {
+            self.warn(&CONST_RESUME, span, msg.into());
+        }
+    }
+
+    /// Statements that fail whenever they are executed, because a mingle
+    /// operand or the value assigned to a 16-bit variable is always too big.
+    fn check_overflow(&mut self) {
+        for (span, msg) in overflows(&self.stmts[..self.nown], self.cst) {
+            self.warn(&ALWAYS_OVERFLOW, span, msg);
+        }
+    }
+
+    /// NEXTs that could overflow the NEXT stack.
+    fn check_next_depth(&mut self, program: Program, cfg: &Cfg) {
+        let graph = CallGraph::new(&program, cfg);
+        for i in graph.overflows() {
+            if i < self.nown {
+                self.warn(&NEXT_OVERFLOW, program.stmts[i].props.span,
+                          format!("this NEXT could overflow the NEXT stack of {} entries",
+                                  MAX_DEPTH));
+            }
+        }
+    }
+
+    /// Politeness outside of, or at the edge of, the accepted range.
+    fn check_politeness(&mut self) {
+        let nstmts = self.stmts.len();
+        let npolite = self.stmts.iter().filter(|s| s.props.polite).count();
+        let (min_polite, max_polite) = polite_bounds(nstmts);
+        let msg = if npolite < min_polite {
+            format!("{} of {} statements are polite, at least {} are needed",
+                    npolite, nstmts, min_polite)
+        } else if npolite > max_polite {
+            format!("{} of {} statements are polite, at most {} are allowed",
+                    npolite, nstmts, max_polite)
+        } else if nstmts > 2 && (npolite <= min_polite || npolite >= max_polite) {
+            format!("{} of {} statements are polite, which is just barely acceptable",
+                    npolite, nstmts)
+        } else {
+            return;
+        };
+        let span = Span { start: 0, end: 0 };
+        self.warn(&POLITENESS, span, msg);
+    }
+}
+
+/// The spot and twospot variables (and arrays) a statement assigns.
+fn assigned_vars(stmt: &Stmt) -> Vec<(u8, usize)> {
+    match stmt.body {
+        StmtBody::Calc(ref var, _) => vec![var.unique()],
+        StmtBody::WriteIn(ref vars) |
+        StmtBody::Retrieve(ref vars) => vars.iter().map(|var| var.unique()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn var_name(key: (u8, usize)) -> String {
+    let sigil = match key.0 { 0 => ".", 1 => ":", 2 => ",", _ => ";" };
+    format!("{}{}", sigil, key.1)
+}
+
+impl Warning {
+    fn level_name(&self) -> &'static str {
+        if self.level == Level::Deny { "error" } else { "warning" }
+    }
+
+    /// Write the warning in the format used by most compilers.
+    pub fn write_plain(&self, o: &mut Write, filename: &str) -> Res<()> {
+        try!(write!(o, "{}:{}:{}: {}: {} [{}]\n", filename, self.line, self.col,
+                    self.level_name(), self.msg, self.lint.name));
+        Ok(())
+    }
+
+    /// Write the warning as a JSON object on a single line.
+    pub fn write_json(&self, o: &mut Write, filename: &str) -> Res<()> {
+        try!(write!(o, "{{\"file\":{},\"line\":{},\"column\":{},\"start\":{},\"end\":{},\
+                        \"level\":\"{}\",\"lint\":\"{}\",\"message\":{}}}\n",
+                    json_str(filename), self.line, self.col, self.span.start, self.span.end,
+                    self.level_name(), self.lint.name, json_str(&self.msg)));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ Linter, UNKNOWN_LABEL };
+
+    #[test]
+    fn unknown_labels() {
+        // the parser stops at the first ABSTAIN/REINSTATE or COME FROM of these (E139,
+        // E444), so the lint must work on the statements before post-processing
+        let code = b"\tDO ABSTAIN FROM (1)\n\tDO REINSTATE (2)\n\tDO (3) NEXT\n\
+                     \tDO COME FROM (4)\n\tPLEASE GIVE UP\n".to_vec();
+        let warnings = Linter::new().lint(&code).unwrap();
+        let found = warnings.iter().filter(|w| w.lint.name == UNKNOWN_LABEL.name)
+                                   .map(|w| (w.line, &w.msg[..])).collect::<Vec<_>>();
+        assert_eq!(found, vec![(1, "label (1) is not defined"), (2, "label (2) is not defined"),
+                               (3, "label (3) is not defined"), (4, "label (4) is not defined")]);
+    }
+}
@@ -30,50 +30,108 @@ extern crate time;
 
 mod err;
 mod lex;
+mod cst;
 mod parse;
 mod ast;
 mod opt;
+mod rules;
+mod bits;
+mod propagate;
+mod width;
 mod eval;
 mod codegen;
 mod stdops;
+// only part of the runtime library, but tested with Rick
+#[cfg(test)]
+#[allow(dead_code)]
+mod args;
 mod syslib;
 mod mandel;
+mod emit;
+mod cfg;
+mod callgraph;
+mod format;
+mod lint;
+mod runtime;
+mod project;
+mod driver;
+mod cache;
+mod crosscheck;
+mod fuzz;
 
 use std::env::args;
 use std::io::{ Read, Write, stdout, stderr };
-use std::fs::{ File, remove_file };
-use std::process::{ Command, Stdio, exit };
-use std::sync::mpsc;
-use std::thread;
+use std::fs::File;
+use std::path::PathBuf;
+use std::process::exit;
 use std::time::Duration;
 
 use parse::Parser;
-use opt::Optimizer;
+use opt::{ Optimizer, Pass };
 use eval::Eval;
 use codegen::Generator;
+use emit::Emitter;
 
 
 fn main() {
     match main_inner() {
         Ok(code) => exit(code),
-        Err(err) => {
-            let mut stderr = stderr();
-            write!(stderr, "{}", err.to_string()).unwrap();
-            exit(1);
-        }
+        Err(err) => err.exit(false),
     }
 }
 
 fn main_inner() -> Result<i32, err::RtError> {
     let args: Vec<String> = args().collect();
+    if args.len() > 1 && args[1] == "fmt" {
+        return run_fmt(&args[2..]);
+    }
+    if args.len() > 1 && args[1] == "lint" {
+        return run_lint(&args[2..]);
+    }
+    if args.len() > 1 && args[1] == "runtime" {
+        return run_runtime(&args[2..]);
+    }
+    if args.len() > 1 && args[1] == "cache" {
+        return run_cache(&args[2..]);
+    }
+    if args.len() > 1 && args[1] == "rules" {
+        return run_rules(&args[2..]);
+    }
+    if args.len() > 1 && args[1] == "crosscheck" {
+        return run_crosscheck(&args[2..]);
+    }
+    if args.len() > 1 && args[1] == "fuzz" {
+        return run_fuzz(&args[2..]);
+    }
     let mut opts = getopts::Options::new();
     opts.optflag("i", "interpret", "interpret code instead of compiling");
     opts.optflag("c", "no-compile", "do not call rustc");
+    opts.optflag("s", "source", "write INTERCAL source to stdout instead of compiling");
+    opts.optflag("g", "graph", "write the control-flow graph in DOT format to stdout");
+    opts.optflag("k", "calls", "write the NEXT/RESUME call graph to stdout (as DOT with -g)");
     opts.optflag("o", "opt", "optimize parsed code");
     opts.optflag("b", "no-bug", "eliminate probability for E774");
     opts.optflag("O", "rustc-opt", "run rustc in optimized mode");
+    opts.optopt("", "output", "name of the executable (default: input without .i)", "FILE");
+    opts.optopt("", "rustc", "rustc to use (default: $RUSTC or rustc)", "RUSTC");
+    opts.optmulti("C", "codegen", "pass a codegen option to rustc", "OPT[=VALUE]");
+    opts.optopt("", "target", "target triple to compile for", "TRIPLE");
+    opts.optflag("", "keep", "keep the generated Rust source");
+    opts.optflag("", "no-cache", "do not use the cache of compiled executables");
+    opts.optopt("", "steps", "stop interpreting with an error after N statements", "N");
+    opts.optflag("", "trace", "print each statement to stderr before interpreting it");
+    opts.optopt("", "seed", "seed the random number generator when interpreting", "N");
+    opts.optopt("", "error-format", "how to print errors: classic (default) or json", "FORMAT");
+    opts.optopt("P", "project", "write a Cargo project to DIR instead of compiling", "DIR");
+    opts.optopt("", "crate-name", "name of the crate written with -P", "NAME");
+    opts.optopt("", "edition", "Rust edition of the crate written with -P", "EDITION");
     opts.optflag("R", "no-random", "use deterministic random seed");
     opts.optflag("F", "no-constout", "do not optimize away const-output programs");
+    opts.optopt("", "passes", "comma-separated optimizer passes to run (with -o)", "PASSES");
+    opts.optopt("", "dump-after", "print the program after each run of an optimizer pass",
+                "PASS");
+    opts.optflag("", "opt-stats", "print what each optimizer pass did");
+    opts.optflag("", "no-warn", "do not warn about values that never fit");
     opts.optflag("d", "debug", "activate printing out debug messages");
     opts.optflag("t", "timing", "print out timing messages");
     opts.optflag("h", "help", "print help message");
@@ -91,7 +149,45 @@ fn main_inner() -> Result<i32, err::RtError> {
         return Ok(0);
     }
 
+    let json_flag = match matches.opt_str("error-format") {
+        None => false,
+        Some(ref f) if f == "classic" => false,
+        Some(ref f) if f == "json" => true,
+        Some(_) => return err::IE990.err(),
+    };
+
+    // no input file? -> do nothing
+    if matches.free.is_empty() {
+        return Ok(0);
+    }
+
+    let infile = &matches.free[0];
+    let mut code = Vec::new();
+    match process(&matches, infile, &mut code, json_flag) {
+        Err(err) => report(&err, false, json_flag, infile, &code),
+        res      => res,
+    }
+}
+
+/// Print an error in the selected format and exit.  The source text is only
+/// included in JSON if the error is located in the input file itself (and not
+/// in an automatically appended library).
+fn report(err: &err::RtError, runtime: bool, json: bool, infile: &str, code: &[u8]) -> ! {
+    let nlines = code.iter().filter(|&&c| c == b'\n').count() + 1;
+    let source = match err.srcline() {
+        Some(line) if line <= nlines => Some(code),
+        _ => None,
+    };
+    err.exit_as(runtime, json, Some(infile), source)
+}
+
+/// Read, parse and optimize the input file, then compile, interpret or dump it.
+fn process(matches: &getopts::Matches, infile: &str, code: &mut Vec<u8>, json_flag: bool)
+           -> Result<i32, err::RtError> {
     let compile_flag = !matches.opt_present("i");
+    let source_flag = matches.opt_present("s");
+    let graph_flag = matches.opt_present("g");
+    let calls_flag = matches.opt_present("k");
     let debug_flag = matches.opt_present("d");
     let timing_flag = matches.opt_present("t");
     let opt_flag = matches.opt_present("o");
@@ -100,14 +196,48 @@ fn main_inner() -> Result<i32, err::RtError> {
     let rustc_flag = !matches.opt_present("c");
     let rustc_opt_flag = matches.opt_present("O");
     let const_out_flag = !matches.opt_present("F");
-
-    // no input file? -> do nothing
-    if matches.free.is_empty() {
-        return Ok(0);
-    }
+    let project_dir = matches.opt_str("P");
+    let cache_flag = !matches.opt_present("no-cache");
+    let step_limit = match matches.opt_str("steps").map(|n| n.parse()) {
+        None         => None,
+        Some(Ok(n))  => Some(n),
+        Some(Err(_)) => return err::IE990.err(),
+    };
+    // None for a random seed
+    let seed = match matches.opt_str("seed").map(|n| n.parse()) {
+        None if rand_flag => None,
+        None         => Some(0),
+        Some(Ok(n))  => Some(n),
+        Some(Err(_)) => return err::IE990.err(),
+    };
+    let mut passes = match matches.opt_str("passes") {
+        None => Pass::defaults(true, true),
+        Some(list) => {
+            let mut passes = Vec::new();
+            for name in list.split(',').filter(|name| !name.is_empty()) {
+                match Pass::from_name(name) {
+                    Some(pass) => passes.push(pass),
+                    None => return err::IE990.err(),
+                }
+            }
+            passes
+        }
+    };
+    // the snapshot can't be written as INTERCAL source
+    passes.retain(|&pass| match pass {
+        Pass::ConstOutput => const_out_flag,
+        Pass::Snapshot => const_out_flag && !source_flag,
+        _ => true,
+    });
+    let dump_after = match matches.opt_str("dump-after").map(|name| Pass::from_name(&name)) {
+        None          => None,
+        Some(Some(p)) => Some(p),
+        Some(None)    => return err::IE990.err(),
+    };
+    let stats_flag = matches.opt_present("opt-stats");
+    let warn_flag = !matches.opt_present("no-warn");
 
     // verify and open input file
-    let infile = &matches.free[0];
     if !infile.ends_with(".i") {
         return err::IE998.err();
     }
@@ -117,64 +247,135 @@ fn main_inner() -> Result<i32, err::RtError> {
     };
 
     // read code from input file
-    let mut code = Vec::new();
-    if let Err(_) = f.read_to_end(&mut code) {
+    if let Err(_) = f.read_to_end(code) {
         return err::IE777.err();
     }
 
     // parse source
     let t0 = time::get_time();
-    let mut program = match Parser::new(&code, 1, bug_flag).get_program() {
-        Ok(program) => {
-            if debug_flag {
-                println!("Parsed program:\n{}", program);
-            }
-            program
+    let mut parser = Parser::new(code, 1, bug_flag);
+    let stmts = try!(parser.parse());
+
+    // statements that always overflow are worth a warning even without `rick
+    // lint`; this looks at the statements before their variables are renumbered
+    if warn_flag {
+        for warning in lint::Linter::new().lint_stmts(&stmts, parser.cst()) {
+            try!(warning.write_plain(&mut stderr(), infile));
         }
-        Err(err)    => return Err(err),
-    };
+    }
+
+    let mut program = try!(parser.post_process(stmts));
+    if debug_flag {
+        println!("Parsed program:\n{}", program);
+    }
 
     // optimize if wanted
     let t1 = time::get_time();
     if opt_flag {
-        program = Optimizer::new(program, const_out_flag).optimize();
+        let (optimized, stats) = Optimizer::new(program, passes.clone(), dump_after,
+                                                stats_flag).optimize();
+        program = optimized;
         if debug_flag {
             println!("Optimized program:\n{}", program);
         }
+        if stats_flag {
+            try!(opt::write_stats(&stats, &mut stderr()));
+        }
     }
 
     // compile or run
     let t2 = time::get_time();
-    if compile_flag {
+    if source_flag {
+        let mut stdout = stdout();
+        try!(Emitter::new(&program).emit(&mut stdout));
+    } else if calls_flag {
+        let mut stdout = stdout();
+        let program = Optimizer::opt_abstain_check(program);
+        let graph = callgraph::CallGraph::new(&program, &cfg::Cfg::new(&program));
+        if graph_flag {
+            try!(graph.write_dot(&program, &mut stdout));
+        } else {
+            try!(graph.write_text(&program, &mut stdout));
+        }
+    } else if graph_flag {
+        let mut stdout = stdout();
+        try!(cfg::Cfg::new(&program).write_dot(&program, &mut stdout));
+    } else if let Some(dir) = project_dir {
+        let name = matches.opt_str("crate-name").unwrap_or(project::crate_name(infile));
+        let edition = matches.opt_str("edition").unwrap_or("2015".into());
+        let project = try!(project::Project::new(&dir, &name, &edition));
+        let output = try!(project.write(infile, code));
+        try!(Generator::new(program, output, infile, debug_flag, rand_flag).generate());
+    } else if compile_flag {
         // PLEASE NOTE the selection of errors generated on different conditions
         // is a bit random
         let outname = String::from(&infile[..infile.len()-2]) + ".rs";
-        // open output file
-        let output = match File::create(&outname) {
-            Err(_) => return err::IE888.err(),
-            Ok(f)  => f,
+        let exename = matches.opt_str("output").unwrap_or(
+            String::from(&infile[..infile.len()-2]));
+        let mut driver = driver::Driver::new();
+        if let Some(rustc) = matches.opt_str("rustc") {
+            driver.rustc = rustc;
+        }
+        driver.opt = rustc_opt_flag;
+        driver.codegen = matches.opt_strs("C");
+        driver.target = matches.opt_str("target");
+        driver.keep = matches.opt_present("keep");
+        // look for the executable in the cache
+        let cache = cache::Cache::new();
+        let mut key = None;
+        if rustc_flag && cache_flag {
+            let flags = format!("{} {:?} {} {} {} {} {:?} {:?}", opt_flag, passes,
+                                rand_flag, debug_flag, driver.opt, program.bugline,
+                                driver.codegen, driver.target);
+            let version = try!(runtime::rustc_version(&driver.rustc));
+            key = Some(cache::key(&[code, flags.as_bytes(), &version]));
+        }
+        let cached = match key {
+            Some(ref key) => cache.fetch(key, infile, &exename),
+            None => false,
         };
-        // generate Rust code
-        try!(Generator::new(program, output, debug_flag, rand_flag).generate());
+        // generate Rust code (not needed for a cached executable, unless the
+        // source is to be kept)
+        if !cached || driver.keep {
+            let output = match File::create(&outname) {
+                Err(_) => return err::IE888.err(),
+                Ok(f)  => f,
+            };
+            try!(Generator::new(program, output, infile, debug_flag, rand_flag).generate());
+        }
         let t3 = time::get_time();
         // if wanted, compile to binary
-        if rustc_flag {
-            try!(run_compiler(&outname, rustc_opt_flag));
+        if rustc_flag && !cached {
+            try!(driver.build(&outname, &exename));
+            if let Some(ref key) = key {
+                try!(cache.store(key, infile, &exename));
+            }
         }
         let t4 = time::get_time();
         if timing_flag {
             println!("parsing:    {}", (t1 - t0));
             println!("optimizing: {}", (t2 - t1));
             println!("code gen:   {}", (t3 - t2));
-            println!("rustc:      {}", (t4 - t3));
+            if cached {
+                println!("rustc:      cached");
+            } else {
+                println!("rustc:      {}", (t4 - t3));
+            }
         }
     } else {
         let mut stdout = stdout();
         if debug_flag {
             println!("Running:");
         }
-        let mut eval = Eval::new(&program, &mut stdout, debug_flag, rand_flag);
-        let num = try!(eval.eval());
+        let mut eval = Eval::new(&program, &mut stdout, debug_flag, seed, step_limit);
+        eval.trace = matches.opt_present("trace");
+        if opt_flag {
+            eval.skip_checks();
+        }
+        let num = match eval.eval() {
+            Ok(num)  => num,
+            Err(err) => report(&err, true, json_flag, infile, code),
+        };
         let t3 = time::get_time();
         if timing_flag {
             println!("#stmts:     {}", num);
@@ -186,41 +387,354 @@ fn main_inner() -> Result<i32, err::RtError> {
     Ok(0)
 }
 
-fn run_compiler(outname: &str, opt_flag: bool) -> Result<(), err::RtError> {
-    let mut cmd = Command::new("rustc");
-    if opt_flag {
-        cmd.arg("-O");
-    }
-    cmd.arg("-o").arg(&outname[..outname.len()-3]);
-    cmd.arg(&outname);
-    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
-    let child = match cmd.spawn() {
-        Err(_) => return err::IE666.err(),
-        Ok(ch) => ch,
-    };
-    // make the user comfortable...
-    let (wchan, rchan) = mpsc::channel();
-    let threadhandle = thread::spawn(move || {
-        let mut printer = mandel::MandelPrinter::new();
-        while let Err(mpsc::TryRecvError::Empty) = rchan.try_recv() {
-            printer.print_char(true);
-            thread::sleep(Duration::from_millis(
-                (2u64).pow((rand::random::<u8>() / 40) as u32)));
-        }
-        printer.finish_current();
-    });
+/// "rick fmt": reformat the given files in place.
+fn run_fmt(args: &[String]) -> Result<i32, err::RtError> {
+    let mut opts = getopts::Options::new();
+    opts.optflag("h", "help", "print help message");
+    let matches = match opts.parse(args) {
+        Ok(m)  => m,
+        Err(e) => { println!("{}", e.to_string());
+                    return err::IE990.err() },
+    };
+    if matches.opt_present("h") {
+        println!("{}", opts.usage("rick fmt input.i..."));
+        return Ok(0);
+    }
+    for infile in &matches.free {
+        if !infile.ends_with(".i") {
+            return err::IE998.err();
+        }
+        let mut code = Vec::new();
+        match File::open(&infile) {
+            Err(_) => return err::IE777.err(),
+            Ok(mut f) => if let Err(_) = f.read_to_end(&mut code) {
+                return err::IE777.err();
+            }
+        }
+        let formatted = try!(format::format(&code));
+        if formatted != code {
+            let mut f = match File::create(&infile) {
+                Err(_) => return err::IE888.err(),
+                Ok(f)  => f,
+            };
+            try!(f.write_all(&formatted));
+        }
+    }
+    Ok(0)
+}
 
-    let wait_res = child.wait_with_output();
-    wchan.send(()).unwrap();
-    threadhandle.join().unwrap();
-    let out = match wait_res {
-        Err(_)  => return err::IE666.err(),
-        Ok(out) => out,
+/// "rick lint": report possible problems in the given files.
+fn run_lint(args: &[String]) -> Result<i32, err::RtError> {
+    let mut opts = getopts::Options::new();
+    opts.optmulti("A", "allow", "do not report the given lint", "LINT");
+    opts.optmulti("W", "warn", "report the given lint as a warning", "LINT");
+    opts.optmulti("D", "deny", "report the given lint as an error", "LINT");
+    opts.optflag("", "json", "write one JSON object per problem");
+    opts.optflag("l", "list", "list the available lints");
+    opts.optflag("h", "help", "print help message");
+    let matches = match opts.parse(args) {
+        Ok(m)  => m,
+        Err(e) => { println!("{}", e.to_string());
+                    return err::IE990.err() },
     };
-    print!("{}", String::from_utf8_lossy(&out.stderr));
-    if !out.status.success() {
-        return err::IE666.err();
+    if matches.opt_present("h") {
+        println!("{}", opts.usage("rick lint [options] input.i..."));
+        return Ok(0);
+    }
+    if matches.opt_present("l") {
+        for lint in lint::lints().iter() {
+            println!("{:16} {}", lint.name, lint.desc);
+        }
+        return Ok(0);
     }
-    let _ = remove_file(outname);
-    Ok(())
+    // later flags override earlier ones, so apply them in command-line order
+    let mut levels = Vec::new();
+    for &(flag, level) in &[("A", lint::Level::Allow), ("W", lint::Level::Warn),
+                            ("D", lint::Level::Deny)] {
+        for (pos, name) in matches.opt_strs_pos(flag) {
+            levels.push((pos, name, level));
+        }
+    }
+    levels.sort_by_key(|&(pos, _, _)| pos);
+    let mut linter = lint::Linter::new();
+    for (_, name, level) in levels {
+        try!(linter.set_level(&name, level));
+    }
+    let json_flag = matches.opt_present("json");
+    let mut failed = false;
+    let mut stdout = stdout();
+    for infile in &matches.free {
+        if !infile.ends_with(".i") {
+            return err::IE998.err();
+        }
+        let mut code = Vec::new();
+        match File::open(&infile) {
+            Err(_) => return err::IE777.err(),
+            Ok(mut f) => if let Err(_) = f.read_to_end(&mut code) {
+                return err::IE777.err();
+            }
+        }
+        for warning in try!(linter.lint(&code)) {
+            failed |= warning.level == lint::Level::Deny;
+            if json_flag {
+                try!(warning.write_json(&mut stdout, infile));
+            } else {
+                try!(warning.write_plain(&mut stdout, infile));
+            }
+        }
+    }
+    Ok(if failed { 1 } else { 0 })
+}
+
+/// "rick rules": list the rules of the expression optimizer, or check them.
+fn run_rules(args: &[String]) -> Result<i32, err::RtError> {
+    let mut opts = getopts::Options::new();
+    opts.optflag("", "check", "check each rule with random inputs");
+    opts.optopt("n", "iterations", "number of random inputs per rule (default 1000)", "N");
+    opts.optflag("h", "help", "print help message");
+    let matches = match opts.parse(args) {
+        Ok(m)  => m,
+        Err(e) => { println!("{}", e.to_string());
+                    return err::IE990.err() },
+    };
+    if matches.opt_present("h") {
+        println!("{}", opts.usage("rick rules [options]"));
+        return Ok(0);
+    }
+    let rules = rules::Rules::builtin();
+    if !matches.opt_present("check") {
+        for rule in &rules.rules {
+            println!("{}", rule.text);
+        }
+        return Ok(0);
+    }
+    let iterations = match matches.opt_str("n").map(|n| n.parse()) {
+        None         => 1000,
+        Some(Ok(n))  => n,
+        Some(Err(_)) => return err::IE990.err(),
+    };
+    let mut failed = false;
+    for rule in &rules.rules {
+        match rule.check(iterations) {
+            Ok(0) => {
+                println!("{:16} NOT CHECKED: no valid inputs found", rule.name);
+                failed = true;
+            }
+            Ok(n) => println!("{:16} ok ({} inputs)", rule.name, n),
+            Err(msg) => {
+                println!("{:16} FAILED {}", rule.name, msg);
+                failed = true;
+            }
+        }
+    }
+    Ok(if failed { 1 } else { 0 })
+}
+
+/// "rick crosscheck": compare the interpreter, optimizer and compiler on a program.
+fn run_crosscheck(args: &[String]) -> Result<i32, err::RtError> {
+    let mut opts = getopts::Options::new();
+    opts.optopt("", "steps", "stop each run after N statements (default 1000000)", "N");
+    opts.optopt("", "seed", "seed for the random number generator (default 0)", "N");
+    opts.optflag("h", "help", "print help message");
+    let matches = match opts.parse(args) {
+        Ok(m)  => m,
+        Err(e) => { println!("{}", e.to_string());
+                    return err::IE990.err() },
+    };
+    if matches.opt_present("h") || matches.free.is_empty() {
+        println!("{}", opts.usage("rick crosscheck [options] input.i [inputfile]"));
+        return Ok(0);
+    }
+    let steps = match matches.opt_str("steps").map(|n| n.parse()) {
+        None         => 1000000,
+        Some(Ok(n))  => n,
+        Some(Err(_)) => return err::IE990.err(),
+    };
+    let seed = match matches.opt_str("seed").map(|n| n.parse()) {
+        None         => 0,
+        Some(Ok(n))  => n,
+        Some(Err(_)) => return err::IE990.err(),
+    };
+    let infile = &matches.free[0];
+    if !infile.ends_with(".i") {
+        return err::IE998.err();
+    }
+    let mut input = Vec::new();
+    if let Some(inputfile) = matches.free.get(1) {
+        match File::open(inputfile) {
+            Err(_) => return err::IE777.err(),
+            Ok(mut f) => if let Err(_) = f.read_to_end(&mut input) {
+                return err::IE777.err();
+            }
+        }
+    }
+    let check = try!(crosscheck::Crosscheck::new(infile, input, steps, seed));
+    let same = try!(check.run(&mut stdout(), true));
+    Ok(if same { 0 } else { 1 })
+}
+
+/// "rick fuzz": crosscheck random programs, and shrink the ones that differ.
+fn run_fuzz(args: &[String]) -> Result<i32, err::RtError> {
+    let mut opts = getopts::Options::new();
+    opts.optopt("", "seed", "seed of the first program (default: from the time)", "N");
+    opts.optopt("", "size", "number of statements per program (default 40)", "N");
+    opts.optopt("n", "count", "number of programs to check (default 1)", "N");
+    opts.optopt("", "dir", "write the programs into DIR (default .)", "DIR");
+    opts.optopt("", "steps", "stop each run after N statements (default 100000)", "N");
+    opts.optflag("", "print", "only write the program for the seed to stdout");
+    opts.optflag("", "write", "only write the program and input for the seed into DIR");
+    opts.optflag("", "shrink", "shrink the given program instead of generating one");
+    opts.optflag("h", "help", "print help message");
+    let matches = match opts.parse(args) {
+        Ok(m)  => m,
+        Err(e) => { println!("{}", e.to_string());
+                    return err::IE990.err() },
+    };
+    if matches.opt_present("h") {
+        println!("{}", opts.usage("rick fuzz [options]\n       \
+                                   rick fuzz [options] --shrink input.i [inputfile]"));
+        return Ok(0);
+    }
+    let mut numbers = Vec::new();
+    for &(opt, default) in &[("seed", 0), ("size", 40), ("n", 1), ("steps", 100000)] {
+        numbers.push(match matches.opt_str(opt).map(|n| n.parse()) {
+            None         => default,
+            Some(Ok(n))  => n,
+            Some(Err(_)) => return err::IE990.err(),
+        });
+    }
+    let (size, count, steps) = (numbers[1] as usize, numbers[2], numbers[3] as usize);
+    let seed = if matches.opt_present("seed") { numbers[0] } else {
+        let now = time::get_time();
+        now.sec as u64 * 1000000 + now.nsec as u64 / 1000
+    };
+    let dir = PathBuf::from(matches.opt_str("dir").unwrap_or(".".into()));
+    let mut stdout = stdout();
+
+    if matches.opt_present("shrink") {
+        let infile = match matches.free.get(0) {
+            Some(infile) if infile.ends_with(".i") => infile,
+            _ => return err::IE998.err(),
+        };
+        let mut code = Vec::new();
+        let mut input = Vec::new();
+        for (name, buf) in matches.free.iter().zip(vec![&mut code, &mut input]) {
+            match File::open(name) {
+                Err(_) => return err::IE777.err(),
+                Ok(mut f) => if let Err(_) = f.read_to_end(buf) {
+                    return err::IE777.err();
+                }
+            }
+        }
+        let check = try!(crosscheck::Crosscheck::new(infile, input.clone(), steps, 0));
+        if try!(check.run(&mut stdout, false)) {
+            println!("no difference, nothing to shrink");
+            return Ok(0);
+        }
+        let outfile = format!("{}-min.i", &infile[..infile.len() - 2]);
+        let n = try!(fuzz::shrink(&code, &input, steps, &outfile));
+        println!("shrunk to {} statements: {}", n, outfile);
+        let check = try!(crosscheck::Crosscheck::new(&outfile, input, steps, 0));
+        try!(check.run(&mut stdout, true));
+        return Ok(1);
+    }
+
+    if matches.opt_present("print") {
+        let (program, _) = try!(fuzz::Fuzzer::new(seed, size).generate());
+        try!(stdout.write_all(program.as_bytes()));
+        return Ok(0);
+    }
+    if matches.opt_present("write") {
+        let (program, input) = try!(fuzz::Fuzzer::new(seed, size).generate());
+        let infile = dir.join(format!("fuzz-{}.i", seed)).to_string_lossy().into_owned();
+        let inputfile = dir.join(format!("fuzz-{}.tst", seed)).to_string_lossy().into_owned();
+        try!(fuzz::write_file(&infile, program.as_bytes()));
+        try!(fuzz::write_file(&inputfile, input.as_bytes()));
+        println!("{} {}", infile, inputfile);
+        return Ok(0);
+    }
+    for i in 0..count {
+        let seed = seed.wrapping_add(i);
+        let (program, input) = try!(fuzz::Fuzzer::new(seed, size).generate());
+        let infile = dir.join(format!("fuzz-{}.i", seed)).to_string_lossy().into_owned();
+        try!(fuzz::write_file(&infile, program.as_bytes()));
+        let check = try!(crosscheck::Crosscheck::new(&infile, input.clone().into_bytes(),
+                                                     steps, 0));
+        if try!(check.run(&mut std::io::sink(), false)) {
+            println!("seed {}: ok", seed);
+            let _ = std::fs::remove_file(&infile);
+            continue;
+        }
+        println!("seed {}: DIFFERENCE, shrinking", seed);
+        let inputfile = dir.join(format!("fuzz-{}.tst", seed)).to_string_lossy().into_owned();
+        try!(fuzz::write_file(&inputfile, input.as_bytes()));
+        let outfile = dir.join(format!("fuzz-{}-min.i", seed)).to_string_lossy().into_owned();
+        let n = try!(fuzz::shrink(&program.into_bytes(), input.as_bytes(), steps, &outfile));
+        println!("shrunk to {} statements: {}", n, outfile);
+        let check = try!(crosscheck::Crosscheck::new(&outfile, input.into_bytes(), steps, 0));
+        try!(check.run(&mut stdout, true));
+        return Ok(1);
+    }
+    Ok(0)
+}
+
+/// "rick cache": list or prune the cached executables.
+fn run_cache(args: &[String]) -> Result<i32, err::RtError> {
+    let mut opts = getopts::Options::new();
+    opts.optopt("", "prune", "remove executables not used for DAYS days (0: all)", "DAYS");
+    opts.optflag("h", "help", "print help message");
+    let matches = match opts.parse(args) {
+        Ok(m)  => m,
+        Err(e) => { println!("{}", e.to_string());
+                    return err::IE990.err() },
+    };
+    if matches.opt_present("h") {
+        println!("{}", opts.usage("rick cache [options]"));
+        return Ok(0);
+    }
+    let cache = cache::Cache::new();
+    if let Some(days) = matches.opt_str("prune") {
+        let days: u64 = match days.parse() {
+            Ok(days) => days,
+            Err(_)   => return err::IE990.err(),
+        };
+        let (n, size) = cache.prune(Duration::from_secs(days.saturating_mul(86400)));
+        println!("removed {} executables ({} KiB)", n, size / 1024);
+    } else {
+        for entry in cache.entries() {
+            let age = entry.age.as_secs();
+            let age = if age >= 86400 { format!("{}d", age / 86400) }
+                      else if age >= 3600 { format!("{}h", age / 3600) }
+                      else { format!("{}m", age / 60) };
+            println!("{}  {:>4}  {:>7} KiB  {}", entry.key, age, entry.size / 1024, entry.input);
+        }
+    }
+    Ok(0)
+}
+
+/// "rick runtime": print the path of the runtime library, building it if needed.
+fn run_runtime(args: &[String]) -> Result<i32, err::RtError> {
+    let mut opts = getopts::Options::new();
+    opts.optopt("", "sources", "write the runtime library sources to DIR instead", "DIR");
+    opts.optopt("", "rustc", "rustc to use (default: $RUSTC or rustc)", "RUSTC");
+    opts.optopt("", "target", "target triple to build the library for", "TRIPLE");
+    opts.optflag("h", "help", "print help message");
+    let matches = match opts.parse(args) {
+        Ok(m)  => m,
+        Err(e) => { println!("{}", e.to_string());
+                    return err::IE990.err() },
+    };
+    if matches.opt_present("h") {
+        println!("{}", opts.usage("rick runtime [options]"));
+        return Ok(0);
+    }
+    if let Some(dir) = matches.opt_str("sources") {
+        try!(runtime::write_sources(&PathBuf::from(dir)));
+    } else {
+        let rustc = matches.opt_str("rustc").unwrap_or(driver::Driver::new().rustc);
+        let target = matches.opt_str("target");
+        let lib = try!(runtime::library(&rustc, target.as_ref().map(|t| &t[..])));
+        println!("{}", lib.display());
+    }
+    Ok(0)
 }
@@ -22,33 +22,173 @@
 /// * constant folding: just reduces (sub)expressions involving no variables
 /// * expressions: looks for common patterns of INTERCAL operator expressions
 ///   and replaces them by equivalent expressions involving native Rust operators
+///   (the patterns are given as rules in `idioms.rules`, see rules.rs); the
+///   rest is simplified by computing what each bit of the result is (bits.rs)
 /// * constant output (can be disabled): if the program neither uses random numbers
 ///   nor takes any input, its output must be constant - the optimizer generates
 ///   this output using the Eval interpreter and replaces the program by a single
 ///   Print instruction (if your program does not terminate, you'd better disable
 ///   this pass with the -F option)
+/// * snapshot (disabled with constant output, and for -s): otherwise, the
+///   beginning of the program is run up to the first input or random number,
+///   and the program starts from the state reached there, after printing the
+///   output so far
 /// * abstain check: marks all statements that cannot be ABSTAINed from, so that
 ///   the code generator can skip emitting guards for them
+/// * dead code: removes statements that control can never reach (such as the
+///   unused routines of the standard library)
 /// * var check: marks all variables that cannot be IGNOREd, so that the code
 ///   generator can use unchecked assignments
+/// * propagation: substitutes the values of variables into later statements,
+///   where they are known to be constant or a copy of another variable
 ///
-/// The patterns recognized by the expression optimizer are pretty random.  They
-/// were selected to optimize performance of the `tpk.i` example program, and
-/// could be expanded a lot.  But at that point it's probably better to take the
-/// route of C-INTERCAL and use a DSL for generic pattern matching.
+/// The list of passes can be changed on the command line (see `Pass`).  It is
+/// run again and again until the program doesn't change anymore, so that e.g.
+/// constants substituted by propagation are folded, and the statistics of
+/// each pass are collected for `--opt-stats`.  Only constant output runs just
+/// once, on the result.
 
 use std::collections::BTreeMap;
-use std::io::Cursor;
+use std::io::{ Cursor, Write, stderr };
 use std::u16;
 
-use ast::{ Program, Stmt, StmtBody, Expr, Var, VarInfo, VType, Abstain };
+use time;
+
+use ast::{ Program, Stmt, StmtBody, ComeFrom, Expr, Var, VarInfo, VType, Abstain, LogLine };
+use cfg::Cfg;
+use err::Res;
 use eval;
-use stdops::{ mingle, select, and_16, and_32, or_16, or_32, xor_16, xor_32 };
+use rules::Rules;
+use bits;
+use propagate;
+use stdops::{ Bind, mingle, select, and_16, and_32, or_16, or_32, xor_16, xor_32 };
 
 
 pub struct Optimizer {
     program: Program,
-    allow_const_out: bool,
+    passes: Vec<Pass>,
+    dump_after: Option<Pass>,
+    stats: bool,
+}
+
+/// A single optimizer pass, as selected with `--passes`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Pass {
+    Fold,
+    Expressions,
+    ConstOutput,
+    Snapshot,
+    AbstainCheck,
+    DeadCode,
+    VarCheck,
+    Propagate,
+}
+
+/// What a pass did, summed over all rounds.
+pub struct PassStats {
+    pub pass: Pass,
+    pub runs: usize,
+    /// Statements whose expressions (or other contents) were changed.
+    pub rewritten: usize,
+    /// Statements removed (or, if negative, added).
+    pub removed: isize,
+    /// Variables found not to need IGNORE or STASH support anymore.
+    pub demoted: usize,
+    pub time: time::Duration,
+}
+
+/// Maximum number of statements to run at compile time for opt_snapshot.
+const MAX_SNAPSHOT_STEPS: usize = 100000;
+
+/// Maximum number of times the list of passes is run.
+const MAX_ROUNDS: usize = 10;
+
+impl Pass {
+    /// The passes that run by default, in order.  `allow_snapshot` only
+    /// takes effect with `allow_const_out`.
+    pub fn defaults(allow_const_out: bool, allow_snapshot: bool) -> Vec<Pass> {
+        let mut passes = vec![Pass::Fold, Pass::Expressions];
+        if allow_const_out {
+            passes.push(Pass::ConstOutput);
+            if allow_snapshot {
+                passes.push(Pass::Snapshot);
+            }
+        }
+        passes.extend(&[Pass::AbstainCheck, Pass::DeadCode, Pass::VarCheck, Pass::Propagate]);
+        passes
+    }
+
+    pub fn all() -> Vec<Pass> {
+        Pass::defaults(true, true)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Pass::Fold         => "fold",
+            Pass::Expressions  => "expressions",
+            Pass::ConstOutput  => "const-output",
+            Pass::Snapshot     => "snapshot",
+            Pass::AbstainCheck => "abstain-check",
+            Pass::DeadCode     => "dead-code",
+            Pass::VarCheck     => "var-check",
+            Pass::Propagate    => "propagate",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Pass> {
+        Pass::all().into_iter().find(|pass| pass.name() == name)
+    }
+
+    fn run(&self, program: Program) -> Program {
+        match *self {
+            Pass::Fold         => Optimizer::opt_constant_fold(program),
+            Pass::Expressions  => Optimizer::opt_expressions(program),
+            Pass::ConstOutput  => Optimizer::opt_const_output(program),
+            Pass::Snapshot     => Optimizer::opt_snapshot(program),
+            Pass::AbstainCheck => Optimizer::opt_abstain_check(program),
+            Pass::DeadCode     => Optimizer::opt_dead_code(program),
+            Pass::VarCheck     => Optimizer::opt_var_check(program),
+            Pass::Propagate    => Optimizer::opt_propagate(program),
+        }
+    }
+}
+
+impl PassStats {
+    fn new(pass: Pass) -> PassStats {
+        PassStats { pass: pass, runs: 0, rewritten: 0, removed: 0, demoted: 0,
+                    time: time::Duration::zero() }
+    }
+
+    /// Add up what changed from `before` to `after`.
+    fn record(&mut self, before: &Program, after: &Program, time: time::Duration) {
+        self.runs += 1;
+        self.time = self.time + time;
+        self.removed += before.stmts.len() as isize - after.stmts.len() as isize;
+        if before.stmts.len() == after.stmts.len() {
+            self.rewritten += before.stmts.iter().zip(&after.stmts)
+                                               .filter(|s| s.0.body != s.1.body).count();
+        }
+        fn demoted(before: &[VarInfo], after: &[VarInfo]) -> usize {
+            let special = |vi: &VarInfo| vi.can_ignore || vi.can_stash;
+            before.iter().zip(after).filter(|v| special(v.0) && !special(v.1)).count()
+        }
+        self.demoted += demoted(&before.var_info.0, &after.var_info.0) +
+            demoted(&before.var_info.1, &after.var_info.1) +
+            demoted(&before.var_info.2, &after.var_info.2) +
+            demoted(&before.var_info.3, &after.var_info.3);
+    }
+}
+
+/// Write the statistics of all passes as a table.
+pub fn write_stats(stats: &[PassStats], o: &mut Write) -> Res<()> {
+    try!(writeln!(o, "{:<14} {:>5} {:>10} {:>8} {:>8} {:>10}",
+                  "pass", "runs", "rewritten", "removed", "demoted", "time (ms)"));
+    for st in stats {
+        let ms = st.time.num_microseconds().unwrap_or(0) as f64 / 1000.;
+        try!(writeln!(o, "{:<14} {:>5} {:>10} {:>8} {:>8} {:>10.3}",
+                      st.pass.name(), st.runs, st.rewritten, st.removed, st.demoted, ms));
+    }
+    Ok(())
 }
 
 fn n(i: u32) -> Box<Expr> {
@@ -56,21 +196,57 @@ fn n(i: u32) -> Box<Expr> {
 }
 
 impl Optimizer {
-    pub fn new(program: Program, allow_const_out: bool) -> Optimizer {
+    /// `dump_after` prints the program to stderr after every run of that pass.
+    /// If `stats` is false, the returned statistics stay empty, which saves
+    /// copying the program before each pass.
+    pub fn new(program: Program, passes: Vec<Pass>, dump_after: Option<Pass>,
+               stats: bool) -> Optimizer {
         Optimizer { program: program,
-                    allow_const_out: allow_const_out }
+                    passes: passes,
+                    dump_after: dump_after,
+                    stats: stats }
     }
 
-    pub fn optimize(self) -> Program {
+    /// Run the passes until the program doesn't change anymore.  Returns the
+    /// optimized program and the statistics for each pass in the list.
+    ///
+    /// Constant output evaluates the whole program, which does not get any
+    /// better by repetition, so it only runs once after the other passes.
+    pub fn optimize(self) -> (Program, Vec<PassStats>) {
+        let (dump_after, with_stats) = (self.dump_after, self.stats);
+        let run = |pass: Pass, program: Program, st: &mut PassStats, round: Option<usize>| {
+            let before = if with_stats { Some(program.clone()) } else { None };
+            let t0 = time::get_time();
+            let program = pass.run(program);
+            if let Some(before) = before {
+                st.record(&before, &program, time::get_time() - t0);
+            }
+            if dump_after == Some(pass) {
+                let _ = match round {
+                    Some(round) => writeln!(stderr(), "Program after {} (round {}):\n{}",
+                                            pass.name(), round, program),
+                    None => writeln!(stderr(), "Program after {}:\n{}", pass.name(), program),
+                };
+            }
+            program
+        };
         let mut program = self.program;
-        program = Optimizer::opt_constant_fold(program);
-        program = Optimizer::opt_expressions(program);
-        if self.allow_const_out {
-            program = Optimizer::opt_const_output(program);
+        let mut stats = self.passes.iter().map(|&pass| PassStats::new(pass)).collect::<Vec<_>>();
+        for round in 0..MAX_ROUNDS {
+            let start = program.clone();
+            for (&pass, st) in self.passes.iter().zip(&mut stats) {
+                if pass != Pass::ConstOutput {
+                    program = run(pass, program, st, Some(round + 1));
+                }
+            }
+            if program == start {
+                break;
+            }
         }
-        program = Optimizer::opt_abstain_check(program);
-        program = Optimizer::opt_var_check(program);
-        program
+        if let Some(i) = self.passes.iter().position(|&pass| pass == Pass::ConstOutput) {
+            program = run(Pass::ConstOutput, program, &mut stats[i], None);
+        }
+        (program, stats)
     }
 
     /// Fold expressions with literal constants, of which there are typically a lot
@@ -113,27 +289,28 @@ impl Optimizer {
                     }
                 }
             }
-            Expr::And(_, ref mut vx) => {
+            Expr::And(vtype, ref mut vx) => {
                 Optimizer::fold(vx);
-                if let box Expr::Num(vtype, v) = *vx {
+                // the operator's width counts, not that of the folded operand
+                if let box Expr::Num(_, v) = *vx {
                     result = Some(match vtype {
                         VType::I16 => Expr::Num(vtype, and_16(v)),
                         VType::I32 => Expr::Num(vtype, and_32(v)),
                     });
                 }
             }
-            Expr::Or(_, ref mut vx) => {
+            Expr::Or(vtype, ref mut vx) => {
                 Optimizer::fold(vx);
-                if let box Expr::Num(vtype, v) = *vx {
+                if let box Expr::Num(_, v) = *vx {
                     result = Some(match vtype {
                         VType::I16 => Expr::Num(vtype, or_16(v)),
                         VType::I32 => Expr::Num(vtype, or_32(v)),
                     });
                 }
             }
-            Expr::Xor(_, ref mut vx) => {
+            Expr::Xor(vtype, ref mut vx) => {
                 Optimizer::fold(vx);
-                if let box Expr::Num(vtype, v) = *vx {
+                if let box Expr::Num(_, v) = *vx {
                     result = Some(match vtype {
                         VType::I16 => Expr::Num(vtype, xor_16(v)),
                         VType::I32 => Expr::Num(vtype, xor_32(v)),
@@ -147,229 +324,27 @@ impl Optimizer {
         }
     }
 
-    /// Optimize expressions.
+    /// Optimize expressions, using the rules from `idioms.rules`, and then the
+    /// bit-level simplifier for what the rules didn't catch.
     pub fn opt_expressions(mut program: Program) -> Program {
+        let rules = Rules::builtin();
+        let opt = |expr: &mut Expr| {
+            rules.rewrite(expr);
+            if bits::simplify(expr) {
+                rules.rewrite(expr);
+            }
+        };
         for stmt in &mut program.stmts {
-            //println!("\n\n{}", stmt.props.srcline);
             match stmt.body {
-                StmtBody::Calc(_, ref mut expr) => Optimizer::opt_expr(expr),
-                StmtBody::Resume(ref mut expr)  => Optimizer::opt_expr(expr),
-                StmtBody::Forget(ref mut expr)  => Optimizer::opt_expr(expr),
+                StmtBody::Calc(_, ref mut expr) => opt(expr),
+                StmtBody::Resume(ref mut expr)  => opt(expr),
+                StmtBody::Forget(ref mut expr)  => opt(expr),
                 _ => { }
             }
         }
         program
     }
 
-    fn opt_expr(expr: &mut Expr) {
-        //println!("optimizing {}", expr);
-        let mut result = None;
-        match *expr {
-            Expr::Select(_, ref mut vx, ref mut wx) => {
-                Optimizer::opt_expr(vx);
-                Optimizer::opt_expr(wx);
-                match *wx {
-                    // Select(UnOP(Mingle(x, y)), 0x55555555) = BinOP(x, y)
-                    box Expr::Num(_, 0x55555555) => {
-                        match *vx {
-                            box Expr::And(_, box Expr::Mingle(ref m1, ref m2)) => {
-                                result = Some(Expr::RsAnd(m1.clone(), m2.clone()));
-                            }
-                            box Expr::Or(_, box Expr::Mingle(ref m1, ref m2)) => {
-                                result = Some(Expr::RsOr(m1.clone(), m2.clone()));
-                            }
-                            box Expr::Xor(_, box Expr::Mingle(ref m1, ref m2)) => {
-                                result = Some(Expr::RsXor(m1.clone(), m2.clone()));
-                            }
-                            _ => { }
-                        }
-                    }
-                    // Select(x, N) is a shift & mask if N has to "inside" zeros
-                    // in binary notation
-                    box Expr::Num(_, i) if i.count_zeros() == i.leading_zeros() + i.trailing_zeros() => {
-                        if i.trailing_zeros() == 0 {
-                            result = Some(Expr::RsAnd(vx.clone(), n(i)));
-                        } else if i.leading_zeros() == 0 {
-                            result = Some(Expr::RsRshift(vx.clone(), n(i.trailing_zeros())));
-                        } else {
-                            result = Some(Expr::RsAnd(
-                                box Expr::RsRshift(vx.clone(), n(i.trailing_zeros())),
-                                n((1 << i.count_ones()) - 1)));
-                        }
-                    }
-                    // Select(Mingle(x, 0), 0x2AAAAAAB)  ->  (x << 1) & 0xFFFF
-                    box Expr::Num(_, 0x2AAAAAAB) => {
-                        if let box Expr::Mingle(ref m1, box Expr::Num(_, 0)) = *vx {
-                            result = Some(Expr::RsAnd(
-                                box Expr::RsLshift(m1.clone(), n(1)), n(0xFFFF)));
-                        }
-                    }
-                    _ => { }
-                }
-            }
-            Expr::Mingle(ref mut vx, ref mut wx) => {
-                Optimizer::opt_expr(vx);
-                Optimizer::opt_expr(wx);
-                // (x ~ 0xA..A) OP (y ~ 0xA..A) $ (x ~ 0x5..5) OP (y ~ 0x5..5)
-                // -> (x OP y) in 32-bit
-                if let box Expr::RsAnd(box Expr::Select(_, ref ax, box Expr::Num(_, 0xAAAAAAAA)),
-                                       box Expr::Select(_, ref bx, box Expr::Num(_, 0xAAAAAAAA))) = *vx {
-                    if let box Expr::RsAnd(box Expr::Select(_, ref cx, box Expr::Num(_, 0x55555555)),
-                                           box Expr::Select(_, ref dx, box Expr::Num(_, 0x55555555))) = *wx {
-                        if *ax == *cx && *bx == *dx {
-                            result = Some(Expr::RsAnd(ax.clone(), bx.clone()));
-                        }
-                    }
-                }
-                if let box Expr::RsOr(box Expr::Select(_, ref ax, box Expr::Num(_, 0xAAAAAAAA)),
-                                      box Expr::Select(_, ref bx, box Expr::Num(_, 0xAAAAAAAA))) = *vx {
-                    if let box Expr::RsOr(box Expr::Select(_, ref cx, box Expr::Num(_, 0x55555555)),
-                                          box Expr::Select(_, ref dx, box Expr::Num(_, 0x55555555))) = *wx {
-                        if *ax == *cx && *bx == *dx {
-                            result = Some(Expr::RsOr(ax.clone(), bx.clone()));
-                        }
-                    }
-                }
-                if let box Expr::RsXor(box Expr::Select(_, ref ax, box Expr::Num(_, 0xAAAAAAAA)),
-                                       box Expr::Select(_, ref bx, box Expr::Num(_, 0xAAAAAAAA))) = *vx {
-                    if let box Expr::RsXor(box Expr::Select(_, ref cx, box Expr::Num(_, 0x55555555)),
-                                           box Expr::Select(_, ref dx, box Expr::Num(_, 0x55555555))) = *wx {
-                        if *ax == *cx && *bx == *dx {
-                            result = Some(Expr::RsXor(ax.clone(), bx.clone()));
-                        }
-                    }
-                }
-                // (x ~ 0xA..A) OP y1 $ (x ~ 0x5..5) OP y2
-                // -> (x OP (y1 << 16 | y2)) in 32-bit
-                if let box Expr::RsAnd(box Expr::Select(_, ref ax, box Expr::Num(_, 0xAAAAAAAA)),
-                                       box Expr::Num(_, bn)) = *vx {
-                    if let box Expr::RsAnd(box Expr::Select(_, ref cx, box Expr::Num(_, 0x55555555)),
-                                           box Expr::Num(_, dn)) = *wx {
-                        if *ax == *cx {
-                            result = Some(Expr::RsAnd(ax.clone(), n((bn << 16) | dn)));
-                        }
-                    }
-                }
-                if let box Expr::RsOr(box Expr::Select(_, ref ax, box Expr::Num(_, 0xAAAAAAAA)),
-                                       box Expr::Num(_, bn)) = *vx {
-                    if let box Expr::RsOr(box Expr::Select(_, ref cx, box Expr::Num(_, 0x55555555)),
-                                           box Expr::Num(_, dn)) = *wx {
-                        if *ax == *cx {
-                            result = Some(Expr::RsOr(ax.clone(), n((bn << 16) | dn)));
-                        }
-                    }
-                }
-                if let box Expr::RsXor(box Expr::Select(_, ref ax, box Expr::Num(_, 0xAAAAAAAA)),
-                                       box Expr::Num(_, bn)) = *vx {
-                    if let box Expr::RsXor(box Expr::Select(_, ref cx, box Expr::Num(_, 0x55555555)),
-                                           box Expr::Num(_, dn)) = *wx {
-                        if *ax == *cx {
-                            result = Some(Expr::RsXor(ax.clone(), n((bn << 16) | dn)));
-                        }
-                    }
-                }
-                // (x != y) $ (z != w)  ->  ((x != y) << 1) | (z != w)
-                if let box Expr::RsNotEqual(..) = *vx {
-                    if let box Expr::RsNotEqual(..) = *wx {
-                        result = Some(Expr::RsOr(box Expr::RsLshift(vx.clone(), n(1)), wx.clone()));
-                    }
-                }
-            }
-            Expr::And(_, ref mut vx) | Expr::Or(_, ref mut vx) | Expr::Xor(_, ref mut vx) => {
-                Optimizer::opt_expr(vx);
-            }
-            Expr::RsNot(ref mut vx) => {
-                Optimizer::opt_expr(vx);
-            }
-            Expr::RsAnd(ref mut vx, ref mut wx) => {
-                Optimizer::opt_expr(vx);
-                Optimizer::opt_expr(wx);
-                // (x ~ x) & 1  ->  x != 0
-                if let box Expr::Select(_, ref sx, ref tx) = *vx {
-                    if *sx == *tx {
-                        if let box Expr::Num(_, 1) = *wx {
-                            result = Some(Expr::RsNotEqual(sx.clone(), n(0)));
-                        }
-                    }
-                }
-                // ?(x $ 1) & 3  ->  1 + (x & 1)
-                if let box Expr::Xor(_, box Expr::Mingle(ref mx, box Expr::Num(_, 1))) = *vx {
-                    if let box Expr::Num(_, 3) = *wx {
-                        result = Some(Expr::RsPlus(n(1), box Expr::RsAnd(mx.clone(), n(1))));
-                    }
-                }
-                // ?(x $ 2) & 3  ->  2 - (x & 1)
-                if let box Expr::Xor(_, box Expr::Mingle(ref mx, box Expr::Num(_, 2))) = *vx {
-                    if let box Expr::Num(_, 3) = *wx {
-                        result = Some(Expr::RsMinus(n(2), box Expr::RsAnd(mx.clone(), n(1))));
-                    }
-                }
-                // x & 0xFFFFFFFF has no effect
-                if let box Expr::Num(_, 0xFFFFFFFF) = *wx {
-                    result = Some(*vx.clone());
-                }
-                // Select(UnOP(Mingle(x, y)), 1) = BinOP(x & 1, y & 1)
-                if let box Expr::Num(_, 1) = *wx {
-                    match *vx {
-                        box Expr::And(_, box Expr::Mingle(ref m1, ref m2)) => {
-                            result = Some(Expr::RsAnd(
-                                box Expr::RsAnd(m1.clone(), n(1)),
-                                box Expr::RsAnd(m2.clone(), n(1))));
-                        }
-                        box Expr::Or(_, box Expr::Mingle(ref m1, ref m2)) => {
-                            result = Some(Expr::RsOr(
-                                box Expr::RsAnd(m1.clone(), n(1)),
-                                box Expr::RsAnd(m2.clone(), n(1))));
-                        }
-                        box Expr::Xor(_, box Expr::Mingle(ref m1, ref m2)) => {
-                            result = Some(Expr::RsXor(
-                                box Expr::RsAnd(m1.clone(), n(1)),
-                                box Expr::RsAnd(m2.clone(), n(1))));
-                        }
-                        _ => { }
-                    }
-                }
-                // ((x & y) & y)  ->  second & has no effect
-                if let box Expr::RsAnd(_, ref v2x) = *vx {
-                    if *v2x == *wx {
-                        result = Some(*vx.clone());
-                    }
-                }
-                // ((x != y) & 1)  ->  & has no effect
-                if let box Expr::RsNotEqual(..) = *vx {
-                    if let box Expr::Num(_, 1) = *wx {
-                        result = Some(*vx.clone());
-                    }
-                }
-            }
-            Expr::RsXor(ref mut vx, ref mut wx) => {
-                Optimizer::opt_expr(vx);
-                Optimizer::opt_expr(wx);
-                if let box Expr::Num(_, 0xFFFFFFFF) = *wx {
-                    result = Some(Expr::RsNot(vx.clone()));
-                }
-                else if let box Expr::Num(_, 0xFFFFFFFF) = *vx {
-                    result = Some(Expr::RsNot(wx.clone()));
-                }
-            }
-            Expr::RsOr(ref mut vx, ref mut wx) |
-            Expr::RsRshift(ref mut vx, ref mut wx) |
-            Expr::RsLshift(ref mut vx, ref mut wx) |
-            // Expr::RsEqual(ref mut vx, ref mut wx) |
-            Expr::RsNotEqual(ref mut vx, ref mut wx) |
-            Expr::RsMinus(ref mut vx, ref mut wx) |
-            Expr::RsPlus(ref mut vx, ref mut wx) => {
-                Optimizer::opt_expr(vx);
-                Optimizer::opt_expr(wx);
-            }
-            Expr::Num(..) | Expr::Var(..) => { }
-        }
-        if let Some(mut result) = result {
-            Optimizer::opt_expr(&mut result);  // XXX will this always terminate?
-            *expr = result;
-        }
-    }
-
     /// Cleverly check for programs that don't take input and always produce the
     /// same output; reduce them to a Print statement.
     pub fn opt_const_output(program: Program) -> Program {
@@ -409,12 +384,52 @@ impl Optimizer {
         // we can do it! evaluate the program and replace all statements
         let out = Vec::new();
         let mut cursor = Cursor::new(out);
-        if let Err(_) = eval::Eval::new(&program, &mut cursor, false, false).eval() {
+        if let Err(_) = eval::Eval::new(&program, &mut cursor, false, Some(0), None).eval() {
             // if eval fails, don't pretend to do anything.
             return program;
         }
+        Optimizer::const_program(cursor.into_inner())
+    }
+
+    /// Run the beginning of the program, until it reads input or uses random
+    /// numbers (or for a while, if it does neither), and let it start from the
+    /// state reached there.  The output so far is written when it starts.
+    pub fn opt_snapshot(mut program: Program) -> Program {
+        if program.start.is_some() {
+            return program;
+        }
+        let mut cursor = Cursor::new(Vec::new());
+        let prefix = {
+            let mut eval = eval::Eval::new(&program, &mut cursor, false, Some(0), None);
+            eval.eval_prefix(MAX_SNAPSHOT_STEPS).map(|pctr| pctr.map(|pctr| eval.snapshot(pctr)))
+        };
+        let mut start = match prefix {
+            // errors are left for runtime
+            Err(_) => return program,
+            Ok(start) => start,
+        };
+        let output = cursor.into_inner();
+        match start {
+            Some(ref mut start) if start.steps > 0 => start.output = output,
+            Some(_) => return program,
+            // if the program ended, it's a constant-output one after all
+            None => return Optimizer::const_program(output),
+        }
+        if let Some(ref start) = start {
+            // statements whose abstention changed must not be treated like they
+            // still have the initial one
+            for (stmt, &count) in program.stmts.iter_mut().zip(&start.abstain) {
+                stmt.props.disabled = count > 0;
+            }
+        }
+        program.start = start;
+        program
+    }
+
+    /// A program that just prints the given output.
+    fn const_program(output: Vec<u8>) -> Program {
         Program {
-            stmts: vec![Stmt::new_with(StmtBody::Print(cursor.into_inner())),
+            stmts: vec![Stmt::new_with(StmtBody::Print(output)),
                         Stmt::new_with(StmtBody::GiveUp)],
             labels: BTreeMap::new(),
             stmt_types: vec![Abstain::Label(0)],
@@ -422,12 +437,24 @@ impl Optimizer {
             uses_complex_comefrom: false,
             added_syslib: false,
             added_floatlib: false,
-            bugline: 2
+            bugline: 2,
+            start: None,
         }
     }
 
     /// Set "can_abstain" to false for all statements that can't be abstained from.
     pub fn opt_abstain_check(mut program: Program) -> Program {
+        let can_abstain = Optimizer::abstainable(&program);
+        for (stmt, can_abstain) in program.stmts.iter_mut().zip(can_abstain) {
+            if stmt.body != StmtBody::GiveUp {
+                stmt.can_abstain = can_abstain;
+            }
+        }
+        program
+    }
+
+    /// Determine which statements are the target of an ABSTAIN or REINSTATE.
+    pub fn abstainable(program: &Program) -> Vec<bool> {
         let mut can_abstain = vec![false; program.stmts.len()];
         for stmt in &program.stmts {
             match stmt.body {
@@ -435,8 +462,9 @@ impl Optimizer {
                 StmtBody::Reinstate(ref whats) => {
                     for what in whats {
                         if let &Abstain::Label(lbl) = what {
-                            let idx = program.labels[&lbl];
-                            can_abstain[idx as usize] = true;
+                            if let Some(&idx) = program.labels.get(&lbl) {
+                                can_abstain[idx as usize] = true;
+                            }
                         } else {
                             for (i, stype) in program.stmt_types.iter().enumerate() {
                                 if stype == what {
@@ -449,11 +477,94 @@ impl Optimizer {
                 _ => { }
             }
         }
-        for (stmt, can_abstain) in program.stmts.iter_mut().zip(can_abstain) {
-            if stmt.body != StmtBody::GiveUp {
-                stmt.can_abstain = can_abstain;
+        can_abstain
+    }
+
+    /// Remove statements that can never be executed, because control can't
+    /// reach them in the control-flow graph.  This needs to know which statements
+    /// can be abstained from, so opt_abstain_check must run before.
+    ///
+    /// Unreachable statements are kept if a kept NEXT, ABSTAIN, REINSTATE or
+    /// COME FROM refers to their label (the NEXT might never run, but the label
+    /// must still exist).
+    pub fn opt_dead_code(mut program: Program) -> Program {
+        let graph = Cfg::new(&program);
+        let reachable = graph.reachable(&program);
+        let mut keep = graph.block_of.iter().map(|&b| reachable[b]).collect::<Vec<_>>();
+        // the NEXTs on a snapshot's stack will be resumed from
+        if let Some(ref start) = program.start {
+            for &j in &start.jumps {
+                keep[j as usize] = true;
             }
         }
+        // a statement kept for its label can refer to other labels in turn
+        let mut todo = (0..keep.len()).filter(|&i| keep[i]).collect::<Vec<_>>();
+        while let Some(i) = todo.pop() {
+            let mut refs = Vec::new();
+            match program.stmts[i].body {
+                StmtBody::Abstain(_, ref whats) | StmtBody::Reinstate(ref whats) => {
+                    for what in whats {
+                        if let Abstain::Label(lbl) = *what {
+                            refs.push(lbl);
+                        }
+                    }
+                }
+                StmtBody::ComeFrom(ComeFrom::Label(lbl)) |
+                StmtBody::DoNext(lbl) => refs.push(lbl),
+                _ => { }
+            }
+            for lbl in refs {
+                if let Some(&j) = program.labels.get(&lbl) {
+                    if !keep[j as usize] {
+                        keep[j as usize] = true;
+                        todo.push(j as usize);
+                    }
+                }
+            }
+        }
+        if keep.iter().all(|&k| k) {
+            return program;
+        }
+        // map old to new statement indices
+        let mut new_index = vec![None; keep.len()];
+        let mut n = 0;
+        for (i, &k) in keep.iter().enumerate() {
+            if k {
+                new_index[i] = Some(n as LogLine);
+                n += 1;
+            }
+        }
+        let stmts = program.stmts.drain(..).zip(&keep).filter(|s| *s.1).map(|s| s.0).collect();
+        program.stmts = stmts;
+        for stmt in &mut program.stmts {
+            stmt.comefrom = stmt.comefrom.and_then(|j| new_index[j as usize]);
+        }
+        let types = program.stmt_types.drain(..).zip(&keep)
+                                      .filter(|t| *t.1).map(|t| t.0).collect();
+        program.stmt_types = types;
+        program.labels = program.labels.iter().filter_map(|(&lbl, &i)| {
+            new_index[i as usize].map(|j| (lbl, j))
+        }).collect();
+        program.bugline = match new_index.get(program.bugline as usize) {
+            Some(&Some(j)) => j,
+            _ => program.stmts.len() as LogLine,  // can never be reached
+        };
+        if let Some(ref mut start) = program.start {
+            start.pctr = new_index[start.pctr].unwrap() as usize;
+            for j in &mut start.jumps {
+                *j = new_index[*j as usize].unwrap();
+            }
+            let abstain = start.abstain.drain(..).zip(&keep)
+                                       .filter(|a| *a.1).map(|a| a.0).collect();
+            start.abstain = abstain;
+        }
+        program
+    }
+
+    /// Substitute the values of variables where they are known, see propagate.rs.
+    /// This needs the results of opt_abstain_check and opt_var_check.
+    pub fn opt_propagate(mut program: Program) -> Program {
+        propagate::propagate(&mut program);
         program
     }
 
@@ -496,6 +607,19 @@ impl Optimizer {
                 _ => { }
             }
         }
+        // a snapshot can start with stashed or ignored variables
+        if let Some(ref start) = program.start {
+            fn from_binds<T>(vis: &mut Vec<VarInfo>, binds: &[Bind<T>]) {
+                for (vi, bind) in vis.iter_mut().zip(binds) {
+                    vi.can_stash |= !bind.stack.is_empty();
+                    vi.can_ignore |= !bind.rw;
+                }
+            }
+            from_binds(&mut program.var_info.0, &start.spot);
+            from_binds(&mut program.var_info.1, &start.twospot);
+            from_binds(&mut program.var_info.2, &start.tail);
+            from_binds(&mut program.var_info.3, &start.hybrid);
+        }
         program
     }
 }
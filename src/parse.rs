@@ -25,7 +25,7 @@
 /// called `post_process`.  It makes a list of statements into a "real" program.
 
 use std::collections::{ BTreeMap, HashMap };
-use std::io::{ Read, BufRead, BufReader, Cursor };
+use std::io::Cursor;
 use std::u16;
 
 use rand::{ self, Rng };
@@ -33,7 +33,8 @@ use rand::{ self, Rng };
 use ast::{ self, Program, Stmt, StmtBody, StmtProps, Expr, Abstain, ComeFrom, Var, VType, VarInfo };
 use err::{ Res, RtError, ErrDesc, IE000, IE017, IE079, IE099, IE139, IE182, IE197, IE200,
            IE444, IE555, IE993 };
-use lex::{ lex, Lexer, SrcLine, TT };
+use cst::{ Cst, StmtNode };
+use lex::{ lex, Lexer, SrcLine, SrcPos, Span, Token, TT };
 use syslib;
 
 
@@ -45,42 +46,23 @@ enum DecodeError {
 type ParseRes<T> = Result<T, DecodeError>;
 
 pub struct Parser<'p> {
-    lines:  Vec<String>,
+    cst:    Cst,
     tokens: Lexer<Cursor<&'p [u8]>>,
-    stash:  Vec<TT>,  // used for backtracking
-    startline: usize,
+    stash:  Vec<Token>,  // used for backtracking
+    exprs:  Vec<Span>,   // spans of the current statement's expressions
     allow_bug: bool,
 }
 
 
 impl<'p> Parser<'p> {
     pub fn new(code: &Vec<u8>, startline: usize, allow_bug: bool) -> Parser {
-        let cursor1 = Cursor::new(&code[..]);
-        // we have to keep a list of all physical source lines to generate
-        // E000 error messages, so duplicate the input stream
-        let lines = Parser::get_lines(BufReader::new(cursor1));
-        let cursor2 = Cursor::new(&code[..]);
-        Parser { lines: lines,
-                 tokens: lex(cursor2, startline),
+        Parser { cst: Cst::new(code, startline),
+                 tokens: lex(Cursor::new(&code[..]), startline),
                  stash: Vec::new(),
-                 startline: startline,
+                 exprs: Vec::new(),
                  allow_bug: allow_bug }
     }
 
-    fn get_lines<T: Read>(mut reader: BufReader<T>) -> Vec<String> {
-        let mut buf = Vec::new();
-        let mut res = Vec::new();
-        while let Ok(n) = reader.read_until('\n' as u8, &mut buf) {
-            if n == 0 {
-                break;
-            }
-            let end = if buf[n - 1] == '\n' as u8 { n - 1 } else { n };
-            res.push(String::from_utf8_lossy(&buf[..end]).into_owned());
-            buf.clear();
-        }
-        res
-    }
-
     /// Parse the whole file as a program.
     pub fn get_program(&mut self) -> Res<Program> {
         // parse all statements
@@ -89,6 +71,16 @@ impl<'p> Parser<'p> {
         self.post_process(stmts)
     }
 
+    /// Parse the whole file and append the standard libraries it needs, but
+    /// do no further processing (so variables keep their numbers).  Also
+    /// returns the number of statements that came from the file itself.
+    pub fn parse_with_stdlibs(&mut self) -> Res<(Vec<Stmt>, usize)> {
+        let stmts = try!(self.parse());
+        let nown = stmts.len();
+        let (mut added_syslib, mut added_floatlib) = (false, false);
+        Ok((self.add_stdlibs(stmts, &mut added_syslib, &mut added_floatlib), nown))
+    }
+
     pub fn parse(&mut self) -> Res<Vec<Stmt>> {
         // a program is a series of statements until EOF
         let mut stmts = Vec::new();
@@ -98,21 +90,33 @@ impl<'p> Parser<'p> {
             }
             stmts.push(try!(self.parse_stmt()));
         }
+        self.cst.tokens = self.tokens.take_log();
         Ok(stmts)
     }
 
+    /// The concrete syntax tree of everything parsed so far.
+    pub fn cst(&self) -> &Cst {
+        &self.cst
+    }
+
     /// Parse a single statement (correct or botched).
     fn parse_stmt(&mut self) -> Res<Stmt> {
         let mut props = StmtProps::default();
+        let start = self.tokens.peek_pos().unwrap_or(0);
         // try to decode a statement
         self.stash.clear();
-        match self.parse_stmt_maybe(&mut props) {
+        self.exprs.clear();
+        let mut stmt = match self.parse_stmt_maybe(&mut props) {
             // a hard error while parsing (rare)
-            Err(DecodeError::Hard(err)) => Err(err),
+            Err(DecodeError::Hard(mut err)) => {
+                let end = self.stash.iter().map(|t| t.2.end).max().unwrap_or(start);
+                err.set_stmt(self.tokens.lineno(), Some((start, end)));
+                return Err(err);
+            }
             // a "soft" error: thrown at runtime as E000
             Err(DecodeError::Soft(srcline)) => {
                 let body = StmtBody::Error(
-                    IE000.new(Some(self.lines[srcline - self.startline].clone()), 0));
+                    IE000.new(Some(self.cst.line_text(srcline)), 0), String::new());
                 // jump over tokens until the next statement beginning
                 loop {
                     match self.tokens.peek() {
@@ -120,27 +124,45 @@ impl<'p> Parser<'p> {
                         Some(&TT::DO) |
                         Some(&TT::PLEASEDO) => break,
                         Some(&TT::WAX) => {
-                            let wax = self.tokens.next().expect("THERE WAX A TOKEN I SWEAR");
+                            let wax = self.tokens.next_token().expect("THERE WAX A TOKEN I SWEAR");
                             if let Some(&TT::NUMBER(_)) = self.tokens.peek() {
-                                self.tokens.push(wax);
+                                self.tokens.push_token(wax);
                                 break;
                             } else {
-                                self.tokens.next();
+                                self.stash.push(wax);
+                                if let Some(tok) = self.tokens.next_token() {
+                                    self.stash.push(tok);
+                                }
                             }
                         }
-                        _ => { self.tokens.next(); }
+                        _ => {
+                            let tok = self.tokens.next_token().expect("peeked token vanished");
+                            self.stash.push(tok);
+                        }
                     }
                 }
+                self.exprs.clear();
                 // return the botched statement
-                Ok(Stmt { body: body, props: props, comefrom: None, can_abstain: true })
+                Stmt { body: body, props: props, comefrom: None, can_abstain: true }
             }
             // a full statement!
             Ok(body) => {
                 let can_abstain = body != StmtBody::GiveUp;
-                Ok(Stmt { body: body, props: props, comefrom: None,
-                          can_abstain: can_abstain })
-            }
-        }
+                Stmt { body: body, props: props, comefrom: None,
+                       can_abstain: can_abstain }
+            }
+        };
+        // record the concrete syntax
+        let end = self.stash.iter().map(|t| t.2.end).max().unwrap_or(start);
+        stmt.props.span = Span { start: start, end: end };
+        let mut splat = false;
+        if let StmtBody::Error(_, ref mut text) = stmt.body {
+            *text = self.cst.text(stmt.props.span).into_owned();
+            splat = true;
+        }
+        self.cst.stmts.push(StmtNode { span: stmt.props.span, splat: splat,
+                                       exprs: self.exprs.clone() });
+        Ok(stmt)
     }
 
     /// Try to parse a full statement.
@@ -277,7 +299,7 @@ impl<'p> Parser<'p> {
             res.push(try!(self.parse_expr()));
             // all others are optional; we need backtracking here
             loop {
-                let state = self.stash.len();
+                let state = (self.stash.len(), self.exprs.len());
                 match self.parse_expr() {
                     Ok(expr) => res.push(expr),
                     Err(DecodeError::Soft(_)) => { self.backtrack(state); break }
@@ -301,64 +323,73 @@ impl<'p> Parser<'p> {
     /// Parse a list of variables (with subscripts) or consts separated by +.
     fn parse_readlist(&mut self) -> ParseRes<Vec<Expr>> {
         let mut res = Vec::new();
-        if self.take(TT::MESH) {
-            let val = try!(self.req_number(u16::MAX, &IE017));
-            res.push(Expr::Num(VType::I16, val as u32));
-        } else {
-            res.push(Expr::Var(try!(self.parse_var(true))));
-        }
+        res.push(try!(self.parse_readitem()));
         while self.take(TT::INTERSECTION) {
-            if self.take(TT::MESH) {
-                let val = try!(self.req_number(u16::MAX, &IE017));
-                res.push(Expr::Num(VType::I16, val as u32));
-            } else {
-                res.push(Expr::Var(try!(self.parse_var(true))));
-            }
+            res.push(try!(self.parse_readitem()));
         }
         Ok(res)
     }
 
+    fn parse_readitem(&mut self) -> ParseRes<Expr> {
+        let start = self.mark();
+        let expr = if self.take(TT::MESH) {
+            let val = try!(self.req_number(u16::MAX, &IE017));
+            Expr::Num(VType::I16, val as u32)
+        } else {
+            Expr::Var(try!(self.parse_var(true)))
+        };
+        self.record(start);
+        Ok(expr)
+    }
+
     /// Maybe parse a variable reference with maybe inline unary op [.:,;] OP N {SUB X}.
     fn parse_item_with_unop(&mut self) -> ParseRes<Option<Expr>> {
-        fn parse_constr(self_: &mut Parser) -> Box<Fn(Expr) -> Expr> {
+        fn parse_constr(self_: &mut Parser) -> Option<Box<Fn(Expr) -> Expr>> {
             if self_.take(TT::AMPERSAND) {
-                box |e| Expr::And(VType::I16, box e)
+                Some(box |e| Expr::And(VType::I16, box e))
             } else if self_.take(TT::BOOK) {
-                box |e| Expr::Or(VType::I16, box e)
+                Some(box |e| Expr::Or(VType::I16, box e))
             } else if self_.take(TT::WHAT) {
-                box |e| Expr::Xor(VType::I16, box e)
+                Some(box |e| Expr::Xor(VType::I16, box e))
             } else {
-                box |e| e
+                None
             }
         }
-        if self.take(TT::MESH) {
+        let start = self.mark();
+        let (constr, item) = if self.take(TT::MESH) {
             let constr = parse_constr(self);
             let val = try!(self.req_number(u16::MAX, &IE017));
-            return Ok(Some(constr(Expr::Num(VType::I16, val as u32))));
-        }
-        if self.take(TT::SPOT) {
+            (constr, Expr::Num(VType::I16, val as u32))
+        } else if self.take(TT::SPOT) {
             let constr = parse_constr(self);
             let val = try!(self.req_number(u16::MAX, &IE200));
-            return Ok(Some(constr(Expr::Var(Var::I16(val as usize)))));
-        }
-        if self.take(TT::TWOSPOT) {
+            (constr, Expr::Var(Var::I16(val as usize)))
+        } else if self.take(TT::TWOSPOT) {
             let constr = parse_constr(self);
             let val = try!(self.req_number(u16::MAX, &IE200));
-            return Ok(Some(constr(Expr::Var(Var::I32(val as usize)))));
-        }
-        if self.take(TT::TAIL) {
+            (constr, Expr::Var(Var::I32(val as usize)))
+        } else if self.take(TT::TAIL) {
             let constr = parse_constr(self);
             let val = try!(self.req_number(u16::MAX, &IE200));
             let subs = try!(self.parse_subs());
-            return Ok(Some(constr(Expr::Var(Var::A16(val as usize, subs)))));
-        }
-        if self.take(TT::HYBRID) {
+            (constr, Expr::Var(Var::A16(val as usize, subs)))
+        } else if self.take(TT::HYBRID) {
             let constr = parse_constr(self);
             let val = try!(self.req_number(u16::MAX, &IE200));
             let subs = try!(self.parse_subs());
-            return Ok(Some(constr(Expr::Var(Var::A32(val as usize, subs)))));
-        }
-        Ok(None)
+            (constr, Expr::Var(Var::A32(val as usize, subs)))
+        } else {
+            return Ok(None);
+        };
+        // the item and the inline operator both span the whole thing
+        self.record(start);
+        Ok(Some(match constr {
+            Some(constr) => {
+                self.record(start);
+                constr(item)
+            }
+            None => item,
+        }))
     }
 
     /// Parse an ABSTAIN statement.
@@ -433,13 +464,16 @@ impl<'p> Parser<'p> {
 
     /// Parse a single expression.
     fn parse_expr(&mut self) -> ParseRes<Expr> {
+        let start = self.mark();
         let left = try!(self.parse_expr2());
         if self.take(TT::MONEY) {
             let right = try!(self.parse_expr2());
+            self.record(start);
             return Ok(Expr::Mingle(box left, box right));
         }
         if self.take(TT::SQUIGGLE) {
             let right = try!(self.parse_expr2());
+            self.record(start);
             return Ok(Expr::Select(right.get_vtype(), box left, box right));
         }
         Ok(left)
@@ -449,22 +483,28 @@ impl<'p> Parser<'p> {
         if let Some(expr) = try!(self.parse_item_with_unop()) {
             return Ok(expr);
         }
+        let start = self.mark();
         if self.take(TT::RABBITEARS) {
             let expr = try!(self.parse_expr());
             try!(self.req(TT::RABBITEARS));
+            self.regroup(start);
             Ok(expr)
         } else if self.take(TT::SPARK) {
             let expr = try!(self.parse_expr());
             try!(self.req(TT::SPARK));
+            self.regroup(start);
             Ok(expr)
         } else if self.take(TT::AMPERSAND) {
             let expr = try!(self.parse_expr());
+            self.record(start);
             Ok(Expr::And(expr.get_vtype(), box expr))
         } else if self.take(TT::BOOK) {
             let expr = try!(self.parse_expr());
+            self.record(start);
             Ok(Expr::Or(expr.get_vtype(), box expr))
         } else if self.take(TT::WHAT) {
             let expr = try!(self.parse_expr());
+            self.record(start);
             Ok(Expr::Xor(expr.get_vtype(), box expr))
         } else {
             Err(self.invalid())
@@ -478,51 +518,73 @@ impl<'p> Parser<'p> {
             Some(ref v) if **v == t => { }
             _ => return false,
         }
-        self.stash.push(self.tokens.next().expect("there just was a token?!"));
+        let tok = self.tokens.next_token().expect("there just was a token?!");
+        self.stash.push(tok);
         true
     }
 
-    /// Push tokens back to the source until stash is of length `state`.
-    fn backtrack(&mut self, state: usize) {
-        while self.stash.len() > state {
-            self.tokens.push(self.stash.pop().expect("Schroedinger stack?"));
+    /// Push tokens back to the source until stash is of length `state`, and
+    /// forget the expressions parsed since then.
+    fn backtrack(&mut self, state: (usize, usize)) {
+        while self.stash.len() > state.0 {
+            self.tokens.push_token(self.stash.pop().expect("Schroedinger stack?"));
         }
+        self.exprs.truncate(state.1);
     }
 
     /// Require a number as next token, with bounds checking.
     fn req_number(&mut self, max: u16, err: &'static ErrDesc) -> ParseRes<u16> {
-        match self.tokens.next() {
-            Some(TT::NUMBER(x)) => {
-                if x > max as u32 {
+        match self.tokens.next_token() {
+            Some(tok) => match tok.0 {
+                TT::NUMBER(x) if x > max as u32 => {
                     Err(DecodeError::Hard(err.new(None, self.tokens.lineno())))
-                } else {
-                    self.stash.push(TT::NUMBER(x));
+                }
+                TT::NUMBER(x) => {
+                    self.stash.push(tok);
                     Ok(x as u16)
                 }
-            }
-            Some(t)  => {
-                self.tokens.push(t);
-                Err(self.invalid())
-            }
-            None     => Err(self.invalid()),
+                _ => {
+                    self.tokens.push_token(tok);
+                    Err(self.invalid())
+                }
+            },
+            None => Err(self.invalid()),
         }
     }
 
     /// Require a token `t` next.
     fn req(&mut self, t: TT) -> ParseRes<()> {
-        match self.tokens.next() {
-            None                    => Err(self.invalid()),
-            Some(ref x) if *x == t  => {
-                self.stash.push(t);
+        match self.tokens.next_token() {
+            None      => Err(self.invalid()),
+            Some(tok) => if tok.0 == t {
+                self.stash.push(tok);
                 Ok(())
-            }
-            Some(t)                 => {
-                self.tokens.push(t);
+            } else {
+                self.tokens.push_token(tok);
                 Err(self.invalid())
             }
         }
     }
 
+    /// Start position of the next token, for recording expression spans.
+    fn mark(&mut self) -> SrcPos {
+        self.tokens.peek_pos().unwrap_or(0)
+    }
+
+    /// Record the span of an expression that was parsed starting at `start`.
+    fn record(&mut self, start: SrcPos) {
+        let end = self.stash.last().map_or(start, |t| t.2.end);
+        self.exprs.push(Span { start: start, end: end });
+    }
+
+    /// Extend the span of the last expression to include the group around it.
+    fn regroup(&mut self, start: SrcPos) {
+        let end = self.stash.last().map_or(start, |t| t.2.end);
+        if let Some(span) = self.exprs.last_mut() {
+            *span = Span { start: start, end: end };
+        }
+    }
+
     #[inline]
     fn invalid(&mut self) -> DecodeError {
         DecodeError::Soft(self.tokens.lineno())
@@ -571,101 +633,9 @@ impl<'p> Parser<'p> {
         stmts
     }
 
-    /// Walk all references to variables, and call a visitor function for each.
-    fn walk_vars<F>(&self, stmt: &mut Stmt, mut visitor: F)
-        where F: FnMut(&mut Var) -> ()
-    {
-        let visitor = &mut visitor;
-
-        fn walk_var<F>(var: &mut Var, visitor: &mut F)
-            where F: FnMut(&mut Var) -> ()
-        {
-            visitor(var);
-            match *var {
-                Var::A16(_, ref mut es) |
-                Var::A32(_, ref mut es) => {
-                    for e in es {
-                        walk_expr(e, visitor);
-                    }
-                }
-                Var::I16(_) |
-                Var::I32(_) => { }
-            }
-        }
-
-        fn walk_expr<F>(expr: &mut Expr, visitor: &mut F)
-            where F: FnMut(&mut Var) -> ()
-        {
-            match *expr {
-                Expr::Var(ref mut v) => walk_var(v, visitor),
-                Expr::And(_, ref mut e) |
-                Expr::Or(_, ref mut e) |
-                Expr::Xor(_, ref mut e) |
-                Expr::RsNot(ref mut e) => walk_expr(e, visitor),
-                Expr::Mingle(ref mut e, ref mut e2) |
-                Expr::Select(_, ref mut e, ref mut e2) |
-                Expr::RsAnd(ref mut e, ref mut e2) |
-                Expr::RsOr(ref mut e, ref mut e2) |
-                Expr::RsXor(ref mut e, ref mut e2) |
-                Expr::RsRshift(ref mut e, ref mut e2) |
-                Expr::RsLshift(ref mut e, ref mut e2) |
-                // Expr::RsEqual(ref mut e, ref mut e2) |
-                Expr::RsNotEqual(ref mut e, ref mut e2) |
-                Expr::RsPlus(ref mut e, ref mut e2) |
-                Expr::RsMinus(ref mut e, ref mut e2) => {
-                    walk_expr(e, visitor);
-                    walk_expr(e2, visitor);
-                }
-                Expr::Num(_, _) => { }
-            }
-        }
-
-        match stmt.body {
-            StmtBody::Calc(ref mut v, ref mut e) => {
-                walk_var(v, visitor);
-                walk_expr(e, visitor);
-            }
-            StmtBody::Dim(ref mut v, ref mut es) => {
-                walk_var(v, visitor);
-                for e in es {
-                    walk_expr(e, visitor);
-                }
-            }
-            StmtBody::Resume(ref mut e) |
-            StmtBody::Forget(ref mut e) => {
-                walk_expr(e, visitor);
-            }
-            StmtBody::Abstain(ref mut maybe_e, _) => {
-                for e in maybe_e.iter_mut() {
-                    walk_expr(e, visitor);
-                }
-            }
-            StmtBody::ComeFrom(ref mut spec) => {
-                if let ComeFrom::Expr(ref mut e) = *spec {
-                    walk_expr(e, visitor);
-                }
-            }
-            StmtBody::Ignore(ref mut vs) |
-            StmtBody::Remember(ref mut vs) |
-            StmtBody::Stash(ref mut vs) |
-            StmtBody::Retrieve(ref mut vs) |
-            StmtBody::WriteIn(ref mut vs) => {
-                for v in vs {
-                    walk_var(v, visitor);
-                }
-            }
-            StmtBody::ReadOut(ref mut es) => {
-                for e in es {
-                    walk_expr(e, visitor);
-                }
-            }
-            _ => { }
-        }
-    }
-
     /// Collect all used variable numbers and renumber them.
     fn collect_vars(&self, vars: &mut Vars, stmt: &mut Stmt) {
-        self.walk_vars(stmt, |var| {
+        stmt.walk_vars(|var| {
             let key = var.unique();
             if !vars.map.contains_key(&key) {
                 let idx = key.0 as usize;
@@ -677,14 +647,15 @@ impl<'p> Parser<'p> {
 
     /// Apply variable renumbering.
     fn rename_vars(&self, vars: &Vars, stmt: &mut Stmt) {
-        self.walk_vars(stmt, |var| {
+        stmt.walk_vars(|var| {
             let key = var.unique();
             var.rename(vars.map[&key]);
         });
     }
 
-    /// Do whatever needs to be done after parsing is complete.
-    fn post_process(&self, stmts: Vec<Stmt>) -> Res<Program> {
+    /// Do whatever needs to be done after parsing is complete: add the standard
+    /// libraries, renumber the variables and collect what the Program needs.
+    pub fn post_process(&self, stmts: Vec<Stmt>) -> Res<Program> {
         let mut added_syslib = false;
         let mut added_floatlib = false;
         let mut stmts = self.add_stdlibs(stmts, &mut added_syslib, &mut added_floatlib);
@@ -708,30 +679,29 @@ impl<'p> Parser<'p> {
                 if i < nstmts - 1 { srclines[i + 1] } else { srclines[i] };
             if stmt.props.label > 0 {
                 if labels.contains_key(&stmt.props.label) {
-                    return Err(IE182.new(None, stmt.props.onthewayto));
+                    return Err(stmt_err(&IE182, stmt));
                 }
                 labels.insert(stmt.props.label, i as u16);
             }
             if stmt.props.polite {
                 npolite += 1;
             }
-            if let StmtBody::Error(ref mut e) = stmt.body {
+            if let StmtBody::Error(ref mut e, _) = stmt.body {
                 e.set_line(stmt.props.onthewayto);
             }
             self.collect_vars(&mut vars, &mut stmt);
         }
         // check politeness
-        if stmts.len() > 2 {
-            if npolite == 0 || (stmts.len() - 1) / npolite >= 5 {
-                return IE079.err();
-            } else if stmts.len() / npolite < 3 {
-                return IE099.err();
-            }
+        let (min_polite, max_polite) = polite_bounds(stmts.len());
+        if npolite < min_polite {
+            return IE079.err();
+        } else if npolite > max_polite {
+            return IE099.err();
         }
         // here we:
         // - create a map of all come-froms to logical lines
         // - apply new variable names
-        // - make sure abstain labels exist
+        // - make sure abstain and reinstate labels exist
         // - make sure TRY AGAIN is last in the file
         let mut uses_complex_comefrom = false;
         for (i, mut stmt) in stmts.iter_mut().enumerate() {
@@ -739,10 +709,10 @@ impl<'p> Parser<'p> {
                 match *spec {
                     ComeFrom::Label(n) => {
                         match labels.get(&n) {
-                            None => return Err(IE444.new(None, stmt.props.onthewayto)),
+                            None => return Err(stmt_err(&IE444, stmt)),
                             Some(j) => {
                                 if comefroms.contains_key(&(*j as usize)) {
-                                    return Err(IE555.new(None, stmt.props.onthewayto));
+                                    return Err(stmt_err(&IE555, stmt));
                                 }
                                 comefroms.insert(*j as usize, i as u16);
                             }
@@ -752,7 +722,7 @@ impl<'p> Parser<'p> {
                         for (j, stype) in types.iter().enumerate() {
                             if *g == *stype {
                                 if comefroms.contains_key(&j) {
-                                    return Err(IE555.new(None, stmt.props.onthewayto));
+                                    return Err(stmt_err(&IE555, stmt));
                                 }
                                 comefroms.insert(j, i as u16);
                             }
@@ -764,17 +734,20 @@ impl<'p> Parser<'p> {
                 }
             }
             self.rename_vars(&vars, &mut stmt);
-            if let StmtBody::Abstain(_, ref v) = stmt.body {
-                if let Abstain::Label(n) = v[0] {
-                    if !labels.contains_key(&n) {
-                        return Err(IE139.new(None, stmt.props.onthewayto));
+            match stmt.body {
+                StmtBody::Abstain(_, ref v) | StmtBody::Reinstate(ref v) => {
+                    if let Abstain::Label(n) = v[0] {
+                        if !labels.contains_key(&n) {
+                            return Err(stmt_err(&IE139, stmt));
+                        }
                     }
                 }
+                _ => { }
             }
             if let StmtBody::TryAgain = stmt.body {
                 // TRY AGAIN must be the last statement in the file
                 if i != nstmts - 1 {
-                    return Err(IE993.new(None, stmt.props.onthewayto));
+                    return Err(stmt_err(&IE993, stmt));
                 }
             }
         }
@@ -802,10 +775,30 @@ impl<'p> Parser<'p> {
                      uses_complex_comefrom: uses_complex_comefrom,
                      added_syslib: added_syslib,
                      added_floatlib: added_floatlib,
-                     bugline: bugline })
+                     bugline: bugline,
+                     start: None })
+    }
+}
+
+
+/// Return the range of polite statement counts that is accepted for a program
+/// with `nstmts` statements.  Programs of up to two statements may be as rude or
+/// as polite as they like.
+pub fn polite_bounds(nstmts: usize) -> (usize, usize) {
+    if nstmts <= 2 {
+        (0, nstmts)
+    } else {
+        ((nstmts - 1) / 5 + 1, nstmts / 3)
     }
 }
 
+/// Create an error caused by the given statement.
+fn stmt_err(desc: &'static ErrDesc, stmt: &Stmt) -> RtError {
+    let mut err = desc.new(None, stmt.props.onthewayto);
+    err.set_stmt(stmt.props.srcline, Some((stmt.props.span.start, stmt.props.span.end)));
+    err
+}
+
 
 #[derive(Debug)]
 struct Vars {
@@ -0,0 +1,135 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// Writes a compiled program as a self-contained Cargo project.
+///
+/// The project consists of a manifest, a README with the original INTERCAL
+/// source, `src/main.rs` (filled in by the code generator) and the runtime
+/// library as a path dependency in `runtime/`.  It needs nothing but Cargo to
+/// build, so it can be kept under version control, built with any profile or
+/// target, or added to another workspace.
+///
+/// The runtime crate is always built with the 2015 edition, since it consists of
+/// the same sources as Rick itself; the edition of the program crate can be
+/// chosen.
+
+use std::fs::{ File, create_dir_all };
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+
+use err::{ Res, IE888, IE990 };
+use runtime;
+
+pub const EDITIONS: [&'static str; 4] = ["2015", "2018", "2021", "2024"];
+
+pub struct Project {
+    dir: PathBuf,
+    name: String,
+    edition: String,
+}
+
+fn ascii_alnum(c: char) -> bool {
+    (c as u32) < 128 && c.is_alphanumeric()
+}
+
+/// Make a valid crate name from an input file name.
+pub fn crate_name(infile: &str) -> String {
+    let stem = Path::new(infile).file_stem().map_or(String::new(),
+                                                    |s| s.to_string_lossy().into_owned());
+    let mut name: String = stem.chars().map(|c| {
+        if ascii_alnum(c) { c.to_lowercase().next().unwrap() } else { '_' }
+    }).collect();
+    if name.is_empty() || name.chars().next().unwrap().is_digit(10) {
+        name.insert(0, 'i');
+    }
+    name
+}
+
+fn create(path: &Path) -> Res<File> {
+    match File::create(path) {
+        Ok(f)  => Ok(f),
+        Err(_) => IE888.err(),
+    }
+}
+
+fn mkdir(path: &Path) -> Res<()> {
+    match create_dir_all(path) {
+        Ok(_)  => Ok(()),
+        Err(_) => IE888.err(),
+    }
+}
+
+impl Project {
+    pub fn new(dir: &str, name: &str, edition: &str) -> Res<Project> {
+        if name.is_empty() || !name.chars().all(|c| ascii_alnum(c) || c == '_' || c == '-') {
+            return IE990.err();
+        }
+        if !EDITIONS.contains(&edition) {
+            return IE990.err();
+        }
+        Ok(Project { dir: PathBuf::from(dir), name: name.into(), edition: edition.into() })
+    }
+
+    /// Write everything except the program itself, and return the file to
+    /// generate it into.
+    pub fn write(&self, infile: &str, code: &[u8]) -> Res<File> {
+        let rtdir = self.dir.join("runtime").join("src");
+        try!(mkdir(&self.dir.join("src")));
+        try!(mkdir(&rtdir));
+        try!(runtime::write_sources(&rtdir));
+
+        let mut f = try!(create(&self.dir.join("Cargo.toml")));
+        try!(write!(f, "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"{}\"\n\n\
+                        [dependencies]\n{} = {{ path = \"runtime\" }}\n",
+                    self.name, self.edition, runtime::CRATE_NAME.replace('_', "-")));
+
+        let mut f = try!(create(&self.dir.join("runtime").join("Cargo.toml")));
+        try!(write!(f, "[package]\nname = \"{}\"\nversion = \"{}.0.0\"\nedition = \"2015\"\n\n\
+                        [lib]\nname = \"{}\"\npath = \"src/lib.rs\"\n",
+                    runtime::CRATE_NAME.replace('_', "-"), runtime::API_VERSION,
+                    runtime::CRATE_NAME));
+
+        let mut f = try!(create(&self.dir.join("README.md")));
+        let source = String::from_utf8_lossy(code);
+        let fence = if source.contains("```") { "~~~~" } else { "```" };
+        try!(write!(f, "# {}\n\n\
+                        This crate was generated by Rick from the INTERCAL program `{}`.  \
+                        Build it with\n`cargo build`; the runtime library is in `runtime/`.\n\n\
+                        ## Source\n\n{}\n{}{}{}\n",
+                    self.name, infile, fence, source,
+                    if source.ends_with('\n') { "" } else { "\n" }, fence));
+
+        create(&self.dir.join("src").join("main.rs"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::crate_name;
+
+    #[test]
+    fn crate_names() {
+        assert_eq!(crate_name("code/beer.i"), "beer");
+        assert_eq!(crate_name("Life-2.i"), "life_2");
+        // crate names can't start with a digit
+        assert_eq!(crate_name("99bottles.i"), "i99bottles");
+        assert_eq!(crate_name(""), "i");
+        // only ASCII letters and digits are kept, one underscore per character
+        assert_eq!(crate_name("grüße.i"), "gr__e");
+        assert_eq!(crate_name("日本.i"), "__");
+    }
+}
@@ -0,0 +1,279 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// Propagates the values of variables from their assignments to later statements.
+///
+/// This is a forward dataflow analysis over the control-flow graph (see cfg.rs).
+/// For every block, it determines which spot and twospot variables are known to
+/// hold a constant, or the same value as another variable, whenever control
+/// enters it.  Since the graph includes every way to get to a block (NEXT, RESUME,
+/// COME FROM and TRY AGAIN), a fact only holds if it holds on all of them.
+///
+/// Within a block, the statements are gone through in order:
+///
+/// * a statement that may be skipped (because it can be abstained from, or has a
+///   chance below 100%) only keeps the facts that are true either way
+/// * an assignment to a variable that can be IGNOREd may not change it at all
+/// * RETRIEVE and WRITE IN make the value of their variables unknown
+///
+/// The known values are then substituted into expressions: assigned values,
+/// array subscripts, ABSTAIN counts, RESUME and FORGET depths and READ OUT.
+
+use std::collections::BTreeMap;
+
+use ast::{ Program, Stmt, StmtBody, Expr, Var, VarInfo, VType };
+use cfg::{ Cfg, Target, may_run, may_skip };
+use rules::{ eval_const, operands_mut };
+
+
+/// A spot or twospot variable: true if it is a twospot, and its number.
+type Key = (bool, usize);
+
+/// What is known about the value of a variable.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Fact {
+    Const(u32),
+    Copy(Key),
+}
+
+type Facts = BTreeMap<Key, Fact>;
+
+type VarInfos = (Vec<VarInfo>, Vec<VarInfo>, Vec<VarInfo>, Vec<VarInfo>);
+
+
+/// Propagate known values through the program.  Returns the number of statements
+/// that were changed.
+pub fn propagate(program: &mut Program) -> usize {
+    let graph = Cfg::new(program);
+    // facts on entry to each block, or None if not reached (yet)
+    let mut entry: Vec<Option<Facts>> = vec![None; graph.blocks.len()];
+    let mut initial = Facts::new();
+    // a snapshot knows the values of all variables at its start
+    if let Some(ref start) = program.start {
+        for (n, bind) in start.spot.iter().enumerate() {
+            initial.insert((false, n), Fact::Const(bind.val as u32));
+        }
+        for (n, bind) in start.twospot.iter().enumerate() {
+            initial.insert((true, n), Fact::Const(bind.val));
+        }
+    }
+    entry[graph.entry] = Some(initial);
+    let mut todo = vec![graph.entry];
+    while let Some(i) = todo.pop() {
+        let block = &graph.blocks[i];
+        let mut facts = entry[i].clone().unwrap();
+        for stmt in &program.stmts[block.start..block.end] {
+            transfer(&program.var_info, stmt, &mut facts);
+        }
+        for edge in &block.succs {
+            if let Target::Block(j) = edge.target {
+                let new = match entry[j] {
+                    None => facts.clone(),
+                    Some(ref old) => meet(old, &facts),
+                };
+                if entry[j].as_ref() != Some(&new) {
+                    entry[j] = Some(new);
+                    if !todo.contains(&j) {
+                        todo.push(j);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut changed = 0;
+    for (block, facts) in graph.blocks.iter().zip(entry) {
+        // blocks that are never reached are left alone
+        let mut facts = match facts {
+            Some(facts) => facts,
+            None => continue,
+        };
+        for i in block.start..block.end {
+            if substitute(&mut program.stmts[i], &facts) {
+                changed += 1;
+            }
+            transfer(&program.var_info, &program.stmts[i], &mut facts);
+        }
+    }
+    changed
+}
+
+/// Update the facts for the effect of a statement.
+fn transfer(var_info: &VarInfos, stmt: &Stmt, facts: &mut Facts) {
+    if !may_run(stmt) {
+        return;
+    }
+    let before = if may_skip(stmt) { Some(facts.clone()) } else { None };
+    match stmt.body {
+        StmtBody::Calc(ref var, ref expr) => {
+            if let Some(key) = key(var) {
+                let fact = fact_of(key, expr, facts);
+                kill(facts, key);
+                let info = if key.0 { &var_info.1[key.1] } else { &var_info.0[key.1] };
+                if !info.can_ignore {
+                    if let Some(fact) = fact {
+                        facts.insert(key, fact);
+                    }
+                }
+            }
+        }
+        StmtBody::Retrieve(ref vars) | StmtBody::WriteIn(ref vars) => {
+            for var in vars {
+                if let Some(key) = key(var) {
+                    kill(facts, key);
+                }
+            }
+        }
+        _ => { }
+    }
+    if let Some(before) = before {
+        *facts = meet(&before, facts);
+    }
+}
+
+/// Determine what is known about a variable after assigning it an expression.
+fn fact_of(key: Key, expr: &Expr, facts: &Facts) -> Option<Fact> {
+    let mut expr = expr.clone();
+    subst_expr(&mut expr, facts);
+    if let Some(v) = eval_const(&expr) {
+        // assigning a too big value to a spot is an error
+        if key.0 || v <= 0xFFFF {
+            return Some(Fact::Const(v));
+        }
+    } else if let Expr::Var(ref var) = expr {
+        if let Some(other) = self::key(var) {
+            if other != key && other.0 == key.0 {
+                return Some(Fact::Copy(other));
+            }
+        }
+    }
+    None
+}
+
+/// Forget everything about a variable, including that others are a copy of it.
+fn kill(facts: &mut Facts, key: Key) {
+    facts.remove(&key);
+    let copies = facts.iter().filter(|&(_, fact)| *fact == Fact::Copy(key))
+                             .map(|(&k, _)| k).collect::<Vec<_>>();
+    for k in copies {
+        facts.remove(&k);
+    }
+}
+
+/// The facts that are true in both sets.
+fn meet(a: &Facts, b: &Facts) -> Facts {
+    a.iter().filter(|&(k, fact)| b.get(k) == Some(fact))
+            .map(|(&k, &fact)| (k, fact)).collect()
+}
+
+fn key(var: &Var) -> Option<Key> {
+    match *var {
+        Var::I16(n) => Some((false, n)),
+        Var::I32(n) => Some((true, n)),
+        _ => None,
+    }
+}
+
+/// Substitute known values in the expressions of a statement.
+fn substitute(stmt: &mut Stmt, facts: &Facts) -> bool {
+    let mut changed = false;
+    match stmt.body {
+        StmtBody::Calc(ref mut var, ref mut expr) => {
+            changed |= subst_subscripts(var, facts);
+            changed |= subst_expr(expr, facts);
+        }
+        StmtBody::Dim(_, ref mut exprs) => {
+            for expr in exprs {
+                changed |= subst_expr(expr, facts);
+            }
+        }
+        StmtBody::Resume(ref mut expr) |
+        StmtBody::Forget(ref mut expr) |
+        StmtBody::Abstain(Some(ref mut expr), _) => {
+            changed |= subst_expr(expr, facts);
+        }
+        StmtBody::WriteIn(ref mut vars) => {
+            for var in vars {
+                changed |= subst_subscripts(var, facts);
+            }
+        }
+        StmtBody::ReadOut(ref mut exprs) => {
+            for expr in exprs {
+                let mut new = expr.clone();
+                if subst_expr(&mut new, facts) {
+                    // only numbers that can be written in the source are read out
+                    if let Expr::Num(_, v) = new {
+                        if v > 0xFFFF {
+                            continue;
+                        }
+                    }
+                    *expr = new;
+                    changed = true;
+                }
+            }
+        }
+        _ => { }
+    }
+    changed
+}
+
+/// Substitute known values in an expression, and fold it if it becomes constant.
+fn subst_expr(expr: &mut Expr, facts: &Facts) -> bool {
+    let new = match *expr {
+        Expr::Var(ref mut var) => match key(var) {
+            Some(key) => match facts.get(&key) {
+                Some(&Fact::Const(v)) => {
+                    Expr::Num(if key.0 { VType::I32 } else { VType::I16 }, v)
+                }
+                Some(&Fact::Copy(other)) => {
+                    Expr::Var(if other.0 { Var::I32(other.1) } else { Var::I16(other.1) })
+                }
+                None => return false,
+            },
+            None => return subst_subscripts(var, facts),
+        },
+        Expr::Num(..) => return false,
+        _ => {
+            let mut changed = false;
+            for arg in operands_mut(expr) {
+                changed |= subst_expr(arg, facts);
+            }
+            if !changed {
+                return false;
+            }
+            match eval_const(expr) {
+                Some(v) => Expr::Num(VType::I32, v),
+                None => return true,
+            }
+        }
+    };
+    *expr = new;
+    true
+}
+
+fn subst_subscripts(var: &mut Var, facts: &Facts) -> bool {
+    match *var {
+        Var::A16(_, ref mut subs) | Var::A32(_, ref mut subs) => {
+            let mut changed = false;
+            for sub in subs {
+                changed |= subst_expr(sub, facts);
+            }
+            changed
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,956 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// A rule language for the expression optimizer.
+///
+/// The idioms that the optimizer replaces by native operations are not hardcoded,
+/// but written as rules in `idioms.rules` (embedded into the binary), similar to
+/// C-INTERCAL's `idiotism.oil`.  Each rule consists of a pattern, a replacement and
+/// optional conditions on the numbers matched by the pattern; the comment at the
+/// top of that file describes the syntax.
+///
+/// Rules are applied bottom-up: first to the operands of an expression, then to
+/// the expression itself.  When a rule matched, the replacement is processed
+/// again, until no rule matches anymore.
+///
+/// Since it is easy to get the bit twiddling wrong, every rule can be checked by
+/// evaluating pattern and replacement with random inputs ("rick rules --check").
+
+use std::cmp;
+
+use rand::{ self, Rng };
+
+use ast::{ Expr, Var, VType };
+use stdops::{ mingle, select, and_16, and_32, or_16, or_32, xor_16, xor_32,
+              times_16, times_32, divide_16, divide_32, modulus_16, modulus_32 };
+
+
+/// The built-in rules.
+static IDIOMS: &'static str = include_str!("idioms.rules");
+
+/// Maximum number of rewrites for one expression, in case some rules undo
+/// each other.
+const MAX_REWRITES: usize = 1000;
+
+/// Binary operators of patterns and replacements.
+const BINOPS: [(&'static str, Op); 14] = [
+    ("$", Op::Mingle), ("~", Op::Select(None)), ("&", Op::RsAnd), ("|", Op::RsOr),
+    ("^", Op::RsXor), (">>", Op::RsRshift), ("<<", Op::RsLshift), ("==", Op::RsEqual),
+    ("!=", Op::RsNotEqual), ("+", Op::RsPlus), ("-", Op::RsMinus), ("*", Op::RsTimes(None)),
+    ("/", Op::RsDivide(None)), ("%", Op::RsModulus(None))];
+
+/// Unary operators of patterns and replacements.
+const UNOPS: [(&'static str, Op); 9] = [
+    ("and", Op::And(None)), ("and16", Op::And(Some(VType::I16))),
+    ("and32", Op::And(Some(VType::I32))), ("or", Op::Or(None)),
+    ("or16", Op::Or(Some(VType::I16))), ("or32", Op::Or(Some(VType::I32))),
+    ("xor", Op::Xor(None)), ("xor16", Op::Xor(Some(VType::I16))),
+    ("xor32", Op::Xor(Some(VType::I32)))];
+
+/// Binary operators of computations.
+const CALC_OPS: [&'static str; 14] = ["==", "!=", "<=", ">=", "<", ">", "+", "-", "*",
+                                      "&", "|", "^", "<<", ">>"];
+
+/// Functions of computations, with their number of arguments.
+const FUNCTIONS: [(&'static str, usize); 5] = [("tz", 1), ("lz", 1), ("pop", 1),
+                                               ("contiguous", 1), ("mingle", 2)];
+
+/// All symbols, longest first.
+const SYMBOLS: [&'static str; 25] = ["->", "<<", ">>", "!=", "==", "<=", ">=", "#", "$",
+                                     "~", "&", "|", "^", "!", "(", ")", ",", ":", "+",
+                                     "-", "*", "/", "%", "<", ">"];
+
+
+/// An operation of an expression.  In patterns, the width of the INTERCAL
+/// operators can be left open.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Op {
+    Mingle,
+    Select(Option<VType>),
+    And(Option<VType>),
+    Or(Option<VType>),
+    Xor(Option<VType>),
+    RsNot,
+    RsAnd,
+    RsOr,
+    RsXor,
+    RsRshift,
+    RsLshift,
+    RsEqual,
+    RsNotEqual,
+    RsPlus,
+    RsMinus,
+    RsTimes(Option<VType>),
+    RsDivide(Option<VType>),
+    RsModulus(Option<VType>),
+}
+
+/// A pattern, or a replacement.
+#[derive(Debug)]
+enum Pat {
+    /// Any expression, optionally only one of the given width (see `width`).
+    Expr(String, Option<VType>),
+    /// Any number.
+    Const(String),
+    /// A given number.
+    Num(u32),
+    /// A computed number (only in replacements).
+    Calc(Calc),
+    /// An operation.
+    Op(Op, Vec<Pat>),
+}
+
+/// A computation on the numbers matched by a pattern.
+#[derive(Debug)]
+enum Calc {
+    Num(u32),
+    Name(String),
+    Not(Box<Calc>),
+    Call(&'static str, Vec<Calc>),
+    Bin(&'static str, Box<Calc>, Box<Calc>),
+}
+
+pub struct Rule {
+    pub name: String,
+    /// The rule as written in the rules file.
+    pub text: String,
+    pattern: Pat,
+    replacement: Pat,
+    conditions: Vec<Calc>,
+}
+
+pub struct Rules {
+    pub rules: Vec<Rule>,
+}
+
+/// Expressions and numbers matched by the names of a pattern.
+struct Binds<'r, 'e> {
+    exprs: Vec<(&'r str, &'e Expr)>,
+    nums: Vec<(&'r str, u32)>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Tok {
+    Ident(String),
+    Num(u32),
+    Sym(&'static str),
+}
+
+struct TokParser {
+    toks: Vec<Tok>,
+    pos: usize,
+}
+
+
+impl Rules {
+    /// The rules from `idioms.rules`.
+    pub fn builtin() -> Rules {
+        match Rules::parse(IDIOMS) {
+            Ok(rules) => rules,
+            Err(msg)  => panic!("error in idioms.rules: {}", msg),
+        }
+    }
+
+    /// Parse rules in the format of `idioms.rules`.
+    pub fn parse(src: &str) -> Result<Rules, String> {
+        let mut rules = Vec::new();
+        let mut text = String::new();
+        let mut lineno = 0;
+        for (i, line) in src.lines().enumerate() {
+            let line = match line.find("//") {
+                Some(j) => &line[..j],
+                None    => line,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            if line.starts_with(' ') || line.starts_with('\t') {
+                if text.is_empty() {
+                    return Err(format!("line {}: continuation line without a rule", i + 1));
+                }
+                text.push(' ');
+                text.push_str(line.trim());
+            } else {
+                if !text.is_empty() {
+                    rules.push(try!(Rule::parse(&text).map_err(
+                        |e| format!("line {}: {}", lineno, e))));
+                }
+                text = line.trim().into();
+                lineno = i + 1;
+            }
+        }
+        if !text.is_empty() {
+            rules.push(try!(Rule::parse(&text).map_err(|e| format!("line {}: {}", lineno, e))));
+        }
+        Ok(Rules { rules: rules })
+    }
+
+    /// Apply the rules to the expression and its operands until none matches
+    /// anymore.  Returns the number of replacements made.
+    pub fn rewrite(&self, expr: &mut Expr) -> usize {
+        let mut count = 0;
+        self.rewrite_inner(expr, &mut count);
+        count
+    }
+
+    fn rewrite_inner(&self, expr: &mut Expr, count: &mut usize) {
+        for arg in operands_mut(expr) {
+            self.rewrite_inner(arg, count);
+        }
+        while *count < MAX_REWRITES {
+            let new = self.rules.iter().filter_map(|rule| rule.apply(expr)).next();
+            match new {
+                Some(new) => {
+                    *expr = new;
+                    *count += 1;
+                    for arg in operands_mut(expr) {
+                        self.rewrite_inner(arg, count);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl Rule {
+    fn parse(text: &str) -> Result<Rule, String> {
+        let mut p = TokParser { toks: try!(tokenize(text)), pos: 0 };
+        let name = match p.next() {
+            Some(Tok::Ident(name)) => name,
+            _ => return Err("rule name expected".into()),
+        };
+        try!(p.expect(":"));
+        let pattern = try!(p.pattern(false));
+        try!(p.expect("->"));
+        let replacement = try!(p.pattern(true));
+        let mut conditions = Vec::new();
+        if p.peek() == Some(&Tok::Ident("if".into())) {
+            p.pos += 1;
+            loop {
+                conditions.push(try!(p.calc()));
+                if !p.sym(",") {
+                    break;
+                }
+            }
+        }
+        if let Some(tok) = p.peek() {
+            return Err(format!("unexpected {:?}", tok));
+        }
+        // all names used in the replacement and conditions must be matched by
+        // the pattern
+        let (mut exprs, mut nums) = (Vec::new(), Vec::new());
+        pattern.names(&mut exprs, &mut nums);
+        let (mut rexprs, mut rnums) = (Vec::new(), Vec::new());
+        replacement.names(&mut rexprs, &mut rnums);
+        for cond in &conditions {
+            cond.names(&mut rnums);
+        }
+        for &(ref name, _) in &exprs {
+            if nums.contains(name) {
+                return Err(format!("{} is used for an expression and a number", name));
+            }
+        }
+        for &(ref name, _) in &rexprs {
+            if !exprs.iter().any(|&(ref n, _)| n == name) {
+                return Err(format!("{} is not matched by the pattern", name));
+            }
+        }
+        for name in &rnums {
+            if !nums.contains(name) {
+                return Err(format!("#{} is not matched by the pattern", name));
+            }
+        }
+        Ok(Rule { name: name, text: text.into(), pattern: pattern,
+                  replacement: replacement, conditions: conditions })
+    }
+
+    /// Return the replacement for the expression, if the rule matches it.
+    fn apply(&self, expr: &Expr) -> Option<Expr> {
+        let mut binds = Binds { exprs: Vec::new(), nums: Vec::new() };
+        if self.pattern.matches(expr, &mut binds) &&
+            self.conditions.iter().all(|cond| cond.eval(&binds) != 0) {
+            Some(self.replacement.instantiate(&binds))
+        } else {
+            None
+        }
+    }
+
+    /// Check the rule by evaluating pattern and replacement with random inputs.
+    /// Inputs for which the pattern can't be evaluated (e.g. because a mingle
+    /// operand doesn't fit into 16 bits) are skipped.  Returns the number of
+    /// inputs that were checked, or a description of the failure.
+    pub fn check(&self, iterations: usize) -> Result<usize, String> {
+        let mut rng = rand::thread_rng();
+        let (mut exprs, mut nums) = (Vec::new(), Vec::new());
+        self.pattern.names(&mut exprs, &mut nums);
+        let mut checked = 0;
+        for _ in 0..iterations {
+            let values: Vec<_> = exprs.iter().map(|&(ref name, vtype)| {
+                let only16 = vtype.map_or_else(|| rng.gen(), |vtype| vtype == VType::I16);
+                let v = random_value(&mut rng, only16);
+                (&name[..], Expr::Num(if v <= 0xFFFF { VType::I16 } else { VType::I32 }, v))
+            }).collect();
+            let mut binds = Binds { exprs: Vec::new(), nums: Vec::new() };
+            for &(name, ref value) in &values {
+                binds.exprs.push((name, value));
+            }
+            // find numbers that fulfill the conditions
+            let mut found = false;
+            for _ in 0..100 {
+                binds.nums.clear();
+                for name in &nums {
+                    let only16 = rng.gen();
+                    binds.nums.push((&name[..], random_value(&mut rng, only16)));
+                }
+                if self.conditions.iter().all(|cond| cond.eval(&binds) != 0) {
+                    found = true;
+                    break;
+                }
+            }
+            if !found {
+                continue;
+            }
+            let expected = match eval_const(&self.pattern.instantiate(&binds)) {
+                Some(v) => v,
+                None    => continue,
+            };
+            let result = eval_const(&self.replacement.instantiate(&binds));
+            if result != Some(expected) {
+                let inputs: Vec<_> = binds.exprs.iter().map(|&(n, e)| format!("{} = {}", n, e))
+                    .chain(binds.nums.iter().map(|&(n, v)| format!("#{} = {}", n, v)))
+                    .collect();
+                return Err(format!("for {}: pattern gives {}, replacement gives {}",
+                                   inputs.join(", "), expected,
+                                   result.map_or("an error".into(), |v| v.to_string())));
+            }
+            checked += 1;
+        }
+        Ok(checked)
+    }
+}
+
+impl Pat {
+    /// Match an expression against the pattern, binding names.
+    fn matches<'r, 'e>(&'r self, expr: &'e Expr, binds: &mut Binds<'r, 'e>) -> bool {
+        match *self {
+            Pat::Expr(ref name, vtype) => {
+                if vtype.map_or(false, |vtype| vtype != width_type(expr)) {
+                    return false;
+                }
+                match binds.expr(name) {
+                    Some(bound) => *bound == *expr,
+                    None => {
+                        binds.exprs.push((name, expr));
+                        true
+                    }
+                }
+            }
+            Pat::Const(ref name) => match *expr {
+                Expr::Num(_, v) => match binds.num(name) {
+                    Some(bound) => bound == v,
+                    None => {
+                        binds.nums.push((name, v));
+                        true
+                    }
+                },
+                _ => false,
+            },
+            Pat::Num(n) => match *expr {
+                Expr::Num(_, v) => v == n,
+                _ => false,
+            },
+            Pat::Calc(_) => false,
+            Pat::Op(op, ref pats) => match split(expr) {
+                Some((eop, args)) => op.matches(eop) &&
+                    pats.iter().zip(args).all(|(pat, arg)| pat.matches(arg, binds)),
+                None => false,
+            },
+        }
+    }
+
+    /// Create the expression for a replacement (or pattern) from the bound names.
+    fn instantiate(&self, binds: &Binds) -> Expr {
+        match *self {
+            Pat::Expr(ref name, _) => binds.expr(name).expect("name not bound").clone(),
+            Pat::Const(ref name) => Expr::Num(VType::I32, binds.num(name).expect("name not bound")),
+            Pat::Num(n) => Expr::Num(VType::I32, n),
+            Pat::Calc(ref calc) => Expr::Num(VType::I32, calc.eval(binds)),
+            Pat::Op(op, ref pats) => build(op, pats.iter().map(|p| p.instantiate(binds)).collect()),
+        }
+    }
+
+    /// Collect the names of expressions (with their width) and numbers.
+    fn names(&self, exprs: &mut Vec<(String, Option<VType>)>, nums: &mut Vec<String>) {
+        match *self {
+            Pat::Expr(ref name, vtype) => if !exprs.iter().any(|&(ref n, _)| n == name) {
+                exprs.push((name.clone(), vtype));
+            },
+            Pat::Const(ref name) => if !nums.contains(name) {
+                nums.push(name.clone());
+            },
+            Pat::Num(_) => { }
+            Pat::Calc(ref calc) => calc.names(nums),
+            Pat::Op(_, ref pats) => for pat in pats {
+                pat.names(exprs, nums);
+            },
+        }
+    }
+}
+
+impl Calc {
+    fn eval(&self, binds: &Binds) -> u32 {
+        match *self {
+            Calc::Num(n) => n,
+            Calc::Name(ref name) => binds.num(name).expect("name not bound"),
+            Calc::Not(ref calc) => !calc.eval(binds),
+            Calc::Call(func, ref args) => {
+                let args: Vec<_> = args.iter().map(|arg| arg.eval(binds)).collect();
+                let v = args[0];
+                match func {
+                    "tz"  => v.trailing_zeros(),
+                    "lz"  => v.leading_zeros(),
+                    "pop" => v.count_ones(),
+                    "contiguous" => (v != 0 &&
+                                     v.count_ones() + v.leading_zeros() +
+                                     v.trailing_zeros() == 32) as u32,
+                    "mingle" => mingle(v, args[1]),
+                    _ => unreachable!(),
+                }
+            }
+            Calc::Bin(op, ref left, ref right) => {
+                let (v, w) = (left.eval(binds), right.eval(binds));
+                match op {
+                    "==" => (v == w) as u32,
+                    "!=" => (v != w) as u32,
+                    "<=" => (v <= w) as u32,
+                    ">=" => (v >= w) as u32,
+                    "<"  => (v < w) as u32,
+                    ">"  => (v > w) as u32,
+                    "+"  => v.wrapping_add(w),
+                    "-"  => v.wrapping_sub(w),
+                    "*"  => v.wrapping_mul(w),
+                    "&"  => v & w,
+                    "|"  => v | w,
+                    "^"  => v ^ w,
+                    "<<" => v.checked_shl(w).unwrap_or(0),
+                    ">>" => v.checked_shr(w).unwrap_or(0),
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+
+    fn names(&self, nums: &mut Vec<String>) {
+        match *self {
+            Calc::Num(_) => { }
+            Calc::Name(ref name) => if !nums.contains(name) {
+                nums.push(name.clone());
+            },
+            Calc::Not(ref calc) => calc.names(nums),
+            Calc::Call(_, ref args) => for arg in args {
+                arg.names(nums);
+            },
+            Calc::Bin(_, ref left, ref right) => {
+                left.names(nums);
+                right.names(nums);
+            }
+        }
+    }
+}
+
+impl Op {
+    /// Check if the operation of an expression matches this one.
+    fn matches(self, other: Op) -> bool {
+        match (self, other) {
+            (Op::Select(None), Op::Select(_)) |
+            (Op::And(None), Op::And(_)) |
+            (Op::Or(None), Op::Or(_)) |
+            (Op::Xor(None), Op::Xor(_)) |
+            (Op::RsTimes(None), Op::RsTimes(_)) |
+            (Op::RsDivide(None), Op::RsDivide(_)) |
+            (Op::RsModulus(None), Op::RsModulus(_)) => true,
+            _ => self == other,
+        }
+    }
+}
+
+impl<'r, 'e> Binds<'r, 'e> {
+    fn expr(&self, name: &str) -> Option<&'e Expr> {
+        self.exprs.iter().find(|&&(n, _)| n == name).map(|&(_, e)| e)
+    }
+
+    fn num(&self, name: &str) -> Option<u32> {
+        self.nums.iter().find(|&&(n, _)| n == name).map(|&(_, v)| v)
+    }
+}
+
+impl TokParser {
+    fn peek(&self) -> Option<&Tok> {
+        self.toks.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Tok> {
+        self.pos += 1;
+        self.toks.get(self.pos - 1).cloned()
+    }
+
+    /// Take the given symbol, if it comes next.
+    fn sym(&mut self, sym: &str) -> bool {
+        if let Some(&Tok::Sym(s)) = self.peek() {
+            if s == sym {
+                self.pos += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn expect(&mut self, sym: &str) -> Result<(), String> {
+        if self.sym(sym) {
+            Ok(())
+        } else {
+            Err(format!("{} expected", sym))
+        }
+    }
+
+    /// Parse a pattern (or replacement): an operand, optionally followed by a
+    /// binary operator and another operand.  The arithmetic operators can be
+    /// given a width, as in `x *16 y`.
+    fn pattern(&mut self, repl: bool) -> Result<Pat, String> {
+        let left = try!(self.operand(repl));
+        for &(sym, op) in BINOPS.iter() {
+            if self.sym(sym) {
+                let vtype = match self.peek() {
+                    Some(&Tok::Num(16)) => Some(VType::I16),
+                    Some(&Tok::Num(32)) => Some(VType::I32),
+                    _ => None,
+                };
+                let op = match (op, vtype) {
+                    (_, None) => op,
+                    (Op::RsTimes(_), _) => Op::RsTimes(vtype),
+                    (Op::RsDivide(_), _) => Op::RsDivide(vtype),
+                    (Op::RsModulus(_), _) => Op::RsModulus(vtype),
+                    _ => return Err(format!("{} has no width", sym)),
+                };
+                if vtype.is_some() {
+                    self.pos += 1;
+                }
+                let right = try!(self.operand(repl));
+                return Ok(Pat::Op(op, vec![left, right]));
+            }
+        }
+        Ok(left)
+    }
+
+    fn operand(&mut self, repl: bool) -> Result<Pat, String> {
+        if self.sym("(") {
+            let pat = try!(self.pattern(repl));
+            try!(self.expect(")"));
+            return Ok(pat);
+        }
+        if self.sym("!") {
+            return Ok(Pat::Op(Op::RsNot, vec![try!(self.operand(repl))]));
+        }
+        if self.sym("#") {
+            return match self.next() {
+                Some(Tok::Num(n)) => Ok(Pat::Num(n)),
+                Some(Tok::Ident(name)) => Ok(Pat::Const(name)),
+                Some(Tok::Sym("(")) if repl => {
+                    let calc = try!(self.calc());
+                    try!(self.expect(")"));
+                    Ok(Pat::Calc(calc))
+                }
+                _ => Err("number or name expected after #".into()),
+            };
+        }
+        match self.next() {
+            Some(Tok::Ident(name)) => {
+                if self.sym("(") {
+                    let op = match UNOPS.iter().find(|&&(n, _)| n == name) {
+                        Some(&(_, op)) => op,
+                        None => return Err(format!("unknown operator {}", name)),
+                    };
+                    let arg = try!(self.pattern(repl));
+                    try!(self.expect(")"));
+                    Ok(Pat::Op(op, vec![arg]))
+                } else if self.sym(":") {
+                    match self.next() {
+                        Some(Tok::Num(16)) => Ok(Pat::Expr(name, Some(VType::I16))),
+                        Some(Tok::Num(32)) => Ok(Pat::Expr(name, Some(VType::I32))),
+                        _ => Err("width 16 or 32 expected".into()),
+                    }
+                } else {
+                    Ok(Pat::Expr(name, None))
+                }
+            }
+            Some(tok) => Err(format!("unexpected {:?}", tok)),
+            None => Err("unexpected end of rule".into()),
+        }
+    }
+
+    /// Parse a computation: an operand, optionally followed by a binary
+    /// operator and another operand.
+    fn calc(&mut self) -> Result<Calc, String> {
+        let left = try!(self.calc_operand());
+        for &sym in CALC_OPS.iter() {
+            if self.sym(sym) {
+                let right = try!(self.calc_operand());
+                return Ok(Calc::Bin(sym, box left, box right));
+            }
+        }
+        Ok(left)
+    }
+
+    fn calc_operand(&mut self) -> Result<Calc, String> {
+        if self.sym("(") {
+            let calc = try!(self.calc());
+            try!(self.expect(")"));
+            return Ok(calc);
+        }
+        if self.sym("!") {
+            return Ok(Calc::Not(box try!(self.calc_operand())));
+        }
+        match self.next() {
+            Some(Tok::Num(n)) => Ok(Calc::Num(n)),
+            Some(Tok::Ident(name)) => {
+                if !self.sym("(") {
+                    return Ok(Calc::Name(name));
+                }
+                let (func, nargs) = match FUNCTIONS.iter().find(|&&(f, _)| f == name) {
+                    Some(&f) => f,
+                    None => return Err(format!("unknown function {}", name)),
+                };
+                let mut args = vec![try!(self.calc())];
+                while self.sym(",") {
+                    args.push(try!(self.calc()));
+                }
+                try!(self.expect(")"));
+                if args.len() != nargs {
+                    return Err(format!("{} takes {} argument(s)", func, nargs));
+                }
+                Ok(Calc::Call(func, args))
+            }
+            Some(tok) => Err(format!("unexpected {:?}", tok)),
+            None => Err("unexpected end of rule".into()),
+        }
+    }
+}
+
+
+fn tokenize(text: &str) -> Result<Vec<Tok>, String> {
+    let bytes = text.as_bytes();
+    let mut toks = Vec::new();
+    let mut i = 0;
+    'outer: while i < bytes.len() {
+        let c = bytes[i] as char;
+        if c == ' ' || c == '\t' {
+            i += 1;
+            continue;
+        }
+        if c.is_digit(10) || c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < bytes.len() && ((bytes[i] as char).is_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            let word = &text[start..i];
+            if !c.is_digit(10) {
+                toks.push(Tok::Ident(word.into()));
+            } else {
+                let num = if word.starts_with("0x") {
+                    u32::from_str_radix(&word[2..], 16)
+                } else {
+                    word.parse()
+                };
+                match num {
+                    Ok(n)  => toks.push(Tok::Num(n)),
+                    Err(_) => return Err(format!("invalid number {}", word)),
+                }
+            }
+            continue;
+        }
+        if bytes[i] < 0x80 {
+            for &sym in SYMBOLS.iter() {
+                if text[i..].starts_with(sym) {
+                    toks.push(Tok::Sym(sym));
+                    i += sym.len();
+                    continue 'outer;
+                }
+            }
+        }
+        return Err(format!("unexpected character {:?}", &text[i..].chars().next().unwrap()));
+    }
+    Ok(toks)
+}
+
+/// Split an expression into its operation and operands.
+fn split(expr: &Expr) -> Option<(Op, Vec<&Expr>)> {
+    Some(match *expr {
+        Expr::Num(..) | Expr::Var(..) => return None,
+        Expr::Mingle(ref v, ref w) => (Op::Mingle, vec![v, w]),
+        Expr::Select(vtype, ref v, ref w) => (Op::Select(Some(vtype)), vec![v, w]),
+        Expr::And(vtype, ref v) => (Op::And(Some(vtype)), vec![v]),
+        Expr::Or(vtype, ref v) => (Op::Or(Some(vtype)), vec![v]),
+        Expr::Xor(vtype, ref v) => (Op::Xor(Some(vtype)), vec![v]),
+        Expr::RsNot(ref v) => (Op::RsNot, vec![v]),
+        Expr::RsAnd(ref v, ref w) => (Op::RsAnd, vec![v, w]),
+        Expr::RsOr(ref v, ref w) => (Op::RsOr, vec![v, w]),
+        Expr::RsXor(ref v, ref w) => (Op::RsXor, vec![v, w]),
+        Expr::RsRshift(ref v, ref w) => (Op::RsRshift, vec![v, w]),
+        Expr::RsLshift(ref v, ref w) => (Op::RsLshift, vec![v, w]),
+        Expr::RsEqual(ref v, ref w) => (Op::RsEqual, vec![v, w]),
+        Expr::RsNotEqual(ref v, ref w) => (Op::RsNotEqual, vec![v, w]),
+        Expr::RsPlus(ref v, ref w) => (Op::RsPlus, vec![v, w]),
+        Expr::RsMinus(ref v, ref w) => (Op::RsMinus, vec![v, w]),
+        Expr::RsTimes(vtype, ref v, ref w) => (Op::RsTimes(Some(vtype)), vec![v, w]),
+        Expr::RsDivide(vtype, ref v, ref w) => (Op::RsDivide(Some(vtype)), vec![v, w]),
+        Expr::RsModulus(vtype, ref v, ref w) => (Op::RsModulus(Some(vtype)), vec![v, w]),
+    })
+}
+
+/// Build an expression from operation and operands.  Open widths are taken
+/// from the operands (the right one for select), except for the arithmetic,
+/// which is done in 32 bits.
+fn build(op: Op, args: Vec<Expr>) -> Expr {
+    let mut args = args.into_iter().map(Box::new);
+    let mut arg = || args.next().expect("operand missing");
+    match op {
+        Op::Mingle => Expr::Mingle(arg(), arg()),
+        Op::Select(vtype) => {
+            let (v, w) = (arg(), arg());
+            Expr::Select(vtype.unwrap_or(width_type(&w)), v, w)
+        }
+        Op::And(vtype) => {
+            let v = arg();
+            Expr::And(vtype.unwrap_or(width_type(&v)), v)
+        }
+        Op::Or(vtype) => {
+            let v = arg();
+            Expr::Or(vtype.unwrap_or(width_type(&v)), v)
+        }
+        Op::Xor(vtype) => {
+            let v = arg();
+            Expr::Xor(vtype.unwrap_or(width_type(&v)), v)
+        }
+        Op::RsNot => Expr::RsNot(arg()),
+        Op::RsAnd => Expr::RsAnd(arg(), arg()),
+        Op::RsOr => Expr::RsOr(arg(), arg()),
+        Op::RsXor => Expr::RsXor(arg(), arg()),
+        Op::RsRshift => Expr::RsRshift(arg(), arg()),
+        Op::RsLshift => Expr::RsLshift(arg(), arg()),
+        Op::RsEqual => Expr::RsEqual(arg(), arg()),
+        Op::RsNotEqual => Expr::RsNotEqual(arg(), arg()),
+        Op::RsPlus => Expr::RsPlus(arg(), arg()),
+        Op::RsMinus => Expr::RsMinus(arg(), arg()),
+        Op::RsTimes(vtype) => Expr::RsTimes(vtype.unwrap_or(VType::I32), arg(), arg()),
+        Op::RsDivide(vtype) => Expr::RsDivide(vtype.unwrap_or(VType::I32), arg(), arg()),
+        Op::RsModulus(vtype) => Expr::RsModulus(vtype.unwrap_or(VType::I32), arg(), arg()),
+    }
+}
+
+/// The operands of an expression.
+pub fn operands_mut(expr: &mut Expr) -> Vec<&mut Expr> {
+    match *expr {
+        Expr::Num(..) | Expr::Var(..) => vec![],
+        Expr::Select(_, ref mut v, ref mut w) |
+        Expr::Mingle(ref mut v, ref mut w) |
+        Expr::RsAnd(ref mut v, ref mut w) |
+        Expr::RsOr(ref mut v, ref mut w) |
+        Expr::RsXor(ref mut v, ref mut w) |
+        Expr::RsRshift(ref mut v, ref mut w) |
+        Expr::RsLshift(ref mut v, ref mut w) |
+        Expr::RsEqual(ref mut v, ref mut w) |
+        Expr::RsNotEqual(ref mut v, ref mut w) |
+        Expr::RsPlus(ref mut v, ref mut w) |
+        Expr::RsMinus(ref mut v, ref mut w) |
+        Expr::RsTimes(_, ref mut v, ref mut w) |
+        Expr::RsDivide(_, ref mut v, ref mut w) |
+        Expr::RsModulus(_, ref mut v, ref mut w) => vec![&mut **v, &mut **w],
+        Expr::And(_, ref mut v) |
+        Expr::Or(_, ref mut v) |
+        Expr::Xor(_, ref mut v) |
+        Expr::RsNot(ref mut v) => vec![&mut **v],
+    }
+}
+
+/// The width of the value of an expression: 16 if it is known to fit into 16
+/// bits, else 32.
+pub fn width(expr: &Expr) -> u8 {
+    match *expr {
+        Expr::Num(_, v) => if v <= 0xFFFF { 16 } else { 32 },
+        Expr::Var(Var::I16(_)) | Expr::Var(Var::A16(..)) => 16,
+        Expr::Select(VType::I16, ..) | Expr::And(VType::I16, _) |
+        Expr::Or(VType::I16, _) | Expr::Xor(VType::I16, _) => 16,
+        Expr::RsAnd(ref v, ref w) => cmp::min(width(v), width(w)),
+        Expr::RsRshift(ref v, _) => width(v),
+        Expr::RsEqual(..) | Expr::RsNotEqual(..) => 16,
+        Expr::RsTimes(VType::I16, ..) | Expr::RsDivide(VType::I16, ..) |
+        Expr::RsModulus(VType::I16, ..) => 16,
+        _ => 32,
+    }
+}
+
+fn width_type(expr: &Expr) -> VType {
+    if width(expr) == 16 { VType::I16 } else { VType::I32 }
+}
+
+/// Evaluate an expression without variables like the interpreter does.  Returns
+/// None if there are variables, or if the evaluation fails.
+pub fn eval_const(expr: &Expr) -> Option<u32> {
+    let (op, args) = match split(expr) {
+        Some(split) => split,
+        None => return match *expr {
+            Expr::Num(_, v) => Some(v),
+            _ => None,
+        },
+    };
+    let mut vals = Vec::with_capacity(2);
+    for arg in args {
+        match eval_const(arg) {
+            Some(v) => vals.push(v),
+            None    => return None,
+        }
+    }
+    let (v, w) = (vals[0], *vals.get(1).unwrap_or(&0));
+    let fits = |x: u32| x <= 0xFFFF;
+    match op {
+        Op::Mingle => if fits(v) && fits(w) { Some(mingle(v, w)) } else { None },
+        Op::Select(Some(VType::I16)) => if fits(w) { Some(select(v, w)) } else { None },
+        Op::Select(_) => Some(select(v, w)),
+        Op::And(Some(VType::I16)) => if fits(v) { Some(and_16(v)) } else { None },
+        Op::And(_) => Some(and_32(v)),
+        Op::Or(Some(VType::I16)) => if fits(v) { Some(or_16(v)) } else { None },
+        Op::Or(_) => Some(or_32(v)),
+        Op::Xor(Some(VType::I16)) => if fits(v) { Some(xor_16(v)) } else { None },
+        Op::Xor(_) => Some(xor_32(v)),
+        Op::RsNot => Some(!v),
+        Op::RsAnd => Some(v & w),
+        Op::RsOr => Some(v | w),
+        Op::RsXor => Some(v ^ w),
+        Op::RsRshift => v.checked_shr(w),
+        Op::RsLshift => v.checked_shl(w),
+        Op::RsEqual => Some((v == w) as u32),
+        Op::RsNotEqual => Some((v != w) as u32),
+        Op::RsPlus => v.checked_add(w),
+        Op::RsMinus => v.checked_sub(w),
+        Op::RsTimes(Some(VType::I16)) => times_16(v, w, 0).ok(),
+        Op::RsTimes(_) => times_32(v, w, 0).ok(),
+        Op::RsDivide(Some(VType::I16)) => divide_16(v, w, 0).ok(),
+        Op::RsDivide(_) => divide_32(v, w, 0).ok(),
+        Op::RsModulus(Some(VType::I16)) => modulus_16(v, w, 0).ok(),
+        Op::RsModulus(_) => modulus_32(v, w, 0).ok(),
+    }
+}
+
+/// A random number for checking rules, with a preference for interesting ones.
+fn random_value<R: Rng>(rng: &mut R, only16: bool) -> u32 {
+    let v = match rng.gen_range(0, 6) {
+        0 => rng.gen_range(0, 4),
+        1 => 0xFFFFFFFF,
+        2 => [0x55555555, 0xAAAAAAAA][rng.gen_range(0, 2)],
+        3 => {
+            // a contiguous mask
+            let ones = rng.gen_range(1, 33);
+            let shift = rng.gen_range(0, 33 - ones);
+            (((1u64 << ones) - 1) << shift) as u32
+        }
+        _ => rng.gen(),
+    };
+    if only16 { v & 0xFFFF } else { v }
+}
+
+#[cfg(test)]
+mod tests {
+    use ast::{ Expr, Var, VType };
+    use stdops::mingle;
+    use super::{ Rules, IDIOMS, eval_const, operands_mut };
+
+    fn var16(n: usize) -> Expr { Expr::Var(Var::I16(n)) }
+    fn var32(n: usize) -> Expr { Expr::Var(Var::I32(n)) }
+
+    fn num(v: u32) -> Expr {
+        Expr::Num(if v <= 0xFFFF { VType::I16 } else { VType::I32 }, v)
+    }
+
+    /// Replace all variables in an expression by a number.
+    fn subst(expr: &mut Expr, v: u32) {
+        if let Expr::Var(_) = *expr {
+            *expr = num(v);
+            return;
+        }
+        for arg in operands_mut(expr) {
+            subst(arg, v);
+        }
+    }
+
+    #[test]
+    fn builtin_rules() {
+        let rules = match Rules::parse(IDIOMS) {
+            Ok(rules) => rules,
+            Err(msg)  => panic!("{}", msg),
+        };
+        for rule in &rules.rules {
+            if let Err(msg) = rule.check(1000) {
+                panic!("rule {} is wrong: {}", rule.name, msg);
+            }
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Rules::parse("a: x -> ").is_err());
+        assert!(Rules::parse("a: x:8 -> x").is_err());
+        assert!(Rules::parse("a: x -> y").is_err());
+    }
+
+    #[test]
+    fn widths() {
+        let rules = Rules::parse("a: x:16 + x -> x * #2\n\
+                                  b: x:32 + x -> x << #1").unwrap();
+        let mut expr = Expr::RsPlus(box var16(0), box var16(0));
+        assert_eq!(rules.rewrite(&mut expr), 1);
+        assert_eq!(expr, Expr::RsTimes(VType::I32, box var16(0), box Expr::Num(VType::I32, 2)));
+        let mut expr = Expr::RsPlus(box var32(0), box var32(0));
+        assert_eq!(rules.rewrite(&mut expr), 1);
+        assert_eq!(expr, Expr::RsLshift(box var32(0), box Expr::Num(VType::I32, 1)));
+        // all occurrences must be the same expression
+        let mut expr = Expr::RsPlus(box var16(0), box var16(1));
+        assert_eq!(rules.rewrite(&mut expr), 0);
+    }
+
+    #[test]
+    fn const_mingle() {
+        let rules = Rules::builtin();
+        let (a, b) = (0x1234, 0xF00F);
+        let ops: [fn(Box<Expr>, Box<Expr>) -> Expr; 3] = [Expr::RsAnd, Expr::RsOr, Expr::RsXor];
+        for op in &ops {
+            let half = |mask, c| op(box Expr::Select(VType::I32, box var32(0), box num(mask)),
+                                    box num(c));
+            let orig = Expr::Mingle(box half(0xAAAAAAAA, a), box half(0x55555555, b));
+            let mut new = orig.clone();
+            assert!(rules.rewrite(&mut new) > 0);
+            assert_eq!(new, op(box var32(0), box Expr::Num(VType::I32, mingle(a, b))));
+            for &v in &[0, 1, 0xFFFF, 0x10000, 0x12345678, 0xDEADBEEF, 0xFFFFFFFF] {
+                let (mut orig, mut new) = (orig.clone(), new.clone());
+                subst(&mut orig, v);
+                subst(&mut new, v);
+                assert!(eval_const(&orig).is_some());
+                assert_eq!(eval_const(&orig), eval_const(&new));
+            }
+        }
+    }
+}
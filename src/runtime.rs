@@ -0,0 +1,273 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// Manages the precompiled runtime library of compiled programs.
+///
+/// The support code needed by generated programs (error handling in err.rs, the
+/// operators, I/O and NEXT stack handling in stdops.rs, and the command line of
+/// compiled programs in args.rs) is compiled once into a library crate called
+/// `rick_runtime`, which generated programs link against with `rustc --extern`.
+/// The library is kept in a cache directory, by default `$XDG_CACHE_HOME/rick` or
+/// `~/.cache/rick` (override with `RICK_RUNTIME_DIR`), which also holds the cache
+/// of compiled executables (see cache.rs).
+///
+/// Since rlibs can only be used by the rustc that built them, and only for the
+/// target they were built for, the cache entry is keyed on the runtime sources,
+/// the output of `rustc -vV` and the target triple.
+///
+/// Generated code only uses the runtime through the module `vN`, where N is
+/// `API_VERSION`.  The version is bumped whenever one of the items listed there
+/// changes, so that sources generated by a newer version of Rick fail to build
+/// with a missing module, instead of a confusing error somewhere in the middle.
+/// The modules of older versions are kept as shims that re-export what the
+/// current one provides, so that sources generated by older versions of Rick
+/// keep compiling (the items that were removed or changed since were not used
+/// by the code that those versions generated).
+
+use std::env;
+use std::fs::{ File, create_dir_all, remove_dir_all, rename };
+use std::io::{ Write, stderr };
+use std::path::PathBuf;
+use std::process::Command;
+
+use rand;
+
+use err::{ Res, IE666, IE888 };
+
+/// The current version of the runtime API used by generated code.
+///
+/// 1. The first version.
+/// 2. The command line of compiled programs (args), and I/O with any reader or
+///    writer instead of stdin/stdout (`read_byte` is gone).
+/// 3. Errors carry the statement and NEXT stack and print themselves on exit
+///    (`RtError::to_string` is gone).
+/// 4. `Monitor::skip` for programs that start from a snapshot.
+/// 5. Native multiplication, division and modulus (`times_16` and so on).
+pub const API_VERSION: u32 = 5;
+
+pub const CRATE_NAME: &'static str = "rick_runtime";
+
+pub const ERR_SRC:    &'static str = include_str!("err.rs");
+pub const STDOPS_SRC: &'static str = include_str!("stdops.rs");
+pub const ARGS_SRC:   &'static str = include_str!("args.rs");
+
+/// The crate root of the runtime library.  The `vN` module lists everything
+/// that generated code can use, so that it is easy to see when a change
+/// affects generated code and `API_VERSION` needs to be bumped; the older
+/// versions follow it.
+pub fn lib_src() -> String {
+    format!("\
+//! The runtime library for INTERCAL programs compiled by rick.
+
+#![allow(dead_code)]
+
+pub mod err;
+pub mod stdops;
+pub mod args;
+
+/// The API used by generated code.
+pub mod v{0} {{
+    pub mod err {{
+        pub use ::err::{{ EXIT_COMPILE, EXIT_RUNTIME, Res, ErrDesc, RtError,
+            json_str, IE000, IE017, IE079, IE099, IE123, IE129, IE139, IE182,
+            IE197, IE200, IE240, IE241, IE252, IE275, IE436, IE444, IE533,
+            IE555, IE562, IE579, IE621, IE632, IE633, IE666, IE774, IE777,
+            IE888, IE980, IE990, IE993, IE994, IE998 }};
+    }}
+
+    pub mod stdops {{
+        pub use ::stdops::{{ Array, Bind, get_random_seed, check_chance,
+            pop_jumps, to_roman, from_english, write_number, write_number_as,
+            write_bytes, read_number, read_number_from, read_byte_from,
+            check_ovf, mingle, select, and_16, and_32, or_16, or_32, xor_16,
+            xor_32, times_16, times_32, divide_16, divide_32, modulus_16,
+            modulus_32, LikeU16 }};
+    }}
+
+    pub mod args {{
+        pub use ::args::{{ Input, Output, Monitor, Args }};
+    }}
+}}
+
+/// Older versions of the API, for sources generated by older versions of rick.
+pub mod v1 {{ pub use ::v{0}::{{ err, stdops }}; }}
+pub mod v2 {{ pub use ::v{0}::{{ err, stdops, args }}; }}
+pub mod v3 {{ pub use ::v{0}::{{ err, stdops, args }}; }}
+pub mod v4 {{ pub use ::v{0}::{{ err, stdops, args }}; }}
+", API_VERSION)
+}
+
+/// Hash some bytes with 64-bit FNV-1a.  The result needs to be stable across
+/// rick builds, which is not guaranteed for the std hashers.
+pub fn fnv1a(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// The initial value for `fnv1a`.
+pub const FNV_INIT: u64 = 0xcbf29ce484222325;
+
+/// Determine the directory for cached runtime libraries (and other things).
+pub fn cache_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("RICK_RUNTIME_DIR") {
+        return PathBuf::from(dir);
+    }
+    if let Some(dir) = env::var_os("XDG_CACHE_HOME") {
+        return PathBuf::from(dir).join("rick");
+    }
+    if let Some(dir) = env::var_os("HOME") {
+        return PathBuf::from(dir).join(".cache").join("rick");
+    }
+    env::temp_dir().join("rick")
+}
+
+/// Get the verbose version info of the given rustc.
+pub fn rustc_version(rustc: &str) -> Res<Vec<u8>> {
+    match Command::new(rustc).arg("-vV").output() {
+        Ok(ref out) if out.status.success() => Ok(out.stdout.clone()),
+        _ => IE666.err(),
+    }
+}
+
+/// Write the sources of the runtime library into a directory.
+pub fn write_sources(dir: &PathBuf) -> Res<()> {
+    for &(name, src) in &[("lib.rs", &lib_src()[..]), ("err.rs", ERR_SRC),
+                          ("stdops.rs", STDOPS_SRC), ("args.rs", ARGS_SRC)] {
+        let mut f = match File::create(dir.join(name)) {
+            Ok(f)  => f,
+            Err(_) => return IE888.err(),
+        };
+        try!(f.write_all(src.as_bytes()));
+    }
+    Ok(())
+}
+
+/// Return the path of the runtime library for the given rustc and target (None
+/// for the host), building it first if it is not yet in the cache.
+pub fn library(rustc: &str, target: Option<&str>) -> Res<PathBuf> {
+    let version = try!(rustc_version(rustc));
+    let mut hash = FNV_INIT;
+    for part in &[lib_src().as_bytes(), ERR_SRC.as_bytes(), STDOPS_SRC.as_bytes(),
+                  ARGS_SRC.as_bytes(), &version[..], target.unwrap_or("").as_bytes()] {
+        hash = fnv1a(hash, part);
+    }
+    let dir = cache_dir().join(format!("runtime-v{}-{:016x}", API_VERSION, hash));
+    let lib = dir.join(format!("lib{}.rlib", CRATE_NAME));
+    if lib.is_file() {
+        return Ok(lib);
+    }
+    // build in a private directory and move the result into place, so that
+    // several rick processes can do this at the same time
+    let build = dir.join(format!("build-{:08x}", rand::random::<u32>()));
+    if let Err(_) = create_dir_all(&build) {
+        return IE888.err();
+    }
+    try!(write_sources(&build));
+    let mut cmd = Command::new(rustc);
+    cmd.arg("--crate-type").arg("rlib")
+        .arg("--crate-name").arg(CRATE_NAME)
+        .arg("-O").arg("--cap-lints").arg("allow");
+    if let Some(target) = target {
+        cmd.arg("--target").arg(target);
+    }
+    let out = cmd.arg("-o").arg(build.join("lib.rlib"))
+        .arg(build.join("lib.rs"))
+        .output();
+    let res = match out {
+        Ok(ref out) if out.status.success() => {
+            match rename(build.join("lib.rlib"), &lib) {
+                Ok(_)  => Ok(lib),
+                Err(_) => IE888.err(),
+            }
+        }
+        Ok(out) => {
+            let _ = stderr().write_all(&out.stderr);
+            IE666.err()
+        }
+        Err(_) => IE666.err(),
+    };
+    let _ = remove_dir_all(&build);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{ File, create_dir_all, remove_dir_all };
+    use std::io::Write;
+    use std::process::Command;
+
+    use rand;
+
+    use super::{ API_VERSION, CRATE_NAME, write_sources };
+
+    /// Sources generated by older versions use the modules like this.
+    const OLD_USES: &'static str = "\
+extern crate rick_runtime;
+
+mod v1 {
+    use rick_runtime::v1::{ err, stdops };
+    use rick_runtime::v1::stdops::*;
+    pub fn run() -> err::Res<()> {
+        let mut out = ::std::io::stdout();
+        try!(write_number(&mut out, 4, 1));
+        try!(stdops::check_ovf(mingle(1, 2), 2));
+        err::IE633.err()
+    }
+}
+
+mod v4 {
+    use rick_runtime::v4::{ err, stdops, args };
+    use rick_runtime::v4::stdops::*;
+    pub fn run() -> err::Res<()> {
+        let mut args = try!(args::Args::from_env());
+        args.monitor.skip(1);
+        try!(write_number(&mut args.output, select(3, 1), 1));
+        try!(stdops::check_ovf(mingle(1, 2), 2));
+        err::IE633.err()
+    }
+}
+
+fn main() {
+    for res in vec![v1::run(), v4::run()] {
+        if let Err(err) = res {
+            print!(\"{}\", err.to_string());
+        }
+    }
+}
+";
+
+    #[test]
+    fn old_versions() {
+        let rustc = env::var("RUSTC").unwrap_or("rustc".into());
+        let dir = env::temp_dir().join(format!("rick-test-{:08x}", rand::random::<u32>()));
+        create_dir_all(&dir).unwrap();
+        write_sources(&dir).unwrap();
+        let status = Command::new(&rustc)
+            .arg("--crate-type").arg("rlib").arg("--crate-name").arg(CRATE_NAME)
+            .arg("-o").arg(dir.join("lib.rlib")).arg(dir.join("lib.rs"))
+            .status().unwrap();
+        assert!(status.success());
+        File::create(dir.join("old.rs")).unwrap().write_all(OLD_USES.as_bytes()).unwrap();
+        let out = Command::new(&rustc)
+            .arg("--extern").arg(format!("{}={}", CRATE_NAME, dir.join("lib.rlib").display()))
+            .arg("-o").arg(dir.join("old")).arg(dir.join("old.rs"))
+            .output().unwrap();
+        remove_dir_all(&dir).unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert_eq!(API_VERSION, 5, "add a shim for the previous version");
+    }
+}
@@ -29,15 +29,16 @@
 /// * Roman numeral and English spelled-out number conversion
 /// * Basic read/write of numbers and bytes
 /// * all the INTERCAL operators (mingle, select, unary and, unary or, unary xor)
+/// * arithmetic with the overflow semantics of the syslib
 
 use std::fmt::{ Debug, Display, Error, Formatter };
 use std::fs::File;
 use std::io::{ BufRead, Read, Write, stdin };
 use std::{ u16, u32 };
 
-use err::{ Res, IE240, IE241, IE252, IE436, IE533, IE562, IE579, IE621, IE632 };
+use err::{ Res, IE000, IE240, IE241, IE252, IE436, IE533, IE562, IE579, IE621, IE632 };
 
-#[derive(Clone, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Array<T> {
     pub dims: Vec<usize>,
     pub elems: Vec<T>,
@@ -55,7 +56,7 @@ impl<T: Clone + Default> Array<T> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Bind<T> {
     pub val: T,
     pub stack: Vec<T>,
@@ -106,7 +107,7 @@ impl<T: LikeU16 + Default> Bind<Array<T>> {
 
     #[allow(dead_code)]  // only used in compiled code
     pub fn set(&mut self, sub: usize, val: T, line: usize) -> Res<()> {
-        if self.val.dims.len() != 1 || sub > self.val.dims[0] {
+        if self.val.dims.len() != 1 || sub == 0 || sub > self.val.dims[0] {
             return IE241.err_with(None, line);
         }
         if self.rw {
@@ -124,7 +125,7 @@ impl<T: LikeU16 + Default> Bind<Array<T>> {
 
     #[allow(dead_code)]  // only used in compiled code
     pub fn set_unchecked(&mut self, sub: usize, val: T, line: usize) -> Res<()> {
-        if self.val.dims.len() != 1 || sub > self.val.dims[0] {
+        if self.val.dims.len() != 1 || sub == 0 || sub > self.val.dims[0] {
             return IE241.err_with(None, line);
         }
         self.val.elems[sub - 1] = val;
@@ -138,7 +139,7 @@ impl<T: LikeU16 + Default> Bind<Array<T>> {
 
     #[allow(dead_code)]  // only used in compiled code
     pub fn get(&self, sub: usize, line: usize) -> Res<T>  {
-        if self.val.dims.len() != 1 || sub > self.val.dims[0] {
+        if self.val.dims.len() != 1 || sub == 0 || sub > self.val.dims[0] {
             return IE241.err_with(None, line);
         }
         Ok(self.val.elems[sub - 1])
@@ -152,7 +153,7 @@ impl<T: LikeU16 + Default> Bind<Array<T>> {
         let mut ix = 0;
         let mut prev_dim = 1;
         for (sub, dim) in subs.iter().zip(&self.val.dims) {
-            if *sub > *dim {
+            if *sub == 0 || *sub > *dim {
                 return IE241.err_with(None, line);
             }
             ix += (sub - 1) * prev_dim;
@@ -190,12 +191,18 @@ impl<T: LikeU16 + Default> Bind<Array<T>> {
     }
 
     pub fn writein(&mut self, state: &mut u8, line: usize) -> Res<()> {
+        let stdin = stdin();
+        let mut slock = stdin.lock();
+        self.writein_from(&mut slock, state, line)
+    }
+
+    pub fn writein_from(&mut self, r: &mut Read, state: &mut u8, line: usize) -> Res<()> {
         if self.val.dims.len() != 1 {
             // only dimension-1 arrays can be input
             return IE241.err_with(None, line);
         }
         for place in &mut self.val.elems {
-            let byte = read_byte();
+            let byte = read_byte_from(r);
             let c = if byte == 256 {
                 *state = 0;
                 256
@@ -377,7 +384,17 @@ pub fn from_english(v: &str, line: usize) -> Res<u32> {
 
 /// Output a number in Roman format.
 pub fn write_number(w: &mut Write, val: u32, line: usize) -> Res<()> {
-    if let Err(_) = write!(w, "{}", to_roman(val)) {
+    write_number_as(w, val, false, line)
+}
+
+/// Output a number in Roman format, or in decimal for wimps.
+pub fn write_number_as(w: &mut Write, val: u32, wimp: bool, line: usize) -> Res<()> {
+    let res = if wimp {
+        write!(w, "{}\n", val)
+    } else {
+        write!(w, "{}", to_roman(val))
+    };
+    if let Err(_) = res {
         return IE252.err_with(None, line);
     }
     Ok(())
@@ -395,19 +412,30 @@ pub fn write_bytes(w: &mut Write, val: Vec<u8>, line: usize) -> Res<()> {
 pub fn read_number(line: usize) -> Res<u32> {
     let stdin = stdin();
     let mut slock = stdin.lock();
+    read_number_from(&mut slock, false, line)
+}
+
+/// Read a number in spelled out English format, or in decimal for wimps.
+pub fn read_number_from(r: &mut BufRead, wimp: bool, line: usize) -> Res<u32> {
     let mut buf = String::new();
-    match slock.read_line(&mut buf) {
-        Ok(n) if n > 1 => from_english(&buf, line),
+    match r.read_line(&mut buf) {
+        Ok(n) if n > 1 => if wimp {
+            match buf.trim().parse::<u64>() {
+                Ok(v) if v > (u32::MAX as u64) => IE533.err_with(None, line),
+                Ok(v)  => Ok(v as u32),
+                Err(_) => IE579.err_with(Some(buf.trim()), line),
+            }
+        } else {
+            from_english(&buf, line)
+        },
         _              => IE562.err_with(None, line)
     }
 }
 
-/// Read a byte from stdin.
-pub fn read_byte() -> u16 {
-    let stdin = stdin();
-    let mut slock = stdin.lock();
+/// Read a byte from the given input.
+pub fn read_byte_from(r: &mut Read) -> u16 {
     let mut buf = [0u8; 1];
-    match slock.read(&mut buf) {
+    match r.read(&mut buf) {
         Ok(1) => buf[0] as u16,
         _     => 256      // EOF is defined to be 256
     }
@@ -500,6 +528,50 @@ pub fn xor_32(v: u32) -> u32 {
     w ^ v
 }
 
+/// Check that the result of an arithmetic operation fits, else give the error
+/// of the syslib (which is the text of its line labeled (1999)).
+fn check_precision(v: u64, max: u32, line: usize) -> Res<u32> {
+    if v > max as u64 {
+        IE000.err_with(Some("DOUBLE OR SINGLE PRECISION OVERFLOW"), line)
+    } else {
+        Ok(v as u32)
+    }
+}
+
+/// Implements multiplication like the syslib's (1030).
+pub fn times_16(v: u32, w: u32, line: usize) -> Res<u32> {
+    check_precision(v as u64 * w as u64, u16::MAX as u32, line)
+}
+
+/// Implements multiplication like the syslib's (1540).
+pub fn times_32(v: u32, w: u32, line: usize) -> Res<u32> {
+    check_precision(v as u64 * w as u64, u32::MAX, line)
+}
+
+/// Implements division like the syslib's (1040) and (1050): division by zero
+/// gives zero.
+pub fn divide_16(v: u32, w: u32, line: usize) -> Res<u32> {
+    let q = if w == 0 { 0 } else { v / w };
+    check_precision(q as u64, u16::MAX as u32, line)
+}
+
+/// Implements division like the syslib's (1550).
+pub fn divide_32(v: u32, w: u32, _line: usize) -> Res<u32> {
+    Ok(if w == 0 { 0 } else { v / w })
+}
+
+/// Implements the remainder of `divide_16`, so that the dividend is left for a
+/// divisor of zero.
+pub fn modulus_16(v: u32, w: u32, line: usize) -> Res<u32> {
+    let r = if w == 0 { v } else { v % w };
+    check_precision(r as u64, u16::MAX as u32, line)
+}
+
+/// Implements the remainder of `divide_32`.
+pub fn modulus_32(v: u32, w: u32, _line: usize) -> Res<u32> {
+    Ok(if w == 0 { v } else { v % w })
+}
+
 pub trait LikeU16: Copy {
     fn from_u16(u16) -> Self;
     fn to_u16(self) -> u16;
@@ -514,3 +586,26 @@ impl LikeU16 for u32 {
     fn from_u16(x: u16) -> u32 { x as u32 }
     fn to_u16(self) -> u16 { self as u16 }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ times_16, times_32, divide_16, divide_32, modulus_16, modulus_32 };
+
+    const OVERFLOW: &'static str =
+        "err::IE000.err_with(Some(\"DOUBLE OR SINGLE PRECISION OVERFLOW\"), 5)";
+
+    #[test]
+    fn arithmetic_like_syslib() {
+        assert_eq!(times_16(256, 255, 5).ok(), Some(65280));
+        assert_eq!(times_16(256, 256, 5).unwrap_err().to_code(), OVERFLOW);
+        assert_eq!(times_32(65536, 65535, 5).ok(), Some(0xFFFF0000));
+        assert_eq!(times_32(65536, 65536, 5).unwrap_err().to_code(), OVERFLOW);
+        // dividing by zero gives zero, and leaves the dividend as the remainder
+        assert_eq!(divide_16(7, 0, 5).ok(), Some(0));
+        assert_eq!(modulus_16(7, 0, 5).ok(), Some(7));
+        assert_eq!(divide_32(70000, 0, 5).ok(), Some(0));
+        assert_eq!(modulus_32(70000, 0, 5).ok(), Some(70000));
+        assert_eq!(divide_16(7, 2, 5).ok(), Some(3));
+        assert_eq!(modulus_16(7, 2, 5).ok(), Some(1));
+    }
+}
@@ -0,0 +1,201 @@
+// -------------------------------------------------------------------------------------------------
+// Rick, a Rust intercal compiler.  Save your souls!
+//
+// Copyright (c) 2015 Georg Brandl
+//
+// This program is free software; you can redistribute it and/or modify it under the terms of the
+// GNU General Public License as published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with this program;
+// if not, write to the Free Software Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+// -------------------------------------------------------------------------------------------------
+
+/// Width inference: upper bounds on the number of significant bits of the
+/// values of variables and expressions.
+///
+/// Whether a value fits into 16 bits is only known when it is used, so the
+/// interpreter and the generated code check every assignment to a spot or tail
+/// (E275) and every operand of a mingle (E533).  The bounds found here show
+/// that most of these checks can't fail: a spot assigned from another spot, a
+/// mingle of two selects with 16-bit masks, or a twospot that only ever gets
+/// 16-bit values.
+///
+/// The bound of a variable covers everything assigned to it: expressions,
+/// WRITE IN and the values in the optimizer's snapshot.  It is found by going
+/// over all statements until no bound grows anymore, which can't take long,
+/// since bounds never exceed 32 bits.  RETRIEVE only brings back values that
+/// were assigned before, and needs no special treatment.
+///
+/// Lower bounds go the other way: they find statements that fail whenever they
+/// are executed, which the linter reports.
+
+use std::cmp::{ max, min };
+use std::iter::once;
+
+use ast::{ Program, StmtBody, Expr, Var, VType };
+use rules::eval_const;
+use stdops::{ Array, Bind, mingle };
+
+
+/// The bounds of all variables of a program.
+pub struct Widths {
+    /// By type: spot, twospot, tail, hybrid.
+    vars: (Vec<u32>, Vec<u32>, Vec<u32>, Vec<u32>),
+}
+
+impl Widths {
+    pub fn new(program: &Program) -> Widths {
+        let info = &program.var_info;
+        let mut widths = Widths { vars: (vec![0; info.0.len()], vec![0; info.1.len()],
+                                         vec![0; info.2.len()], vec![0; info.3.len()]) };
+        if let Some(ref start) = program.start {
+            widths.vars.0 = start.spot.iter().map(bind_width).collect();
+            widths.vars.1 = start.twospot.iter().map(bind_width).collect();
+            widths.vars.2 = start.tail.iter().map(array_width).collect();
+            widths.vars.3 = start.hybrid.iter().map(array_width).collect();
+        }
+        loop {
+            let mut changed = false;
+            for stmt in &program.stmts {
+                match stmt.body {
+                    StmtBody::Calc(ref var, ref expr) => {
+                        let w = widths.expr(expr);
+                        changed |= widths.widen(var, w);
+                    }
+                    StmtBody::WriteIn(ref vars) => for var in vars {
+                        changed |= widths.widen(var, 32);
+                    },
+                    _ => { }
+                }
+            }
+            if !changed {
+                return widths;
+            }
+        }
+    }
+
+    /// Raise the bound of a variable (up to the width of its type).  Returns
+    /// true if it changed.
+    fn widen(&mut self, var: &Var, w: u32) -> bool {
+        let w = min(w, type_width(var.get_vtype()));
+        let bound = match *var {
+            Var::I16(n) => &mut self.vars.0[n],
+            Var::I32(n) => &mut self.vars.1[n],
+            Var::A16(n, _) => &mut self.vars.2[n],
+            Var::A32(n, _) => &mut self.vars.3[n],
+        };
+        if w > *bound {
+            *bound = w;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn var(&self, var: &Var) -> u32 {
+        match *var {
+            Var::I16(n) => self.vars.0[n],
+            Var::I32(n) => self.vars.1[n],
+            Var::A16(n, _) => self.vars.2[n],
+            Var::A32(n, _) => self.vars.3[n],
+        }
+    }
+
+    pub fn expr(&self, expr: &Expr) -> u32 {
+        bound(expr, &|var: &Var| self.var(var))
+    }
+
+    /// True if the expression's value always fits into 16 bits.
+    pub fn fits16(&self, expr: &Expr) -> bool {
+        self.expr(expr) <= 16
+    }
+
+    /// True if assigning the expression to the variable can't overflow.
+    pub fn fits(&self, var: &Var, expr: &Expr) -> bool {
+        var.get_vtype() == VType::I32 || self.fits16(expr)
+    }
+}
+
+fn type_width(vtype: VType) -> u32 {
+    if vtype == VType::I16 { 16 } else { 32 }
+}
+
+fn bits(v: u32) -> u32 {
+    32 - v.leading_zeros()
+}
+
+/// The widest value of a variable in a snapshot, including its stash.
+fn bind_width<T: Copy + Into<u32>>(bind: &Bind<T>) -> u32 {
+    once(&bind.val).chain(&bind.stack).map(|&v| bits(v.into())).max().unwrap_or(0)
+}
+
+fn array_width<T: Copy + Into<u32>>(bind: &Bind<Array<T>>) -> u32 {
+    once(&bind.val).chain(&bind.stack).flat_map(|arr| arr.elems.iter())
+                   .map(|&v| bits(v.into())).max().unwrap_or(0)
+}
+
+/// Upper bound on the number of significant bits of an expression's value,
+/// given bounds for the variables.  Operations that fail at runtime if an
+/// operand doesn't fit (like a mingle) assume that it does.
+pub fn bound<F: Fn(&Var) -> u32>(expr: &Expr, var: &F) -> u32 {
+    let width = |expr: &Expr| bound(expr, var);
+    match *expr {
+        Expr::Num(_, v) => bits(v),
+        Expr::Var(ref v) => var(v),
+        Expr::Mingle(ref vx, ref wx) => {
+            min(32, max(2 * width(vx), (2 * width(wx)).saturating_sub(1)))
+        }
+        Expr::Select(vtype, _, ref wx) => match **wx {
+            Expr::Num(_, m) => m.count_ones(),
+            _ => min(type_width(vtype), width(wx)),
+        },
+        Expr::And(vtype, _) | Expr::Or(vtype, _) | Expr::Xor(vtype, _) => type_width(vtype),
+        Expr::RsNot(_) => 32,
+        Expr::RsAnd(ref vx, ref wx) => min(width(vx), width(wx)),
+        Expr::RsOr(ref vx, ref wx) |
+        Expr::RsXor(ref vx, ref wx) => max(width(vx), width(wx)),
+        Expr::RsRshift(ref vx, ref wx) => match **wx {
+            Expr::Num(_, k) => width(vx).saturating_sub(k),
+            _ => width(vx),
+        },
+        Expr::RsLshift(ref vx, ref wx) => match **wx {
+            Expr::Num(..) if width(vx) == 0 => 0,
+            Expr::Num(_, k) => min(32, width(vx).saturating_add(k)),
+            _ => 32,
+        },
+        Expr::RsEqual(..) | Expr::RsNotEqual(..) => 1,
+        Expr::RsPlus(ref vx, ref wx) => min(32, max(width(vx), width(wx)) + 1),
+        // the optimizer never creates a subtraction that can underflow
+        Expr::RsMinus(ref vx, _) => width(vx),
+        Expr::RsTimes(vtype, ref vx, ref wx) => min(type_width(vtype), width(vx) + width(wx)),
+        Expr::RsDivide(vtype, ref vx, _) => min(type_width(vtype), width(vx)),
+        Expr::RsModulus(vtype, ref vx, ref wx) => match **wx {
+            Expr::Num(_, c) if c > 0 => min(width(vx), width(wx)),
+            // the remainder of a division by zero is the dividend
+            _ => min(type_width(vtype), width(vx)),
+        },
+    }
+}
+
+/// Lower bound on an expression's value.  Variables can always be zero.
+pub fn minimum(expr: &Expr) -> u32 {
+    if let Some(v) = eval_const(expr) {
+        return v;
+    }
+    match *expr {
+        Expr::Mingle(ref vx, ref wx) => {
+            // each operand's bits are there, no matter what the other one is
+            let v = min(minimum(vx), 0xFFFF);
+            let w = min(minimum(wx), 0xFFFF);
+            max(mingle(v, 0), mingle(0, w))
+        }
+        Expr::RsOr(ref vx, ref wx) => max(minimum(vx), minimum(wx)),
+        Expr::RsPlus(ref vx, ref wx) => minimum(vx).checked_add(minimum(wx)).unwrap_or(0),
+        _ => 0,
+    }
+}
@@ -16,13 +16,23 @@
 # -------------------------------------------------------------------------------------------------
 
 import os
+import re
 import sys
 import time
 import difflib
+import tempfile
 from os import path
-from subprocess import Popen, PIPE, STDOUT
+from subprocess import Popen, PIPE, check_output
 
 already_compiled = set()
+RICK = path.join('target', 'debug', 'rick')
+ERROR_RE = re.compile(br'ICL\d\d\dI\t')
+# output of the analyses, checked if the test has a file with the extension
+GRAPHS = [('-g', '.dot', 'control-flow graph'), ('-k', '.calls', 'call graph')]
+# the program written back as INTERCAL (-s), which is run again for every test
+EMITTED = [('-Rbs', 'emitted source'), ('-Rbso', 'emitted optimized source')]
+# seeds of the random programs that are crosschecked (when compiling)
+FUZZ_SEEDS = [1, 2, 3]
 
 
 def run_test(testname, testcode, compiled):
@@ -33,40 +43,120 @@ def run_test(testname, testcode, compiled):
     with open(testname + '.chk', 'rb') as stdoutfile:
         stdout = stdoutfile.read()
 
-    def check(proc, remove_cargo):
-        real_stdout, _ = proc.communicate(stdin)
-        # remove cargo's "Running" line
-        if remove_cargo:
-            errindex = real_stdout.find('An unknown error occurred')
-            if errindex == -1:
-                errindex = real_stdout.find('error: Process didn\'t exit successfully')
-            if errindex > -1:
-                real_stdout = real_stdout[:errindex]
-        if real_stdout != stdout:
-            print('*** ERROR: standard output does not match check file')
-            print(''.join(difflib.unified_diff(stdout.splitlines(True),
-                                               real_stdout.splitlines(True))))
+    # programs that end with an error exit with a status of 20-29, and print
+    # the error message (the end of the check file) to stderr
+    expect_error = b'CORRECT SOURCE AND RESUBNIT' in stdout
+    stderr = b''
+    if expect_error:
+        start = [m.start() for m in ERROR_RE.finditer(stdout)][-1]
+        stdout, stderr = stdout[:start], stdout[start:]
+    # warnings come from rick when it reads the program, not from the program
+    warnings = b''
+    if path.isfile(testname + '.wrn'):
+        with open(testname + '.wrn', 'rb') as warnfile:
+            warnings = warnfile.read()
+
+    def check(proc, expected_stderr, only_code=False):
+        real_stdout, real_stderr = proc.communicate(stdin)
+        status_ok = (20 <= proc.returncode <= 29) if expect_error \
+            else proc.returncode == 0
+        if not status_ok:
+            print('*** ERROR: unexpected exit status %d' % proc.returncode)
             raise RuntimeError
+        compare('standard output', stdout, real_stdout)
+        if only_code:
+            # up to the tab after ICLnnnI, or all of it if there is no error
+            compare('error code', expected_stderr.split(b'\t')[0],
+                    real_stderr.split(b'\t')[0])
+        else:
+            compare('standard error', expected_stderr, real_stderr)
 
     print('')
     print('>>> Test: ' + testname)
     print('  > Step 1: interpreted')
-    check(Popen(['cargo', 'run', '-q', '--', '-Rbi', testcode],
-                stdin=PIPE, stdout=PIPE, stderr=STDOUT), True)
+    check(Popen([RICK, '-Rbi', testcode],
+                stdin=PIPE, stdout=PIPE, stderr=PIPE), warnings + stderr)
 
     print('  > Step 2: interpreted + optimized')
-    check(Popen(['cargo', 'run', '-q', '--', '-Rbio', testcode],
-                stdin=PIPE, stdout=PIPE, stderr=STDOUT), True)
+    check(Popen([RICK, '-Rbio', testcode],
+                stdin=PIPE, stdout=PIPE, stderr=PIPE), warnings + stderr)
 
     if compiled:
         print('  > Step 3: compiled + optimized')
         if testcode not in already_compiled:
-            if os.system('cargo run -q -- -RFbo %s > /dev/null' % testcode) != 0:
+            proc = Popen([RICK, '-RFbo', testcode], stdout=PIPE, stderr=PIPE)
+            _, real_warnings = proc.communicate()
+            if proc.returncode != 0:
                 print('*** ERROR: compilation failed')
+                print(real_warnings.decode('utf-8', 'replace'))
                 raise RuntimeError
+            compare('compiler warnings', warnings, real_warnings)
             already_compiled.add(testcode)
-        check(Popen([testcode[:-2]], stdin=PIPE, stdout=PIPE, stderr=STDOUT),
-              False)
+        check(Popen([testcode[:-2]], stdin=PIPE, stdout=PIPE, stderr=PIPE), stderr)
+
+    for flag, ext, what in GRAPHS:
+        if path.isfile(testname + ext):
+            print('  > Extra: ' + what)
+            with open(testname + ext, 'rb') as graphfile:
+                graph = graphfile.read()
+            real_graph, _ = Popen([RICK, flag, testcode], stdout=PIPE).communicate()
+            compare(what, graph, real_graph)
+
+    # the emitted source has to be accepted again, and do the same; only the
+    # error message can differ in its line numbers (and splats in their text)
+    for flags, what in EMITTED:
+        print('  > Extra: ' + what)
+        proc = Popen([RICK, flags, testcode], stdout=PIPE, stderr=PIPE)
+        source, errors = proc.communicate()
+        if proc.returncode != 0:
+            print('*** ERROR: writing the source failed')
+            print(errors.decode('utf-8', 'replace'))
+            raise RuntimeError
+        fd, emitted = tempfile.mkstemp(suffix='.i')
+        try:
+            with os.fdopen(fd, 'wb') as emittedfile:
+                emittedfile.write(source)
+            check(Popen([RICK, '-Rbi', '--no-warn', emitted],
+                        stdin=PIPE, stdout=PIPE, stderr=PIPE), stderr, only_code=True)
+        finally:
+            os.remove(emitted)
+
+
+def run_fuzz(seed):
+    print('')
+    print('>>> Test: random program %d' % seed)
+    tmpdir = tempfile.mkdtemp()
+    try:
+        print('  > Step 1: generated')
+        proc = Popen([RICK, 'fuzz', '--write', '--seed', str(seed), '--dir', tmpdir],
+                     stdout=PIPE, stderr=PIPE)
+        _, errors = proc.communicate()
+        if proc.returncode != 0:
+            print('*** ERROR: generating the program failed')
+            print(errors.decode('utf-8', 'replace'))
+            raise RuntimeError
+        print('  > Step 2: crosschecked')
+        name = path.join(tmpdir, 'fuzz-%d' % seed)
+        proc = Popen([RICK, 'crosscheck', name + '.i', name + '.tst'],
+                     stdout=PIPE, stderr=PIPE)
+        out, errors = proc.communicate()
+        if proc.returncode != 0:
+            print('*** ERROR: the results differ')
+            print((out + errors).decode('utf-8', 'replace'))
+            raise RuntimeError
+    finally:
+        for fn in os.listdir(tmpdir):
+            os.remove(path.join(tmpdir, fn))
+        os.rmdir(tmpdir)
+
+
+def compare(what, expected, real):
+    if real != expected:
+        print('*** ERROR: %s does not match check file' % what)
+        print(''.join(difflib.unified_diff(
+            expected.decode('utf-8', 'replace').splitlines(True),
+            real.decode('utf-8', 'replace').splitlines(True))))
+        raise RuntimeError
 
 
 def main():
@@ -78,6 +168,13 @@ def main():
     print('Building...')
     if os.system('cargo build') != 0:
         return 2
+    # rick links the lexer plugin and libstd dynamically; cargo run would set
+    # this up, but we run the binary directly
+    sysroot = check_output(['rustc', '--print', 'sysroot']).decode().strip()
+    libdirs = [path.join(sysroot, 'lib'), path.abspath(path.join('target', 'debug', 'deps'))]
+    if os.environ.get('LD_LIBRARY_PATH'):
+        libdirs.append(os.environ['LD_LIBRARY_PATH'])
+    os.environ['LD_LIBRARY_PATH'] = os.pathsep.join(libdirs)
     print('Running tests, please wait...')
     passed = 0
     total = 0
@@ -111,6 +208,18 @@ def main():
                 print('--- passed  (%5.2f sec)' % (t2 - t1))
             except RuntimeError:
                 failed.append(testname)
+    # the crosscheck compiles the random programs
+    if compile_flag and not tests:
+        for seed in FUZZ_SEEDS:
+            total += 1
+            try:
+                t1 = time.time()
+                run_fuzz(seed)
+                t2 = time.time()
+                passed += 1
+                print('--- passed  (%5.2f sec)' % (t2 - t1))
+            except RuntimeError:
+                failed.append('random program %d' % seed)
     end = time.time()
     print('')
     print('RESULT: %d/%d tests passed  (%6.2f sec)' % (passed, total, end - start))